@@ -1,37 +1,486 @@
-use std::{fs::create_dir_all, path::PathBuf, process::Command};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
 
 extern crate cc;
 
+/// The features selecting a Lua version, along with the version.
+const VERSIONS: &[(&str, &str)] = &[
+    ("LUA51", "5.1"),
+    ("LUA52", "5.2"),
+    ("LUA53", "5.3"),
+    ("LUA54", "5.4"),
+];
+
+/// The version built when no version feature is enabled.
+const DEFAULT_VERSION: &str = "5.4";
+
+/// Checks that the headers use the version and the integer and number types `lua_core` is built for.
+const CONFIG_CHECK: &str = r#"#include "lua.h"
+
+_Static_assert(LUA_VERSION_NUM == EXPECTED_VERSION_NUM, "the Lua headers are for another version than the selected feature");
+_Static_assert(sizeof(LUA_INTEGER) == EXPECTED_INTEGER_SIZE, "Lua integers don't have the size of LuaInt");
+_Static_assert(_Generic((LUA_NUMBER)0, EXPECTED_NUMBER: 1, default: 0), "Lua numbers don't have the type of LuaNum");
+
+#ifdef CHECK_DETERMINISTIC
+#include <float.h>
+_Static_assert(FLT_EVAL_METHOD == 0, "floats are evaluated with extra precision, so results depend on register allocation");
+#ifdef __FAST_MATH__
+#error "fast math reorders float operations, so results depend on the optimizer"
+#endif
+#endif
+"#;
+
+/// Compiler flags that let float results differ between builds.
+const NONDETERMINISTIC_FLAGS: [&str; 5] = [
+    "-ffast-math",
+    "-Ofast",
+    "-funsafe-math-optimizations",
+    "-ffp-contract=fast",
+    "/fp:fast",
+];
+
+/// The types of Lua integers and numbers, which `lua_core` declares as `LuaInt` and `LuaNum`.
+struct NumberTypes {
+    /// Rust type of `lua_Integer`.
+    integer: &'static str,
+    /// Rust type of `lua_Number`.
+    number: &'static str,
+}
+
+impl NumberTypes {
+    /// Returns the size of an integer in bytes.
+    fn integer_size(&self) -> &'static str {
+        match self.integer {
+            "i32" => "4",
+            _ => "8",
+        }
+    }
+
+    /// Returns the C type of a number.
+    fn c_number(&self) -> &'static str {
+        match self.number {
+            "f32" => "float",
+            _ => "double",
+        }
+    }
+}
+
 fn main() {
-    let lua_path: PathBuf = ".lua".into();
+    let version = selected_version();
+    let values: Vec<String> = VERSIONS.iter().map(|(_, v)| format!("\"{v}\"")).collect();
+    println!(
+        "cargo:rustc-check-cfg=cfg(lua_version, values({}))",
+        values.join(", ")
+    );
+    println!("cargo:rustc-cfg=lua_version=\"{version}\"");
+
+    let types = number_types(version);
+    println!("cargo:rustc-check-cfg=cfg(lua_integer, values(\"i32\", \"i64\"))");
+    println!("cargo:rustc-check-cfg=cfg(lua_number, values(\"f32\", \"f64\"))");
+    println!("cargo:rustc-cfg=lua_integer=\"{}\"", types.integer);
+    println!("cargo:rustc-cfg=lua_number=\"{}\"", types.number);
+
+    let include_dir = match env::var_os("CARGO_FEATURE_SYSTEM_LUA") {
+        Some(_) => {
+            let include_dir = link_system_lua(version);
+            if env::var_os("CARGO_FEATURE_DETERMINISTIC").is_some() {
+                println!("cargo:warning=the flags the system Lua was compiled with can't be checked for deterministic floats");
+            }
+            check_config(&include_dir, version, &types, &[]);
+            include_dir
+        }
+        None => build_vendored_lua(version, &types),
+    };
+
+    let release = read_version(&include_dir.join("lua.h"));
+    println!("cargo:rustc-env=LLUA_LUA_VERSION={release}");
+
+    // Dependents read this as DEP_LLUA_INCLUDE to compile C modules against the same headers
+    let include_dir = include_dir.canonicalize().unwrap_or(include_dir);
+    println!("cargo:include={}", include_dir.display());
+
+    if env::var_os("CARGO_FEATURE_TEST_MODULES").is_some() {
+        build_test_modules(&include_dir);
+    }
+}
+
+/// Returns the version selected by the `lua5x` features.
+fn selected_version() -> &'static str {
+    let selected: Vec<&str> = VERSIONS
+        .iter()
+        .filter(|(feature, _)| env::var_os(format!("CARGO_FEATURE_{feature}")).is_some())
+        .map(|(_, version)| *version)
+        .collect();
+
+    match selected[..] {
+        [] => DEFAULT_VERSION,
+        [version] => version,
+        _ => panic!("only one of the lua51, lua52, lua53 and lua54 features can be enabled"),
+    }
+}
+
+/// Returns the number types selected by the `int32` and `float32` features.
+/// Before Lua 5.3 they are fixed: integers are `ptrdiff_t` and numbers are `double`.
+fn number_types(version: &str) -> NumberTypes {
+    let int32 = env::var_os("CARGO_FEATURE_INT32").is_some();
+    let float32 = env::var_os("CARGO_FEATURE_FLOAT32").is_some();
+
+    if matches!(version, "5.1" | "5.2") {
+        if int32 || float32 {
+            panic!("the int32 and float32 features need Lua 5.3 or later");
+        }
+        let pointer_width = env::var("CARGO_CFG_TARGET_POINTER_WIDTH").unwrap_or_default();
+        return NumberTypes {
+            integer: if pointer_width == "32" { "i32" } else { "i64" },
+            number: "f64",
+        };
+    }
+
+    NumberTypes {
+        integer: if int32 { "i32" } else { "i64" },
+        number: if float32 { "f32" } else { "f64" },
+    }
+}
+
+/// Compiles the vendored sources, or those in `LUA_SRC_DIR`, into OUT_DIR after checking their configuration. Returns the directory with `lua.h`.
+fn build_vendored_lua(version: &str, types: &NumberTypes) -> PathBuf {
+    println!("cargo:rerun-if-env-changed=LUA_SRC_DIR");
+    let source_dir: PathBuf = match env::var_os("LUA_SRC_DIR") {
+        Some(dir) => dir.into(),
+        None => format!("vendor/lua-{version}").into(),
+    };
+    println!("cargo:rerun-if-changed={}", source_dir.display());
+
+    let integer_type = match types.integer {
+        "i32" => "LUA_INT_INT",
+        _ => "LUA_INT_LONGLONG",
+    };
+    let float_type = match types.number {
+        "f32" => "LUA_FLOAT_FLOAT",
+        _ => "LUA_FLOAT_DOUBLE",
+    };
+
+    let (source_dir, type_defines) = match version {
+        // Lua 5.3 only picks its defaults when the types are not defined already
+        "5.3" => (
+            source_dir,
+            vec![
+                ("LUA_INT_TYPE", integer_type),
+                ("LUA_FLOAT_TYPE", float_type),
+            ],
+        ),
+        // Lua 5.4 always defines the types, so they are changed in a copy of `luaconf.h`
+        "5.4" if (integer_type, float_type) != ("LUA_INT_LONGLONG", "LUA_FLOAT_DOUBLE") => (
+            configured_copy(&source_dir, integer_type, float_type),
+            Vec::new(),
+        ),
+        _ => (source_dir, Vec::new()),
+    };
+    check_config(&source_dir, version, types, &type_defines);
+
+    let mut build = cc::Build::new();
+    build.include(&source_dir).define("MAKE_LIB", "1");
+    for (name, value) in type_defines {
+        build.define(name, value);
+    }
+    if env::var_os("CARGO_FEATURE_APICHECK").is_some() {
+        build.define("LUA_USE_APICHECK", None);
+    }
+    if env::var_os("CARGO_FEATURE_DLOPEN").is_some() {
+        enable_dlopen(&mut build);
+    }
+    if env::var_os("CARGO_FEATURE_DETERMINISTIC").is_some() {
+        strict_floats(&mut build);
+    }
+
+    let one_file = source_dir.join("onelua.c");
+    if one_file.exists() {
+        build.file(one_file);
+    } else {
+        build.files(library_files(&source_dir));
+    }
+
+    build.compile("liblua");
+
+    source_dir
+}
 
-    // Clone Lua
-    if !lua_path.exists() {
-        create_dir_all(&lua_path).unwrap();
-        let lua_repo = "https://github.com/ericrobolson/lua.git";
+/// Lets `require` load C modules from shared libraries, and exports the Lua API from executables for them.
+fn enable_dlopen(build: &mut cc::Build) {
+    let target_os = env::var("CARGO_CFG_TARGET_OS").unwrap_or_default();
+    if target_os == "windows" {
+        build.define("LUA_DL_DLL", None);
+        return;
+    }
+
+    build.define("LUA_USE_DLOPEN", None);
+    if target_os == "linux" {
+        println!("cargo:rustc-link-lib=dl");
+    }
+    if target_os != "macos" {
+        println!("cargo:rustc-link-arg=-Wl,--export-dynamic");
+    }
+}
+
+/// Makes float operations round the same way on every build, failing if flags from the environment allow otherwise.
+fn strict_floats(build: &mut cc::Build) {
+    let target_arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap_or_default();
+    let target_features = env::var("CARGO_CFG_TARGET_FEATURE").unwrap_or_default();
+    if target_arch == "x86" && !target_features.split(',').any(|feature| feature == "sse2") {
+        panic!("the deterministic feature needs SSE2 on x86, as x87 floats are evaluated with extra precision");
+    }
+
+    let compiler = build.get_compiler();
+    if let Some(flag) = compiler.args().iter().find(|arg| {
+        NONDETERMINISTIC_FLAGS
+            .iter()
+            .any(|flag| arg.to_str() == Some(flag))
+    }) {
+        panic!("the deterministic feature can't be used with {flag:?} in the C flags");
+    }
+
+    // Fused multiply-adds round once instead of twice, so they change results depending on the target
+    if compiler.is_like_msvc() {
+        build.flag("/fp:precise");
+    } else {
+        build.flag_if_supported("-ffp-contract=off");
+    }
+}
 
-        let clone_output = Command::new("git")
-            .arg("clone")
-            .arg(lua_repo)
-            .arg(&lua_path)
-            .output()
-            .unwrap();
+/// Links the system Lua library. Returns the directory with `lua.h`.
+fn link_system_lua(version: &str) -> PathBuf {
+    for var in ["LUA_LIB", "LUA_LIB_NAME", "LUA_INCLUDE"] {
+        println!("cargo:rerun-if-env-changed={var}");
+    }
+
+    let include_dirs = match env::var_os("LUA_LIB") {
+        Some(lib_dir) => {
+            let name = env::var("LUA_LIB_NAME").unwrap_or_else(|_| format!("lua{version}"));
+            println!(
+                "cargo:rustc-link-search=native={}",
+                Path::new(&lib_dir).display()
+            );
+            println!("cargo:rustc-link-lib={name}");
+            Vec::new()
+        }
+        None => probe_pkg_config(version),
+    };
 
-        if !clone_output.status.success() {
-            let error = String::from_utf8(clone_output.stderr.clone()).unwrap();
+    env::var_os("LUA_INCLUDE")
+        .map(PathBuf::from)
+        .into_iter()
+        .chain(include_dirs)
+        .chain([format!("/usr/include/lua{version}").into()])
+        .chain(["/usr/local/include", "/usr/include"].map(PathBuf::from))
+        .find(|dir| dir.join("lua.h").exists())
+        .unwrap_or_else(|| panic!("cannot find lua.h for the system Lua, set LUA_INCLUDE"))
+}
+
+/// Finds the library with pkg-config, which also prints the link flags. Returns its include directories.
+/// Distributions package the library as `lua5.4`, `lua-5.4`, `lua54` or plain `lua`.
+#[cfg(feature = "system-lua")]
+fn probe_pkg_config(version: &str) -> Vec<PathBuf> {
+    let short = version.replace('.', "");
+    let names = [
+        format!("lua{version}"),
+        format!("lua-{version}"),
+        format!("lua{short}"),
+        "lua".into(),
+    ];
+    let (major, minor) = version.split_once('.').unwrap();
+    let next = format!("{major}.{}", minor.parse::<u32>().unwrap() + 1);
 
-            panic!("{error}");
+    let mut errors = Vec::new();
+    for name in &names {
+        match pkg_config::Config::new()
+            .range_version(version..next.as_str())
+            .probe(name)
+        {
+            Ok(library) => return library.include_paths,
+            Err(e) => errors.push(e.to_string()),
         }
     }
 
-    // Build Lua
-    const LUA_INT_LONGLONG: &'static str = "3";
-    const LUA_FLOAT_DOUBLE: &'static str = "2";
+    panic!(
+        "cannot find Lua {version} with pkg-config, set LUA_LIB and LUA_INCLUDE instead:\n{}",
+        errors.join("\n")
+    );
+}
+
+#[cfg(not(feature = "system-lua"))]
+fn probe_pkg_config(_version: &str) -> Vec<PathBuf> {
+    unreachable!("pkg-config is only used with the system-lua feature")
+}
+
+/// Compiles a file of static assertions against the headers, failing the build if they don't match.
+/// `type_defines` are the definitions the library is compiled with.
+fn check_config(
+    include_dir: &Path,
+    version: &str,
+    types: &NumberTypes,
+    type_defines: &[(&str, &str)],
+) {
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let check = out_dir.join("config_check.c");
+    fs::write(&check, CONFIG_CHECK).unwrap();
+    let mut defines = vec![
+        format!("EXPECTED_VERSION_NUM={}", version.replace('.', "0")),
+        format!("EXPECTED_INTEGER_SIZE={}", types.integer_size()),
+        format!("EXPECTED_NUMBER={}", types.c_number()),
+    ];
+    if env::var_os("CARGO_FEATURE_DETERMINISTIC").is_some() {
+        defines.push("CHECK_DETERMINISTIC=1".into());
+    }
+    defines.extend(
+        type_defines
+            .iter()
+            .map(|(name, value)| format!("{name}={value}")),
+    );
+
+    let compiler = cc::Build::new().get_compiler();
+    let mut command = compiler.to_command();
+    if compiler.is_like_msvc() {
+        command
+            .args(defines.iter().map(|define| format!("/D{define}")))
+            .arg(format!("/I{}", include_dir.display()))
+            .arg("/c")
+            .arg(format!("/Fo{}", out_dir.join("config_check.o").display()));
+    } else {
+        command
+            .args(defines.iter().map(|define| format!("-D{define}")))
+            .arg("-I")
+            .arg(include_dir)
+            .arg("-c")
+            .arg("-o")
+            .arg(out_dir.join("config_check.o"));
+    }
+
+    let output = command
+        .arg(&check)
+        .output()
+        .unwrap_or_else(|e| panic!("cannot run the C compiler to check the Lua headers: {e}"));
+    if !output.status.success() {
+        panic!(
+            "the Lua headers in {} don't match llua's configuration:\n{}",
+            include_dir.display(),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+}
+
+/// Copies the sources into OUT_DIR with the default integer and float types of `luaconf.h` replaced.
+fn configured_copy(source_dir: &Path, integer_type: &str, float_type: &str) -> PathBuf {
+    let copy_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("lua-src");
+    fs::create_dir_all(&copy_dir).unwrap();
+    for entry in fs::read_dir(source_dir)
+        .unwrap_or_else(|e| panic!("cannot read {}: {e}", source_dir.display()))
+    {
+        let path = entry.unwrap().path();
+        if path.is_file() {
+            fs::copy(&path, copy_dir.join(path.file_name().unwrap())).unwrap();
+        }
+    }
+
+    let config_path = copy_dir.join("luaconf.h");
+    let config = fs::read_to_string(&config_path)
+        .unwrap_or_else(|e| panic!("cannot read {}: {e}", config_path.display()));
+    let config: Vec<String> = config
+        .lines()
+        .map(
+            |line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+                ["#define", "LUA_INT_DEFAULT", _] => {
+                    format!("#define LUA_INT_DEFAULT {integer_type}")
+                }
+                ["#define", "LUA_FLOAT_DEFAULT", _] => {
+                    format!("#define LUA_FLOAT_DEFAULT {float_type}")
+                }
+                _ => line.to_string(),
+            },
+        )
+        .collect();
+    fs::write(&config_path, config.join("\n") + "\n").unwrap();
+
+    copy_dir
+}
+
+/// Compiles the C module in `tests/modules` into a static library, only referenced by the tests.
+/// With the `dlopen` feature it is also built as a shared library in `OUT_DIR/modules`.
+fn build_test_modules(include_dir: &Path) {
+    let source = Path::new("tests/modules/llua_greet.c");
+    println!("cargo:rerun-if-changed={}", source.display());
+
     cc::Build::new()
-        .file(".lua/onelua.c")
-        .include(".lua/")
-        .define("MAKE_LIB", "1")
-        .define("LUA_INT_TYPE", LUA_INT_LONGLONG)
-        .define("LUA_FLOAT_TYPE", LUA_FLOAT_DOUBLE)
-        .compile("liblua");
+        .include(include_dir)
+        .file(source)
+        .compile("llua_test_modules");
+
+    let target_family = env::var("CARGO_CFG_TARGET_FAMILY").unwrap_or_default();
+    if env::var_os("CARGO_FEATURE_DLOPEN").is_none() || target_family != "unix" {
+        return;
+    }
+
+    let modules_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("modules");
+    fs::create_dir_all(&modules_dir).unwrap();
+    let compiler = cc::Build::new().get_compiler();
+    let mut command = compiler.to_command();
+    command.args(["-shared", "-fPIC"]);
+    if env::var("CARGO_CFG_TARGET_OS").unwrap_or_default() == "macos" {
+        command.args(["-undefined", "dynamic_lookup"]);
+    }
+    let output = command
+        .arg("-I")
+        .arg(include_dir)
+        .arg(source)
+        .arg("-o")
+        .arg(modules_dir.join("llua_greet.so"))
+        .output()
+        .unwrap_or_else(|e| panic!("cannot run the C compiler to build the test module: {e}"));
+    if !output.status.success() {
+        panic!(
+            "cannot build the test module:\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+}
+
+/// Returns the exact version, such as `5.4.7`, from the definitions in `lua.h`.
+fn read_version(header_path: &Path) -> String {
+    let header = fs::read_to_string(header_path)
+        .unwrap_or_else(|e| panic!("cannot read {}: {e}", header_path.display()));
+    let define = |name: &str| {
+        header
+            .lines()
+            .filter_map(|line| line.strip_prefix("#define"))
+            .find_map(|line| {
+                let (defined, value) = line.trim().split_once(char::is_whitespace)?;
+                (defined == name).then(|| value.trim().trim_matches('"').to_string())
+            })
+    };
+
+    match define("LUA_VERSION_MAJOR") {
+        Some(major) => format!(
+            "{major}.{}.{}",
+            define("LUA_VERSION_MINOR").unwrap_or_default(),
+            define("LUA_VERSION_RELEASE").unwrap_or_default()
+        ),
+        // Lua 5.1 only defines the release as a whole, such as "Lua 5.1.5"
+        None => define("LUA_RELEASE")
+            .and_then(|release| release.strip_prefix("Lua ").map(String::from))
+            .unwrap_or_else(|| panic!("{} does not define the Lua version", header_path.display())),
+    }
+}
+
+/// Returns the C files of the library, leaving out the standalone interpreter and compiler.
+fn library_files(source_dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(source_dir)
+        .unwrap_or_else(|e| panic!("cannot read {}: {e}", source_dir.display()))
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "c"))
+        .filter(|path| !path.ends_with("lua.c") && !path.ends_with("luac.c"))
+        .collect();
+    files.sort();
+
+    files
 }
@@ -0,0 +1,27 @@
+use llua::{dap::Debugger, *};
+
+/// Runs a script under the debugger.
+/// Speaks over stdio, or waits for a client on the given port.
+fn main() -> Result<(), Error> {
+    let mut args = std::env::args().skip(1);
+    let path = args.next().expect("usage: dap <script.lua> [port]");
+    let code = std::fs::read_to_string(&path).unwrap();
+
+    let mut m = Lua::new();
+    m.activate(Library::all())?;
+
+    match args.next() {
+        Some(port) => {
+            let debugger = Debugger::listen(&mut m, ("127.0.0.1", port.parse().unwrap())).unwrap();
+            let result = m.interpret_named(&path, &code);
+            debugger.detach(&mut m).unwrap();
+            result
+        }
+        None => {
+            let debugger = Debugger::stdio(&mut m).unwrap();
+            let result = m.interpret_named(&path, &code);
+            debugger.detach(&mut m).unwrap();
+            result
+        }
+    }
+}
@@ -9,9 +9,8 @@ pub fn multiplication(state: State) -> Int {
 
     stack.push(n.into());
 
-    let num_return_values = 1;
-
-    num_return_values
+    // Number of return values
+    1
 }
 
 fn main() -> Result<(), Error> {
@@ -1,6 +1,6 @@
 use llua::*;
 
-const CODE: &'static str = "
+const CODE: &str = "
 function my_func()
     print(\"Hello from lua!\")
 end
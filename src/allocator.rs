@@ -0,0 +1,190 @@
+extern crate alloc;
+
+use alloc::boxed::Box;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ffi::c_void,
+    ptr::null_mut,
+};
+
+/// Alignment of every block handed to Lua, large enough for any type Lua stores.
+const ALIGN: usize = 16;
+
+/// Allocator that forwards to the Rust global allocator.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RustAllocator;
+unsafe impl GlobalAlloc for RustAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        alloc::alloc::alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        alloc::alloc::dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        alloc::alloc::realloc(ptr, layout, new_size)
+    }
+}
+
+/// Allocator used by a Lua state, which tracks and limits how much memory it uses.
+pub(crate) struct Allocator {
+    allocator: Box<dyn GlobalAlloc>,
+    /// Maximum number of bytes the state may use.
+    limit: Option<usize>,
+    /// Number of bytes currently in use.
+    used: usize,
+    /// Largest number of bytes in use at once.
+    peak: usize,
+}
+impl Allocator {
+    /// Creates a new allocator.
+    pub(crate) fn new<A: GlobalAlloc + 'static>(allocator: A) -> Self {
+        Self {
+            allocator: Box::new(allocator),
+            limit: None,
+            used: 0,
+            peak: 0,
+        }
+    }
+
+    /// Returns the number of bytes currently in use.
+    pub(crate) fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Returns the largest number of bytes in use at once.
+    pub(crate) fn peak(&self) -> usize {
+        self.peak
+    }
+
+    /// Sets the maximum number of bytes the state may use.
+    pub(crate) fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+    }
+
+    /// Reallocates a block, following the semantics of `lua_Alloc`.
+    unsafe fn reallocate(&mut self, ptr: *mut u8, old_size: usize, new_size: usize) -> *mut u8 {
+        // When there is no block, the old size is a type tag instead.
+        let old_size = if ptr.is_null() { 0 } else { old_size };
+
+        if new_size == 0 {
+            if !ptr.is_null() {
+                self.allocator.dealloc(ptr, layout(old_size));
+                self.used -= old_size;
+            }
+
+            return null_mut();
+        }
+
+        // Sizes too large for a layout can't be allocated
+        let Ok(new_layout) = Layout::from_size_align(new_size, ALIGN) else {
+            return null_mut();
+        };
+
+        // Only growing is limited, so Lua can always free memory.
+        if new_size > old_size {
+            if let Some(limit) = self.limit {
+                if self.used + (new_size - old_size) > limit {
+                    return null_mut();
+                }
+            }
+        }
+
+        let block = if ptr.is_null() {
+            self.allocator.alloc(new_layout)
+        } else {
+            self.allocator.realloc(ptr, layout(old_size), new_size)
+        };
+
+        if !block.is_null() {
+            self.used = self.used - old_size + new_size;
+            self.peak = self.peak.max(self.used);
+        }
+
+        block
+    }
+}
+
+/// Returns the layout of an allocated block of the given size.
+/// Its size was checked to make a valid layout when the block was allocated.
+fn layout(size: usize) -> Layout {
+    unsafe { Layout::from_size_align_unchecked(size, ALIGN) }
+}
+
+/// The `lua_Alloc` function given to Lua states, with an `Allocator` as the user data.
+pub(crate) unsafe extern "C" fn allocate(
+    ud: *mut c_void,
+    ptr: *mut c_void,
+    old_size: usize,
+    new_size: usize,
+) -> *mut c_void {
+    let allocator = &mut *(ud as *mut Allocator);
+    allocator.reallocate(ptr as *mut u8, old_size, new_size) as *mut c_void
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reallocate_tracks_usage() {
+        let mut a = Allocator::new(RustAllocator);
+        unsafe {
+            let block = a.reallocate(null_mut(), 0, 100);
+            assert_eq!(100, a.used());
+
+            let block = a.reallocate(block, 100, 300);
+            assert_eq!(300, a.used());
+
+            let block = a.reallocate(block, 300, 50);
+            assert_eq!(50, a.used());
+            assert_eq!(300, a.peak());
+
+            a.reallocate(block, 50, 0);
+        }
+
+        assert_eq!(0, a.used());
+        assert_eq!(300, a.peak());
+    }
+
+    #[test]
+    fn reallocate_null_ignores_type_tag() {
+        let mut a = Allocator::new(RustAllocator);
+        unsafe {
+            let block = a.reallocate(null_mut(), 5, 10);
+            assert_eq!(10, a.used());
+            a.reallocate(block, 10, 0);
+        }
+    }
+
+    #[test]
+    fn reallocate_too_large_returns_null() {
+        let mut a = Allocator::new(RustAllocator);
+        unsafe {
+            assert!(a.reallocate(null_mut(), 0, usize::MAX).is_null());
+
+            let block = a.reallocate(null_mut(), 0, 10);
+            assert!(a.reallocate(block, 10, isize::MAX as usize).is_null());
+            assert_eq!(10, a.used());
+            a.reallocate(block, 10, 0);
+        }
+    }
+
+    #[test]
+    fn reallocate_over_limit_returns_null() {
+        let mut a = Allocator::new(RustAllocator);
+        a.set_limit(Some(100));
+        unsafe {
+            let block = a.reallocate(null_mut(), 0, 80);
+            assert!(!block.is_null());
+
+            assert!(a.reallocate(block, 80, 120).is_null());
+            assert_eq!(80, a.used());
+
+            let block = a.reallocate(block, 80, 40);
+            assert!(!block.is_null());
+            a.reallocate(block, 40, 0);
+        }
+    }
+}
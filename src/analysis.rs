@@ -0,0 +1,377 @@
+extern crate alloc;
+
+use crate::{
+    bytecode::{
+        Constant, Instruction, Prototype, OP_ADDI, OP_CLOSURE, OP_EQK, OP_FORLOOP, OP_FORPREP,
+        OP_GETFIELD, OP_GETTABUP, OP_GETUPVAL, OP_JMP, OP_LOADK, OP_MMBINI, OP_MMBINK, OP_SELF,
+        OP_SETFIELD, OP_SETTABUP, OP_SETUPVAL, OP_SHLI, OP_TFORLOOP, OP_TFORPREP,
+    },
+    lua_core::Int,
+};
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+    vec,
+    vec::Vec,
+};
+
+/// Opcodes that only matter for following registers.
+const OP_MOVE: u8 = 0;
+const OP_LOADKX: u8 = 4;
+const OP_LOADNIL: u8 = 8;
+const OP_GETTABLE: u8 = 12;
+const OP_GETI: u8 = 13;
+const OP_SETTABLE: u8 = 16;
+const OP_SETI: u8 = 17;
+const OP_ADD: u8 = 34;
+const OP_SHR: u8 = 45;
+const OP_MMBIN: u8 = 46;
+const OP_UNM: u8 = 49;
+const OP_LEN: u8 = 52;
+const OP_CONCAT: u8 = 53;
+const OP_CLOSE: u8 = 54;
+const OP_TBC: u8 = 55;
+const OP_EQ: u8 = 57;
+const OP_LE: u8 = 59;
+const OP_TEST: u8 = 66;
+const OP_TESTSET: u8 = 67;
+const OP_CALL: u8 = 68;
+const OP_TAILCALL: u8 = 69;
+const OP_RETURN: u8 = 70;
+const OP_RETURN1: u8 = 72;
+const OP_TFORCALL: u8 = 76;
+const OP_SETLIST: u8 = 78;
+const OP_VARARG: u8 = 80;
+const OP_VARARGPREP: u8 = 81;
+const OP_EXTRAARG: u8 = 82;
+
+/// The highest register a function can use.
+const MAX_REGISTER: u32 = 255;
+
+/// The globals a chunk reads and writes, found from its bytecode without running it.
+/// Each name maps to the lines it is used on, which are empty for stripped chunks.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct GlobalUsage {
+    reads: BTreeMap<String, BTreeSet<Int>>,
+    writes: BTreeMap<String, BTreeSet<Int>>,
+    /// Lines where the global environment is used in a way that can't be followed.
+    unanalyzable: Option<BTreeSet<Int>>,
+}
+impl GlobalUsage {
+    /// Finds the globals used by a binary chunk, returning `None` if it isn't a valid Lua 5.4 chunk.
+    pub fn from_bytecode(chunk: &[u8]) -> Option<Self> {
+        let main = Prototype::parse(chunk)?;
+        let mut usage = Self::default();
+        // The main function's only upvalue is the global environment
+        usage.add_function(&main, &[0]);
+
+        Some(usage)
+    }
+
+    /// Returns the globals that are read, with the lines they are read on.
+    pub fn reads(&self) -> &BTreeMap<String, BTreeSet<Int>> {
+        &self.reads
+    }
+
+    /// Returns the globals that are written, with the lines they are written on.
+    pub fn writes(&self) -> &BTreeMap<String, BTreeSet<Int>> {
+        &self.writes
+    }
+
+    /// Returns the lines where the global environment is used in a way that can't be followed, such as being passed to a function or captured by one.
+    /// The chunk may then use globals that aren't listed. Returns `None` if every use was followed.
+    pub fn unanalyzable(&self) -> Option<&BTreeSet<Int>> {
+        self.unanalyzable.as_ref()
+    }
+
+    /// Returns whether the global is read or written.
+    pub fn uses(&self, name: &str) -> bool {
+        self.reads.contains_key(name) || self.writes.contains_key(name)
+    }
+
+    /// Returns the globals that are read but never written by the chunk, leaving out the given known names.
+    pub fn undefined<'a>(&'a self, known: &[&str]) -> Vec<(&'a str, &'a BTreeSet<Int>)> {
+        self.reads
+            .iter()
+            .filter(|(name, _)| !self.writes.contains_key(*name) && !known.contains(&name.as_str()))
+            .map(|(name, lines)| (name.as_str(), lines))
+            .collect()
+    }
+
+    /// Adds the globals used by the function and the functions nested in it.
+    /// `env` holds the indices of the upvalues that are the global environment.
+    fn add_function(&mut self, function: &Prototype, env: &[u8]) {
+        let lines = function.lines();
+        let key = |index: u32| match function.constants.get(index as usize) {
+            Some(Constant::String(name)) => Some(name.clone()),
+            _ => None,
+        };
+
+        // Registers holding the global environment, and those holding constant keys
+        let mut env_registers = BTreeSet::new();
+        let mut keys = BTreeMap::new();
+
+        for (pc, instruction) in function.code.iter().enumerate() {
+            let i = *instruction;
+            let line = lines.get(pc).copied();
+            let mut read = registers_read(i);
+            let mut access = None;
+
+            match i.opcode() {
+                OP_GETTABUP if env.contains(&(i.b() as u8)) => {
+                    access = Some((false, key(i.c())));
+                }
+                OP_SETTABUP if env.contains(&(i.a() as u8)) => {
+                    access = Some((true, key(i.b())));
+                }
+                OP_GETFIELD if env_registers.contains(&i.b()) => {
+                    read.retain(|r| *r != i.b());
+                    access = Some((false, key(i.c())));
+                }
+                OP_GETTABLE if env_registers.contains(&i.b()) => {
+                    read.retain(|r| *r != i.b() && *r != i.c());
+                    access = Some((false, keys.get(&i.c()).cloned()));
+                }
+                OP_SETFIELD if env_registers.contains(&i.a()) => {
+                    read.retain(|r| *r != i.a());
+                    access = Some((true, key(i.b())));
+                }
+                OP_SETTABLE if env_registers.contains(&i.a()) => {
+                    read.retain(|r| *r != i.a() && *r != i.b());
+                    access = Some((true, keys.get(&i.b()).cloned()));
+                }
+                // Replacing the environment changes what every later access refers to
+                OP_SETUPVAL if env.contains(&(i.b() as u8)) => self.add_unanalyzable(line),
+                OP_CLOSURE => {
+                    let captured = function
+                        .prototypes
+                        .get(i.bx() as usize)
+                        .is_some_and(|nested| {
+                            nested
+                                .upvalues
+                                .iter()
+                                .any(|u| u.in_stack && env_registers.contains(&(u.index as u32)))
+                        });
+                    if captured {
+                        self.add_unanalyzable(line);
+                    }
+                }
+                _ => {}
+            }
+
+            if read.iter().any(|r| env_registers.contains(r)) {
+                self.add_unanalyzable(line);
+            }
+            match access {
+                // Fields of the environment that aren't constant strings, such as `_ENV[name]`
+                Some((_, None)) if i.opcode() == OP_GETTABLE || i.opcode() == OP_SETTABLE => {
+                    self.add_unanalyzable(line)
+                }
+                Some((write, Some(name))) => {
+                    let map = if write {
+                        &mut self.writes
+                    } else {
+                        &mut self.reads
+                    };
+                    let used = map.entry(name).or_default();
+                    used.extend(line);
+                }
+                _ => {}
+            }
+
+            let (first, last) = registers_written(i);
+            env_registers.retain(|r| !(first..=last).contains(r));
+            keys.retain(|r, _| !(first..=last).contains(r));
+            match i.opcode() {
+                OP_GETUPVAL if env.contains(&(i.b() as u8)) => {
+                    env_registers.insert(i.a());
+                }
+                OP_LOADK => {
+                    if let Some(name) = key(i.bx()) {
+                        keys.insert(i.a(), name);
+                    }
+                }
+                OP_LOADKX => {
+                    let index = function.code.get(pc + 1).map(|extra| extra.ax());
+                    if let Some(name) = index.and_then(key) {
+                        keys.insert(i.a(), name);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for nested in &function.prototypes {
+            // Follow the environment through the upvalues of the enclosing function
+            let nested_env: Vec<u8> = nested
+                .upvalues
+                .iter()
+                .enumerate()
+                .filter(|(_, upvalue)| !upvalue.in_stack && env.contains(&upvalue.index))
+                .map(|(i, _)| i as u8)
+                .collect();
+            self.add_function(nested, &nested_env);
+        }
+    }
+
+    /// Marks the line as using the environment in a way that can't be followed.
+    fn add_unanalyzable(&mut self, line: Option<Int>) {
+        self.unanalyzable
+            .get_or_insert_with(BTreeSet::new)
+            .extend(line);
+    }
+}
+
+/// Returns the registers an instruction reads.
+fn registers_read(i: Instruction) -> Vec<u32> {
+    let (a, b, c) = (i.a(), i.b(), i.c());
+    // The value operand of some instructions is a constant rather than a register
+    let value = if i.k() { None } else { Some(c) };
+    // A count of 0 means up to the top of the stack
+    let span = |first: u32, count: Option<u32>| match count {
+        Some(count) => (first..first + count).collect(),
+        None => (first..=MAX_REGISTER).collect(),
+    };
+    let count = |operand: u32, extra: u32| match operand {
+        0 => None,
+        operand => Some(operand - extra),
+    };
+
+    match i.opcode() {
+        OP_MOVE | OP_GETI | OP_GETFIELD | OP_ADDI..=OP_SHLI | OP_UNM..=OP_LEN | OP_TESTSET => {
+            vec![b]
+        }
+        OP_GETTABLE | OP_ADD..=OP_SHR => vec![b, c],
+        OP_SELF => [Some(b), value].into_iter().flatten().collect(),
+        OP_SETTABUP => value.into_iter().collect(),
+        OP_SETTABLE => [Some(a), Some(b), value].into_iter().flatten().collect(),
+        OP_SETI | OP_SETFIELD => [Some(a), value].into_iter().flatten().collect(),
+        OP_SETUPVAL | OP_MMBINI | OP_MMBINK | OP_TBC | OP_EQK..=OP_TEST | OP_RETURN1 => vec![a],
+        OP_MMBIN | OP_EQ..=OP_LE => vec![a, b],
+        OP_CONCAT => span(a, Some(b)),
+        // The function and its arguments
+        OP_CALL | OP_TAILCALL => span(a, count(b, 0)),
+        OP_RETURN => span(a, count(b, 1)),
+        OP_SETLIST => span(a, count(b, 0).map(|b| b + 1)),
+        OP_FORPREP | OP_FORLOOP => span(a, Some(3)),
+        OP_TFORPREP | OP_TFORCALL | OP_TFORLOOP => span(a, Some(5)),
+        _ => Vec::new(),
+    }
+}
+
+/// Returns the first and last registers an instruction may write, which are empty when the first is after the last.
+fn registers_written(i: Instruction) -> (u32, u32) {
+    let a = i.a();
+    match i.opcode() {
+        OP_SETTABUP..=OP_SETFIELD
+        | OP_SETUPVAL
+        | OP_MMBIN..=OP_MMBINK
+        | OP_CLOSE
+        | OP_TBC
+        | OP_JMP..=OP_TEST
+        | OP_RETURN..=OP_RETURN1
+        | OP_TFORPREP
+        | OP_SETLIST
+        | OP_VARARGPREP
+        | OP_EXTRAARG => (1, 0),
+        OP_CALL | OP_TAILCALL | OP_VARARG => (a, MAX_REGISTER),
+        OP_TFORCALL => (a + 4, MAX_REGISTER),
+        OP_LOADNIL => (a, a + i.b()),
+        OP_SELF => (a, a + 1),
+        OP_FORPREP | OP_FORLOOP => (a, a + 3),
+        OP_TFORLOOP => (a + 2, a + 2),
+        _ => (a, a),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Lua;
+
+    fn analyze(code: &str) -> GlobalUsage {
+        Lua::new().analyze_globals("mod.lua", code).unwrap()
+    }
+
+    #[test]
+    fn reads_and_writes_have_lines() {
+        let usage =
+            analyze("local x = os.time()\nresult = x\n\nfunction update()\n  print(result)\nend");
+
+        assert_eq!(Some(&BTreeSet::from([1])), usage.reads().get("os"));
+        assert_eq!(Some(&BTreeSet::from([5])), usage.reads().get("print"));
+        assert_eq!(Some(&BTreeSet::from([5])), usage.reads().get("result"));
+        assert_eq!(Some(&BTreeSet::from([2])), usage.writes().get("result"));
+        assert_eq!(Some(&BTreeSet::from([4])), usage.writes().get("update"));
+        assert!(!usage.uses("io"));
+    }
+
+    #[test]
+    fn locals_and_fields_are_not_globals() {
+        let usage =
+            analyze("local t = {}\nt.field = 1\nlocal function f() return t.field end\nreturn f()");
+
+        assert_eq!(GlobalUsage::default(), usage);
+    }
+
+    #[test]
+    fn local_env_is_not_global() {
+        let usage = analyze("local _ENV = { print = 1 }\nx = print");
+
+        assert!(!usage.uses("x"));
+        assert!(!usage.uses("print"));
+    }
+
+    #[test]
+    fn many_constants_use_env_register() {
+        // Past 255 constants, globals are read and written through _ENV loaded into a register
+        let constants: Vec<String> = (0..300).map(|i| format!("'c{i}'")).collect();
+        let code = format!(
+            "local t = {{{}}}\nos.exit(1)\nresult = t",
+            constants.join(", ")
+        );
+        let usage = analyze(&code);
+
+        assert_eq!(Some(&BTreeSet::from([2])), usage.reads().get("os"));
+        assert_eq!(Some(&BTreeSet::from([3])), usage.writes().get("result"));
+        assert_eq!(None, usage.unanalyzable());
+    }
+
+    #[test]
+    fn env_in_local_is_followed() {
+        let usage = analyze("local e = _ENV\ne.os.exit(1)\ne.done = true");
+
+        assert_eq!(Some(&BTreeSet::from([2])), usage.reads().get("os"));
+        assert_eq!(Some(&BTreeSet::from([3])), usage.writes().get("done"));
+        assert_eq!(None, usage.unanalyzable());
+    }
+
+    #[test]
+    fn env_passed_moved_or_captured_is_unanalyzable() {
+        let usage = analyze(
+            "local e = _ENV\nprint(e)\nlocal f = e\nlocal function g() return e end\nlocal k = 'x'\nreturn e[k .. 'y']",
+        );
+
+        assert_eq!(Some(&BTreeSet::from([2, 3, 4, 6])), usage.unanalyzable());
+    }
+
+    #[test]
+    fn undefined_leaves_out_written_and_known() {
+        let usage = analyze("count = 0\nfunction f() count = count + step print(count) end");
+
+        assert_eq!(
+            vec![("step", &BTreeSet::from([2]))],
+            usage.undefined(&["print"])
+        );
+    }
+
+    #[test]
+    fn from_bytecode_stripped_has_no_lines() {
+        let chunk = Lua::new().compile("mod.lua", "io.write(x)", true).unwrap();
+        let usage = GlobalUsage::from_bytecode(&chunk).unwrap();
+
+        assert_eq!(Some(&BTreeSet::new()), usage.reads().get("io"));
+        assert!(usage.uses("x"));
+        assert_eq!(None, GlobalUsage::from_bytecode(b"io.write(x)"));
+    }
+}
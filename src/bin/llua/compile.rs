@@ -0,0 +1,136 @@
+//! The `llua compile` and `llua list` commands, which work like `luac`.
+
+use crate::run::{message, read_script};
+use llua::{disassemble, Lua};
+use std::{fs, process::ExitCode};
+
+const COMPILE_USAGE: &str = "usage: llua compile [-s] [-o output] script
+
+Compiles a script to bytecode without running it.
+  -s          strip debug information
+  -o output   write to 'output' instead of luac.out";
+
+const LIST_USAGE: &str = "usage: llua list file
+
+Lists the bytecode of a script or of a compiled chunk.";
+
+/// Marks the start of a binary chunk.
+const SIGNATURE: &[u8] = b"\x1bLua";
+
+/// The parsed `compile` options.
+#[derive(PartialEq, Debug)]
+struct Options {
+    strip: bool,
+    output: String,
+    script: String,
+}
+impl Options {
+    fn parse(args: Vec<String>) -> Result<Self, String> {
+        let mut strip = false;
+        let mut output = "luac.out".to_string();
+        let mut script = None;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-s" => strip = true,
+                "-o" => output = args.next().ok_or("'-o' needs an argument")?,
+                arg if arg.starts_with('-') => return Err(format!("unrecognized option '{arg}'")),
+                _ if script.is_some() => return Err("only one script can be compiled".into()),
+                _ => script = Some(arg),
+            }
+        }
+
+        Ok(Options {
+            strip,
+            output,
+            script: script.ok_or("no script given")?,
+        })
+    }
+}
+
+/// Compiles a script to its bytecode.
+fn compile(path: &str, strip: bool) -> Result<Vec<u8>, String> {
+    let code = read_script(path)?;
+    Lua::new().compile(path, &code, strip).map_err(message)
+}
+
+/// Returns the listing of a script or compiled chunk.
+fn list(path: &str) -> Result<String, String> {
+    let bytes = fs::read(path).map_err(|e| format!("cannot open {path}: {e}"))?;
+    let chunk = match bytes.starts_with(SIGNATURE) {
+        true => bytes,
+        false => compile(path, false)?,
+    };
+
+    disassemble(&chunk).ok_or(format!("{path}: not a Lua 5.4 chunk"))
+}
+
+/// Compiles the script given on the command line.
+pub fn run(args: Vec<String>) -> ExitCode {
+    let options = match Options::parse(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("llua compile: {e}\n{COMPILE_USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let written = compile(&options.script, options.strip).and_then(|chunk| {
+        fs::write(&options.output, chunk)
+            .map_err(|e| format!("cannot write {}: {e}", options.output))
+    });
+    match written {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("llua compile: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Prints the listing of the file given on the command line.
+pub fn run_list(args: Vec<String>) -> ExitCode {
+    let [path] = args.as_slice() else {
+        eprintln!("{LIST_USAGE}");
+        return ExitCode::FAILURE;
+    };
+
+    match list(path) {
+        Ok(listing) => {
+            print!("{listing}");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("llua list: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(a: &[&str]) -> Vec<String> {
+        a.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_reads_options() {
+        assert_eq!(
+            Ok(Options {
+                strip: true,
+                output: "out.luac".into(),
+                script: "main.lua".into()
+            }),
+            Options::parse(args(&["-s", "-o", "out.luac", "main.lua"]))
+        );
+        assert_eq!(
+            Ok("luac.out".to_string()),
+            Options::parse(args(&["main.lua"])).map(|o| o.output)
+        );
+        assert!(Options::parse(args(&[])).is_err());
+        assert!(Options::parse(args(&["a.lua", "b.lua"])).is_err());
+    }
+}
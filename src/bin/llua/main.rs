@@ -0,0 +1,47 @@
+//! The `llua` command line tool.
+
+mod compile;
+mod repl;
+mod run;
+mod test;
+
+use llua::Lua;
+use std::{env, process::ExitCode};
+
+const USAGE: &str = "usage: llua [command] [args]
+
+commands:
+  compile compile a script to bytecode like luac
+  list    list the bytecode of a script or compiled chunk
+  run     run a script like the lua executable
+  test    run Lua test files
+
+Without a command, starts an interactive prompt.";
+
+fn main() -> ExitCode {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("compile") => compile::run(args.split_off(1)),
+        Some("list") => compile::run_list(args.split_off(1)),
+        Some("run") => run::run(args.split_off(1)),
+        Some("test") => test::run(args.split_off(1)),
+        Some("-h" | "--help") => {
+            println!("{USAGE}");
+            ExitCode::SUCCESS
+        }
+        None => repl::run(args),
+        Some(arg) if arg.starts_with('-') => repl::run(args),
+        Some(_) => {
+            eprintln!("{USAGE}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Makes Ctrl-C interrupt the code running on the state.
+fn handle_interrupts(lua: &mut Lua) {
+    let interrupt = lua.interrupt_handle();
+    if let Err(e) = ctrlc::set_handler(move || interrupt.interrupt()) {
+        eprintln!("llua: {e}");
+    }
+}
@@ -0,0 +1,297 @@
+//! The interactive `llua` prompt.
+
+use llua::{Error, Library, Lua};
+use rustyline::{
+    completion::Completer, error::ReadlineError, highlight::Highlighter, hint::Hinter,
+    history::DefaultHistory, validate::Validator, Context, Editor, Helper,
+};
+use std::{cell::RefCell, env, path::PathBuf, process::ExitCode, rc::Rc};
+
+const USAGE: &str = "usage: llua [-l library]... [--no-libs]
+
+Starts an interactive prompt. Every library is activated unless some are given with -l.
+Libraries: base, coroutine, package, string, utf8, table, math, io, os, debug";
+
+/// Name of the chunks typed at the prompt, used in error messages.
+const CHUNK_NAME: &str = "stdin";
+
+/// Calls `print` with the values of the expression that follows, if there are any.
+const PRINT_PREFIX: &str =
+    "(function(...) if print and select and select('#', ...) > 0 then print(...) end end)(";
+
+/// The result of evaluating input.
+#[derive(PartialEq, Debug)]
+enum Evaluated {
+    Done,
+    /// The input is the start of a chunk, so more lines are needed.
+    Incomplete,
+}
+
+/// Runs the input, printing the values if it is an expression.
+fn evaluate(lua: &mut Lua, code: &str) -> Result<Evaluated, Error> {
+    // The closing parenthesis is on its own line so comments can't hide it
+    let printed = format!("{PRINT_PREFIX}{code}\n)");
+    if lua.check_syntax(CHUNK_NAME, &printed).is_ok() {
+        lua.interpret_named(CHUNK_NAME, &printed)?;
+        return Ok(Evaluated::Done);
+    }
+
+    match lua.check_syntax(CHUNK_NAME, code) {
+        // Lua 5.1 quotes the end of the input, as in "near '<eof>'"
+        Err(Error::Runtime(message))
+            if message.ends_with("<eof>") || message.ends_with("'<eof>'") =>
+        {
+            Ok(Evaluated::Incomplete)
+        }
+        Err(e) => Err(e),
+        Ok(()) => {
+            lua.interpret_named(CHUNK_NAME, code)?;
+            Ok(Evaluated::Done)
+        }
+    }
+}
+
+/// Returns the library with the given name.
+fn parse_library(name: &str) -> Option<Library> {
+    let name = match name {
+        "base" => "_G",
+        name => name,
+    };
+
+    Library::all().iter().copied().find(|l| l.name() == name)
+}
+
+/// Returns the libraries selected by the command line arguments.
+fn parse_args(args: Vec<String>) -> Result<Vec<Library>, String> {
+    let mut libraries = None;
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-l" | "--lib" => {
+                let name = args.next().ok_or("-l needs a library name")?;
+                let library = parse_library(&name).ok_or(format!("unknown library {name}"))?;
+                libraries.get_or_insert_with(Vec::new).push(library);
+            }
+            "--no-libs" => libraries = Some(Vec::new()),
+            arg => return Err(format!("unknown option {arg}")),
+        }
+    }
+
+    Ok(libraries.unwrap_or_else(|| Library::all().to_vec()))
+}
+
+/// Splits the word being completed into the fields leading to its table and the start of the key.
+/// Returns the position the key starts at along with them.
+fn completion_target(line: &str, pos: usize) -> (usize, Vec<&str>, &str) {
+    let before = &line[..pos];
+    let start = before
+        .rfind(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.' || c == ':'))
+        .map_or(0, |i| i + 1);
+    let word = &before[start..];
+
+    match word.rfind(['.', ':']) {
+        Some(i) => (
+            start + i + 1,
+            word[..i].split(['.', ':']).collect(),
+            &word[i + 1..],
+        ),
+        None => (start, Vec::new(), word),
+    }
+}
+
+/// Completes globals and table fields from the state.
+struct LuaHelper {
+    lua: Rc<RefCell<Lua>>,
+}
+impl Completer for LuaHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let (start, path, prefix) = completion_target(line, pos);
+        let keys = self.lua.borrow().table_keys(&path).unwrap_or_default();
+        let candidates = keys.into_iter().filter(|k| k.starts_with(prefix)).collect();
+
+        Ok((start, candidates))
+    }
+}
+impl Hinter for LuaHelper {
+    type Hint = String;
+}
+impl Highlighter for LuaHelper {}
+impl Validator for LuaHelper {}
+impl Helper for LuaHelper {}
+
+/// Returns where the history is kept between sessions.
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".llua_history"))
+}
+
+/// Starts the prompt with the libraries selected by the arguments.
+pub fn run(args: Vec<String>) -> ExitCode {
+    let libraries = match parse_args(args) {
+        Ok(libraries) => libraries,
+        Err(e) => {
+            eprintln!("llua: {e}\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut lua = Lua::new();
+    if let Err(e) = lua.activate(&libraries) {
+        eprintln!("llua: {e:?}");
+        return ExitCode::FAILURE;
+    }
+
+    // Ctrl-C only reaches the handler while code runs, as the editor reads it as a key
+    crate::handle_interrupts(&mut lua);
+
+    interact(lua)
+}
+
+/// Runs the prompt on the state until the input ends.
+pub fn interact(lua: Lua) -> ExitCode {
+    let lua = Rc::new(RefCell::new(lua));
+    let mut editor: Editor<LuaHelper, DefaultHistory> = match Editor::new() {
+        Ok(editor) => editor,
+        Err(e) => {
+            eprintln!("llua: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    editor.set_helper(Some(LuaHelper { lua: lua.clone() }));
+    if let Some(path) = history_path() {
+        let _ = editor.load_history(&path);
+    }
+
+    let mut input = String::new();
+    loop {
+        let prompt = match input.is_empty() {
+            true => "> ",
+            false => ">> ",
+        };
+
+        match editor.readline(prompt) {
+            Ok(line) => {
+                if !input.is_empty() {
+                    input.push('\n');
+                }
+                input.push_str(&line);
+            }
+            Err(ReadlineError::Interrupted) => {
+                input.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("llua: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+
+        match evaluate(&mut lua.borrow_mut(), &input) {
+            Ok(Evaluated::Incomplete) => continue,
+            Ok(Evaluated::Done) => {}
+            Err(Error::Runtime(message)) => eprintln!("{message}"),
+            Err(Error::Interrupted) => eprintln!("interrupted"),
+            Err(e) => eprintln!("{e:?}"),
+        }
+
+        let _ = editor.add_history_entry(input.as_str());
+        input.clear();
+    }
+
+    if let Some(path) = history_path() {
+        let _ = editor.save_history(&path);
+    }
+
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use llua::Data;
+
+    #[test]
+    fn evaluate_incomplete_returns_incomplete() {
+        let mut lua = Lua::new();
+
+        assert_eq!(
+            Ok(Evaluated::Incomplete),
+            evaluate(&mut lua, "function f()")
+        );
+        assert_eq!(Ok(Evaluated::Incomplete), evaluate(&mut lua, "x = {"));
+        assert_eq!(
+            Ok(Evaluated::Done),
+            evaluate(&mut lua, "function f()\n  return 1\nend")
+        );
+    }
+
+    #[test]
+    fn evaluate_runs_statements_once() {
+        let mut lua = Lua::new();
+        lua.interpret("count = 0 function bump() count = count + 1 end")
+            .unwrap();
+
+        assert_eq!(Ok(Evaluated::Done), evaluate(&mut lua, "bump()"));
+        assert_eq!(Ok(Evaluated::Done), evaluate(&mut lua, "x = 1"));
+        assert_eq!(Ok(Data::Number(1.0)), lua.get_global("count"));
+        assert_eq!(Ok(Data::Number(1.0)), lua.get_global("x"));
+    }
+
+    #[test]
+    fn evaluate_prints_expressions() {
+        let mut lua = Lua::new();
+        lua.interpret("function print(...) printed = select('#', ...) end function select(n, ...) return #{...} end")
+            .unwrap();
+
+        evaluate(&mut lua, "1, 2").unwrap();
+        assert_eq!(Ok(Data::Number(2.0)), lua.get_global("printed"));
+    }
+
+    #[test]
+    fn evaluate_error_returns_message() {
+        let mut lua = Lua::new();
+
+        assert_eq!(
+            Err(Error::Runtime("stdin:1: unexpected symbol near '1'".into())),
+            evaluate(&mut lua, "1 x")
+        );
+        assert!(matches!(
+            evaluate(&mut lua, "nil + 1"),
+            Err(Error::Runtime(_))
+        ));
+    }
+
+    #[test]
+    fn parse_args_selects_libraries() {
+        let args = |a: &[&str]| a.iter().map(|s| s.to_string()).collect();
+
+        assert_eq!(Ok(Library::all().to_vec()), parse_args(args(&[])));
+        assert_eq!(
+            Ok(vec![Library::Basic, Library::Math]),
+            parse_args(args(&["-l", "base", "--lib", "math"]))
+        );
+        assert_eq!(Ok(vec![]), parse_args(args(&["--no-libs"])));
+        assert!(parse_args(args(&["-l", "nope"])).is_err());
+    }
+
+    #[test]
+    fn completion_target_splits_fields() {
+        assert_eq!((0, vec![], "pri"), completion_target("pri", 3));
+        assert_eq!(
+            (11, vec!["string"], "fo"),
+            completion_target("x = string.fo", 13)
+        );
+        assert_eq!(
+            (13, vec!["game", "player"], ""),
+            completion_target("(game.player:", 13)
+        );
+    }
+}
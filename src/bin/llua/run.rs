@@ -0,0 +1,450 @@
+//! The `llua run` command, which behaves like the reference `lua` executable.
+
+use crate::repl;
+use llua::{Data, Error, Library, LibraryErr, Lua};
+use std::{
+    env, fs,
+    io::{self, IsTerminal, Read},
+    process::ExitCode,
+};
+
+const USAGE: &str = "usage: llua run [options] [script [args]]
+
+options:
+  -e stat                  execute string 'stat'
+  -i                       enter interactive mode after executing 'script'
+  -l mod                   require library 'mod' into global 'mod'
+  -l g=mod                 require library 'mod' into global 'g'
+  -v                       show version information
+  --sandbox preset         activate only the libraries of a preset: full, safe or minimal
+  --memory-limit bytes     limit the memory the state may use
+  --instruction-limit n    limit the instructions each chunk may run
+  --                       stop handling options
+  -                        stop handling options and execute stdin";
+
+/// Name of the chunks given with `-e` and `-l`, used in error messages.
+const COMMAND_LINE: &str = "(command line)";
+
+/// Basic library functions that cannot load code or reach the file system.
+const SAFE_BASIC: &[&str] = &[
+    "assert",
+    "error",
+    "getmetatable",
+    "ipairs",
+    "next",
+    "pairs",
+    "pcall",
+    "print",
+    "rawequal",
+    "rawget",
+    #[cfg(not(lua_version = "5.1"))]
+    "rawlen",
+    "rawset",
+    "select",
+    "setmetatable",
+    "tonumber",
+    "tostring",
+    "type",
+    #[cfg(lua_version = "5.1")]
+    "unpack",
+    #[cfg(lua_version = "5.4")]
+    "warn",
+    "xpcall",
+    "_VERSION",
+];
+
+/// Os library functions that only read the time.
+const SAFE_OS: &[&str] = &["clock", "date", "difftime", "time"];
+
+/// Which libraries are activated.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Sandbox {
+    /// Every library.
+    Full,
+    /// Everything except loading code, files, processes and the debug library.
+    Safe,
+    /// Only the basic functions and the string, table and math libraries.
+    Minimal,
+}
+impl Sandbox {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "full" => Some(Sandbox::Full),
+            "safe" => Some(Sandbox::Safe),
+            "minimal" => Some(Sandbox::Minimal),
+            _ => None,
+        }
+    }
+
+    fn activate(self, lua: &mut Lua) -> Result<(), LibraryErr> {
+        match self {
+            Sandbox::Full => lua.activate(Library::all())?,
+            Sandbox::Safe => lua
+                .activate_functions(Library::Basic, SAFE_BASIC)?
+                .activate_functions(Library::Os, SAFE_OS)?
+                .activate(&[
+                    Library::Coroutine,
+                    Library::String,
+                    #[cfg(any(lua_version = "5.3", lua_version = "5.4"))]
+                    Library::Utf8,
+                    Library::Table,
+                    Library::Math,
+                ])?,
+            Sandbox::Minimal => lua
+                .activate_functions(Library::Basic, SAFE_BASIC)?
+                .activate(&[Library::String, Library::Table, Library::Math])?,
+        };
+
+        Ok(())
+    }
+}
+
+/// Code run before the script, in the order it was given.
+#[derive(PartialEq, Debug)]
+enum Action {
+    Execute(String),
+    Require { global: String, module: String },
+}
+
+/// Where the script is read from.
+#[derive(PartialEq, Debug)]
+enum Script {
+    Stdin,
+    File(String),
+}
+
+/// The parsed command line options.
+#[derive(PartialEq, Debug)]
+struct Options {
+    actions: Vec<Action>,
+    interactive: bool,
+    version: bool,
+    sandbox: Sandbox,
+    memory_limit: Option<usize>,
+    instruction_limit: Option<u64>,
+    script: Option<Script>,
+    /// Position of the script in the arguments, or of the first argument if there is none.
+    script_index: usize,
+}
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Options {
+            actions: Vec::new(),
+            interactive: false,
+            version: false,
+            sandbox: Sandbox::Full,
+            memory_limit: None,
+            instruction_limit: None,
+            script: None,
+            script_index: 0,
+        };
+
+        let mut i = 0;
+        while i < args.len() {
+            let arg = args[i].as_str();
+            // Options that take a value accept it attached or as the next argument
+            let mut value = |name: &str| match arg.strip_prefix(name) {
+                Some(value) if !value.is_empty() => Ok(value.to_string()),
+                _ => {
+                    i += 1;
+                    args.get(i)
+                        .cloned()
+                        .ok_or(format!("'{name}' needs an argument"))
+                }
+            };
+
+            match arg {
+                "--" => {
+                    if let Some(script) = args.get(i + 1) {
+                        options.script = Some(Script::File(script.clone()));
+                        options.script_index = i + 1;
+                    }
+                    break;
+                }
+                "-" => {
+                    options.script = Some(Script::Stdin);
+                    options.script_index = i;
+                    break;
+                }
+                "-i" => {
+                    options.interactive = true;
+                    options.version = true;
+                }
+                "-v" => options.version = true,
+                "--sandbox" => {
+                    let name = value("--sandbox")?;
+                    options.sandbox =
+                        Sandbox::parse(&name).ok_or(format!("unknown sandbox {name}"))?;
+                }
+                "--memory-limit" => options.memory_limit = Some(parse_number(&value(arg)?)?),
+                "--instruction-limit" => {
+                    options.instruction_limit = Some(parse_number(&value(arg)?)?)
+                }
+                arg if arg.starts_with("-e") => options.actions.push(Action::Execute(value("-e")?)),
+                arg if arg.starts_with("-l") => {
+                    let name = value("-l")?;
+                    let (global, module) = name.split_once('=').unwrap_or((&name, &name));
+                    options.actions.push(Action::Require {
+                        global: global.into(),
+                        module: module.into(),
+                    });
+                }
+                arg if arg.starts_with('-') => return Err(format!("unrecognized option '{arg}'")),
+                script => {
+                    options.script = Some(Script::File(script.into()));
+                    options.script_index = i;
+                    break;
+                }
+            }
+
+            i += 1;
+        }
+
+        Ok(options)
+    }
+}
+
+/// Parses the value of a numeric option.
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid number {value}"))
+}
+
+/// Sets the global `arg` table, which holds the script at index 0.
+/// Arguments before it have negative indices and the script arguments positive ones.
+fn set_arg_table(lua: &mut Lua, args: &[String], script_index: usize) -> Result<(), Error> {
+    let mut values = vec![Data::from(-(script_index as i64))];
+    values.extend(args.iter().map(|arg| Data::String(arg.clone())));
+
+    lua.interpret_with_args(
+        COMMAND_LINE,
+        "local values = {...}
+arg = {}
+for i = 2, #values do arg[values[1] + i - 2] = values[i] end",
+        &values,
+    )
+}
+
+/// Runs `LUA_INIT_5_4` (for the version that was built) or `LUA_INIT`, which is either code or `@` followed by a file name.
+fn run_init(lua: &mut Lua) -> Result<(), String> {
+    let versioned = format!("LUA_INIT_{}", Lua::version()[..3].replace('.', "_"));
+    let Some((name, init)) = [versioned.as_str(), "LUA_INIT"]
+        .into_iter()
+        .find_map(|name| env::var(name).ok().map(|init| (name, init)))
+    else {
+        return Ok(());
+    };
+
+    match init.strip_prefix('@') {
+        Some(path) => run_file(lua, path, &[]),
+        None => lua.interpret_named(name, &init).map_err(message),
+    }
+}
+
+/// Runs the code from `-e` and `-l`.
+fn run_action(lua: &mut Lua, action: &Action) -> Result<(), String> {
+    match action {
+        Action::Execute(code) => lua.interpret_named(COMMAND_LINE, code),
+        Action::Require { global, module } => lua.interpret_with_args(
+            COMMAND_LINE,
+            // Lua 5.1 has no _ENV, and its chunks run in the table getfenv returns
+            match Lua::version().starts_with("5.1") {
+                true => "local global, module = ... getfenv(1)[global] = require(module)",
+                false => "local global, module = ... _ENV[global] = require(module)",
+            },
+            &[global.as_str().into(), module.as_str().into()],
+        ),
+    }
+    .map_err(message)
+}
+
+/// Reads a script, skipping a first line that starts with `#` as the reference executable does.
+pub fn read_script(path: &str) -> Result<String, String> {
+    let mut code = fs::read_to_string(path).map_err(|e| format!("cannot open {path}: {e}"))?;
+    if code.starts_with('#') {
+        // Keep the newline so line numbers stay the same
+        code.drain(..code.find('\n').unwrap_or(code.len()));
+    }
+
+    Ok(code)
+}
+
+/// Runs a file with the given arguments.
+fn run_file(lua: &mut Lua, path: &str, args: &[Data]) -> Result<(), String> {
+    let code = read_script(path)?;
+    lua.interpret_with_args(path, &code, args).map_err(message)
+}
+
+/// Runs the code read from stdin.
+fn run_stdin(lua: &mut Lua, args: &[Data]) -> Result<(), String> {
+    let mut code = String::new();
+    io::stdin()
+        .read_to_string(&mut code)
+        .map_err(|e| format!("cannot read stdin: {e}"))?;
+
+    lua.interpret_with_args("stdin", &code, args)
+        .map_err(message)
+}
+
+/// Returns the message printed for an error.
+pub fn message(error: Error) -> String {
+    match error {
+        Error::Runtime(message) => message,
+        Error::Interrupted => "interrupted".into(),
+        Error::BudgetExceeded => "instruction limit exceeded".into(),
+        Error::Memory => "not enough memory".into(),
+        error => format!("{error:?}"),
+    }
+}
+
+/// Sets up the state and runs everything the options ask for.
+/// Returns the state if the interactive prompt should start afterwards.
+fn execute(options: &Options, args: &[String]) -> Result<Option<Lua>, String> {
+    let mut lua = Lua::new();
+    lua.set_traceback(true);
+    lua.set_memory_limit(options.memory_limit);
+    lua.set_instruction_limit(options.instruction_limit);
+    options
+        .sandbox
+        .activate(&mut lua)
+        .map_err(|e| format!("{e:?}"))?;
+    crate::handle_interrupts(&mut lua);
+
+    // The `arg` indices count from the program name when there is no script
+    let (script_index, script_args) = match options.script {
+        Some(_) => (options.script_index + 2, &args[options.script_index + 1..]),
+        None => (0, &[][..]),
+    };
+    let mut all_args = vec!["llua".to_string(), "run".to_string()];
+    all_args.extend_from_slice(args);
+    set_arg_table(&mut lua, &all_args, script_index).map_err(message)?;
+
+    run_init(&mut lua)?;
+    for action in &options.actions {
+        run_action(&mut lua, action)?;
+    }
+
+    let script_args: Vec<Data> = script_args
+        .iter()
+        .map(|arg| Data::String(arg.clone()))
+        .collect();
+    match &options.script {
+        Some(Script::File(path)) => run_file(&mut lua, path, &script_args)?,
+        Some(Script::Stdin) => run_stdin(&mut lua, &script_args)?,
+        None if options.interactive || !options.actions.is_empty() || options.version => {}
+        None if io::stdin().is_terminal() => return Ok(Some(lua)),
+        None => run_stdin(&mut lua, &[])?,
+    }
+
+    Ok(options.interactive.then_some(lua))
+}
+
+/// Runs a script with the options given on the command line.
+pub fn run(args: Vec<String>) -> ExitCode {
+    let options = match Options::parse(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("llua: {e}\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if options.version {
+        println!(
+            "llua {} (Lua {})",
+            env!("CARGO_PKG_VERSION"),
+            Lua::version()
+        );
+    }
+
+    match execute(&options, &args) {
+        Ok(Some(lua)) => repl::interact(lua),
+        Ok(None) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("llua: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(a: &[&str]) -> Vec<String> {
+        a.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_stops_at_script() {
+        let options = Options::parse(&args(&[
+            "-e", "x = 1", "-lm", "-l", "j=json", "main.lua", "-e", "a",
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                Action::Execute("x = 1".into()),
+                Action::Require {
+                    global: "m".into(),
+                    module: "m".into()
+                },
+                Action::Require {
+                    global: "j".into(),
+                    module: "json".into()
+                },
+            ],
+            options.actions
+        );
+        assert_eq!(Some(Script::File("main.lua".into())), options.script);
+        assert_eq!(5, options.script_index);
+    }
+
+    #[test]
+    fn parse_handles_stdin_and_limits() {
+        let options = Options::parse(&args(&[
+            "--sandbox",
+            "safe",
+            "--memory-limit",
+            "1024",
+            "--instruction-limit",
+            "50",
+            "-",
+            "a",
+        ]))
+        .unwrap();
+
+        assert_eq!(Sandbox::Safe, options.sandbox);
+        assert_eq!(Some(1024), options.memory_limit);
+        assert_eq!(Some(50), options.instruction_limit);
+        assert_eq!(Some(Script::Stdin), options.script);
+
+        assert!(Options::parse(&args(&["-e"])).is_err());
+        assert!(Options::parse(&args(&["--sandbox", "none"])).is_err());
+        assert!(Options::parse(&args(&["-x"])).is_err());
+    }
+
+    #[test]
+    fn set_arg_table_indexes_from_script() {
+        let mut lua = Lua::new();
+        set_arg_table(&mut lua, &args(&["llua", "run", "main.lua", "a"]), 2).unwrap();
+        lua.interpret("first, script, last = arg[-2], arg[0], arg[1]")
+            .unwrap();
+
+        assert_eq!(Ok(Data::String("llua".into())), lua.get_global("first"));
+        assert_eq!(
+            Ok(Data::String("main.lua".into())),
+            lua.get_global("script")
+        );
+        assert_eq!(Ok(Data::String("a".into())), lua.get_global("last"));
+    }
+
+    #[test]
+    fn sandbox_safe_removes_loading() {
+        let mut lua = Lua::new();
+        Sandbox::Safe.activate(&mut lua).unwrap();
+        lua.interpret("ok = print ~= nil and load == nil and io == nil and os.exit == nil")
+            .unwrap();
+
+        assert_eq!(Ok(Data::Bool(true)), lua.get_global("ok"));
+    }
+}
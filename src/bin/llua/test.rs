@@ -0,0 +1,169 @@
+//! The `llua test` command.
+
+use llua::{TestReport, TestRunner};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+const USAGE: &str = "usage: llua test [--tap | --junit] [--mock name]... <file or directory>...
+
+Runs the tests in each file, and in every file ending in `_test.lua` or `_spec.lua` in each directory.";
+
+/// How the results are written.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Format {
+    Text,
+    Tap,
+    JUnit,
+}
+
+/// The parsed command line options.
+#[derive(PartialEq, Debug)]
+struct Options {
+    format: Format,
+    mocks: Vec<String>,
+    paths: Vec<PathBuf>,
+}
+impl Options {
+    fn parse(args: Vec<String>) -> Result<Self, String> {
+        let mut options = Options {
+            format: Format::Text,
+            mocks: Vec::new(),
+            paths: Vec::new(),
+        };
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--tap" => options.format = Format::Tap,
+                "--junit" => options.format = Format::JUnit,
+                "--mock" => match args.next() {
+                    Some(name) => options.mocks.push(name),
+                    None => return Err("--mock needs a name".into()),
+                },
+                arg if arg.starts_with("--") => return Err(format!("unknown option {arg}")),
+                path => options.paths.push(path.into()),
+            }
+        }
+
+        if options.paths.is_empty() {
+            return Err("no test files given".into());
+        }
+
+        Ok(options)
+    }
+}
+
+/// Returns whether the file in a directory holds tests.
+fn is_test_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.ends_with("_test.lua") || name.ends_with("_spec.lua"))
+}
+
+/// Adds the test files at the path, searching directories recursively.
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !path.is_dir() {
+        files.push(path.into());
+        return Ok(());
+    }
+
+    let mut entries: Vec<PathBuf> = fs::read_dir(path)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<_>>()?;
+    entries.sort();
+
+    for entry in entries {
+        if entry.is_dir() {
+            collect_files(&entry, files)?;
+        } else if is_test_file(&entry) {
+            files.push(entry);
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs the command, returning whether every test passed.
+pub fn run(args: Vec<String>) -> ExitCode {
+    let options = match Options::parse(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("llua test: {e}\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut runner = TestRunner::new();
+    for mock in &options.mocks {
+        runner.mock(mock);
+    }
+
+    let mut files = Vec::new();
+    let mut report = TestReport::new();
+    for path in &options.paths {
+        if let Err(e) = collect_files(path, &mut files) {
+            eprintln!("llua test: {}: {e}", path.display());
+            return ExitCode::FAILURE;
+        }
+    }
+    for file in &files {
+        match runner.run_file(file) {
+            Ok(results) => report.add(results),
+            Err(e) => {
+                eprintln!("llua test: {}: {e}", file.display());
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let stdout = io::stdout().lock();
+    let written = match options.format {
+        Format::Text => report.write_text(stdout),
+        Format::Tap => report.write_tap(stdout),
+        Format::JUnit => report.write_junit(stdout),
+    };
+    if let Err(e) = written {
+        eprintln!("llua test: {e}");
+        return ExitCode::FAILURE;
+    }
+
+    match report.failed() {
+        0 => ExitCode::SUCCESS,
+        _ => ExitCode::FAILURE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_reads_options() {
+        let options = Options::parse(args(&["--junit", "--mock", "spawn", "tests"])).unwrap();
+
+        assert_eq!(Format::JUnit, options.format);
+        assert_eq!(vec!["spawn".to_string()], options.mocks);
+        assert_eq!(vec![PathBuf::from("tests")], options.paths);
+    }
+
+    #[test]
+    fn parse_without_paths_returns_err() {
+        assert!(Options::parse(args(&["--tap"])).is_err());
+        assert!(Options::parse(args(&["--mock"])).is_err());
+        assert!(Options::parse(args(&["--bogus", "a.lua"])).is_err());
+    }
+
+    #[test]
+    fn is_test_file_matches_suffixes() {
+        assert!(is_test_file(Path::new("dir/player_test.lua")));
+        assert!(is_test_file(Path::new("player_spec.lua")));
+        assert!(!is_test_file(Path::new("player.lua")));
+    }
+}
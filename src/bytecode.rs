@@ -0,0 +1,1046 @@
+extern crate alloc;
+
+use crate::lua_core::*;
+#[cfg(feature = "std")]
+use alloc::collections::BTreeSet;
+use alloc::{format, string::String, vec::Vec};
+use core::{ffi::c_void, fmt};
+
+/// How deeply functions may be nested in a chunk that is read, so untrusted chunks can't overflow the stack.
+const MAX_DEPTH: usize = 200;
+
+/// Marks an instruction whose line is stored in the absolute line info.
+const ABSLINEINFO: i8 = -0x80;
+
+/// Constant type tags.
+const LUA_VNIL: u8 = 0x00;
+const LUA_VFALSE: u8 = 0x01;
+const LUA_VTRUE: u8 = 0x11;
+const LUA_VNUMINT: u8 = 0x03;
+const LUA_VNUMFLT: u8 = 0x13;
+const LUA_VSHRSTR: u8 = 0x04;
+const LUA_VLNGSTR: u8 = 0x14;
+
+/// How the operands of an instruction are laid out.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Mode {
+    Abc,
+    Abx,
+    AsBx,
+    Ax,
+    SJ,
+}
+
+/// Name and operand layout of each opcode, in opcode order.
+const OPCODES: [(&str, Mode); 83] = [
+    ("MOVE", Mode::Abc),
+    ("LOADI", Mode::AsBx),
+    ("LOADF", Mode::AsBx),
+    ("LOADK", Mode::Abx),
+    ("LOADKX", Mode::Abx),
+    ("LOADFALSE", Mode::Abc),
+    ("LFALSESKIP", Mode::Abc),
+    ("LOADTRUE", Mode::Abc),
+    ("LOADNIL", Mode::Abc),
+    ("GETUPVAL", Mode::Abc),
+    ("SETUPVAL", Mode::Abc),
+    ("GETTABUP", Mode::Abc),
+    ("GETTABLE", Mode::Abc),
+    ("GETI", Mode::Abc),
+    ("GETFIELD", Mode::Abc),
+    ("SETTABUP", Mode::Abc),
+    ("SETTABLE", Mode::Abc),
+    ("SETI", Mode::Abc),
+    ("SETFIELD", Mode::Abc),
+    ("NEWTABLE", Mode::Abc),
+    ("SELF", Mode::Abc),
+    ("ADDI", Mode::Abc),
+    ("ADDK", Mode::Abc),
+    ("SUBK", Mode::Abc),
+    ("MULK", Mode::Abc),
+    ("MODK", Mode::Abc),
+    ("POWK", Mode::Abc),
+    ("DIVK", Mode::Abc),
+    ("IDIVK", Mode::Abc),
+    ("BANDK", Mode::Abc),
+    ("BORK", Mode::Abc),
+    ("BXORK", Mode::Abc),
+    ("SHRI", Mode::Abc),
+    ("SHLI", Mode::Abc),
+    ("ADD", Mode::Abc),
+    ("SUB", Mode::Abc),
+    ("MUL", Mode::Abc),
+    ("MOD", Mode::Abc),
+    ("POW", Mode::Abc),
+    ("DIV", Mode::Abc),
+    ("IDIV", Mode::Abc),
+    ("BAND", Mode::Abc),
+    ("BOR", Mode::Abc),
+    ("BXOR", Mode::Abc),
+    ("SHL", Mode::Abc),
+    ("SHR", Mode::Abc),
+    ("MMBIN", Mode::Abc),
+    ("MMBINI", Mode::Abc),
+    ("MMBINK", Mode::Abc),
+    ("UNM", Mode::Abc),
+    ("BNOT", Mode::Abc),
+    ("NOT", Mode::Abc),
+    ("LEN", Mode::Abc),
+    ("CONCAT", Mode::Abc),
+    ("CLOSE", Mode::Abc),
+    ("TBC", Mode::Abc),
+    ("JMP", Mode::SJ),
+    ("EQ", Mode::Abc),
+    ("LT", Mode::Abc),
+    ("LE", Mode::Abc),
+    ("EQK", Mode::Abc),
+    ("EQI", Mode::Abc),
+    ("LTI", Mode::Abc),
+    ("LEI", Mode::Abc),
+    ("GTI", Mode::Abc),
+    ("GEI", Mode::Abc),
+    ("TEST", Mode::Abc),
+    ("TESTSET", Mode::Abc),
+    ("CALL", Mode::Abc),
+    ("TAILCALL", Mode::Abc),
+    ("RETURN", Mode::Abc),
+    ("RETURN0", Mode::Abc),
+    ("RETURN1", Mode::Abc),
+    ("FORLOOP", Mode::Abx),
+    ("FORPREP", Mode::Abx),
+    ("TFORPREP", Mode::Abx),
+    ("TFORCALL", Mode::Abc),
+    ("TFORLOOP", Mode::Abx),
+    ("SETLIST", Mode::Abc),
+    ("CLOSURE", Mode::Abx),
+    ("VARARG", Mode::Abc),
+    ("VARARGPREP", Mode::Abc),
+    ("EXTRAARG", Mode::Ax),
+];
+
+/// Opcodes referred to by name.
+pub(crate) const OP_LOADK: u8 = 3;
+pub(crate) const OP_GETUPVAL: u8 = 9;
+pub(crate) const OP_SETUPVAL: u8 = 10;
+pub(crate) const OP_GETTABUP: u8 = 11;
+pub(crate) const OP_GETFIELD: u8 = 14;
+pub(crate) const OP_SETTABUP: u8 = 15;
+pub(crate) const OP_SETFIELD: u8 = 18;
+pub(crate) const OP_SELF: u8 = 20;
+pub(crate) const OP_ADDI: u8 = 21;
+const OP_ADDK: u8 = 22;
+const OP_BXORK: u8 = 31;
+const OP_SHRI: u8 = 32;
+pub(crate) const OP_SHLI: u8 = 33;
+pub(crate) const OP_MMBINI: u8 = 47;
+pub(crate) const OP_MMBINK: u8 = 48;
+pub(crate) const OP_JMP: u8 = 56;
+pub(crate) const OP_EQK: u8 = 60;
+const OP_EQI: u8 = 61;
+const OP_GEI: u8 = 65;
+pub(crate) const OP_FORLOOP: u8 = 73;
+pub(crate) const OP_FORPREP: u8 = 74;
+pub(crate) const OP_TFORPREP: u8 = 75;
+pub(crate) const OP_TFORLOOP: u8 = 77;
+pub(crate) const OP_CLOSURE: u8 = 79;
+
+/// Offset of the signed operands, which are stored with this added.
+const OFFSET_SBX: i32 = 0xffff;
+const OFFSET_SJ: i32 = 0xff_ffff;
+const OFFSET_SC: i32 = 0x7f;
+
+/// A single bytecode instruction.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) struct Instruction(u32);
+impl Instruction {
+    pub(crate) fn opcode(self) -> u8 {
+        (self.0 & 0x7f) as u8
+    }
+
+    pub(crate) fn a(self) -> u32 {
+        (self.0 >> 7) & 0xff
+    }
+
+    pub(crate) fn k(self) -> bool {
+        (self.0 >> 15) & 1 != 0
+    }
+
+    pub(crate) fn b(self) -> u32 {
+        (self.0 >> 16) & 0xff
+    }
+
+    pub(crate) fn c(self) -> u32 {
+        self.0 >> 24
+    }
+
+    pub(crate) fn bx(self) -> u32 {
+        self.0 >> 15
+    }
+
+    fn sbx(self) -> i32 {
+        self.bx() as i32 - OFFSET_SBX
+    }
+
+    pub(crate) fn ax(self) -> u32 {
+        self.0 >> 7
+    }
+
+    fn sj(self) -> i32 {
+        self.ax() as i32 - OFFSET_SJ
+    }
+
+    /// Returns the operands as they are listed.
+    fn operands(self) -> String {
+        let k = if self.k() { "k" } else { "" };
+        match self.mode() {
+            Mode::Abc => match self.opcode() {
+                OP_ADDI | OP_SHRI | OP_SHLI => {
+                    format!("{} {} {}", self.a(), self.b(), self.c() as i32 - OFFSET_SC)
+                }
+                OP_MMBINI | OP_EQI..=OP_GEI => {
+                    format!(
+                        "{} {} {}{k}",
+                        self.a(),
+                        self.b() as i32 - OFFSET_SC,
+                        self.c()
+                    )
+                }
+                _ => format!("{} {} {}{k}", self.a(), self.b(), self.c()),
+            },
+            Mode::Abx => format!("{} {}", self.a(), self.bx()),
+            Mode::AsBx => format!("{} {}", self.a(), self.sbx()),
+            Mode::Ax => format!("{}", self.ax()),
+            Mode::SJ => format!("{}", self.sj()),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        OPCODES
+            .get(self.opcode() as usize)
+            .map_or("?", |(name, _)| name)
+    }
+
+    fn mode(self) -> Mode {
+        OPCODES
+            .get(self.opcode() as usize)
+            .map_or(Mode::Abc, |(_, mode)| *mode)
+    }
+}
+
+/// A constant used by a function.
+#[derive(Clone, PartialEq, Debug)]
+pub(crate) enum Constant {
+    Nil,
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+}
+impl fmt::Display for Constant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Constant::Nil => write!(f, "nil"),
+            Constant::Bool(b) => write!(f, "{b}"),
+            Constant::Integer(i) => write!(f, "{i}"),
+            // Debug keeps the fraction of whole numbers, so they read as floats
+            Constant::Float(n) => write!(f, "{n:?}"),
+            Constant::String(s) => write!(f, "{s:?}"),
+        }
+    }
+}
+
+/// How a function reaches a variable of an enclosing function.
+#[derive(Clone, PartialEq, Debug)]
+pub(crate) struct Upvalue {
+    pub(crate) name: Option<String>,
+    /// Whether the variable is a local of the enclosing function, rather than one of its upvalues.
+    pub(crate) in_stack: bool,
+    pub(crate) index: u8,
+}
+
+/// A local variable and the instructions it is active for.
+#[derive(Clone, PartialEq, Debug)]
+struct Local {
+    name: Option<String>,
+    start_pc: Int,
+    end_pc: Int,
+}
+
+/// A function read from a binary chunk.
+pub(crate) struct Prototype {
+    /// Name of the chunk, which nested functions leave out if it is the same as their parent's.
+    source: Option<String>,
+    pub(crate) line_defined: Int,
+    last_line_defined: Int,
+    num_params: u8,
+    pub(crate) is_vararg: bool,
+    max_stack_size: u8,
+    pub(crate) code: Vec<Instruction>,
+    pub(crate) constants: Vec<Constant>,
+    pub(crate) upvalues: Vec<Upvalue>,
+    /// Functions defined inside this one.
+    pub(crate) prototypes: Vec<Prototype>,
+    /// Line of each instruction relative to the one before it.
+    line_info: Vec<i8>,
+    /// Lines of instructions too far from the one before them, as pairs of instruction and line.
+    abs_line_info: Vec<(Int, Int)>,
+    locals: Vec<Local>,
+}
+impl Prototype {
+    /// Reads the main function of a binary chunk, returning `None` if it isn't a valid Lua 5.4 chunk.
+    pub(crate) fn parse(chunk: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(chunk);
+        reader.header()?;
+        // Number of upvalues of the main function
+        reader.byte()?;
+
+        reader.function(0)
+    }
+
+    /// Returns the line of each instruction, or nothing if the chunk was stripped.
+    pub(crate) fn lines(&self) -> Vec<Int> {
+        let mut line = self.line_defined;
+
+        self.line_info
+            .iter()
+            .enumerate()
+            .map(|(pc, delta)| {
+                line = match *delta {
+                    ABSLINEINFO => self
+                        .abs_line_info
+                        .iter()
+                        .find(|(abs_pc, _)| *abs_pc as usize == pc)
+                        .map_or(line, |(_, abs_line)| *abs_line),
+                    delta => line + delta as Int,
+                };
+                line
+            })
+            .collect()
+    }
+
+    /// Returns the lines with code, matching the `activelines` Lua gives for the function.
+    #[cfg(all(feature = "std", lua_version = "5.4"))]
+    pub(crate) fn active_lines(&self) -> BTreeSet<Int> {
+        self.lines()
+            .into_iter()
+            .enumerate()
+            // Vararg functions start with an instruction to set up the arguments
+            .filter(|(pc, _)| !(self.is_vararg && *pc == 0))
+            .map(|(_, line)| line)
+            .collect()
+    }
+
+    /// Calls the given function for this function and every function nested in it.
+    #[cfg(all(feature = "std", lua_version = "5.4"))]
+    pub(crate) fn visit<F: FnMut(&Prototype)>(&self, f: &mut F) {
+        f(self);
+        for prototype in &self.prototypes {
+            prototype.visit(f);
+        }
+    }
+
+    /// Writes a listing of the function and the functions nested in it.
+    fn write_listing<W: fmt::Write>(&self, source: &str, main: bool, w: &mut W) -> fmt::Result {
+        let source = self.source.as_deref().unwrap_or(source);
+        let name = source.strip_prefix(['@', '=']).unwrap_or(source);
+        let plural = |count: usize| if count == 1 { "" } else { "s" };
+
+        writeln!(
+            w,
+            "{} <{name}:{},{}> ({} instruction{})",
+            if main { "main" } else { "function" },
+            self.line_defined,
+            self.last_line_defined,
+            self.code.len(),
+            plural(self.code.len())
+        )?;
+        writeln!(
+            w,
+            "{}{} param{}, {} slot{}, {} upvalue{}, {} local{}, {} constant{}, {} function{}",
+            self.num_params,
+            if self.is_vararg { "+" } else { "" },
+            plural(self.num_params as usize),
+            self.max_stack_size,
+            plural(self.max_stack_size as usize),
+            self.upvalues.len(),
+            plural(self.upvalues.len()),
+            self.locals.len(),
+            plural(self.locals.len()),
+            self.constants.len(),
+            plural(self.constants.len()),
+            self.prototypes.len(),
+            plural(self.prototypes.len())
+        )?;
+
+        let lines = self.lines();
+        for (pc, instruction) in self.code.iter().enumerate() {
+            let line = lines.get(pc).map_or("-".into(), |line| format!("{line}"));
+            write!(
+                w,
+                "\t{}\t[{line}]\t{:<9}\t{}",
+                pc + 1,
+                instruction.name(),
+                instruction.operands()
+            )?;
+            match self.comment(pc, *instruction) {
+                Some(comment) => writeln!(w, "\t; {comment}")?,
+                None => writeln!(w)?,
+            }
+        }
+
+        writeln!(w, "constants ({}):", self.constants.len())?;
+        for (i, constant) in self.constants.iter().enumerate() {
+            let kind = match constant {
+                Constant::Nil => "N",
+                Constant::Bool(_) => "B",
+                Constant::Integer(_) => "I",
+                Constant::Float(_) => "F",
+                Constant::String(_) => "S",
+            };
+            writeln!(w, "\t{i}\t{kind}\t{constant}")?;
+        }
+
+        writeln!(w, "locals ({}):", self.locals.len())?;
+        for (i, local) in self.locals.iter().enumerate() {
+            writeln!(
+                w,
+                "\t{i}\t{}\t{}\t{}",
+                local.name.as_deref().unwrap_or("?"),
+                local.start_pc + 1,
+                local.end_pc + 1
+            )?;
+        }
+
+        writeln!(w, "upvalues ({}):", self.upvalues.len())?;
+        for (i, upvalue) in self.upvalues.iter().enumerate() {
+            writeln!(
+                w,
+                "\t{i}\t{}\t{}\t{}",
+                upvalue.name.as_deref().unwrap_or("-"),
+                upvalue.in_stack as u8,
+                upvalue.index
+            )?;
+        }
+
+        for prototype in &self.prototypes {
+            writeln!(w)?;
+            prototype.write_listing(source, false, w)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns a note on the constants, upvalues, functions or jump targets an instruction uses.
+    fn comment(&self, pc: usize, instruction: Instruction) -> Option<String> {
+        let constant = |index: u32| {
+            self.constants
+                .get(index as usize)
+                .map_or("?".into(), |c| format!("{c}"))
+        };
+        let upvalue = |index: u32| {
+            self.upvalues
+                .get(index as usize)
+                .and_then(|u| u.name.clone())
+                .unwrap_or("-".into())
+        };
+        let jump = |offset: i32| format!("to {}", pc as i64 + offset as i64 + 2);
+
+        let i = instruction;
+        match i.opcode() {
+            OP_LOADK => Some(constant(i.bx())),
+            OP_GETUPVAL | OP_SETUPVAL => Some(upvalue(i.b())),
+            OP_GETTABUP => Some(format!("{} {}", upvalue(i.b()), constant(i.c()))),
+            OP_SETTABUP => Some(format!("{} {}", upvalue(i.a()), constant(i.b()))),
+            OP_GETFIELD => Some(constant(i.c())),
+            OP_SETFIELD => Some(constant(i.b())),
+            OP_SELF if i.k() => Some(constant(i.c())),
+            OP_ADDK..=OP_BXORK => Some(constant(i.c())),
+            OP_MMBINK | OP_EQK => Some(constant(i.b())),
+            OP_JMP => Some(jump(i.sj())),
+            OP_FORLOOP | OP_TFORLOOP => Some(jump(-(i.bx() as i32))),
+            OP_FORPREP => Some(jump(i.bx() as i32 + 1)),
+            OP_TFORPREP => Some(jump(i.bx() as i32)),
+            OP_CLOSURE => Some(format!("function {}", i.bx())),
+            _ => None,
+        }
+    }
+}
+
+/// Returns a readable listing of the instructions, constants, locals and upvalues of each function in a binary chunk.
+/// Returns `None` if it isn't a valid Lua 5.4 chunk.
+pub fn disassemble(chunk: &[u8]) -> Option<String> {
+    let main = Prototype::parse(chunk)?;
+    let mut listing = String::new();
+    main.write_listing("=?", true, &mut listing).ok()?;
+
+    Some(listing)
+}
+
+/// Reads values in the format written by `lua_dump`.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    integer_size: usize,
+    number_size: usize,
+}
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            integer_size: 8,
+            number_size: 8,
+        }
+    }
+
+    fn take(&mut self, count: usize) -> Option<&'a [u8]> {
+        if count > self.bytes.len() {
+            return None;
+        }
+
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    /// Reads a size, stored most significant group first with the last byte marked.
+    fn size(&mut self) -> Option<usize> {
+        let mut size: usize = 0;
+        loop {
+            let byte = self.byte()?;
+            size = size.checked_mul(1 << 7)? | (byte & 0x7f) as usize;
+            if byte & 0x80 != 0 {
+                return Some(size);
+            }
+        }
+    }
+
+    fn int(&mut self) -> Option<Int> {
+        Int::try_from(self.size()?).ok()
+    }
+
+    fn string(&mut self) -> Option<Option<String>> {
+        match self.size()? {
+            0 => Some(None),
+            size => {
+                let bytes = self.take(size - 1)?;
+                Some(Some(String::from_utf8_lossy(bytes).into()))
+            }
+        }
+    }
+
+    /// Reads a count followed by that many values.
+    fn vector<T, F: FnMut(&mut Self) -> Option<T>>(&mut self, mut read: F) -> Option<Vec<T>> {
+        let count = self.int()?;
+        (0..count).map(|_| read(self)).collect()
+    }
+
+    fn header(&mut self) -> Option<()> {
+        let valid = self.take(4)? == b"\x1bLua"
+            && self.byte()? == 0x54
+            && self.byte()? == 0
+            && self.take(6)? == b"\x19\x93\r\n\x1a\n"
+            && self.byte()? == 4;
+        if !valid {
+            return None;
+        }
+
+        self.integer_size = self.byte()? as usize;
+        self.number_size = self.byte()? as usize;
+        // Values used to check the integer and number formats
+        self.take(self.integer_size)?;
+        self.take(self.number_size)?;
+
+        Some(())
+    }
+
+    /// Reads a function and the functions nested in it, at the given depth of nesting.
+    fn function(&mut self, depth: usize) -> Option<Prototype> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+
+        let source = self.string()?;
+        let line_defined = self.int()?;
+        let last_line_defined = self.int()?;
+        let num_params = self.byte()?;
+        let is_vararg = self.byte()? != 0;
+        let max_stack_size = self.byte()?;
+
+        let code = self.vector(|r| {
+            let bytes = r.take(4)?;
+            Some(Instruction(u32::from_ne_bytes(bytes.try_into().ok()?)))
+        })?;
+        let constants = self.vector(|r| r.constant())?;
+        let mut upvalues = self.vector(|r| {
+            let in_stack = r.byte()? != 0;
+            let index = r.byte()?;
+            // Kind of variable, such as const or to-be-closed
+            r.byte()?;
+            Some(Upvalue {
+                name: None,
+                in_stack,
+                index,
+            })
+        })?;
+        let prototypes = self.vector(|r| r.function(depth + 1))?;
+
+        let line_info = self.vector(|r| r.byte().map(|b| b as i8))?;
+        let abs_line_info = self.vector(|r| Some((r.int()?, r.int()?)))?;
+        let locals = self.vector(|r| {
+            Some(Local {
+                name: r.string()?,
+                start_pc: r.int()?,
+                end_pc: r.int()?,
+            })
+        })?;
+        // Stripped chunks leave out the upvalue names
+        let names = self.vector(|r| r.string())?;
+        for (upvalue, name) in upvalues.iter_mut().zip(names) {
+            upvalue.name = name;
+        }
+
+        Some(Prototype {
+            source,
+            line_defined,
+            last_line_defined,
+            num_params,
+            is_vararg,
+            max_stack_size,
+            code,
+            constants,
+            upvalues,
+            prototypes,
+            line_info,
+            abs_line_info,
+            locals,
+        })
+    }
+
+    fn constant(&mut self) -> Option<Constant> {
+        let constant = match self.byte()? {
+            LUA_VNIL => Constant::Nil,
+            LUA_VFALSE => Constant::Bool(false),
+            LUA_VTRUE => Constant::Bool(true),
+            LUA_VNUMINT => {
+                let bytes = self.take(self.integer_size)?;
+                Constant::Integer(match bytes.len() {
+                    4 => i32::from_ne_bytes(bytes.try_into().ok()?) as i64,
+                    _ => i64::from_ne_bytes(bytes.try_into().ok()?),
+                })
+            }
+            LUA_VNUMFLT => {
+                let bytes = self.take(self.number_size)?;
+                Constant::Float(match bytes.len() {
+                    4 => f32::from_ne_bytes(bytes.try_into().ok()?) as f64,
+                    _ => f64::from_ne_bytes(bytes.try_into().ok()?),
+                })
+            }
+            LUA_VSHRSTR | LUA_VLNGSTR => Constant::String(self.string()?.unwrap_or_default()),
+            _ => return None,
+        };
+
+        Some(constant)
+    }
+}
+
+/// Lines with code of a function read from a binary chunk.
+#[cfg(feature = "std")]
+pub(crate) struct FunctionLines {
+    pub(crate) line_defined: Int,
+    pub(crate) last_line_defined: Int,
+    pub(crate) lines: BTreeSet<Int>,
+}
+
+/// Returns the lines with code of the main function of a binary chunk and of every function nested in it, matching the `activelines` Lua gives for each.
+/// Returns `None` if it isn't a valid chunk.
+#[cfg(all(feature = "std", lua_version = "5.4"))]
+pub(crate) fn function_lines(chunk: &[u8]) -> Option<Vec<FunctionLines>> {
+    let main = Prototype::parse(chunk)?;
+    let mut functions = Vec::new();
+    main.visit(&mut |function| {
+        functions.push(FunctionLines {
+            line_defined: function.line_defined,
+            last_line_defined: function.last_line_defined,
+            lines: function.active_lines(),
+        })
+    });
+
+    Some(functions)
+}
+
+/// Returns the lines with code of the main function of a binary chunk and of every function nested in it, matching the `activelines` Lua gives for each.
+/// Returns `None` if it isn't a valid chunk.
+#[cfg(all(feature = "std", not(lua_version = "5.4")))]
+pub(crate) fn function_lines(chunk: &[u8]) -> Option<Vec<FunctionLines>> {
+    let mut reader = LineReader {
+        bytes: chunk,
+        size_t_size: 8,
+        integer_size: 8,
+        number_size: 8,
+    };
+    reader.header()?;
+    // Number of upvalues of the main function
+    #[cfg(lua_version = "5.3")]
+    reader.byte()?;
+
+    let mut functions = Vec::new();
+    reader.function(0, &mut functions)?;
+
+    Some(functions)
+}
+
+/// Reads the lines of each function from a binary chunk of Lua 5.1 to 5.3, skipping everything else.
+#[cfg(all(feature = "std", not(lua_version = "5.4")))]
+struct LineReader<'a> {
+    bytes: &'a [u8],
+    size_t_size: usize,
+    integer_size: usize,
+    number_size: usize,
+}
+#[cfg(all(feature = "std", not(lua_version = "5.4")))]
+impl<'a> LineReader<'a> {
+    fn take(&mut self, count: usize) -> Option<&'a [u8]> {
+        if count > self.bytes.len() {
+            return None;
+        }
+
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    /// Skips the given number of values of the given size.
+    fn skip(&mut self, count: usize, size: usize) -> Option<()> {
+        self.take(count.checked_mul(size)?).map(|_| ())
+    }
+
+    fn int(&mut self) -> Option<Int> {
+        let bytes = self.take(4)?;
+        Some(i32::from_ne_bytes(bytes.try_into().ok()?))
+    }
+
+    /// Reads a count of the values that follow.
+    fn count(&mut self) -> Option<usize> {
+        usize::try_from(self.int()?).ok()
+    }
+
+    fn size_t(&mut self) -> Option<usize> {
+        let bytes = self.take(self.size_t_size)?;
+        match bytes.len() {
+            4 => Some(u32::from_ne_bytes(bytes.try_into().ok()?) as usize),
+            _ => usize::try_from(u64::from_ne_bytes(bytes.try_into().ok()?)).ok(),
+        }
+    }
+
+    /// Skips a string, which Lua 5.3 stores without its terminator and with a shorter size when it can.
+    fn string(&mut self) -> Option<()> {
+        #[cfg(lua_version = "5.3")]
+        let size = match self.byte()? {
+            0xff => self.size_t()?,
+            size => size as usize,
+        }
+        .saturating_sub(1);
+        #[cfg(not(lua_version = "5.3"))]
+        let size = self.size_t()?;
+
+        self.skip(size, 1)
+    }
+
+    fn header(&mut self) -> Option<()> {
+        #[cfg(lua_version = "5.1")]
+        const VERSION: u8 = 0x51;
+        #[cfg(lua_version = "5.2")]
+        const VERSION: u8 = 0x52;
+        #[cfg(lua_version = "5.3")]
+        const VERSION: u8 = 0x53;
+
+        if self.take(4)? != b"\x1bLua" || self.byte()? != VERSION || self.byte()? != 0 {
+            return None;
+        }
+
+        // Lua 5.3 checks for corruption before the sizes, and the others the byte order after
+        #[cfg(lua_version = "5.3")]
+        if self.take(6)? != b"\x19\x93\r\n\x1a\n" {
+            return None;
+        }
+        #[cfg(not(lua_version = "5.3"))]
+        self.byte()?;
+
+        let int_size = self.byte()?;
+        self.size_t_size = self.byte()? as usize;
+        let instruction_size = self.byte()?;
+        #[cfg(lua_version = "5.3")]
+        {
+            self.integer_size = self.byte()? as usize;
+        }
+        self.number_size = self.byte()? as usize;
+        if int_size != 4 || instruction_size != 4 || !matches!(self.size_t_size, 4 | 8) {
+            return None;
+        }
+
+        // Whether numbers are integers, then the tail checking for corruption
+        #[cfg(lua_version = "5.2")]
+        if self.byte().is_none() || self.take(6)? != b"\x19\x93\r\n\x1a\n" {
+            return None;
+        }
+        #[cfg(lua_version = "5.1")]
+        self.byte()?;
+        // Values used to check the integer and number formats
+        #[cfg(lua_version = "5.3")]
+        {
+            self.take(self.integer_size)?;
+            self.take(self.number_size)?;
+        }
+
+        Some(())
+    }
+
+    /// Reads a function and the functions nested in it, adding the lines of each.
+    fn function(&mut self, depth: usize, functions: &mut Vec<FunctionLines>) -> Option<()> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+
+        // Lua 5.2 keeps the source with the debug information
+        #[cfg(not(lua_version = "5.2"))]
+        self.string()?;
+        let line_defined = self.int()?;
+        let last_line_defined = self.int()?;
+        // Number of upvalues, which later versions store with the upvalues
+        #[cfg(lua_version = "5.1")]
+        self.byte()?;
+        // Number of parameters, whether it is vararg and the stack size
+        self.skip(3, 1)?;
+
+        let code = self.count()?;
+        self.skip(code, 4)?;
+        for _ in 0..self.count()? {
+            self.constant()?;
+        }
+        #[cfg(not(lua_version = "5.3"))]
+        for _ in 0..self.count()? {
+            self.function(depth + 1, functions)?;
+        }
+        #[cfg(not(lua_version = "5.1"))]
+        {
+            let upvalues = self.count()?;
+            self.skip(upvalues, 2)?;
+        }
+        #[cfg(lua_version = "5.3")]
+        for _ in 0..self.count()? {
+            self.function(depth + 1, functions)?;
+        }
+
+        #[cfg(lua_version = "5.2")]
+        self.string()?;
+        let lines = (0..self.count()?)
+            .map(|_| self.int())
+            .collect::<Option<BTreeSet<Int>>>()?;
+        for _ in 0..self.count()? {
+            self.string()?;
+            self.skip(2, 4)?;
+        }
+        for _ in 0..self.count()? {
+            self.string()?;
+        }
+
+        functions.push(FunctionLines {
+            line_defined,
+            last_line_defined,
+            lines,
+        });
+
+        Some(())
+    }
+
+    /// Skips a constant.
+    fn constant(&mut self) -> Option<()> {
+        match self.byte()? {
+            // Nil
+            0 => Some(()),
+            // Boolean
+            1 => self.byte().map(|_| ()),
+            // Number, which is a float from Lua 5.3
+            3 => self.skip(1, self.number_size),
+            // Integer, from Lua 5.3
+            0x13 => self.skip(1, self.integer_size),
+            // Short and long strings
+            4 | 0x14 => self.string(),
+            _ => None,
+        }
+    }
+}
+
+/// Appends each piece of a dumped chunk to the `Vec<u8>` given as user data.
+unsafe extern "C" fn write_chunk(
+    _state: State,
+    p: *const c_void,
+    size: usize,
+    ud: *mut c_void,
+) -> Int {
+    let chunk = &mut *(ud as *mut Vec<u8>);
+    chunk.extend_from_slice(core::slice::from_raw_parts(p as *const u8, size));
+    0
+}
+
+/// Dumps the Lua function on top of the stack as a binary chunk, returning `None` if it isn't a Lua function.
+/// Stripping leaves out the debug information.
+pub(crate) unsafe fn dump(state: State, strip: bool) -> Option<Vec<u8>> {
+    let mut chunk = Vec::new();
+    match lua_dump(
+        state,
+        write_chunk,
+        &mut chunk as *mut Vec<u8> as *mut c_void,
+        strip as Int,
+    ) {
+        0 if !chunk.is_empty() => Some(chunk),
+        _ => None,
+    }
+}
+
+#[cfg(all(test, feature = "std", lua_version = "5.4"))]
+mod tests {
+    use super::*;
+    use alloc::ffi::CString;
+    use core::ffi::c_char;
+
+    /// Loads the code, returning its dump and the `activelines` of each function it defines.
+    fn load(code: &str) -> (Vec<u8>, Vec<BTreeSet<Int>>) {
+        unsafe {
+            let state = luaL_newstate();
+            let name = CString::new("=test").unwrap();
+            luaL_loadbufferx(
+                state,
+                code.as_ptr() as *const c_char,
+                code.len(),
+                name.as_ptr(),
+                core::ptr::null(),
+            );
+            let chunk = dump(state, false).unwrap();
+
+            // Run it so the functions it defines can be inspected
+            lua_pcall(state, 0, 0, 0);
+            let mut expected = Vec::new();
+            for global in ["f", "g"] {
+                let global = CString::new(global).unwrap();
+                lua_getglobal(state, global.as_ptr());
+                let mut ar = LuaDebug::new();
+                lua_getinfo(state, c">L".as_ptr(), &mut ar);
+
+                let mut lines = BTreeSet::new();
+                lua_pushnil(state);
+                while lua_next(state, -2) != 0 {
+                    lua_pop(state, 1);
+                    lines.insert(lua_tointeger(state, -1) as Int);
+                }
+                lua_pop(state, 1);
+                expected.push(lines);
+            }
+            lua_close(state);
+
+            (chunk, expected)
+        }
+    }
+
+    #[test]
+    fn active_lines_match_lua() {
+        let code = "local x = 1\nfunction f(a)\n  local b = a\n\n  return b + x\nend\nfunction g(...)\n  return ...\nend\n";
+        let (chunk, expected) = load(code);
+        let main = Prototype::parse(&chunk).unwrap();
+
+        assert_eq!(2, main.prototypes.len());
+        assert_eq!(expected[0], main.prototypes[0].active_lines());
+        assert_eq!(expected[1], main.prototypes[1].active_lines());
+    }
+
+    #[test]
+    fn active_lines_far_apart_uses_absolute_lines() {
+        let mut code = String::from("function f()\n  local a = 1\n");
+        code.push_str(&"\n".repeat(300));
+        code.push_str("  return a\nend\nfunction g() end\n");
+        let (chunk, expected) = load(&code);
+        let main = Prototype::parse(&chunk).unwrap();
+
+        assert_eq!(expected[0], main.prototypes[0].active_lines());
+        assert!(main.prototypes[0].active_lines().contains(&303));
+    }
+
+    #[test]
+    fn visit_includes_nested_functions() {
+        let (chunk, _) = load("function f() return function() end end\nfunction g() end");
+        let main = Prototype::parse(&chunk).unwrap();
+
+        let mut count = 0;
+        main.visit(&mut |_| count += 1);
+        assert_eq!(4, count);
+    }
+
+    #[test]
+    fn parse_reads_constants_and_upvalues() {
+        let (chunk, _) = load(
+            "local t = {1.5, 100000, 'name', true}\nfunction f() return t end\nfunction g() end",
+        );
+        let main = Prototype::parse(&chunk).unwrap();
+
+        assert!(main.constants.contains(&Constant::Float(1.5)));
+        assert!(main.constants.contains(&Constant::Integer(100000)));
+        assert!(main.constants.contains(&Constant::String("name".into())));
+        assert_eq!(Some("_ENV".into()), main.upvalues[0].name);
+        assert_eq!(Some("t".into()), main.prototypes[0].upvalues[0].name);
+        assert_eq!(OP_GETUPVAL, main.prototypes[0].code[0].opcode());
+    }
+
+    #[test]
+    fn disassemble_lists_functions() {
+        let (chunk, _) =
+            load("local x = 1\nfunction f()\n  for i = 1, 2 do x = i end\nend\nfunction g() end");
+        let listing = disassemble(&chunk).unwrap();
+
+        assert!(listing.starts_with("main <test:0,0>"));
+        assert!(listing.contains("\tSETTABUP \t0 0 1\t; _ENV \"f\"\n"));
+        assert!(listing.contains("function <test:2,4>"));
+        assert!(listing.contains("\t6\t[3]\tFORLOOP  \t0 2\t; to 5\n"));
+        assert!(listing.contains("\t0\tx\t1\t0\n"));
+        assert!(listing.contains("locals (4):\n\t0\t(for state)"));
+    }
+
+    /// Returns a chunk with functions nested to the given depth, each with nothing but the next one.
+    fn nested(depth: usize) -> Vec<u8> {
+        let (chunk, _) = load("function f() end\nfunction g() end");
+        // The header, which ends with the sizes and sample values of integers and numbers, then the number of upvalues of the main function
+        let header = 15 + chunk[13] as usize + chunk[14] as usize;
+        let mut nested = chunk[..header].to_vec();
+        nested.push(1);
+
+        for level in 0..depth {
+            // No source or lines, 2 slots and no code, constants or upvalues
+            nested.extend_from_slice(&[0x80, 0x80, 0x80, 0, 0, 2, 0x80, 0x80, 0x80]);
+            nested.push(if level + 1 < depth { 0x81 } else { 0x80 });
+        }
+        // No line info, locals or upvalue names
+        for _ in 0..depth {
+            nested.extend_from_slice(&[0x80; 4]);
+        }
+
+        nested
+    }
+
+    #[test]
+    fn parse_nested_too_deep_returns_none() {
+        assert!(Prototype::parse(&nested(100)).is_some());
+        assert!(Prototype::parse(&nested(MAX_DEPTH + 2)).is_none());
+    }
+
+    #[test]
+    fn parse_invalid_returns_none() {
+        assert!(Prototype::parse(b"print('hi')").is_none());
+        assert!(Prototype::parse(b"\x1bLua\x54").is_none());
+    }
+}
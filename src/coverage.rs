@@ -0,0 +1,337 @@
+//! Line coverage for scripts running in a `Lua` state.
+
+use crate::{
+    bytecode, lua_core::Int, DebugInfo, FunctionKind, HookContext, HookEvent, HookMask, Lua,
+};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashSet},
+    io::{self, Write},
+    rc::Rc,
+};
+
+/// Coverage of a single chunk.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct FileCoverage {
+    /// How many times each line with code ran.
+    lines: BTreeMap<Int, u64>,
+}
+impl FileCoverage {
+    /// Returns how many times each line with code ran.
+    pub fn lines(&self) -> &BTreeMap<Int, u64> {
+        &self.lines
+    }
+
+    /// Returns how many times the given line ran, or `None` if it has no code.
+    pub fn hits(&self, line: Int) -> Option<u64> {
+        self.lines.get(&line).copied()
+    }
+
+    /// Returns the number of lines with code.
+    pub fn lines_found(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Returns the number of lines with code that ran.
+    pub fn lines_hit(&self) -> usize {
+        self.lines.values().filter(|hits| **hits > 0).count()
+    }
+
+    /// Returns the fraction of lines with code that ran.
+    pub fn line_rate(&self) -> f64 {
+        line_rate(self.lines_hit(), self.lines_found())
+    }
+}
+
+/// The results of collecting coverage, keyed by chunk name.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Coverage {
+    files: BTreeMap<String, FileCoverage>,
+}
+impl Coverage {
+    /// Returns the coverage of every chunk that ran.
+    pub fn files(&self) -> &BTreeMap<String, FileCoverage> {
+        &self.files
+    }
+
+    /// Returns the coverage of the chunk with the given name.
+    pub fn file(&self, chunk_name: &str) -> Option<&FileCoverage> {
+        self.files.get(chunk_name)
+    }
+
+    /// Writes the coverage in the LCOV tracefile format.
+    pub fn write_lcov<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        for (name, file) in &self.files {
+            writeln!(writer, "TN:")?;
+            writeln!(writer, "SF:{}", name)?;
+            for (line, hits) in &file.lines {
+                writeln!(writer, "DA:{},{}", line, hits)?;
+            }
+            writeln!(writer, "LF:{}", file.lines_found())?;
+            writeln!(writer, "LH:{}", file.lines_hit())?;
+            writeln!(writer, "end_of_record")?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the coverage in the Cobertura XML format.
+    pub fn write_cobertura<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let found: usize = self.files.values().map(FileCoverage::lines_found).sum();
+        let hit: usize = self.files.values().map(FileCoverage::lines_hit).sum();
+        let rate = line_rate(hit, found);
+
+        writeln!(writer, r#"<?xml version="1.0" ?>"#)?;
+        writeln!(
+            writer,
+            r#"<coverage line-rate="{rate:.4}" branch-rate="0" lines-covered="{hit}" lines-valid="{found}" branches-covered="0" branches-valid="0" complexity="0" version="{}" timestamp="0">"#,
+            env!("CARGO_PKG_VERSION")
+        )?;
+        writeln!(writer, "  <sources><source>.</source></sources>")?;
+        writeln!(writer, "  <packages>")?;
+        writeln!(
+            writer,
+            r#"    <package name="lua" line-rate="{rate:.4}" branch-rate="0" complexity="0">"#
+        )?;
+        writeln!(writer, "      <classes>")?;
+
+        for (name, file) in &self.files {
+            let name = escape_xml(name);
+            writeln!(
+                writer,
+                r#"        <class name="{name}" filename="{name}" line-rate="{:.4}" branch-rate="0" complexity="0">"#,
+                file.line_rate()
+            )?;
+            writeln!(writer, "          <methods/>")?;
+            writeln!(writer, "          <lines>")?;
+            for (line, hits) in &file.lines {
+                writeln!(
+                    writer,
+                    r#"            <line number="{line}" hits="{hits}"/>"#
+                )?;
+            }
+            writeln!(writer, "          </lines>")?;
+            writeln!(writer, "        </class>")?;
+        }
+
+        writeln!(writer, "      </classes>")?;
+        writeln!(writer, "    </package>")?;
+        writeln!(writer, "  </packages>")?;
+        writeln!(writer, "</coverage>")
+    }
+}
+
+/// Returns the fraction of lines that ran, treating no lines as fully covered.
+fn line_rate(hit: usize, found: usize) -> f64 {
+    match found {
+        0 => 1.0,
+        found => hit as f64 / found as f64,
+    }
+}
+
+/// Escapes text for use in an XML attribute.
+pub(crate) fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Returns the name coverage is reported under for the chunk a function was defined in.
+fn chunk_name(info: &DebugInfo) -> String {
+    match info.source.strip_prefix(['@', '=']) {
+        Some(name) => name.into(),
+        None => info.short_source.clone(),
+    }
+}
+
+/// Records which lines run.
+#[derive(Default)]
+struct Recorder {
+    coverage: Coverage,
+    /// Functions whose lines have been found, by source, line defined and last line defined.
+    seen: HashSet<(String, Int, Int)>,
+}
+impl Recorder {
+    fn on_event(&mut self, ctx: &HookContext, event: HookEvent) {
+        match event {
+            HookEvent::Call | HookEvent::TailCall => self.add_function(ctx),
+            HookEvent::Line(line) => {
+                let info = ctx.info();
+                let file = self.coverage.files.entry(chunk_name(&info)).or_default();
+                *file.lines.entry(line).or_default() += 1;
+            }
+            HookEvent::Return | HookEvent::Count => {}
+        }
+    }
+
+    /// Adds the lines with code of the called function the first time it runs, and of the functions defined in it.
+    fn add_function(&mut self, ctx: &HookContext) {
+        let info = ctx.info();
+        if info.what == FunctionKind::C {
+            return;
+        }
+
+        let line_defined = info.line_defined.unwrap_or(0);
+        let last_line_defined = info.last_line_defined.unwrap_or(0);
+        if !self
+            .seen
+            .insert((info.source.clone(), line_defined, last_line_defined))
+        {
+            return;
+        }
+
+        let file = self.coverage.files.entry(chunk_name(&info)).or_default();
+        for line in ctx.active_lines() {
+            file.lines.entry(line).or_default();
+        }
+
+        // Functions that never run must still count, so the lines of those defined in this one are read from its bytecode
+        let functions = ctx
+            .dump_function()
+            .and_then(|chunk| bytecode::function_lines(&chunk));
+        for function in functions.unwrap_or_default() {
+            self.seen.insert((
+                info.source.clone(),
+                function.line_defined,
+                function.last_line_defined,
+            ));
+            for line in function.lines {
+                file.lines.entry(line).or_default();
+            }
+        }
+    }
+}
+
+/// Collects line coverage for the code run on a `Lua` state.
+pub struct CoverageCollector {
+    recorder: Rc<RefCell<Recorder>>,
+}
+impl CoverageCollector {
+    /// Starts collecting coverage for the given state. This replaces any hook set on it.
+    pub fn start(lua: &mut Lua) -> Self {
+        let recorder = Rc::new(RefCell::new(Recorder::default()));
+        let hook_recorder = recorder.clone();
+        lua.set_hook(
+            HookMask {
+                call: true,
+                ret: false,
+                line: true,
+                count: 0,
+            },
+            move |ctx, event| {
+                hook_recorder.borrow_mut().on_event(ctx, event);
+                Ok(())
+            },
+        );
+
+        Self { recorder }
+    }
+
+    /// Stops collecting coverage and returns the results.
+    pub fn stop(self, lua: &mut Lua) -> Coverage {
+        lua.remove_hook();
+        core::mem::take(&mut self.recorder.borrow_mut().coverage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = "local function used(n)
+  if n > 1 then
+    return n
+  end
+  return 0
+end
+
+local function unused()
+  return 1
+end
+
+used(2)
+used(3)";
+
+    fn coverage() -> Coverage {
+        let mut lua = Lua::new();
+        let collector = CoverageCollector::start(&mut lua);
+        lua.interpret_named("scripts/game.lua", SCRIPT).unwrap();
+        collector.stop(&mut lua)
+    }
+
+    #[test]
+    fn records_hits_per_line() {
+        let coverage = coverage();
+        let file = coverage.file("scripts/game.lua").unwrap();
+
+        assert_eq!(Some(2), file.hits(2));
+        assert_eq!(Some(2), file.hits(3));
+        assert_eq!(Some(0), file.hits(5));
+        assert_eq!(Some(1), file.hits(12));
+        assert_eq!(None, file.hits(7));
+    }
+
+    #[test]
+    fn unused_functions_count_as_not_hit() {
+        let coverage = coverage();
+        let file = coverage.file("scripts/game.lua").unwrap();
+
+        assert_eq!(Some(0), file.hits(9));
+        assert!(file.lines_hit() < file.lines_found());
+    }
+
+    #[test]
+    fn write_lcov_outputs_records() {
+        let mut lua = Lua::new();
+        let collector = CoverageCollector::start(&mut lua);
+        lua.interpret_named("a.lua", "local x = 1\n\nx = x + 1")
+            .unwrap();
+        let coverage = collector.stop(&mut lua);
+
+        let mut lcov = Vec::new();
+        coverage.write_lcov(&mut lcov).unwrap();
+        assert_eq!(
+            "TN:\nSF:a.lua\nDA:1,1\nDA:3,1\nLF:2\nLH:2\nend_of_record\n",
+            String::from_utf8(lcov).unwrap()
+        );
+    }
+
+    #[test]
+    fn write_cobertura_outputs_lines() {
+        let coverage = coverage();
+        let mut xml = Vec::new();
+        coverage.write_cobertura(&mut xml).unwrap();
+        let xml = String::from_utf8(xml).unwrap();
+
+        assert!(xml.starts_with("<?xml"));
+        assert!(xml.contains(r#"filename="scripts/game.lua""#));
+        assert!(xml.contains(r#"<line number="5" hits="0"/>"#));
+        assert!(xml.contains(r#"<line number="9" hits="0"/>"#));
+        assert!(xml.contains(r#"<line number="2" hits="2"/>"#));
+        assert!(xml.trim_end().ends_with("</coverage>"));
+    }
+
+    #[test]
+    fn escape_xml_escapes_markup() {
+        assert_eq!(
+            "[string &quot;a &lt; b&quot;]",
+            escape_xml("[string \"a < b\"]")
+        );
+    }
+
+    #[test]
+    fn line_rate_no_lines_returns_one() {
+        assert_eq!(1.0, line_rate(0, 0));
+        assert_eq!(0.5, line_rate(1, 2));
+    }
+}
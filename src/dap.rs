@@ -0,0 +1,810 @@
+//! A Debug Adapter Protocol server for debugging scripts running in a `Lua` state.
+//!
+//! The debugger runs inside the state's line hook. While paused it blocks on the connection,
+//! answering requests about the paused frame until the client resumes execution.
+
+use crate::{
+    lua_core::Int, DebugInfo, Error, FunctionKind, HookContext, HookMask, Lua, Output, Variable,
+};
+use serde_json::{json, Value};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    rc::Rc,
+};
+
+/// The only thread reported to clients.
+const THREAD_ID: i64 = 1;
+
+/// Connection over standard input and output.
+pub struct Stdio;
+impl Read for Stdio {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::stdin().read(buf)
+    }
+}
+impl Write for Stdio {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stdout().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+/// A debugger attached to a `Lua` state.
+pub struct Debugger<T: Read + Write> {
+    session: Rc<RefCell<Session<T>>>,
+}
+impl Debugger<Stdio> {
+    /// Attaches a debugger that speaks over standard input and output.
+    /// As standard output carries the protocol, what scripts print is sent to the client instead, replacing the state's output.
+    pub fn stdio(lua: &mut Lua) -> io::Result<Self> {
+        let debugger = Self::attach(lua, Stdio)?;
+        debugger.forward_output(lua);
+        Ok(debugger)
+    }
+}
+impl Debugger<TcpStream> {
+    /// Waits for a client to connect on the given address, then attaches a debugger for it.
+    pub fn listen<A: ToSocketAddrs>(lua: &mut Lua, address: A) -> io::Result<Self> {
+        let (stream, _) = TcpListener::bind(address)?.accept()?;
+        Self::attach(lua, stream)
+    }
+}
+impl<T: Read + Write + 'static> Debugger<T> {
+    /// Attaches a debugger that speaks over the given connection.
+    /// Returns once the client has finished configuring breakpoints.
+    pub fn attach(lua: &mut Lua, connection: T) -> io::Result<Self> {
+        let mut session = Session::new(connection);
+        session.configure()?;
+
+        let session = Rc::new(RefCell::new(session));
+        let hook_session = session.clone();
+        lua.set_hook(
+            HookMask {
+                line: true,
+                ..Default::default()
+            },
+            move |ctx, _| {
+                hook_session.borrow_mut().on_line(ctx);
+                Ok(())
+            },
+        );
+
+        Ok(Self { session })
+    }
+
+    /// Sends what scripts print and warn to the client as `output` events, replacing the state's output.
+    pub fn forward_output(&self, lua: &mut Lua) {
+        let pending = self.session.borrow().pending_output.clone();
+        lua.set_output(EventOutput {
+            session: self.session.clone(),
+            pending,
+        });
+    }
+
+    /// Tells the client the program has finished and removes the debugger from the state.
+    pub fn detach(self, lua: &mut Lua) -> io::Result<()> {
+        lua.remove_hook();
+
+        let mut session = self.session.borrow_mut();
+        if session.disconnected {
+            return Ok(());
+        }
+
+        session.event("terminated", json!({}))?;
+        session.disconnected = true;
+        Ok(())
+    }
+}
+
+/// When execution should next stop.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Mode {
+    /// Only stop at breakpoints.
+    Continue,
+    /// Stop at the next line.
+    StepIn,
+    /// Stop at the next line at or above the given call depth.
+    StepOver(Int),
+    /// Stop at the next line above the given call depth.
+    StepOut(Int),
+}
+
+/// What to do after handling a request.
+enum Flow {
+    Wait,
+    Resume,
+}
+
+/// State of a debugging session.
+struct Session<T: Read + Write> {
+    connection: BufReader<T>,
+    /// Sequence number of the next message sent.
+    seq: i64,
+    /// Lines with breakpoints, keyed by the source path given by the client.
+    breakpoints: HashMap<String, HashSet<Int>>,
+    mode: Mode,
+    /// Whether the next stop is the entry stop.
+    entry: bool,
+    disconnected: bool,
+    /// Output events from expressions evaluated while paused, sent once the request is handled.
+    pending_output: Rc<RefCell<Vec<Value>>>,
+}
+impl<T: Read + Write> Session<T> {
+    fn new(connection: T) -> Self {
+        Self {
+            connection: BufReader::new(connection),
+            seq: 1,
+            breakpoints: HashMap::new(),
+            mode: Mode::Continue,
+            entry: false,
+            disconnected: false,
+            pending_output: Rc::default(),
+        }
+    }
+
+    /// Handles requests until the client is done configuring the session.
+    fn configure(&mut self) -> io::Result<()> {
+        while let Some(request) = self.read()? {
+            match command(&request) {
+                "initialize" => {
+                    let capabilities = json!({
+                        "supportsConfigurationDoneRequest": true,
+                        "supportsEvaluateForHovers": true,
+                    });
+                    self.respond(&request, capabilities)?;
+                    self.event("initialized", json!({}))?;
+                }
+                "launch" | "attach" => {
+                    if request["arguments"]["stopOnEntry"].as_bool() == Some(true) {
+                        self.mode = Mode::StepIn;
+                        self.entry = true;
+                    }
+                    self.respond(&request, json!({}))?;
+                }
+                "configurationDone" => {
+                    self.respond(&request, json!({}))?;
+                    return Ok(());
+                }
+                "disconnect" => {
+                    self.respond(&request, json!({}))?;
+                    self.disconnected = true;
+                    return Ok(());
+                }
+                _ => {
+                    self.handle_common(&request)?;
+                }
+            }
+        }
+
+        self.disconnected = true;
+        Ok(())
+    }
+
+    /// Called for every new line, pausing if needed.
+    fn on_line(&mut self, ctx: &HookContext) {
+        if self.disconnected {
+            return;
+        }
+
+        let info = ctx.info();
+        let depth = ctx.depth();
+        let reason = if self.has_breakpoint(&info) {
+            "breakpoint"
+        } else {
+            match self.mode {
+                Mode::Continue => return,
+                Mode::StepIn => "step",
+                Mode::StepOver(from) if depth <= from => "step",
+                Mode::StepOut(from) if depth < from => "step",
+                _ => return,
+            }
+        };
+
+        let reason = match core::mem::take(&mut self.entry) {
+            true => "entry",
+            false => reason,
+        };
+
+        // Losing the connection detaches the debugger
+        if self.pause(ctx, reason).is_err() {
+            self.disconnected = true;
+        }
+    }
+
+    /// Tells the client execution stopped, then handles requests until it resumes.
+    fn pause(&mut self, ctx: &HookContext, reason: &str) -> io::Result<()> {
+        self.event(
+            "stopped",
+            json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }),
+        )?;
+
+        while let Some(request) = self.read()? {
+            if let Flow::Resume = self.handle_paused(ctx, &request)? {
+                return Ok(());
+            }
+        }
+
+        self.disconnected = true;
+        Ok(())
+    }
+
+    /// Handles a request made while paused.
+    fn handle_paused(&mut self, ctx: &HookContext, request: &Value) -> io::Result<Flow> {
+        let arguments = &request["arguments"];
+        match command(request) {
+            "continue" => {
+                self.mode = Mode::Continue;
+                self.respond(request, json!({ "allThreadsContinued": true }))?;
+                return Ok(Flow::Resume);
+            }
+            "next" => {
+                self.mode = Mode::StepOver(ctx.depth());
+                self.respond(request, json!({}))?;
+                return Ok(Flow::Resume);
+            }
+            "stepIn" => {
+                self.mode = Mode::StepIn;
+                self.respond(request, json!({}))?;
+                return Ok(Flow::Resume);
+            }
+            "stepOut" => {
+                self.mode = Mode::StepOut(ctx.depth());
+                self.respond(request, json!({}))?;
+                return Ok(Flow::Resume);
+            }
+            "disconnect" => {
+                self.respond(request, json!({}))?;
+                self.disconnected = true;
+                return Ok(Flow::Resume);
+            }
+            "stackTrace" => {
+                let frames: Vec<Value> = (0..ctx.depth())
+                    .filter_map(|level| ctx.frame(level).map(|info| stack_frame(level, &info)))
+                    .collect();
+                let total = frames.len();
+                self.respond(
+                    request,
+                    json!({ "stackFrames": frames, "totalFrames": total }),
+                )?;
+            }
+            "scopes" => {
+                let frame = arguments["frameId"].as_i64().unwrap_or(1);
+                let scopes = json!([
+                    { "name": "Locals", "variablesReference": frame * 2, "expensive": false },
+                    { "name": "Upvalues", "variablesReference": frame * 2 + 1, "expensive": false },
+                ]);
+                self.respond(request, json!({ "scopes": scopes }))?;
+            }
+            "variables" => {
+                let reference = arguments["variablesReference"].as_i64().unwrap_or(0);
+                let level = (reference / 2 - 1) as Int;
+                let variables = match reference % 2 {
+                    0 => ctx.locals(level),
+                    _ => ctx.upvalues(level),
+                };
+                let variables: Vec<Value> =
+                    variables.unwrap_or_default().iter().map(variable).collect();
+                self.respond(request, json!({ "variables": variables }))?;
+            }
+            "evaluate" => {
+                let level = arguments["frameId"].as_i64().map_or(0, |frame| frame - 1) as Int;
+                let expression = arguments["expression"].as_str().unwrap_or_default();
+                let result = ctx.evaluate(level, expression);
+                let pending = core::mem::take(&mut *self.pending_output.borrow_mut());
+                for body in pending {
+                    self.event("output", body)?;
+                }
+
+                match result {
+                    Ok(result) => self.respond(
+                        request,
+                        json!({ "result": result, "variablesReference": 0 }),
+                    )?,
+                    Err(Error::Runtime(message)) => self.respond_error(request, &message)?,
+                    Err(e) => self.respond_error(request, &format!("{e:?}"))?,
+                }
+            }
+            _ => self.handle_common(request)?,
+        }
+
+        Ok(Flow::Wait)
+    }
+
+    /// Handles requests that can be made at any time.
+    fn handle_common(&mut self, request: &Value) -> io::Result<()> {
+        match command(request) {
+            "setBreakpoints" => {
+                let arguments = &request["arguments"];
+                let path = arguments["source"]["path"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .replace('\\', "/");
+                let lines: HashSet<Int> = arguments["breakpoints"]
+                    .as_array()
+                    .map(|breakpoints| {
+                        breakpoints
+                            .iter()
+                            .filter_map(|b| b["line"].as_i64())
+                            .map(|line| line as Int)
+                            .collect()
+                    })
+                    .unwrap_or_default();
+
+                let mut verified: Vec<Value> = lines
+                    .iter()
+                    .map(|line| json!({ "verified": true, "line": line }))
+                    .collect();
+                verified.sort_by_key(|b| b["line"].as_i64());
+
+                self.breakpoints.insert(path, lines);
+                self.respond(request, json!({ "breakpoints": verified }))
+            }
+            "setExceptionBreakpoints" | "setFunctionBreakpoints" => {
+                self.respond(request, json!({ "breakpoints": [] }))
+            }
+            "threads" => self.respond(
+                request,
+                json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] }),
+            ),
+            command => self.respond_error(request, &format!("unsupported request '{command}'")),
+        }
+    }
+
+    /// Returns whether there is a breakpoint on the line being run.
+    fn has_breakpoint(&self, info: &DebugInfo) -> bool {
+        let (Some(line), Some(chunk)) = (info.current_line, info.source.strip_prefix('@')) else {
+            return false;
+        };
+
+        self.breakpoints
+            .iter()
+            .any(|(path, lines)| lines.contains(&line) && same_source(path, chunk))
+    }
+
+    /// Reads the next message, returning `None` once the client disconnects.
+    fn read(&mut self) -> io::Result<Option<Value>> {
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            if self.connection.read_line(&mut header)? == 0 {
+                return Ok(None);
+            }
+
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(value) = header.strip_prefix("Content-Length:") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+
+        let length = length
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+        let mut body = vec![0; length];
+        self.connection.read_exact(&mut body)?;
+
+        serde_json::from_slice(&body)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Sends a message to the client.
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+
+        let body = message.to_string();
+        let connection = self.connection.get_mut();
+        write!(connection, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        connection.flush()
+    }
+
+    fn respond(&mut self, request: &Value, body: Value) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }))
+    }
+
+    fn respond_error(&mut self, request: &Value, message: &str) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }))
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+}
+
+/// Sends output to the client as `output` events.
+struct EventOutput<T: Read + Write> {
+    session: Rc<RefCell<Session<T>>>,
+    pending: Rc<RefCell<Vec<Value>>>,
+}
+impl<T: Read + Write> EventOutput<T> {
+    fn send(&mut self, category: &str, text: &str) {
+        let body = json!({ "category": category, "output": text });
+
+        // The session is busy while paused, when the output comes from an evaluated expression
+        let Ok(mut session) = self.session.try_borrow_mut() else {
+            self.pending.borrow_mut().push(body);
+            return;
+        };
+        if session.disconnected {
+            return;
+        }
+
+        // Losing the connection detaches the debugger
+        if session.event("output", body).is_err() {
+            session.disconnected = true;
+        }
+    }
+}
+impl<T: Read + Write> Output for EventOutput<T> {
+    fn stdout(&mut self, text: &str) {
+        self.send("stdout", text);
+    }
+
+    fn stderr(&mut self, text: &str) {
+        self.send("stderr", text);
+    }
+
+    fn warn(&mut self, message: &str) {
+        self.send("stderr", &format!("Lua warning: {message}\n"));
+    }
+}
+
+/// Returns the command of a request.
+fn command(request: &Value) -> &str {
+    request["command"].as_str().unwrap_or_default()
+}
+
+/// Returns whether a path from the client refers to the given chunk.
+fn same_source(path: &str, chunk: &str) -> bool {
+    let chunk = chunk.trim_start_matches("./");
+    path == chunk || path.ends_with(&format!("/{chunk}"))
+}
+
+/// Maps a function on the call stack to a DAP stack frame.
+fn stack_frame(level: Int, info: &DebugInfo) -> Value {
+    let name = match (&info.name, info.what) {
+        (Some(name), _) => name.clone(),
+        (None, FunctionKind::Main) => "main chunk".into(),
+        (None, _) => "?".into(),
+    };
+
+    let mut frame = json!({
+        "id": level + 1,
+        "name": name,
+        "line": info.current_line.unwrap_or(0),
+        "column": 1,
+    });
+
+    match info.source.strip_prefix('@') {
+        Some(path) => frame["source"] = json!({ "name": info.short_source, "path": path }),
+        None if info.what == FunctionKind::C => frame["presentationHint"] = json!("subtle"),
+        None => frame["source"] = json!({ "name": info.short_source }),
+    }
+
+    frame
+}
+
+/// Maps a variable to a DAP variable.
+fn variable(variable: &Variable) -> Value {
+    json!({
+        "name": variable.name,
+        "value": variable.value,
+        "type": format!("{:?}", variable.m_type),
+        "variablesReference": 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Library;
+    use std::io::Cursor;
+
+    /// Scripted client, with all requests written up front and responses collected.
+    struct Client {
+        requests: Cursor<Vec<u8>>,
+        responses: Rc<RefCell<Vec<u8>>>,
+    }
+    impl Read for Client {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.requests.read(buf)
+        }
+    }
+    impl Write for Client {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.responses.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn client(requests: &[Value]) -> (Client, Rc<RefCell<Vec<u8>>>) {
+        let mut bytes = Vec::new();
+        for (seq, request) in requests.iter().enumerate() {
+            let mut request = request.clone();
+            request["seq"] = json!(seq + 1);
+            request["type"] = json!("request");
+            let body = request.to_string();
+            write!(bytes, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        }
+
+        let responses = Rc::new(RefCell::new(Vec::new()));
+        let client = Client {
+            requests: Cursor::new(bytes),
+            responses: responses.clone(),
+        };
+
+        (client, responses)
+    }
+
+    /// Parses the messages sent by the debugger.
+    fn messages(bytes: &[u8]) -> Vec<Value> {
+        let mut session = Session::new(Cursor::new(bytes.to_vec()));
+        let mut messages = Vec::new();
+        while let Some(message) = session.read().unwrap() {
+            messages.push(message);
+        }
+
+        messages
+    }
+
+    fn response<'a>(messages: &'a [Value], command: &str) -> Vec<&'a Value> {
+        messages
+            .iter()
+            .filter(|m| m["type"] == "response" && m["command"] == command)
+            .collect()
+    }
+
+    fn stops(messages: &[Value]) -> Vec<String> {
+        messages
+            .iter()
+            .filter(|m| m["event"] == "stopped")
+            .map(|m| m["body"]["reason"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    const SCRIPT: &str = "local function add(a, b)
+  local sum = a + b
+  return sum
+end
+local total = add(1, 2)
+total = add(total, 3)
+result = total";
+
+    fn run(requests: &[Value]) -> (Vec<Value>, Result<(), Error>) {
+        let (client, responses) = client(requests);
+        let mut lua = Lua::new();
+        lua.activate(Library::all()).unwrap();
+
+        let debugger = Debugger::attach(&mut lua, client).unwrap();
+        let result = lua.interpret_named("game/main.lua", SCRIPT);
+        debugger.detach(&mut lua).unwrap();
+
+        let messages = messages(&responses.borrow());
+        (messages, result)
+    }
+
+    fn handshake() -> Vec<Value> {
+        vec![
+            json!({ "command": "initialize", "arguments": { "adapterID": "llua" } }),
+            json!({ "command": "launch", "arguments": {} }),
+            json!({ "command": "setBreakpoints", "arguments": {
+                "source": { "path": "/home/dev/project/game/main.lua" },
+                "breakpoints": [{ "line": 2 }],
+            }}),
+            json!({ "command": "configurationDone" }),
+        ]
+    }
+
+    #[test]
+    fn breakpoint_stops_and_inspects_frame() {
+        let mut requests = handshake();
+        requests.extend([
+            json!({ "command": "threads" }),
+            json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+            json!({ "command": "scopes", "arguments": { "frameId": 1 } }),
+            json!({ "command": "variables", "arguments": { "variablesReference": 2 } }),
+            json!({ "command": "evaluate", "arguments": { "expression": "a * 10 + b", "frameId": 1 } }),
+            json!({ "command": "evaluate", "arguments": { "expression": "nope(", "frameId": 1 } }),
+            json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+            json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+        ]);
+
+        let (messages, result) = run(&requests);
+        assert_eq!(Ok(()), result);
+        assert_eq!(vec!["breakpoint", "breakpoint"], stops(&messages));
+
+        let frames = &response(&messages, "stackTrace")[0]["body"]["stackFrames"];
+        assert_eq!("add", frames[0]["name"]);
+        assert_eq!(2, frames[0]["line"]);
+        assert_eq!("game/main.lua", frames[0]["source"]["path"]);
+        assert_eq!("main chunk", frames[1]["name"]);
+        assert_eq!(5, frames[1]["line"]);
+
+        let variables = &response(&messages, "variables")[0]["body"]["variables"];
+        assert_eq!("a", variables[0]["name"]);
+        assert_eq!("1", variables[0]["value"]);
+        assert_eq!("b", variables[1]["name"]);
+        assert_eq!("2", variables[1]["value"]);
+
+        let evaluations = response(&messages, "evaluate");
+        assert_eq!("12", evaluations[0]["body"]["result"]);
+        assert_eq!(false, evaluations[1]["success"]);
+
+        assert!(messages.iter().any(|m| m["event"] == "terminated"));
+    }
+
+    #[test]
+    fn step_over_and_out() {
+        let mut requests = handshake();
+        requests.extend([
+            // Stopped in add at line 2
+            json!({ "command": "next", "arguments": { "threadId": 1 } }),
+            // Line 3, then out to line 6 of the main chunk
+            json!({ "command": "stepOut", "arguments": { "threadId": 1 } }),
+            // Over the call at line 6 straight to line 7
+            json!({ "command": "next", "arguments": { "threadId": 1 } }),
+            json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+            json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+        ]);
+        // Only break once, on the first call
+        requests[2]["arguments"]["breakpoints"] = json!([{ "line": 5 }]);
+        requests.insert(
+            4,
+            json!({ "command": "stepIn", "arguments": { "threadId": 1 } }),
+        );
+
+        let (messages, result) = run(&requests);
+        assert_eq!(Ok(()), result);
+
+        // Breakpoint at 5, into add at 2, over to 3, out to 6, over to 7
+        assert_eq!(
+            vec!["breakpoint", "step", "step", "step", "step"],
+            stops(&messages)
+        );
+        let frames = &response(&messages, "stackTrace")[0]["body"]["stackFrames"];
+        assert_eq!(7, frames[0]["line"]);
+    }
+
+    #[test]
+    fn stop_on_entry() {
+        let mut requests = handshake();
+        requests[1]["arguments"]["stopOnEntry"] = json!(true);
+        requests[2]["arguments"]["breakpoints"] = json!([]);
+        requests.extend([
+            json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+            json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+        ]);
+
+        let (messages, result) = run(&requests);
+        assert_eq!(Ok(()), result);
+        assert_eq!(vec!["entry"], stops(&messages));
+
+        let frames = &response(&messages, "stackTrace")[0]["body"]["stackFrames"];
+        assert_eq!("main chunk", frames[0]["name"]);
+        assert_eq!(1, frames.as_array().unwrap().len());
+    }
+
+    #[test]
+    fn client_disconnect_lets_script_finish() {
+        let mut requests = handshake();
+        requests.push(json!({ "command": "disconnect" }));
+
+        let (messages, result) = run(&requests);
+        assert_eq!(Ok(()), result);
+        assert_eq!(vec!["breakpoint"], stops(&messages));
+        assert!(!messages.iter().any(|m| m["event"] == "terminated"));
+    }
+
+    #[test]
+    fn connection_closed_lets_script_finish() {
+        let (messages, result) = run(&handshake());
+        assert_eq!(Ok(()), result);
+        assert_eq!(vec!["breakpoint"], stops(&messages));
+    }
+
+    #[test]
+    fn unsupported_request_returns_error() {
+        let mut requests = handshake();
+        requests.insert(1, json!({ "command": "restartFrame" }));
+
+        let (messages, _) = run(&requests);
+        let responses = response(&messages, "restartFrame");
+        assert_eq!(false, responses[0]["success"]);
+    }
+
+    #[test]
+    fn listen_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+
+        let client = std::thread::spawn(move || {
+            let mut stream = loop {
+                match TcpStream::connect(address) {
+                    Ok(stream) => break stream,
+                    Err(_) => std::thread::sleep(std::time::Duration::from_millis(5)),
+                }
+            };
+
+            let mut requests = handshake();
+            requests.push(json!({ "command": "continue", "arguments": { "threadId": 1 } }));
+            let (mut scripted, _) = client(&requests);
+            io::copy(&mut scripted.requests, &mut stream).unwrap();
+            stream.shutdown(std::net::Shutdown::Write).unwrap();
+
+            let mut responses = Vec::new();
+            stream.read_to_end(&mut responses).unwrap();
+            messages(&responses)
+        });
+
+        let mut lua = Lua::new();
+        let debugger = Debugger::listen(&mut lua, address).unwrap();
+        let result = lua.interpret_named("game/main.lua", SCRIPT);
+        debugger.detach(&mut lua).unwrap();
+        drop(lua);
+
+        // The client goes away at the second breakpoint
+        let messages = client.join().unwrap();
+        assert_eq!(Ok(()), result);
+        assert_eq!(vec!["breakpoint", "breakpoint"], stops(&messages));
+    }
+
+    #[test]
+    fn forward_output_sends_output_events() {
+        let mut requests = handshake();
+        requests.extend([
+            json!({ "command": "evaluate", "arguments": { "expression": "print('paused')", "frameId": 1 } }),
+            json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+        ]);
+        let (client, responses) = client(&requests);
+        let mut lua = Lua::new();
+        lua.activate(Library::all()).unwrap();
+
+        let debugger = Debugger::attach(&mut lua, client).unwrap();
+        debugger.forward_output(&mut lua);
+        let code = "print('before')\nlocal x = 1\nio.write('after')";
+        let result = lua.interpret_named("game/main.lua", code);
+        debugger.detach(&mut lua).unwrap();
+
+        let messages = messages(&responses.borrow());
+        assert_eq!(Ok(()), result);
+        let output: Vec<&Value> = messages
+            .iter()
+            .filter(|m| m["event"] == "output")
+            .map(|m| &m["body"]["output"])
+            .collect();
+        assert_eq!(vec!["before\n", "paused\n", "after"], output);
+        assert_eq!(true, response(&messages, "evaluate")[0]["success"]);
+    }
+
+    #[test]
+    fn same_source_matches_suffix() {
+        assert!(same_source("/a/b/game/main.lua", "game/main.lua"));
+        assert!(same_source("game/main.lua", "./game/main.lua"));
+        assert!(!same_source("/a/b/othergame/main.lua", "game/main.lua"));
+    }
+}
@@ -10,8 +10,10 @@ pub enum DataErr {
 }
 
 /// A piece of data.
-#[derive(Clone, PartialEq, Debug)]
+#[derive(Clone, PartialEq, Debug, Default)]
+#[allow(unpredictable_function_pointer_comparisons)]
 pub enum Data {
+    #[default]
     Nil,
     Bool(bool),
     Number(LuaNum),
@@ -44,13 +46,14 @@ impl Data {
 
     /// Attempts to pop some data off the stack.
     pub(crate) fn pop(state: State) -> Result<Self, DataErr> {
-        let mtype = Type::get_type(state, -1);
         let has_data = unsafe { lua_gettop(state) > 0 };
 
         if !has_data {
             return Err(DataErr::StackUnderflow);
         }
 
+        let mtype = Type::get_type(state, -1);
+
         let data = match mtype {
             Some(ty) => match ty {
                 Type::Nil => Data::Nil,
@@ -100,10 +103,13 @@ impl Data {
                 Data::Number(num) => lua_pushnumber(state, *num),
                 Data::String(s) => {
                     let s = CString::new(s.clone()).unwrap();
-                    lua_pushstring(state, s.as_c_str().as_ptr() as *const u8);
+                    lua_pushstring(state, s.as_c_str().as_ptr());
                 }
                 Data::Table => todo!(),
-                Data::Function(f) => lua_pushcfunction(state, *f),
+                #[cfg(not(feature = "tracing"))]
+                Data::Function(f) => push_function(state, *f),
+                #[cfg(feature = "tracing")]
+                Data::Function(f) => crate::instrument::push_traced(state, *f),
                 Data::UserData => todo!(),
                 Data::Thread => todo!(),
             }
@@ -111,11 +117,20 @@ impl Data {
     }
 }
 
-impl Default for Data {
-    fn default() -> Self {
-        Self::Nil
-    }
+/// Pushes the Rust function as a C closure calling it.
+#[cfg(not(feature = "tracing"))]
+unsafe fn push_function(state: State, function: LuaFn) {
+    lua_pushlightuserdata(state, function as *mut core::ffi::c_void);
+    lua_pushcclosure(state, call_function, 1);
 }
+
+/// Calls the Rust function in its upvalue.
+#[cfg(not(feature = "tracing"))]
+unsafe extern "C" fn call_function(state: State) -> Int {
+    let function: LuaFn = core::mem::transmute(lua_touserdata(state, lua_upvalueindex(1)));
+    function(state)
+}
+
 impl<'a> From<&'a str> for Data {
     fn from(s: &'a str) -> Self {
         Data::String(s.into())
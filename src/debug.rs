@@ -0,0 +1,472 @@
+extern crate alloc;
+
+use crate::{lua_core::*, Error, Type};
+use alloc::{boxed::Box, ffi::CString, format, string::String, vec::Vec};
+use core::ffi::{c_char, CStr};
+
+/// How many instructions an expression evaluated from a hook may run.
+const EVALUATION_BUDGET: Int = 1_000_000;
+
+/// A hook set from Rust. Returning an error aborts the running call with it.
+pub(crate) type HookFn = Box<dyn FnMut(&HookContext, HookEvent) -> Result<(), Error>>;
+
+/// The events a hook is called for.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct HookMask {
+    /// Called when a function is called.
+    pub call: bool,
+    /// Called when a function returns.
+    pub ret: bool,
+    /// Called when a new line of code starts executing.
+    pub line: bool,
+    /// Called every `count` instructions. 0 disables it.
+    pub count: Int,
+}
+impl HookMask {
+    /// Returns the Lua mask for the call, return and line events.
+    pub(crate) fn lua_mask(&self) -> Int {
+        let mut mask = 0;
+        if self.call {
+            mask |= LUA_MASKCALL;
+        }
+        if self.ret {
+            mask |= LUA_MASKRET;
+        }
+        if self.line {
+            mask |= LUA_MASKLINE;
+        }
+
+        mask
+    }
+}
+
+/// An event a hook was called for.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HookEvent {
+    Call,
+    TailCall,
+    Return,
+    /// A new line started executing.
+    Line(Int),
+    Count,
+}
+impl HookEvent {
+    /// Maps the event from Lua.
+    pub(crate) fn from_lua(event: Int, line: Int) -> Option<Self> {
+        match event {
+            LUA_HOOKCALL => Some(HookEvent::Call),
+            #[cfg(not(lua_version = "5.1"))]
+            LUA_HOOKTAILCALL => Some(HookEvent::TailCall),
+            // Lua 5.1 reports returns from tail calls instead of the calls
+            #[cfg(lua_version = "5.1")]
+            LUA_HOOKTAILRET => Some(HookEvent::Return),
+            LUA_HOOKRET => Some(HookEvent::Return),
+            LUA_HOOKLINE => Some(HookEvent::Line(line)),
+            LUA_HOOKCOUNT => Some(HookEvent::Count),
+            _ => None,
+        }
+    }
+}
+
+/// The kinds of functions that can be running.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FunctionKind {
+    Lua,
+    C,
+    /// The main part of a chunk.
+    Main,
+}
+
+/// Information about a running function.
+#[derive(Clone, PartialEq, Debug)]
+pub struct DebugInfo {
+    /// Where the function was defined, such as `@file.lua` or the code itself.
+    pub source: String,
+    /// A printable version of the source.
+    pub short_source: String,
+    /// The line currently being executed.
+    pub current_line: Option<Int>,
+    /// The name the function was called by, if it could be found.
+    pub name: Option<String>,
+    /// How the name was found, such as `global`, `local`, `method` or `field`.
+    pub name_what: String,
+    /// The kind of function.
+    pub what: FunctionKind,
+    /// The line the function definition starts on.
+    pub line_defined: Option<Int>,
+    /// The line the function definition ends on.
+    pub last_line_defined: Option<Int>,
+}
+impl DebugInfo {
+    /// Reads the information from an activation record filled in with `nSl`.
+    pub(crate) unsafe fn from_lua(ar: &LuaDebug) -> Self {
+        let what = match to_string(ar.what).as_deref() {
+            Some("C") => FunctionKind::C,
+            Some("main") => FunctionKind::Main,
+            _ => FunctionKind::Lua,
+        };
+
+        Self {
+            source: to_string(ar.source).unwrap_or_default(),
+            short_source: to_string(ar.short_src.as_ptr()).unwrap_or_default(),
+            current_line: to_line(ar.currentline),
+            name: to_string(ar.name),
+            name_what: to_string(ar.namewhat).unwrap_or_default(),
+            what,
+            line_defined: to_line(ar.linedefined),
+            last_line_defined: to_line(ar.lastlinedefined),
+        }
+    }
+}
+
+/// A variable visible to a running function.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Variable {
+    pub name: String,
+    /// A printable version of the value.
+    pub value: String,
+    pub m_type: Type,
+}
+
+/// Gives hooks access to the state they were called from.
+pub struct HookContext {
+    state: State,
+    ar: *mut LuaDebug,
+}
+impl HookContext {
+    /// Creates a context for the given hook call.
+    pub(crate) fn new(state: State, ar: *mut LuaDebug) -> Self {
+        Self { state, ar }
+    }
+
+    /// Returns information about the function that triggered the hook.
+    pub fn info(&self) -> DebugInfo {
+        unsafe {
+            lua_getinfo(self.state, c"nSl".as_ptr(), self.ar);
+            DebugInfo::from_lua(&*self.ar)
+        }
+    }
+
+    /// Returns the lines with code of the function that triggered the hook, which are none for C functions.
+    #[cfg(feature = "std")]
+    pub(crate) fn active_lines(&self) -> Vec<Int> {
+        let mut lines = Vec::new();
+        unsafe {
+            lua_getinfo(self.state, c"L".as_ptr(), self.ar);
+            if lua_type(self.state, -1) == LUA_TTABLE {
+                lua_pushnil(self.state);
+                while lua_next(self.state, -2) != 0 {
+                    lines.push(lua_tointeger(self.state, -2) as Int);
+                    lua_pop(self.state, 1);
+                }
+            }
+            lua_pop(self.state, 1);
+        }
+
+        lines
+    }
+
+    /// Dumps the function that triggered the hook as a binary chunk, returning `None` for C functions.
+    #[cfg(feature = "std")]
+    pub(crate) fn dump_function(&self) -> Option<Vec<u8>> {
+        unsafe {
+            lua_getinfo(self.state, c"f".as_ptr(), self.ar);
+            let chunk = crate::bytecode::dump(self.state, false);
+            lua_pop(self.state, 1);
+
+            chunk
+        }
+    }
+
+    /// Returns information about the function at the given level of the call stack.
+    /// Level 0 is the running function, level 1 is the function that called it and so on.
+    pub fn frame(&self, level: Int) -> Option<DebugInfo> {
+        let mut ar = LuaDebug::new();
+        unsafe {
+            if lua_getstack(self.state, level, &mut ar) == 0 {
+                return None;
+            }
+
+            lua_getinfo(self.state, c"nSl".as_ptr(), &mut ar);
+            Some(DebugInfo::from_lua(&ar))
+        }
+    }
+
+    /// Returns the number of functions on the call stack.
+    pub fn depth(&self) -> Int {
+        let mut ar = LuaDebug::new();
+        let mut depth = 0;
+        while unsafe { lua_getstack(self.state, depth, &mut ar) } != 0 {
+            depth += 1;
+        }
+
+        depth
+    }
+
+    /// Returns the local variables in scope for the function at the given level of the call stack.
+    pub fn locals(&self, level: Int) -> Option<Vec<Variable>> {
+        let ar = self.activation(level)?;
+        let mut locals = Vec::new();
+
+        for n in 1.. {
+            let name = unsafe { lua_getlocal(self.state, &ar, n) };
+            if name.is_null() {
+                break;
+            }
+
+            // Skip internal locals such as `(temporary)`
+            let variable = self.pop_variable(name);
+            if !variable.name.starts_with('(') {
+                locals.push(variable);
+            }
+        }
+
+        Some(locals)
+    }
+
+    /// Returns the upvalues of the function at the given level of the call stack.
+    pub fn upvalues(&self, level: Int) -> Option<Vec<Variable>> {
+        let mut ar = self.activation(level)?;
+        let mut upvalues = Vec::new();
+
+        unsafe {
+            lua_getinfo(self.state, c"f".as_ptr(), &mut ar);
+
+            for n in 1.. {
+                let name = lua_getupvalue(self.state, -1, n);
+                if name.is_null() {
+                    break;
+                }
+
+                upvalues.push(self.pop_variable(name));
+            }
+
+            lua_pop(self.state, 1);
+        }
+
+        Some(upvalues)
+    }
+
+    /// Evaluates an expression as if it were written in the function at the given level of the call stack.
+    /// Locals and upvalues of the function are visible to it, along with globals.
+    /// Expressions that run for more than a million instructions are stopped with an error.
+    pub fn evaluate(&self, level: Int, expression: &str) -> Result<String, Error> {
+        let mut ar = self
+            .activation(level)
+            .ok_or_else(|| Error::Runtime(format!("no function at level {level}")))?;
+
+        let top = unsafe { lua_gettop(self.state) };
+        let result = unsafe { self.evaluate_in(&mut ar, expression) };
+        unsafe { lua_settop(self.state, top) };
+
+        result
+    }
+
+    /// Evaluates the expression, leaving values on the stack for the caller to clean up.
+    unsafe fn evaluate_in(&self, ar: &mut LuaDebug, expression: &str) -> Result<String, Error> {
+        let state = self.state;
+
+        // The environment, which falls back to the function's _ENV or the globals
+        lua_newtable(state);
+        let env = lua_gettop(state);
+        lua_newtable(state);
+        let meta = lua_gettop(state);
+        let mut fallback = false;
+
+        // Upvalues are set first so locals shadow them
+        lua_getinfo(state, c"f".as_ptr(), ar);
+        for n in 1.. {
+            let name = lua_getupvalue(state, -1, n);
+            if name.is_null() {
+                break;
+            }
+
+            if CStr::from_ptr(name).to_bytes() == b"_ENV" {
+                lua_setfield(state, meta, c"__index".as_ptr());
+                fallback = true;
+            } else {
+                lua_setfield(state, env, name as *const c_char);
+            }
+        }
+        lua_pop(state, 1);
+
+        for n in 1.. {
+            let name = lua_getlocal(state, ar, n);
+            if name.is_null() {
+                break;
+            }
+
+            // Internal locals such as `(for state)` can't be named in code
+            if *name == b'(' as c_char {
+                lua_pop(state, 1);
+            } else {
+                lua_setfield(state, env, name as *const c_char);
+            }
+        }
+
+        if !fallback {
+            lua_pushglobaltable(state);
+            lua_setfield(state, meta, c"__index".as_ptr());
+        }
+        lua_setmetatable(state, env);
+
+        let code = format!("return {expression}");
+        let name = CString::new("=(eval)").unwrap();
+        let loaded = luaL_loadbufferx(
+            state,
+            code.as_ptr() as *const c_char,
+            code.len(),
+            name.as_ptr(),
+            core::ptr::null(),
+        ) == LUA_OK;
+
+        if loaded {
+            // Replace the chunk's _ENV, which is its environment in Lua 5.1
+            lua_pushvalue(state, env);
+            #[cfg(lua_version = "5.1")]
+            lua_setfenv(state, -2);
+            #[cfg(not(lua_version = "5.1"))]
+            lua_setupvalue(state, -2, 1);
+        }
+
+        if loaded {
+            // Hooks are off while a hook runs, so the expression runs on its own thread to give it a budget
+            let thread = lua_newthread(state);
+            lua_sethook(
+                thread,
+                Some(stop_evaluation),
+                LUA_MASKCOUNT,
+                EVALUATION_BUDGET,
+            );
+            lua_pushvalue(state, -2);
+            lua_xmove(state, thread, 1);
+            let status = lua_pcall(thread, 0, 1, 0);
+            lua_xmove(thread, state, 1);
+
+            // The budget ran out even if the expression caught the error
+            if lua_gethookcount(thread) != EVALUATION_BUDGET {
+                return Err(Error::Runtime("evaluation budget exceeded".into()));
+            }
+            if status == LUA_OK {
+                return match Type::get_type(state, -1) {
+                    Some(Type::String) => {
+                        Ok(to_string(lua_tostring(state, -1)).unwrap_or_default())
+                    }
+                    _ => Ok(describe(state, -1)),
+                };
+            }
+        }
+
+        Err(Error::Runtime(
+            to_string(lua_tostring(state, -1)).unwrap_or_else(|| describe(state, -1)),
+        ))
+    }
+
+    /// Returns the activation record for the given level of the call stack.
+    fn activation(&self, level: Int) -> Option<LuaDebug> {
+        let mut ar = LuaDebug::new();
+        match unsafe { lua_getstack(self.state, level, &mut ar) } {
+            0 => None,
+            _ => Some(ar),
+        }
+    }
+
+    /// Pops a value pushed by the debug interface, returning it as a variable.
+    fn pop_variable(&self, name: *const c_char) -> Variable {
+        let variable = Variable {
+            name: unsafe { to_string(name) }.unwrap_or_default(),
+            value: unsafe { describe(self.state, -1) },
+            m_type: Type::get_type(self.state, -1).unwrap_or(Type::Nil),
+        };
+        unsafe { lua_pop(self.state, 1) };
+
+        variable
+    }
+}
+
+/// Stops an evaluation that ran out of instructions, and keeps stopping it so it can't catch the error.
+unsafe extern "C" fn stop_evaluation(state: State, _: *mut LuaDebug) {
+    lua_sethook(state, Some(stop_evaluation), LUA_MASKCOUNT, 1);
+    lua_pushstring(state, c"evaluation budget exceeded".as_ptr());
+    lua_error(state);
+}
+
+/// Returns a printable version of the value at the given index, without calling any metamethods.
+pub(crate) unsafe fn describe(state: State, index: Int) -> String {
+    match Type::get_type(state, index) {
+        None | Some(Type::Nil) => "nil".into(),
+        Some(Type::Bool) => match lua_toboolean(state, index) {
+            0 => "false".into(),
+            _ => "true".into(),
+        },
+        Some(Type::Number) => match lua_isinteger(state, index) {
+            0 => format!("{}", lua_tonumber(state, index)),
+            _ => format!("{}", lua_tointeger(state, index)),
+        },
+        Some(Type::String) => format!(
+            "{:?}",
+            to_string(lua_tostring(state, index)).unwrap_or_default()
+        ),
+        Some(_) => {
+            let type_name = to_string(lua_typename(state, lua_type(state, index)));
+            format!(
+                "{}: {:p}",
+                type_name.unwrap_or_default(),
+                lua_topointer(state, index)
+            )
+        }
+    }
+}
+
+/// Copies a C string that may be null.
+unsafe fn to_string(chars: *const c_char) -> Option<String> {
+    if chars.is_null() {
+        return None;
+    }
+
+    Some(CStr::from_ptr(chars).to_string_lossy().into())
+}
+
+/// Maps a line number where -1 means not available.
+fn to_line(line: Int) -> Option<Int> {
+    match line {
+        line if line < 0 => None,
+        line => Some(line),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lua_mask_maps_events() {
+        let mask = HookMask {
+            call: true,
+            ret: false,
+            line: true,
+            count: 0,
+        };
+
+        assert_eq!(LUA_MASKCALL | LUA_MASKLINE, mask.lua_mask());
+    }
+
+    #[test]
+    fn from_lua_line_returns_line() {
+        assert_eq!(
+            Some(HookEvent::Line(4)),
+            HookEvent::from_lua(LUA_HOOKLINE, 4)
+        );
+    }
+
+    #[test]
+    fn from_lua_invalid_returns_none() {
+        assert_eq!(None, HookEvent::from_lua(99, 0));
+    }
+
+    #[test]
+    fn to_line_negative_returns_none() {
+        assert_eq!(None, to_line(-1));
+        assert_eq!(Some(3), to_line(3));
+    }
+}
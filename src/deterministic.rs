@@ -0,0 +1,826 @@
+//! Deterministic execution, for lockstep simulations and replays.
+//!
+//! Once `Lua::set_deterministic` is called, `math.random` draws from a generator seeded from Rust, `os.time`, `os.clock` and `os.date` read a clock set from Rust, and `pairs` visits keys in a sorted order.
+
+use crate::{
+    extra::Extra,
+    lua_core::*,
+    overrides::{call_replaced, check, replace},
+    Library,
+};
+use alloc::{format, string::String, vec::Vec};
+use core::{
+    cmp::Ordering,
+    ffi::{c_char, CStr},
+};
+
+/// The time scripts see in deterministic mode, which only changes when it is set from Rust.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VirtualClock {
+    /// Seconds since the Unix epoch, returned by `os.time` and formatted by `os.date` in UTC.
+    pub time: i64,
+    /// Processor time in seconds, returned by `os.clock`.
+    pub clock: f64,
+}
+
+/// The generator and clock of the deterministic mode.
+pub(crate) struct Deterministic {
+    /// Seed the generator restarts from when `math.randomseed` is called without arguments.
+    seed: u64,
+    random: Random,
+    pub(crate) clock: VirtualClock,
+}
+impl Deterministic {
+    pub(crate) fn new(seed: u64, clock: VirtualClock) -> Self {
+        Self {
+            seed,
+            random: Random::new(seed),
+            clock,
+        }
+    }
+}
+
+/// The xoshiro256** generator of Lua 5.4, with its state filled by splitmix64 so any seed works.
+struct Random([u64; 4]);
+impl Random {
+    fn new(seed: u64) -> Self {
+        let mut state = seed;
+        Self(core::array::from_fn(|_| {
+            state = state.wrapping_add(0x9e3779b97f4a7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            z ^ (z >> 31)
+        }))
+    }
+
+    fn next(&mut self) -> u64 {
+        let s = &mut self.0;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+
+        result
+    }
+
+    /// Returns a number in [0, 1) with every bit of its mantissa random.
+    fn float(&mut self) -> LuaNum {
+        let bits = LuaNum::MANTISSA_DIGITS;
+        (self.next() >> (64 - bits)) as LuaNum / (1u64 << bits) as LuaNum
+    }
+
+    /// Returns an integer in [0, n], drawing again when the masked value is above `n` so every result is as likely.
+    fn project(&mut self, n: u64) -> u64 {
+        if n & n.wrapping_add(1) == 0 {
+            return self.next() & n;
+        }
+
+        let mut mask = n;
+        for shift in [1, 2, 4, 8, 16, 32] {
+            mask |= mask >> shift;
+        }
+        loop {
+            let value = self.next() & mask;
+            if value <= n {
+                return value;
+            }
+        }
+    }
+}
+
+/// Replaces the functions of the given libraries that depend on the time, the process or addresses.
+pub(crate) fn install(state: State, libraries: &[Library]) {
+    unsafe {
+        for library in libraries {
+            let (table, functions): (Option<&CStr>, &[(&CStr, CFunction)]) = match library {
+                Library::Basic => (None, &[(c"pairs", pairs)]),
+                Library::Math => (
+                    Some(c"math"),
+                    &[(c"random", random), (c"randomseed", randomseed)],
+                ),
+                Library::Os => (
+                    Some(c"os"),
+                    &[(c"time", time), (c"clock", clock), (c"date", date)],
+                ),
+                _ => continue,
+            };
+
+            let found = match table {
+                Some(name) => lua_getglobal(state, name.as_ptr()) == LUA_TTABLE,
+                None => {
+                    lua_pushglobaltable(state);
+                    true
+                }
+            };
+            if found {
+                for (name, function) in functions {
+                    replace(state, name, *function);
+                }
+            }
+            lua_pop(state, 1);
+        }
+    }
+}
+
+/// Returns the deterministic mode of the state, if it is on.
+unsafe fn deterministic<'a>(state: State) -> Option<&'a mut Deterministic> {
+    Extra::from_state(state)?.deterministic()
+}
+
+/// Returns the name of the type of the value at the index, or "no value".
+unsafe fn type_name(state: State, index: Int) -> String {
+    CStr::from_ptr(lua_typename(state, lua_type(state, index)))
+        .to_string_lossy()
+        .into()
+}
+
+/// Returns the number at the index if it is an integer, even when stored as a float.
+unsafe fn to_integer(state: State, index: Int) -> Option<i64> {
+    if lua_isinteger(state, index) != 0 {
+        return Some(lua_tointeger(state, index) as i64);
+    }
+
+    let number = lua_tonumber(state, index);
+    (lua_type(state, index) == LUA_TNUMBER && number as i64 as LuaNum == number)
+        .then_some(number as i64)
+}
+
+/// Returns the argument as an integer, or the error Lua gives for the function.
+unsafe fn check_integer(state: State, arg: Int, function: &str) -> Result<i64, String> {
+    if lua_type(state, arg) != LUA_TNUMBER {
+        return Err(format!(
+            "bad argument #{arg} to '{function}' (number expected, got {})",
+            type_name(state, arg)
+        ));
+    }
+
+    to_integer(state, arg).ok_or_else(|| {
+        format!("bad argument #{arg} to '{function}' (number has no integer representation)")
+    })
+}
+
+/// `math.random([m [, n]])`, drawing from the seeded generator.
+unsafe extern "C" fn random(state: State) -> Int {
+    let result = draw(state);
+    check(state, result)
+}
+
+unsafe fn draw(state: State) -> Result<Int, String> {
+    let Some(deterministic) = deterministic(state) else {
+        return Ok(call_replaced(state));
+    };
+
+    let args = lua_gettop(state);
+    let (low, up) = match args {
+        0 => (0, 0),
+        1 => (1, check_integer(state, 1, "random")?),
+        2 => (
+            check_integer(state, 1, "random")?,
+            check_integer(state, 2, "random")?,
+        ),
+        _ => return Err("wrong number of arguments".into()),
+    };
+    // A single 0 asks for an integer with all its bits random
+    if args == 1 && up == 0 {
+        lua_pushinteger(state, deterministic.random.next() as LuaInt);
+        return Ok(1);
+    }
+    if low > up {
+        return Err(format!(
+            "bad argument #{args} to 'random' (interval is empty)"
+        ));
+    }
+
+    if args == 0 {
+        lua_pushnumber(state, deterministic.random.float());
+    } else {
+        let offset = deterministic.random.project(up.wrapping_sub(low) as u64);
+        lua_pushinteger(state, low.wrapping_add(offset as i64) as LuaInt);
+    }
+
+    Ok(1)
+}
+
+/// `math.randomseed([x])`, restarting the generator from `x`, or from the seed given in Rust.
+/// Returns the seed and 0, like the two parts of the seed Lua 5.4 returns.
+unsafe extern "C" fn randomseed(state: State) -> Int {
+    let Some(deterministic) = deterministic(state) else {
+        return call_replaced(state);
+    };
+
+    let seed = match lua_type(state, 1) {
+        LUA_TNONE | LUA_TNIL => deterministic.seed,
+        LUA_TNUMBER => match to_integer(state, 1) {
+            Some(seed) => seed as u64,
+            None => (lua_tonumber(state, 1) as f64).to_bits(),
+        },
+        _ => {
+            let message = format!(
+                "bad argument #1 to 'randomseed' (number expected, got {})",
+                type_name(state, 1)
+            );
+            return check(state, Err(message));
+        }
+    };
+    deterministic.random = Random::new(seed);
+    lua_pushinteger(state, seed as LuaInt);
+    lua_pushinteger(state, 0);
+
+    2
+}
+
+/// `os.clock()`, returning the processor time of the virtual clock.
+unsafe extern "C" fn clock(state: State) -> Int {
+    let Some(deterministic) = deterministic(state) else {
+        return call_replaced(state);
+    };
+    lua_pushnumber(state, deterministic.clock.clock as LuaNum);
+
+    1
+}
+
+/// `os.time([table])`, returning the time of the virtual clock, or of the date in the table read as UTC.
+unsafe extern "C" fn time(state: State) -> Int {
+    let Some(deterministic) = deterministic(state) else {
+        return call_replaced(state);
+    };
+    let now = deterministic.clock.time;
+
+    let result = match lua_type(state, 1) {
+        LUA_TNONE | LUA_TNIL => Ok(now),
+        LUA_TTABLE => table_time(state),
+        _ => Err(format!(
+            "bad argument #1 to 'time' (table expected, got {})",
+            type_name(state, 1)
+        )),
+    };
+    let result = result.map(|time| {
+        push_time(state, time);
+        1
+    });
+    check(state, result)
+}
+
+/// Pushes a time as an integer, or as a float if it doesn't fit in one.
+unsafe fn push_time(state: State, time: i64) {
+    match LuaInt::try_from(time) {
+        Ok(time) => lua_pushinteger(state, time),
+        Err(_) => lua_pushnumber(state, time as LuaNum),
+    }
+}
+
+/// Returns the time of the date in the table at index 1.
+unsafe fn table_time(state: State) -> Result<i64, String> {
+    let year = date_field(state, c"year", None)?;
+    let month = date_field(state, c"month", None)?;
+    let day = date_field(state, c"day", None)?;
+    let hour = date_field(state, c"hour", Some(12))?;
+    let min = date_field(state, c"min", Some(0))?;
+    let sec = date_field(state, c"sec", Some(0))?;
+
+    let days = days_from_civil(year, month, day);
+    Ok(days * 86400 + hour * 3600 + min * 60 + sec)
+}
+
+/// Reads a field of the date table at index 1, which must fit in a C `int` like in `os.time`.
+unsafe fn date_field(state: State, name: &CStr, default: Option<i64>) -> Result<i64, String> {
+    let kind = lua_getfield(state, 1, name.as_ptr());
+    let value = to_integer(state, -1);
+    lua_pop(state, 1);
+
+    let name = name.to_string_lossy();
+    match (kind, value, default) {
+        (LUA_TNIL, _, Some(default)) => Ok(default),
+        (LUA_TNIL, _, None) => Err(format!("field '{name}' missing in date table")),
+        (_, Some(value), _) if i32::try_from(value).is_ok() => Ok(value),
+        (_, Some(_), _) => Err(format!("field '{name}' is out-of-bound")),
+        (_, None, _) => Err(format!("field '{name}' is not an integer")),
+    }
+}
+
+/// `os.date([format [, time]])`, formatting the time of the virtual clock, or the given one, in UTC.
+unsafe extern "C" fn date(state: State) -> Int {
+    let Some(deterministic) = deterministic(state) else {
+        return call_replaced(state);
+    };
+    let now = deterministic.clock.time;
+
+    let result = format_date(state, now);
+    check(state, result)
+}
+
+unsafe fn format_date(state: State, now: i64) -> Result<Int, String> {
+    let format: &[u8] = match lua_type(state, 1) {
+        LUA_TNONE | LUA_TNIL => b"%c",
+        LUA_TSTRING | LUA_TNUMBER => {
+            let mut len: SizeT = 0;
+            let format = lua_tolstring(state, 1, &mut len);
+            core::slice::from_raw_parts(format as *const u8, len)
+        }
+        _ => {
+            return Err(format!(
+                "bad argument #1 to 'date' (string expected, got {})",
+                type_name(state, 1)
+            ))
+        }
+    };
+    let time = match lua_type(state, 2) {
+        LUA_TNONE | LUA_TNIL => now,
+        _ => check_integer(state, 2, "date")?,
+    };
+
+    // Dates are always in UTC, so the `!` asking for it changes nothing
+    let format = format.strip_prefix(b"!").unwrap_or(format);
+    let date = Date::from_time(time);
+    if format.starts_with(b"*t") {
+        date.push_table(state);
+        return Ok(1);
+    }
+
+    let mut text = Vec::with_capacity(format.len());
+    let mut bytes = format.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte != b'%' {
+            text.push(byte);
+            continue;
+        }
+
+        let spec = bytes.next().copied();
+        match spec.and_then(|spec| date.conversion(spec)) {
+            Some(converted) => text.extend_from_slice(converted.as_bytes()),
+            None => {
+                let spec = spec.map(char::from).map(String::from).unwrap_or_default();
+                return Err(format!(
+                    "bad argument #1 to 'date' (invalid conversion specifier '%{spec}')"
+                ));
+            }
+        }
+    }
+    lua_pushlstring(state, text.as_ptr() as *const c_char, text.len());
+
+    Ok(1)
+}
+
+const DAYS: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+/// A UTC date, with the fields of the table from `os.date("*t")`.
+struct Date {
+    year: i64,
+    /// From 1 for January.
+    month: i64,
+    day: i64,
+    hour: i64,
+    min: i64,
+    sec: i64,
+    /// From 0 for Sunday.
+    weekday: i64,
+    /// From 1 for January 1st.
+    yearday: i64,
+}
+impl Date {
+    fn from_time(time: i64) -> Self {
+        let days = time.div_euclid(86400);
+        let seconds = time.rem_euclid(86400);
+        let (year, month, day) = civil_from_days(days);
+
+        Self {
+            year,
+            month,
+            day,
+            hour: seconds / 3600,
+            min: seconds / 60 % 60,
+            sec: seconds % 60,
+            // January 1st 1970 was a Thursday
+            weekday: (days + 4).rem_euclid(7),
+            yearday: days - days_from_civil(year, 1, 1) + 1,
+        }
+    }
+
+    unsafe fn push_table(&self, state: State) {
+        lua_createtable(state, 0, 9);
+        let fields = [
+            (c"year", self.year),
+            (c"month", self.month),
+            (c"day", self.day),
+            (c"hour", self.hour),
+            (c"min", self.min),
+            (c"sec", self.sec),
+            (c"wday", self.weekday + 1),
+            (c"yday", self.yearday),
+        ];
+        for (name, value) in fields {
+            push_time(state, value);
+            lua_setfield(state, -2, name.as_ptr());
+        }
+        lua_pushboolean(state, 0);
+        lua_setfield(state, -2, c"isdst".as_ptr());
+    }
+
+    /// Returns the text of a `strftime` conversion in the C locale, or `None` if it isn't supported.
+    fn conversion(&self, spec: u8) -> Option<String> {
+        let weekday = DAYS[self.weekday as usize];
+        let month = MONTHS[self.month as usize - 1];
+        let converted = match spec {
+            b'a' => weekday[..3].into(),
+            b'A' => weekday.into(),
+            b'b' | b'h' => month[..3].into(),
+            b'B' => month.into(),
+            b'c' => format!(
+                "{} {} {:2} {:02}:{:02}:{:02} {}",
+                &weekday[..3],
+                &month[..3],
+                self.day,
+                self.hour,
+                self.min,
+                self.sec,
+                self.year
+            ),
+            b'd' => format!("{:02}", self.day),
+            b'D' | b'x' => format!(
+                "{:02}/{:02}/{:02}",
+                self.month,
+                self.day,
+                self.year.rem_euclid(100)
+            ),
+            b'e' => format!("{:2}", self.day),
+            b'F' => format!("{}-{:02}-{:02}", self.year, self.month, self.day),
+            b'H' => format!("{:02}", self.hour),
+            b'I' => format!("{:02}", (self.hour + 11) % 12 + 1),
+            b'j' => format!("{:03}", self.yearday),
+            b'm' => format!("{:02}", self.month),
+            b'M' => format!("{:02}", self.min),
+            b'n' => "\n".into(),
+            b'p' => if self.hour < 12 { "AM" } else { "PM" }.into(),
+            b'S' => format!("{:02}", self.sec),
+            b't' => "\t".into(),
+            b'T' | b'X' => format!("{:02}:{:02}:{:02}", self.hour, self.min, self.sec),
+            b'u' => format!("{}", (self.weekday + 6) % 7 + 1),
+            b'w' => format!("{}", self.weekday),
+            b'y' => format!("{:02}", self.year.rem_euclid(100)),
+            b'Y' => format!("{}", self.year),
+            b'%' => "%".into(),
+            _ => return None,
+        };
+
+        Some(converted)
+    }
+}
+
+/// Returns the days since the Unix epoch of a date, where months and days out of their range carry over.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = year + (month - 1).div_euclid(12);
+    let month = (month - 1).rem_euclid(12) + 1;
+
+    // Years start in March, so the leap day is the last one
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+/// Returns the year, month and day of the days since the Unix epoch.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    (year, month, day)
+}
+
+/// A table key of a type whose order doesn't depend on addresses.
+enum Key {
+    Integer(LuaInt),
+    Float(LuaNum),
+    String(Vec<u8>),
+    Boolean(bool),
+}
+impl Key {
+    /// Reads the key at the index, failing for tables, functions and other values compared by address.
+    unsafe fn read(state: State, index: Int) -> Result<Self, String> {
+        match lua_type(state, index) {
+            LUA_TNUMBER if lua_isinteger(state, index) != 0 => {
+                Ok(Key::Integer(lua_tointeger(state, index)))
+            }
+            LUA_TNUMBER => Ok(Key::Float(lua_tonumber(state, index))),
+            LUA_TSTRING => {
+                let mut len: SizeT = 0;
+                let string = lua_tolstring(state, index, &mut len);
+                Ok(Key::String(
+                    core::slice::from_raw_parts(string as *const u8, len).to_vec(),
+                ))
+            }
+            LUA_TBOOLEAN => Ok(Key::Boolean(lua_toboolean(state, index) != 0)),
+            _ => Err(format!(
+                "pairs order is not deterministic for keys of type '{}'",
+                type_name(state, index)
+            )),
+        }
+    }
+
+    unsafe fn push(&self, state: State) {
+        match self {
+            Key::Integer(key) => lua_pushinteger(state, *key),
+            Key::Float(key) => lua_pushnumber(state, *key),
+            Key::String(key) => {
+                lua_pushlstring(state, key.as_ptr() as *const c_char, key.len());
+            }
+            Key::Boolean(key) => lua_pushboolean(state, *key as Int),
+        }
+    }
+
+    /// Orders numbers by value, with an integer before a float of the same value, then strings by their bytes, then booleans.
+    fn order(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Key::Integer(a), Key::Integer(b)) => a.cmp(b),
+            (Key::Float(a), Key::Float(b)) => a.total_cmp(b),
+            (Key::Integer(a), Key::Float(b)) => (*a as LuaNum).total_cmp(b).then(Ordering::Less),
+            (Key::Float(a), Key::Integer(b)) => {
+                a.total_cmp(&(*b as LuaNum)).then(Ordering::Greater)
+            }
+            (Key::String(a), Key::String(b)) => a.cmp(b),
+            (Key::Boolean(a), Key::Boolean(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            Key::Integer(_) | Key::Float(_) => 0,
+            Key::String(_) => 1,
+            Key::Boolean(_) => 2,
+        }
+    }
+}
+
+/// `pairs(t)`, visiting the keys of plain tables in order. Values with a `__pairs` metamethod use it instead.
+unsafe extern "C" fn pairs(state: State) -> Int {
+    if lua_type(state, 1) != LUA_TTABLE || has_pairs_metamethod(state) {
+        return call_replaced(state);
+    }
+
+    let result = sorted_pairs(state);
+    check(state, result)
+}
+
+/// Returns whether the value at index 1 has a `__pairs` metamethod, which Lua 5.1 doesn't have.
+unsafe fn has_pairs_metamethod(state: State) -> bool {
+    if cfg!(lua_version = "5.1") || lua_getmetatable(state, 1) == 0 {
+        return false;
+    }
+
+    let found = lua_getfield(state, -1, c"__pairs".as_ptr()) != LUA_TNIL;
+    lua_pop(state, 2);
+    found
+}
+
+/// Pushes an iterator over the keys of the table at index 1 in order, with the table and a nil key.
+unsafe fn sorted_pairs(state: State) -> Result<Int, String> {
+    let mut keys = Vec::new();
+    lua_pushnil(state);
+    while lua_next(state, 1) != 0 {
+        lua_pop(state, 1);
+        keys.push(Key::read(state, -1)?);
+    }
+    keys.sort_by(Key::order);
+
+    lua_pushvalue(state, 1);
+    lua_createtable(state, keys.len() as Int, 0);
+    for (i, key) in keys.iter().enumerate() {
+        key.push(state);
+        lua_rawseti(state, -2, i as LuaInt + 1);
+    }
+    lua_pushinteger(state, 0);
+    lua_pushcclosure(state, next_sorted, 3);
+    lua_pushvalue(state, 1);
+    lua_pushnil(state);
+
+    Ok(3)
+}
+
+/// Returns the next key in order and its value, skipping keys removed since `pairs` was called.
+/// The upvalues are the table, the sorted keys and the position of the last key returned.
+unsafe extern "C" fn next_sorted(state: State) -> Int {
+    let mut position = lua_tointeger(state, lua_upvalueindex(3));
+    loop {
+        position += 1;
+        if lua_rawgeti(state, lua_upvalueindex(2), position) == LUA_TNIL {
+            return 1;
+        }
+
+        lua_pushvalue(state, -1);
+        if lua_rawget(state, lua_upvalueindex(1)) != LUA_TNIL {
+            lua_pushinteger(state, position);
+            lua_replace(state, lua_upvalueindex(3));
+            return 2;
+        }
+        lua_pop(state, 2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{overrides::lua_with, Data, Error, Lua};
+
+    fn lua_at(seed: u64, time: i64) -> Lua {
+        lua_with(|lua| lua.set_deterministic(seed, VirtualClock { time, clock: 1.5 }))
+    }
+
+    fn numbers(lua: &mut Lua) -> Data {
+        lua.interpret(
+            "local t = {}
+            for i = 1, 20 do t[#t + 1] = math.random(1, 1000) end
+            t[#t + 1] = math.random()
+            result = table.concat(t, ' ')",
+        )
+        .unwrap();
+        lua.get_global("result").unwrap()
+    }
+
+    #[test]
+    fn random_same_seed_returns_same_numbers() {
+        let first = numbers(&mut lua_at(42, 0));
+        assert_eq!(first, numbers(&mut lua_at(42, 0)));
+        assert_ne!(first, numbers(&mut lua_at(43, 0)));
+    }
+
+    #[test]
+    fn randomseed_restarts_sequence() {
+        let mut lua = lua_at(42, 0);
+        let first = numbers(&mut lua);
+        lua.interpret("math.randomseed()").unwrap();
+        assert_eq!(first, numbers(&mut lua));
+
+        lua.interpret("math.randomseed(7)").unwrap();
+        let seeded = numbers(&mut lua);
+        lua.interpret("math.randomseed(7)").unwrap();
+        assert_eq!(seeded, numbers(&mut lua));
+    }
+
+    #[test]
+    fn randomseed_returns_seed() {
+        let mut lua = lua_at(42, 0);
+        lua.interpret("a, b = math.randomseed(7) c = math.randomseed()")
+            .unwrap();
+        assert_eq!(Data::from(7), lua.get_global("a").unwrap());
+        assert_eq!(Data::from(0), lua.get_global("b").unwrap());
+        assert_eq!(Data::from(42), lua.get_global("c").unwrap());
+    }
+
+    #[test]
+    fn random_zero_returns_full_integer() {
+        let mut lua = lua_at(42, 0);
+        let expected = Random::new(42).next() as LuaInt;
+        lua.interpret(&format!("same = math.random(0) == {expected}"))
+            .unwrap();
+        assert_eq!(Data::Bool(true), lua.get_global("same").unwrap());
+    }
+
+    #[test]
+    fn random_stays_in_interval() {
+        let mut lua = lua_at(1, 0);
+        lua.interpret(
+            "for i = 1, 1000 do
+                local n = math.random(-3, 3)
+                assert(n >= -3 and n <= 3 and n % 1 == 0)
+                local f = math.random()
+                assert(f >= 0 and f < 1)
+                assert(math.random(5) >= 1)
+            end",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn random_empty_interval_returns_err() {
+        let mut lua = lua_at(1, 0);
+        let Err(Error::Runtime(message)) = lua.interpret("math.random(3, 1)") else {
+            panic!("math.random(3, 1) should fail");
+        };
+        assert!(message.contains("interval is empty"), "{message}");
+    }
+
+    #[test]
+    fn os_functions_read_virtual_clock() {
+        let mut lua = lua_at(0, 86400 + 3661);
+        lua.interpret("t, c, d = os.time(), os.clock(), os.date('!%Y-%m-%d %H:%M:%S %A %j')")
+            .unwrap();
+        assert_eq!(Data::from(86400 + 3661), lua.get_global("t").unwrap());
+        assert_eq!(Data::Number(1.5), lua.get_global("c").unwrap());
+        assert_eq!(
+            Data::String("1970-01-02 01:01:01 Friday 002".into()),
+            lua.get_global("d").unwrap()
+        );
+
+        lua.set_clock(VirtualClock {
+            time: 951782400,
+            clock: 2.0,
+        });
+        lua.interpret("d, c = os.date('%c'), os.clock()").unwrap();
+        assert_eq!(
+            Data::String("Tue Feb 29 00:00:00 2000".into()),
+            lua.get_global("d").unwrap()
+        );
+        assert_eq!(Data::Number(2.0), lua.get_global("c").unwrap());
+    }
+
+    #[test]
+    fn os_time_converts_table_as_utc() {
+        let mut lua = lua_at(0, 0);
+        lua.interpret(
+            "t = os.time({ year = 2000, month = 2, day = 29, hour = 0 })
+            local d = os.date('*t', t)
+            same = d.year == 2000 and d.month == 2 and d.day == 29 and d.yday == 60 and d.wday == 3
+            carried = os.time({ year = 1999, month = 14, day = 0, hour = 0 })",
+        )
+        .unwrap();
+        assert_eq!(Data::from(951782400), lua.get_global("t").unwrap());
+        assert_eq!(Data::Bool(true), lua.get_global("same").unwrap());
+        assert_eq!(Data::from(949276800), lua.get_global("carried").unwrap());
+    }
+
+    #[test]
+    fn os_date_invalid_conversion_returns_err() {
+        let mut lua = lua_at(0, 0);
+        let Err(Error::Runtime(message)) = lua.interpret("os.date('%Q')") else {
+            panic!("os.date('%Q') should fail");
+        };
+        assert!(
+            message.contains("invalid conversion specifier '%Q'"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn pairs_visits_keys_in_order() {
+        let mut lua = lua_at(0, 0);
+        lua.interpret(
+            "local t = { 'a', 'b', zeta = 1, alpha = 2, [true] = 3, [false] = 4, [-1.5] = 5, [10] = 6 }
+            local keys = {}
+            for k, v in pairs(t) do
+                keys[#keys + 1] = tostring(k)
+                t.zeta = nil
+            end
+            result = table.concat(keys, ' ')",
+        )
+        .unwrap();
+        assert_eq!(
+            Data::String("-1.5 1 2 10 alpha false true".into()),
+            lua.get_global("result").unwrap()
+        );
+    }
+
+    #[test]
+    fn pairs_table_key_returns_err() {
+        let mut lua = lua_at(0, 0);
+        let Err(Error::Runtime(message)) = lua.interpret("for k in pairs({ [{}] = 1 }) do end")
+        else {
+            panic!("pairs with a table key should fail");
+        };
+        assert!(
+            message.contains("pairs order is not deterministic for keys of type 'table'"),
+            "{message}"
+        );
+    }
+}
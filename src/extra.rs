@@ -0,0 +1,574 @@
+extern crate alloc;
+
+#[cfg(feature = "deterministic")]
+use crate::deterministic::Deterministic;
+use crate::{
+    debug::{HookContext, HookFn},
+    lua_core::*,
+    strict::Globals,
+    Error, HookEvent, HookMask, InterruptHandle,
+};
+#[cfg(feature = "std")]
+use crate::{output::Sink, Output, Vfs};
+use alloc::{ffi::CString, format, string::String};
+#[cfg(feature = "std")]
+use std::{
+    boxed::Box,
+    time::{Duration, Instant},
+};
+
+/// How many instructions run between checks of the execution limits.
+const CHECK_INTERVAL: u64 = 1000;
+
+/// Registry field holding the extra data when the state has no extra space.
+#[cfg(any(lua_version = "5.1", lua_version = "5.2"))]
+const REGISTRY_KEY: &core::ffi::CStr = c"llua.extra";
+
+/// Rust data attached to a Lua state, reachable from hooks through the state's extra space.
+#[derive(Default)]
+pub(crate) struct Extra {
+    /// Maximum number of instructions a single call may run.
+    instruction_limit: Option<u64>,
+    /// Number of instructions run by the current call.
+    instructions: u64,
+    /// Number of instructions between each count hook.
+    hook_count: Int,
+    /// Events the hook is installed for, apart from the count.
+    hook_mask: Int,
+    /// Instructions left until the limits are next checked, or 0 if there are none.
+    limit_countdown: Int,
+    /// Maximum time a single call may run.
+    #[cfg(feature = "std")]
+    time_limit: Option<Duration>,
+    /// When the current call must finish by.
+    #[cfg(feature = "std")]
+    deadline: Option<Instant>,
+    /// Handle used to interrupt the current call from another thread.
+    interrupt: Option<InterruptHandle>,
+    /// Hook set from Rust, along with the events it is called for.
+    hook: Option<(HookMask, HookFn)>,
+    /// Instructions left until the Rust hook's next count event, or 0 if it has none.
+    hook_countdown: Int,
+    /// The error that aborted the current call.
+    aborted: Option<Error>,
+    /// Whether a protected call is running, as errors can't be raised outside of one.
+    in_call: bool,
+    /// Strict mode and watched globals.
+    globals: Globals,
+    /// Filesystem that scripts and files are read through.
+    #[cfg(feature = "std")]
+    vfs: Option<Box<dyn Vfs>>,
+    /// Where printed text and warnings go.
+    #[cfg(feature = "std")]
+    output: Option<Sink>,
+    /// Spans of the Rust callbacks running, innermost last.
+    #[cfg(feature = "tracing")]
+    spans: alloc::vec::Vec<tracing::span::EnteredSpan>,
+    /// Traceback of the error that ended the current call, kept to record it.
+    #[cfg(any(feature = "log", feature = "tracing"))]
+    traceback: Option<String>,
+    /// Random generator and clock of the deterministic mode, when it is on.
+    #[cfg(feature = "deterministic")]
+    deterministic: Option<Deterministic>,
+}
+impl Extra {
+    /// Attaches the extra data to the given state.
+    /// The extra data must outlive the state.
+    #[cfg(any(lua_version = "5.3", lua_version = "5.4"))]
+    pub(crate) fn attach(&mut self, state: State) {
+        unsafe {
+            *lua_getextraspace(state) = self as *mut Self as *mut core::ffi::c_void;
+        }
+    }
+
+    /// Attaches the extra data to the given state.
+    /// The extra data must outlive the state.
+    /// Versions before 5.3 have no extra space, so it is kept in the registry.
+    #[cfg(any(lua_version = "5.1", lua_version = "5.2"))]
+    pub(crate) fn attach(&mut self, state: State) {
+        unsafe {
+            lua_pushlightuserdata(state, self as *mut Self as *mut core::ffi::c_void);
+            lua_setfield(state, LUA_REGISTRYINDEX, REGISTRY_KEY.as_ptr());
+        }
+    }
+
+    /// Returns the extra data attached to the given state.
+    #[cfg(any(lua_version = "5.3", lua_version = "5.4"))]
+    pub(crate) unsafe fn from_state<'a>(state: State) -> Option<&'a mut Self> {
+        let extra = *lua_getextraspace(state) as *mut Self;
+        extra.as_mut()
+    }
+
+    /// Returns the extra data attached to the given state.
+    #[cfg(any(lua_version = "5.1", lua_version = "5.2"))]
+    pub(crate) unsafe fn from_state<'a>(state: State) -> Option<&'a mut Self> {
+        lua_getfield(state, LUA_REGISTRYINDEX, REGISTRY_KEY.as_ptr());
+        let extra = lua_touserdata(state, -1) as *mut Self;
+        lua_pop(state, 1);
+        extra.as_mut()
+    }
+
+    /// Returns the rules for accessing globals.
+    pub(crate) fn globals(&mut self) -> &mut Globals {
+        &mut self.globals
+    }
+
+    /// Returns the filesystem that scripts and files are read through.
+    #[cfg(feature = "std")]
+    pub(crate) fn vfs(&self) -> Option<&dyn Vfs> {
+        self.vfs.as_deref()
+    }
+
+    /// Sets the filesystem that scripts and files are read through.
+    #[cfg(feature = "std")]
+    pub(crate) fn set_vfs(&mut self, vfs: Box<dyn Vfs>) {
+        self.vfs = Some(vfs);
+    }
+
+    /// Returns where printed text and warnings go.
+    #[cfg(feature = "std")]
+    pub(crate) fn output(&mut self) -> Option<&mut Sink> {
+        self.output.as_mut()
+    }
+
+    /// Sets where printed text and warnings go.
+    #[cfg(feature = "std")]
+    pub(crate) fn set_output(&mut self, output: Box<dyn Output>) {
+        self.output = Some(Sink::new(output));
+    }
+
+    /// Returns the random generator and clock of the deterministic mode, if it is on.
+    #[cfg(feature = "deterministic")]
+    pub(crate) fn deterministic(&mut self) -> Option<&mut Deterministic> {
+        self.deterministic.as_mut()
+    }
+
+    /// Turns on the deterministic mode, or restarts it.
+    #[cfg(feature = "deterministic")]
+    pub(crate) fn set_deterministic(&mut self, deterministic: Deterministic) {
+        self.deterministic = Some(deterministic);
+    }
+
+    /// Keeps the traceback of the error ending the current call.
+    #[cfg(any(feature = "log", feature = "tracing"))]
+    pub(crate) fn set_traceback(&mut self, traceback: String) {
+        self.traceback = Some(traceback);
+    }
+
+    /// Returns the traceback of the error that ended the call, if one was kept.
+    #[cfg(any(feature = "log", feature = "tracing"))]
+    pub(crate) fn take_traceback(&mut self) -> Option<String> {
+        self.traceback.take()
+    }
+
+    /// Keeps the span of a Rust callback until it returns, returning how many spans were open before it.
+    #[cfg(feature = "tracing")]
+    pub(crate) fn open_span(&mut self, span: tracing::span::EnteredSpan) -> usize {
+        self.spans.push(span);
+        self.spans.len() - 1
+    }
+
+    /// Closes the spans above the depth, innermost first.
+    /// Spans of callbacks that raised an error are left open until a callback around them returns or the protected call ends.
+    #[cfg(feature = "tracing")]
+    pub(crate) fn close_spans(&mut self, depth: usize) {
+        while self.spans.len() > depth {
+            self.spans.pop();
+        }
+    }
+
+    /// Sets the maximum number of instructions a single call may run.
+    pub(crate) fn set_instruction_limit(&mut self, state: State, limit: Option<u64>) {
+        self.instruction_limit = limit;
+        self.update_hook(state);
+    }
+
+    /// Sets the maximum time a single call may run.
+    #[cfg(feature = "std")]
+    pub(crate) fn set_time_limit(&mut self, state: State, limit: Option<Duration>) {
+        self.time_limit = limit;
+        self.update_hook(state);
+    }
+
+    /// Returns a handle for interrupting calls, enabling interrupt checks.
+    pub(crate) fn interrupt_handle(&mut self, state: State) -> InterruptHandle {
+        if let Some(handle) = &self.interrupt {
+            return handle.clone();
+        }
+
+        let handle = InterruptHandle::default();
+        self.interrupt = Some(handle.clone());
+        self.update_hook(state);
+
+        handle
+    }
+
+    /// Sets the hook called from Rust, replacing any existing one.
+    pub(crate) fn set_hook(&mut self, state: State, mask: HookMask, hook: HookFn) {
+        self.hook_countdown = mask.count.max(0);
+        self.hook = Some((mask, hook));
+        self.update_hook(state);
+    }
+
+    /// Removes the hook called from Rust.
+    pub(crate) fn remove_hook(&mut self, state: State) {
+        self.hook = None;
+        self.hook_countdown = 0;
+        self.update_hook(state);
+    }
+
+    /// Resets the limits for a new call.
+    pub(crate) fn begin_call(&mut self, state: State) {
+        self.instructions = 0;
+        if let Some(interrupt) = &self.interrupt {
+            interrupt.clear();
+        }
+        if self.aborted.take().is_some() {
+            self.update_hook(state);
+        }
+
+        #[cfg(feature = "std")]
+        {
+            self.deadline = self.time_limit.map(|limit| Instant::now() + limit);
+        }
+    }
+
+    /// Marks whether a protected call is running.
+    /// Outside of one, such as in a metamethod run by `Lua::get_global`, the limits and hooks can't abort.
+    pub(crate) fn set_in_call(&mut self, in_call: bool) {
+        self.in_call = in_call;
+    }
+
+    /// Returns the error that aborted the call, if any.
+    pub(crate) fn end_call(&mut self, state: State) -> Option<Error> {
+        let aborted = self.aborted.take();
+        if aborted.is_some() {
+            self.update_hook(state);
+        }
+
+        aborted
+    }
+
+    /// Returns how many instructions run between checks of the limits, or 0 if there are none.
+    fn limit_interval(&self) -> Int {
+        #[cfg(feature = "std")]
+        let has_time_limit = self.time_limit.is_some();
+        #[cfg(not(feature = "std"))]
+        let has_time_limit = false;
+
+        if self.instruction_limit.is_none() && !has_time_limit && self.interrupt.is_none() {
+            return 0;
+        }
+
+        self.instruction_limit
+            .map_or(CHECK_INTERVAL, |limit| limit.clamp(1, CHECK_INTERVAL)) as Int
+    }
+
+    /// Installs or removes the hook depending on what is enabled.
+    fn update_hook(&mut self, state: State) {
+        self.limit_countdown = self.limit_interval();
+        self.hook_mask = self.hook.as_ref().map_or(0, |(mask, _)| mask.lua_mask());
+        self.schedule_hook(state);
+    }
+
+    /// Installs the hook to count until the next limit check or Rust count event, whichever comes first.
+    fn schedule_hook(&mut self, state: State) {
+        let count = match (self.limit_countdown, self.hook_countdown) {
+            (0, count) | (count, 0) => count,
+            (limit, hook) => limit.min(hook),
+        };
+
+        let mut mask = self.hook_mask;
+        if count > 0 {
+            mask |= LUA_MASKCOUNT;
+        }
+        self.hook_count = count;
+
+        unsafe {
+            match mask {
+                0 => lua_sethook(state, None, 0, 0),
+                _ => lua_sethook(state, Some(hook), mask, count),
+            }
+        }
+    }
+
+    /// Counts down the instructions run since the last count event, checking the limits and calling the Rust hook when they are due.
+    unsafe fn count(&mut self, state: State, ar: *mut LuaDebug) -> Option<Error> {
+        let elapsed = self.hook_count;
+        if self.limit_countdown > 0 {
+            self.instructions += elapsed as u64;
+        }
+        let limit_interval = self.limit_interval();
+        let limits_due = count_down(&mut self.limit_countdown, elapsed, limit_interval);
+        let hook_count = self.hook.as_ref().map_or(0, |(mask, _)| mask.count.max(0));
+        let hook_due = count_down(&mut self.hook_countdown, elapsed, hook_count);
+        self.schedule_hook(state);
+
+        if limits_due {
+            if let Some(aborted) = self.check_limits(0) {
+                return Some(aborted);
+            }
+        }
+
+        match hook_due {
+            true => self.call_hook(state, ar, HookEvent::Count),
+            false => None,
+        }
+    }
+
+    /// Checks whether the current call has gone over its limits, after running the given number of instructions.
+    fn check_limits(&mut self, instructions: u64) -> Option<Error> {
+        if self.aborted.is_some() {
+            return self.aborted.clone();
+        }
+
+        self.instructions += instructions;
+        if let Some(limit) = self.instruction_limit {
+            if self.instructions > limit {
+                self.aborted = Some(Error::BudgetExceeded);
+            }
+        }
+
+        #[cfg(feature = "std")]
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline {
+                self.aborted = Some(Error::Timeout);
+            }
+        }
+
+        if let Some(interrupt) = &self.interrupt {
+            if interrupt.take() {
+                self.aborted = Some(Error::Interrupted);
+            }
+        }
+
+        self.aborted.clone()
+    }
+
+    /// Calls the Rust hook for the given event, returning the error to abort with if it fails.
+    unsafe fn call_hook(
+        &mut self,
+        state: State,
+        ar: *mut LuaDebug,
+        event: HookEvent,
+    ) -> Option<Error> {
+        let (mask, hook) = self.hook.as_mut()?;
+        if let HookEvent::Line(_) = event {
+            if !mask.line {
+                return None;
+            }
+        }
+
+        let context = HookContext::new(state, ar);
+        // A panic can't unwind through Lua, so it aborts the call instead
+        #[cfg(feature = "std")]
+        let result =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| hook(&context, event)))
+                .unwrap_or_else(|panic| Err(Error::Runtime(panic_message(&*panic))));
+        #[cfg(not(feature = "std"))]
+        let result = hook(&context, event);
+
+        if let Err(e) = result {
+            self.aborted = Some(e);
+        }
+
+        self.aborted.clone()
+    }
+}
+
+/// Counts down the instructions run, returning whether the countdown is up and restarting it from the interval.
+/// A countdown of 0 is off.
+fn count_down(countdown: &mut Int, elapsed: Int, interval: Int) -> bool {
+    if *countdown == 0 {
+        return false;
+    }
+
+    *countdown -= elapsed;
+    if *countdown > 0 {
+        return false;
+    }
+
+    *countdown = interval;
+    true
+}
+
+/// Returns the message of a panic in a hook.
+#[cfg(feature = "std")]
+fn panic_message(panic: &(dyn core::any::Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        return format!("hook panicked: {}", message);
+    }
+    match panic.downcast_ref::<String>() {
+        Some(message) => format!("hook panicked: {}", message),
+        None => "hook panicked".into(),
+    }
+}
+
+/// Returns the message Lua is given when a call is aborted with the given error.
+fn abort_message(error: &Error) -> String {
+    match error {
+        Error::BudgetExceeded => "instruction budget exceeded".into(),
+        Error::Timeout => "execution timed out".into(),
+        Error::Interrupted => "execution interrupted".into(),
+        Error::Runtime(message) => message.clone(),
+        error => format!("{:?}", error),
+    }
+}
+
+/// Runs the checks for a hook call, pushing the error message if the call should be aborted.
+unsafe fn push_abort(state: State, ar: *mut LuaDebug) -> bool {
+    let Some(extra) = Extra::from_state(state) else {
+        return false;
+    };
+    if !extra.in_call {
+        return false;
+    }
+
+    let aborted = match (*ar).event {
+        _ if extra.aborted.is_some() => extra.aborted.clone(),
+        LUA_HOOKCOUNT => extra.count(state, ar),
+        event => match extra.check_limits(0) {
+            Some(aborted) => Some(aborted),
+            None => match HookEvent::from_lua(event, (*ar).currentline) {
+                Some(event) => extra.call_hook(state, ar, event),
+                None => None,
+            },
+        },
+    };
+
+    match aborted {
+        Some(aborted) => {
+            let message = CString::new(abort_message(&aborted)).unwrap_or_default();
+            lua_pushstring(state, message.as_ptr());
+            true
+        }
+        None => false,
+    }
+}
+
+/// The hook installed on states with an attached `Extra`.
+unsafe extern "C" fn hook(state: State, ar: *mut LuaDebug) {
+    // Nothing owned may be alive when raising the error, as it never returns.
+    if !push_abort(state, ar) {
+        return;
+    }
+
+    // Keep raising on every instruction so scripts can't swallow the error with pcall.
+    lua_sethook(state, Some(hook), LUA_MASKCOUNT, 1);
+    lua_error(state);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_limits_under_budget_returns_none() {
+        let state = unsafe { luaL_newstate() };
+        let mut extra = Extra {
+            instruction_limit: Some(10),
+            ..Default::default()
+        };
+        extra.begin_call(state);
+
+        assert_eq!(None, extra.check_limits(5));
+        assert_eq!(None, extra.check_limits(5));
+        unsafe { lua_close(state) };
+    }
+
+    #[test]
+    fn check_limits_over_budget_returns_budget_exceeded() {
+        let state = unsafe { luaL_newstate() };
+        let mut extra = Extra {
+            instruction_limit: Some(10),
+            ..Default::default()
+        };
+        extra.begin_call(state);
+        extra.check_limits(5);
+        extra.check_limits(5);
+
+        assert_eq!(Some(Error::BudgetExceeded), extra.check_limits(5));
+        assert_eq!(Some(Error::BudgetExceeded), extra.end_call(state));
+        assert_eq!(None, extra.end_call(state));
+        unsafe { lua_close(state) };
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn check_limits_past_deadline_returns_timeout() {
+        let state = unsafe { luaL_newstate() };
+        let mut extra = Extra {
+            time_limit: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        extra.begin_call(state);
+
+        assert_eq!(Some(Error::Timeout), extra.check_limits(5));
+        unsafe { lua_close(state) };
+    }
+
+    #[test]
+    fn check_limits_interrupted_returns_interrupted() {
+        let state = unsafe { luaL_newstate() };
+        let mut extra = Extra::default();
+        let handle = extra.interrupt_handle(state);
+        extra.begin_call(state);
+        handle.interrupt();
+
+        assert_eq!(Some(Error::Interrupted), extra.check_limits(5));
+        unsafe { lua_close(state) };
+    }
+
+    #[test]
+    fn begin_call_clears_interrupt() {
+        let state = unsafe { luaL_newstate() };
+        let mut extra = Extra::default();
+        let handle = extra.interrupt_handle(state);
+        handle.interrupt();
+        extra.begin_call(state);
+
+        assert_eq!(None, extra.check_limits(5));
+        unsafe { lua_close(state) };
+    }
+
+    #[test]
+    fn count_down_due_restarts_from_interval() {
+        let mut countdown = 3;
+
+        assert!(!count_down(&mut countdown, 2, 3));
+        assert!(count_down(&mut countdown, 1, 3));
+        assert_eq!(3, countdown);
+    }
+
+    #[test]
+    fn count_down_off_returns_false() {
+        let mut countdown = 0;
+
+        assert!(!count_down(&mut countdown, 1, 0));
+        assert_eq!(0, countdown);
+    }
+
+    #[test]
+    fn abort_message_runtime_returns_message() {
+        assert_eq!(
+            "stop",
+            abort_message(&Error::Runtime("stop".into())).as_str()
+        );
+    }
+
+    #[test]
+    fn begin_call_resets() {
+        let state = unsafe { luaL_newstate() };
+        let mut extra = Extra {
+            instruction_limit: Some(1),
+            ..Default::default()
+        };
+        extra.begin_call(state);
+        extra.check_limits(5);
+        extra.check_limits(5);
+        extra.begin_call(state);
+
+        assert_eq!(None, extra.check_limits(1));
+        unsafe { lua_close(state) };
+    }
+}
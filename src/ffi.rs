@@ -0,0 +1,746 @@
+//! Raw bindings to the Lua 5.4 C API and auxiliary library, for what the safe API doesn't cover.
+//!
+//! The declarations follow `lua.h`, `lauxlib.h` and `lualib.h`, and the macros of those headers are
+//! inline functions. `lua_Integer` and `lua_Number` follow the `int32` and `float32` features.
+//! `Lua::as_raw` and `Lua::from_raw` convert between a `Lua` and a `*mut lua_State`.
+//!
+//! Only available with Lua 5.4. `lua_pushvfstring` is left out, as Rust cannot pass a `va_list`.
+//! Every function has the requirements the Lua manual gives for it.
+
+#![allow(non_camel_case_types, non_snake_case, clippy::missing_safety_doc)]
+
+use core::ffi::{c_char, c_int, c_long, c_uint, c_void, CStr};
+
+pub use crate::lua_core::{
+    Alloc as lua_Alloc, Hook as lua_Hook, KFunction as lua_KFunction, LuaDebug as lua_Debug,
+    LuaInt as lua_Integer, LuaNum as lua_Number, LuaState as lua_State, Writer as lua_Writer,
+};
+
+/// Unsigned counterpart of `lua_Integer`.
+#[cfg(lua_integer = "i32")]
+pub type lua_Unsigned = u32;
+/// Unsigned counterpart of `lua_Integer`.
+#[cfg(lua_integer = "i64")]
+pub type lua_Unsigned = u64;
+/// Context passed to continuation functions.
+pub type lua_KContext = isize;
+/// A function called from Lua.
+pub type lua_CFunction = unsafe extern "C" fn(L: *mut lua_State) -> c_int;
+/// A function providing pieces of a chunk to `lua_load`.
+pub type lua_Reader =
+    unsafe extern "C" fn(L: *mut lua_State, ud: *mut c_void, sz: *mut usize) -> *const c_char;
+/// A function receiving warnings.
+pub type lua_WarnFunction =
+    unsafe extern "C" fn(ud: *mut c_void, msg: *const c_char, tocont: c_int);
+
+pub const LUA_VERSION_MAJOR: &CStr = c"5";
+pub const LUA_VERSION_MINOR: &CStr = c"4";
+pub const LUA_VERSION_NUM: c_int = 504;
+pub const LUA_VERSION: &CStr = c"Lua 5.4";
+pub const LUA_SIGNATURE: &[u8] = b"\x1bLua";
+
+/// Option for returning all results of a call.
+pub const LUA_MULTRET: c_int = -1;
+
+/// Size of the stack, which bounds valid indices.
+pub const LUAI_MAXSTACK: c_int = 1_000_000;
+pub const LUA_REGISTRYINDEX: c_int = -LUAI_MAXSTACK - 1000;
+
+pub const LUA_OK: c_int = 0;
+pub const LUA_YIELD: c_int = 1;
+pub const LUA_ERRRUN: c_int = 2;
+pub const LUA_ERRSYNTAX: c_int = 3;
+pub const LUA_ERRMEM: c_int = 4;
+pub const LUA_ERRERR: c_int = 5;
+pub const LUA_ERRFILE: c_int = LUA_ERRERR + 1;
+
+pub const LUA_TNONE: c_int = -1;
+pub const LUA_TNIL: c_int = 0;
+pub const LUA_TBOOLEAN: c_int = 1;
+pub const LUA_TLIGHTUSERDATA: c_int = 2;
+pub const LUA_TNUMBER: c_int = 3;
+pub const LUA_TSTRING: c_int = 4;
+pub const LUA_TTABLE: c_int = 5;
+pub const LUA_TFUNCTION: c_int = 6;
+pub const LUA_TUSERDATA: c_int = 7;
+pub const LUA_TTHREAD: c_int = 8;
+pub const LUA_NUMTYPES: c_int = 9;
+
+/// Stack slots available to a C function.
+pub const LUA_MINSTACK: c_int = 20;
+
+pub const LUA_RIDX_MAINTHREAD: lua_Integer = 1;
+pub const LUA_RIDX_GLOBALS: lua_Integer = 2;
+pub const LUA_RIDX_LAST: lua_Integer = LUA_RIDX_GLOBALS;
+
+pub const LUA_OPADD: c_int = 0;
+pub const LUA_OPSUB: c_int = 1;
+pub const LUA_OPMUL: c_int = 2;
+pub const LUA_OPMOD: c_int = 3;
+pub const LUA_OPPOW: c_int = 4;
+pub const LUA_OPDIV: c_int = 5;
+pub const LUA_OPIDIV: c_int = 6;
+pub const LUA_OPBAND: c_int = 7;
+pub const LUA_OPBOR: c_int = 8;
+pub const LUA_OPBXOR: c_int = 9;
+pub const LUA_OPSHL: c_int = 10;
+pub const LUA_OPSHR: c_int = 11;
+pub const LUA_OPUNM: c_int = 12;
+pub const LUA_OPBNOT: c_int = 13;
+
+pub const LUA_OPEQ: c_int = 0;
+pub const LUA_OPLT: c_int = 1;
+pub const LUA_OPLE: c_int = 2;
+
+pub const LUA_GCSTOP: c_int = 0;
+pub const LUA_GCRESTART: c_int = 1;
+pub const LUA_GCCOLLECT: c_int = 2;
+pub const LUA_GCCOUNT: c_int = 3;
+pub const LUA_GCCOUNTB: c_int = 4;
+pub const LUA_GCSTEP: c_int = 5;
+pub const LUA_GCSETPAUSE: c_int = 6;
+pub const LUA_GCSETSTEPMUL: c_int = 7;
+pub const LUA_GCISRUNNING: c_int = 9;
+pub const LUA_GCGEN: c_int = 10;
+pub const LUA_GCINC: c_int = 11;
+
+pub const LUA_HOOKCALL: c_int = 0;
+pub const LUA_HOOKRET: c_int = 1;
+pub const LUA_HOOKLINE: c_int = 2;
+pub const LUA_HOOKCOUNT: c_int = 3;
+pub const LUA_HOOKTAILCALL: c_int = 4;
+
+pub const LUA_MASKCALL: c_int = 1 << LUA_HOOKCALL;
+pub const LUA_MASKRET: c_int = 1 << LUA_HOOKRET;
+pub const LUA_MASKLINE: c_int = 1 << LUA_HOOKLINE;
+pub const LUA_MASKCOUNT: c_int = 1 << LUA_HOOKCOUNT;
+
+/// Size of the raw memory area before each state.
+pub const LUA_EXTRASPACE: usize = core::mem::size_of::<*const c_void>();
+/// Maximum size of the description of a function's source.
+pub const LUA_IDSIZE: usize = 60;
+
+pub const LUA_GNAME: &CStr = c"_G";
+pub const LUA_LOADED_TABLE: &CStr = c"_LOADED";
+pub const LUA_PRELOAD_TABLE: &CStr = c"_PRELOAD";
+pub const LUA_FILEHANDLE: &CStr = c"FILE*";
+
+pub const LUA_NOREF: c_int = -2;
+pub const LUA_REFNIL: c_int = -1;
+
+/// Sizes of the number types, checked by `luaL_checkversion`.
+pub const LUAL_NUMSIZES: usize =
+    core::mem::size_of::<lua_Integer>() * 16 + core::mem::size_of::<lua_Number>();
+/// Size of the buffer inside a `luaL_Buffer`.
+pub const LUAL_BUFFERSIZE: usize =
+    16 * core::mem::size_of::<*const c_void>() * core::mem::size_of::<lua_Number>();
+
+pub const LUA_COLIBNAME: &CStr = c"coroutine";
+pub const LUA_TABLIBNAME: &CStr = c"table";
+pub const LUA_IOLIBNAME: &CStr = c"io";
+pub const LUA_OSLIBNAME: &CStr = c"os";
+pub const LUA_STRLIBNAME: &CStr = c"string";
+pub const LUA_UTF8LIBNAME: &CStr = c"utf8";
+pub const LUA_MATHLIBNAME: &CStr = c"math";
+pub const LUA_DBLIBNAME: &CStr = c"debug";
+pub const LUA_LOADLIBNAME: &CStr = c"package";
+
+/// A function to register with `luaL_setfuncs`. Lists end with a null `name`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct luaL_Reg {
+    pub name: *const c_char,
+    /// `None` registers `false` as a placeholder.
+    pub func: Option<lua_CFunction>,
+}
+
+/// A string being built on the stack.
+#[repr(C)]
+pub struct luaL_Buffer {
+    pub b: *mut c_char,
+    pub size: usize,
+    pub n: usize,
+    pub L: *mut lua_State,
+    pub init: luaL_BufferInit,
+}
+
+/// Initial storage of a `luaL_Buffer`, aligned for any value.
+#[repr(C)]
+pub union luaL_BufferInit {
+    pub n: lua_Number,
+    pub u: f64,
+    pub s: *mut c_void,
+    pub i: lua_Integer,
+    pub l: c_long,
+    pub b: [c_char; LUAL_BUFFERSIZE],
+}
+
+/// Start of the userdata of files from the io library.
+#[repr(C)]
+pub struct luaL_Stream {
+    /// The `FILE *` of the file, null while it is being created.
+    pub f: *mut c_void,
+    /// Closes the file, `None` once it is closed.
+    pub closef: Option<lua_CFunction>,
+}
+
+extern "C" {
+    // State manipulation
+    pub fn lua_newstate(f: lua_Alloc, ud: *mut c_void) -> *mut lua_State;
+    pub fn lua_close(L: *mut lua_State);
+    pub fn lua_newthread(L: *mut lua_State) -> *mut lua_State;
+    pub fn lua_closethread(L: *mut lua_State, from: *mut lua_State) -> c_int;
+    pub fn lua_resetthread(L: *mut lua_State) -> c_int;
+    pub fn lua_atpanic(L: *mut lua_State, panicf: Option<lua_CFunction>) -> Option<lua_CFunction>;
+    pub fn lua_version(L: *mut lua_State) -> lua_Number;
+
+    // Basic stack manipulation
+    pub fn lua_absindex(L: *mut lua_State, idx: c_int) -> c_int;
+    pub fn lua_gettop(L: *mut lua_State) -> c_int;
+    pub fn lua_settop(L: *mut lua_State, idx: c_int);
+    pub fn lua_pushvalue(L: *mut lua_State, idx: c_int);
+    pub fn lua_rotate(L: *mut lua_State, idx: c_int, n: c_int);
+    pub fn lua_copy(L: *mut lua_State, fromidx: c_int, toidx: c_int);
+    pub fn lua_checkstack(L: *mut lua_State, n: c_int) -> c_int;
+    pub fn lua_xmove(from: *mut lua_State, to: *mut lua_State, n: c_int);
+
+    // Access functions
+    pub fn lua_isnumber(L: *mut lua_State, idx: c_int) -> c_int;
+    pub fn lua_isstring(L: *mut lua_State, idx: c_int) -> c_int;
+    pub fn lua_iscfunction(L: *mut lua_State, idx: c_int) -> c_int;
+    pub fn lua_isinteger(L: *mut lua_State, idx: c_int) -> c_int;
+    pub fn lua_isuserdata(L: *mut lua_State, idx: c_int) -> c_int;
+    pub fn lua_type(L: *mut lua_State, idx: c_int) -> c_int;
+    pub fn lua_typename(L: *mut lua_State, tp: c_int) -> *const c_char;
+
+    pub fn lua_tonumberx(L: *mut lua_State, idx: c_int, isnum: *mut c_int) -> lua_Number;
+    pub fn lua_tointegerx(L: *mut lua_State, idx: c_int, isnum: *mut c_int) -> lua_Integer;
+    pub fn lua_toboolean(L: *mut lua_State, idx: c_int) -> c_int;
+    pub fn lua_tolstring(L: *mut lua_State, idx: c_int, len: *mut usize) -> *const c_char;
+    pub fn lua_rawlen(L: *mut lua_State, idx: c_int) -> lua_Unsigned;
+    pub fn lua_tocfunction(L: *mut lua_State, idx: c_int) -> Option<lua_CFunction>;
+    pub fn lua_touserdata(L: *mut lua_State, idx: c_int) -> *mut c_void;
+    pub fn lua_tothread(L: *mut lua_State, idx: c_int) -> *mut lua_State;
+    pub fn lua_topointer(L: *mut lua_State, idx: c_int) -> *const c_void;
+
+    // Comparison and arithmetic functions
+    pub fn lua_arith(L: *mut lua_State, op: c_int);
+    pub fn lua_rawequal(L: *mut lua_State, idx1: c_int, idx2: c_int) -> c_int;
+    pub fn lua_compare(L: *mut lua_State, idx1: c_int, idx2: c_int, op: c_int) -> c_int;
+
+    // Push functions
+    pub fn lua_pushnil(L: *mut lua_State);
+    pub fn lua_pushnumber(L: *mut lua_State, n: lua_Number);
+    pub fn lua_pushinteger(L: *mut lua_State, n: lua_Integer);
+    pub fn lua_pushlstring(L: *mut lua_State, s: *const c_char, len: usize) -> *const c_char;
+    pub fn lua_pushstring(L: *mut lua_State, s: *const c_char) -> *const c_char;
+    pub fn lua_pushfstring(L: *mut lua_State, fmt: *const c_char, ...) -> *const c_char;
+    pub fn lua_pushcclosure(L: *mut lua_State, f: lua_CFunction, n: c_int);
+    pub fn lua_pushboolean(L: *mut lua_State, b: c_int);
+    pub fn lua_pushlightuserdata(L: *mut lua_State, p: *mut c_void);
+    pub fn lua_pushthread(L: *mut lua_State) -> c_int;
+
+    // Get functions
+    pub fn lua_getglobal(L: *mut lua_State, name: *const c_char) -> c_int;
+    pub fn lua_gettable(L: *mut lua_State, idx: c_int) -> c_int;
+    pub fn lua_getfield(L: *mut lua_State, idx: c_int, k: *const c_char) -> c_int;
+    pub fn lua_geti(L: *mut lua_State, idx: c_int, n: lua_Integer) -> c_int;
+    pub fn lua_rawget(L: *mut lua_State, idx: c_int) -> c_int;
+    pub fn lua_rawgeti(L: *mut lua_State, idx: c_int, n: lua_Integer) -> c_int;
+    pub fn lua_rawgetp(L: *mut lua_State, idx: c_int, p: *const c_void) -> c_int;
+
+    pub fn lua_createtable(L: *mut lua_State, narr: c_int, nrec: c_int);
+    pub fn lua_newuserdatauv(L: *mut lua_State, sz: usize, nuvalue: c_int) -> *mut c_void;
+    pub fn lua_getmetatable(L: *mut lua_State, objindex: c_int) -> c_int;
+    pub fn lua_getiuservalue(L: *mut lua_State, idx: c_int, n: c_int) -> c_int;
+
+    // Set functions
+    pub fn lua_setglobal(L: *mut lua_State, name: *const c_char);
+    pub fn lua_settable(L: *mut lua_State, idx: c_int);
+    pub fn lua_setfield(L: *mut lua_State, idx: c_int, k: *const c_char);
+    pub fn lua_seti(L: *mut lua_State, idx: c_int, n: lua_Integer);
+    pub fn lua_rawset(L: *mut lua_State, idx: c_int);
+    pub fn lua_rawseti(L: *mut lua_State, idx: c_int, n: lua_Integer);
+    pub fn lua_rawsetp(L: *mut lua_State, idx: c_int, p: *const c_void);
+    pub fn lua_setmetatable(L: *mut lua_State, objindex: c_int) -> c_int;
+    pub fn lua_setiuservalue(L: *mut lua_State, idx: c_int, n: c_int) -> c_int;
+
+    // Load and call functions
+    pub fn lua_callk(
+        L: *mut lua_State,
+        nargs: c_int,
+        nresults: c_int,
+        ctx: lua_KContext,
+        k: Option<lua_KFunction>,
+    );
+    pub fn lua_pcallk(
+        L: *mut lua_State,
+        nargs: c_int,
+        nresults: c_int,
+        errfunc: c_int,
+        ctx: lua_KContext,
+        k: Option<lua_KFunction>,
+    ) -> c_int;
+    pub fn lua_load(
+        L: *mut lua_State,
+        reader: lua_Reader,
+        dt: *mut c_void,
+        chunkname: *const c_char,
+        mode: *const c_char,
+    ) -> c_int;
+    pub fn lua_dump(
+        L: *mut lua_State,
+        writer: lua_Writer,
+        data: *mut c_void,
+        strip: c_int,
+    ) -> c_int;
+
+    // Coroutine functions
+    pub fn lua_yieldk(
+        L: *mut lua_State,
+        nresults: c_int,
+        ctx: lua_KContext,
+        k: Option<lua_KFunction>,
+    ) -> c_int;
+    pub fn lua_resume(
+        L: *mut lua_State,
+        from: *mut lua_State,
+        narg: c_int,
+        nres: *mut c_int,
+    ) -> c_int;
+    pub fn lua_status(L: *mut lua_State) -> c_int;
+    pub fn lua_isyieldable(L: *mut lua_State) -> c_int;
+
+    // Warning-related functions
+    pub fn lua_setwarnf(L: *mut lua_State, f: Option<lua_WarnFunction>, ud: *mut c_void);
+    pub fn lua_warning(L: *mut lua_State, msg: *const c_char, tocont: c_int);
+
+    // Garbage collection
+    pub fn lua_gc(L: *mut lua_State, what: c_int, ...) -> c_int;
+
+    // Miscellaneous functions
+    pub fn lua_error(L: *mut lua_State) -> c_int;
+    pub fn lua_next(L: *mut lua_State, idx: c_int) -> c_int;
+    pub fn lua_concat(L: *mut lua_State, n: c_int);
+    pub fn lua_len(L: *mut lua_State, idx: c_int);
+    pub fn lua_stringtonumber(L: *mut lua_State, s: *const c_char) -> usize;
+    pub fn lua_getallocf(L: *mut lua_State, ud: *mut *mut c_void) -> lua_Alloc;
+    pub fn lua_setallocf(L: *mut lua_State, f: lua_Alloc, ud: *mut c_void);
+    pub fn lua_toclose(L: *mut lua_State, idx: c_int);
+    pub fn lua_closeslot(L: *mut lua_State, idx: c_int);
+
+    // Debug API
+    pub fn lua_getstack(L: *mut lua_State, level: c_int, ar: *mut lua_Debug) -> c_int;
+    pub fn lua_getinfo(L: *mut lua_State, what: *const c_char, ar: *mut lua_Debug) -> c_int;
+    pub fn lua_getlocal(L: *mut lua_State, ar: *const lua_Debug, n: c_int) -> *const c_char;
+    pub fn lua_setlocal(L: *mut lua_State, ar: *const lua_Debug, n: c_int) -> *const c_char;
+    pub fn lua_getupvalue(L: *mut lua_State, funcindex: c_int, n: c_int) -> *const c_char;
+    pub fn lua_setupvalue(L: *mut lua_State, funcindex: c_int, n: c_int) -> *const c_char;
+    pub fn lua_upvalueid(L: *mut lua_State, fidx: c_int, n: c_int) -> *mut c_void;
+    pub fn lua_upvaluejoin(L: *mut lua_State, fidx1: c_int, n1: c_int, fidx2: c_int, n2: c_int);
+    pub fn lua_sethook(L: *mut lua_State, func: Option<lua_Hook>, mask: c_int, count: c_int);
+    pub fn lua_gethook(L: *mut lua_State) -> Option<lua_Hook>;
+    pub fn lua_gethookmask(L: *mut lua_State) -> c_int;
+    pub fn lua_gethookcount(L: *mut lua_State) -> c_int;
+    pub fn lua_setcstacklimit(L: *mut lua_State, limit: c_uint) -> c_int;
+}
+
+extern "C" {
+    pub fn luaL_checkversion_(L: *mut lua_State, ver: lua_Number, sz: usize);
+    pub fn luaL_getmetafield(L: *mut lua_State, obj: c_int, e: *const c_char) -> c_int;
+    pub fn luaL_callmeta(L: *mut lua_State, obj: c_int, e: *const c_char) -> c_int;
+    pub fn luaL_tolstring(L: *mut lua_State, idx: c_int, len: *mut usize) -> *const c_char;
+    pub fn luaL_argerror(L: *mut lua_State, arg: c_int, extramsg: *const c_char) -> c_int;
+    pub fn luaL_typeerror(L: *mut lua_State, arg: c_int, tname: *const c_char) -> c_int;
+    pub fn luaL_checklstring(L: *mut lua_State, arg: c_int, l: *mut usize) -> *const c_char;
+    pub fn luaL_optlstring(
+        L: *mut lua_State,
+        arg: c_int,
+        def: *const c_char,
+        l: *mut usize,
+    ) -> *const c_char;
+    pub fn luaL_checknumber(L: *mut lua_State, arg: c_int) -> lua_Number;
+    pub fn luaL_optnumber(L: *mut lua_State, arg: c_int, def: lua_Number) -> lua_Number;
+    pub fn luaL_checkinteger(L: *mut lua_State, arg: c_int) -> lua_Integer;
+    pub fn luaL_optinteger(L: *mut lua_State, arg: c_int, def: lua_Integer) -> lua_Integer;
+
+    pub fn luaL_checkstack(L: *mut lua_State, sz: c_int, msg: *const c_char);
+    pub fn luaL_checktype(L: *mut lua_State, arg: c_int, t: c_int);
+    pub fn luaL_checkany(L: *mut lua_State, arg: c_int);
+
+    pub fn luaL_newmetatable(L: *mut lua_State, tname: *const c_char) -> c_int;
+    pub fn luaL_setmetatable(L: *mut lua_State, tname: *const c_char);
+    pub fn luaL_testudata(L: *mut lua_State, ud: c_int, tname: *const c_char) -> *mut c_void;
+    pub fn luaL_checkudata(L: *mut lua_State, ud: c_int, tname: *const c_char) -> *mut c_void;
+
+    pub fn luaL_where(L: *mut lua_State, lvl: c_int);
+    pub fn luaL_error(L: *mut lua_State, fmt: *const c_char, ...) -> c_int;
+
+    pub fn luaL_checkoption(
+        L: *mut lua_State,
+        arg: c_int,
+        def: *const c_char,
+        lst: *const *const c_char,
+    ) -> c_int;
+
+    pub fn luaL_fileresult(L: *mut lua_State, stat: c_int, fname: *const c_char) -> c_int;
+    pub fn luaL_execresult(L: *mut lua_State, stat: c_int) -> c_int;
+
+    pub fn luaL_ref(L: *mut lua_State, t: c_int) -> c_int;
+    pub fn luaL_unref(L: *mut lua_State, t: c_int, r#ref: c_int);
+
+    pub fn luaL_loadfilex(L: *mut lua_State, filename: *const c_char, mode: *const c_char)
+        -> c_int;
+    pub fn luaL_loadbufferx(
+        L: *mut lua_State,
+        buff: *const c_char,
+        sz: usize,
+        name: *const c_char,
+        mode: *const c_char,
+    ) -> c_int;
+    pub fn luaL_loadstring(L: *mut lua_State, s: *const c_char) -> c_int;
+
+    pub fn luaL_newstate() -> *mut lua_State;
+
+    pub fn luaL_len(L: *mut lua_State, idx: c_int) -> lua_Integer;
+
+    pub fn luaL_addgsub(b: *mut luaL_Buffer, s: *const c_char, p: *const c_char, r: *const c_char);
+    pub fn luaL_gsub(
+        L: *mut lua_State,
+        s: *const c_char,
+        p: *const c_char,
+        r: *const c_char,
+    ) -> *const c_char;
+
+    pub fn luaL_setfuncs(L: *mut lua_State, l: *const luaL_Reg, nup: c_int);
+    pub fn luaL_getsubtable(L: *mut lua_State, idx: c_int, fname: *const c_char) -> c_int;
+    pub fn luaL_traceback(L: *mut lua_State, L1: *mut lua_State, msg: *const c_char, level: c_int);
+    pub fn luaL_requiref(
+        L: *mut lua_State,
+        modname: *const c_char,
+        openf: lua_CFunction,
+        glb: c_int,
+    );
+
+    pub fn luaL_buffinit(L: *mut lua_State, B: *mut luaL_Buffer);
+    pub fn luaL_prepbuffsize(B: *mut luaL_Buffer, sz: usize) -> *mut c_char;
+    pub fn luaL_addlstring(B: *mut luaL_Buffer, s: *const c_char, l: usize);
+    pub fn luaL_addstring(B: *mut luaL_Buffer, s: *const c_char);
+    pub fn luaL_addvalue(B: *mut luaL_Buffer);
+    pub fn luaL_pushresult(B: *mut luaL_Buffer);
+    pub fn luaL_pushresultsize(B: *mut luaL_Buffer, sz: usize);
+    pub fn luaL_buffinitsize(L: *mut lua_State, B: *mut luaL_Buffer, sz: usize) -> *mut c_char;
+}
+
+extern "C" {
+    pub fn luaopen_base(L: *mut lua_State) -> c_int;
+    pub fn luaopen_coroutine(L: *mut lua_State) -> c_int;
+    pub fn luaopen_table(L: *mut lua_State) -> c_int;
+    pub fn luaopen_io(L: *mut lua_State) -> c_int;
+    pub fn luaopen_os(L: *mut lua_State) -> c_int;
+    pub fn luaopen_string(L: *mut lua_State) -> c_int;
+    pub fn luaopen_utf8(L: *mut lua_State) -> c_int;
+    pub fn luaopen_math(L: *mut lua_State) -> c_int;
+    pub fn luaopen_debug(L: *mut lua_State) -> c_int;
+    pub fn luaopen_package(L: *mut lua_State) -> c_int;
+
+    pub fn luaL_openlibs(L: *mut lua_State);
+}
+
+// Macros of lua.h
+
+pub const fn lua_upvalueindex(i: c_int) -> c_int {
+    LUA_REGISTRYINDEX - i
+}
+
+pub unsafe fn lua_call(L: *mut lua_State, nargs: c_int, nresults: c_int) {
+    lua_callk(L, nargs, nresults, 0, None)
+}
+
+pub unsafe fn lua_pcall(L: *mut lua_State, nargs: c_int, nresults: c_int, errfunc: c_int) -> c_int {
+    lua_pcallk(L, nargs, nresults, errfunc, 0, None)
+}
+
+pub unsafe fn lua_yield(L: *mut lua_State, nresults: c_int) -> c_int {
+    lua_yieldk(L, nresults, 0, None)
+}
+
+pub unsafe fn lua_getextraspace(L: *mut lua_State) -> *mut c_void {
+    (L as *mut u8).sub(LUA_EXTRASPACE) as *mut c_void
+}
+
+pub unsafe fn lua_tonumber(L: *mut lua_State, idx: c_int) -> lua_Number {
+    lua_tonumberx(L, idx, core::ptr::null_mut())
+}
+
+pub unsafe fn lua_tointeger(L: *mut lua_State, idx: c_int) -> lua_Integer {
+    lua_tointegerx(L, idx, core::ptr::null_mut())
+}
+
+pub unsafe fn lua_pop(L: *mut lua_State, n: c_int) {
+    lua_settop(L, -n - 1)
+}
+
+pub unsafe fn lua_newtable(L: *mut lua_State) {
+    lua_createtable(L, 0, 0)
+}
+
+pub unsafe fn lua_register(L: *mut lua_State, name: *const c_char, f: lua_CFunction) {
+    lua_pushcfunction(L, f);
+    lua_setglobal(L, name)
+}
+
+pub unsafe fn lua_pushcfunction(L: *mut lua_State, f: lua_CFunction) {
+    lua_pushcclosure(L, f, 0)
+}
+
+pub unsafe fn lua_isfunction(L: *mut lua_State, n: c_int) -> bool {
+    lua_type(L, n) == LUA_TFUNCTION
+}
+
+pub unsafe fn lua_istable(L: *mut lua_State, n: c_int) -> bool {
+    lua_type(L, n) == LUA_TTABLE
+}
+
+pub unsafe fn lua_islightuserdata(L: *mut lua_State, n: c_int) -> bool {
+    lua_type(L, n) == LUA_TLIGHTUSERDATA
+}
+
+pub unsafe fn lua_isnil(L: *mut lua_State, n: c_int) -> bool {
+    lua_type(L, n) == LUA_TNIL
+}
+
+pub unsafe fn lua_isboolean(L: *mut lua_State, n: c_int) -> bool {
+    lua_type(L, n) == LUA_TBOOLEAN
+}
+
+pub unsafe fn lua_isthread(L: *mut lua_State, n: c_int) -> bool {
+    lua_type(L, n) == LUA_TTHREAD
+}
+
+pub unsafe fn lua_isnone(L: *mut lua_State, n: c_int) -> bool {
+    lua_type(L, n) == LUA_TNONE
+}
+
+pub unsafe fn lua_isnoneornil(L: *mut lua_State, n: c_int) -> bool {
+    lua_type(L, n) <= 0
+}
+
+pub unsafe fn lua_pushliteral(L: *mut lua_State, s: &CStr) -> *const c_char {
+    lua_pushstring(L, s.as_ptr())
+}
+
+pub unsafe fn lua_pushglobaltable(L: *mut lua_State) -> c_int {
+    lua_rawgeti(L, LUA_REGISTRYINDEX, LUA_RIDX_GLOBALS)
+}
+
+pub unsafe fn lua_tostring(L: *mut lua_State, idx: c_int) -> *const c_char {
+    lua_tolstring(L, idx, core::ptr::null_mut())
+}
+
+pub unsafe fn lua_insert(L: *mut lua_State, idx: c_int) {
+    lua_rotate(L, idx, 1)
+}
+
+pub unsafe fn lua_remove(L: *mut lua_State, idx: c_int) {
+    lua_rotate(L, idx, -1);
+    lua_pop(L, 1)
+}
+
+pub unsafe fn lua_replace(L: *mut lua_State, idx: c_int) {
+    lua_copy(L, -1, idx);
+    lua_pop(L, 1)
+}
+
+pub unsafe fn lua_newuserdata(L: *mut lua_State, sz: usize) -> *mut c_void {
+    lua_newuserdatauv(L, sz, 1)
+}
+
+pub unsafe fn lua_getuservalue(L: *mut lua_State, idx: c_int) -> c_int {
+    lua_getiuservalue(L, idx, 1)
+}
+
+pub unsafe fn lua_setuservalue(L: *mut lua_State, idx: c_int) -> c_int {
+    lua_setiuservalue(L, idx, 1)
+}
+
+pub unsafe fn lua_pushunsigned(L: *mut lua_State, n: lua_Unsigned) {
+    lua_pushinteger(L, n as lua_Integer)
+}
+
+pub unsafe fn lua_tounsignedx(L: *mut lua_State, idx: c_int, isnum: *mut c_int) -> lua_Unsigned {
+    lua_tointegerx(L, idx, isnum) as lua_Unsigned
+}
+
+pub unsafe fn lua_tounsigned(L: *mut lua_State, idx: c_int) -> lua_Unsigned {
+    lua_tounsignedx(L, idx, core::ptr::null_mut())
+}
+
+// Macros of lauxlib.h
+
+pub unsafe fn luaL_checkversion(L: *mut lua_State) {
+    luaL_checkversion_(L, LUA_VERSION_NUM as lua_Number, LUAL_NUMSIZES)
+}
+
+pub unsafe fn luaL_loadfile(L: *mut lua_State, filename: *const c_char) -> c_int {
+    luaL_loadfilex(L, filename, core::ptr::null())
+}
+
+/// Creates a table sized for the functions in `l`, which ends with a null entry.
+pub unsafe fn luaL_newlibtable(L: *mut lua_State, l: &[luaL_Reg]) {
+    lua_createtable(L, 0, l.len().saturating_sub(1) as c_int)
+}
+
+/// Creates a table with the functions in `l`, which ends with a null entry.
+pub unsafe fn luaL_newlib(L: *mut lua_State, l: &[luaL_Reg]) {
+    luaL_checkversion(L);
+    luaL_newlibtable(L, l);
+    luaL_setfuncs(L, l.as_ptr(), 0)
+}
+
+pub unsafe fn luaL_argcheck(L: *mut lua_State, cond: bool, arg: c_int, extramsg: *const c_char) {
+    if !cond {
+        luaL_argerror(L, arg, extramsg);
+    }
+}
+
+pub unsafe fn luaL_argexpected(L: *mut lua_State, cond: bool, arg: c_int, tname: *const c_char) {
+    if !cond {
+        luaL_typeerror(L, arg, tname);
+    }
+}
+
+pub unsafe fn luaL_checkstring(L: *mut lua_State, arg: c_int) -> *const c_char {
+    luaL_checklstring(L, arg, core::ptr::null_mut())
+}
+
+pub unsafe fn luaL_optstring(L: *mut lua_State, arg: c_int, def: *const c_char) -> *const c_char {
+    luaL_optlstring(L, arg, def, core::ptr::null_mut())
+}
+
+pub unsafe fn luaL_typename(L: *mut lua_State, idx: c_int) -> *const c_char {
+    lua_typename(L, lua_type(L, idx))
+}
+
+/// Loads and runs the file. Returns `LUA_OK` or the code of the error, which is on the stack.
+pub unsafe fn luaL_dofile(L: *mut lua_State, filename: *const c_char) -> c_int {
+    match luaL_loadfile(L, filename) {
+        LUA_OK => lua_pcall(L, 0, LUA_MULTRET, 0),
+        code => code,
+    }
+}
+
+/// Loads and runs the string. Returns `LUA_OK` or the code of the error, which is on the stack.
+pub unsafe fn luaL_dostring(L: *mut lua_State, s: *const c_char) -> c_int {
+    match luaL_loadstring(L, s) {
+        LUA_OK => lua_pcall(L, 0, LUA_MULTRET, 0),
+        code => code,
+    }
+}
+
+pub unsafe fn luaL_getmetatable(L: *mut lua_State, tname: *const c_char) -> c_int {
+    lua_getfield(L, LUA_REGISTRYINDEX, tname)
+}
+
+pub unsafe fn luaL_loadbuffer(
+    L: *mut lua_State,
+    buff: *const c_char,
+    sz: usize,
+    name: *const c_char,
+) -> c_int {
+    luaL_loadbufferx(L, buff, sz, name, core::ptr::null())
+}
+
+pub unsafe fn luaL_pushfail(L: *mut lua_State) {
+    lua_pushnil(L)
+}
+
+pub unsafe fn luaL_bufflen(B: *const luaL_Buffer) -> usize {
+    (*B).n
+}
+
+pub unsafe fn luaL_buffaddr(B: *const luaL_Buffer) -> *mut c_char {
+    (*B).b
+}
+
+pub unsafe fn luaL_addchar(B: *mut luaL_Buffer, c: c_char) {
+    if (*B).n >= (*B).size {
+        luaL_prepbuffsize(B, 1);
+    }
+    *(*B).b.add((*B).n) = c;
+    (*B).n += 1;
+}
+
+pub unsafe fn luaL_addsize(B: *mut luaL_Buffer, s: usize) {
+    (*B).n += s
+}
+
+pub unsafe fn luaL_buffsub(B: *mut luaL_Buffer, s: usize) {
+    (*B).n -= s
+}
+
+pub unsafe fn luaL_prepbuffer(B: *mut luaL_Buffer) -> *mut c_char {
+    luaL_prepbuffsize(B, LUAL_BUFFERSIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    unsafe extern "C" fn add(L: *mut lua_State) -> c_int {
+        let sum = luaL_checkinteger(L, 1) + luaL_checkinteger(L, 2);
+        lua_pushinteger(L, sum);
+        1
+    }
+
+    unsafe extern "C" fn shout(L: *mut lua_State) -> c_int {
+        let mut buffer = core::mem::MaybeUninit::<luaL_Buffer>::uninit();
+        let buffer = buffer.as_mut_ptr();
+        luaL_buffinit(L, buffer);
+        luaL_addstring(buffer, luaL_checkstring(L, 1));
+        luaL_addchar(buffer, b'!' as c_char);
+        luaL_pushresult(buffer);
+        1
+    }
+
+    #[test]
+    fn newlib_registers_functions() {
+        let functions = [
+            luaL_Reg {
+                name: c"add".as_ptr(),
+                func: Some(add),
+            },
+            luaL_Reg {
+                name: c"shout".as_ptr(),
+                func: Some(shout),
+            },
+            luaL_Reg {
+                name: core::ptr::null(),
+                func: None,
+            },
+        ];
+
+        unsafe {
+            let L = luaL_newstate();
+            luaL_newlib(L, &functions);
+            lua_setglobal(L, c"lib".as_ptr());
+
+            let code = luaL_dostring(L, c"return lib.add(2, 3), lib.shout('hi')".as_ptr());
+            assert_eq!(LUA_OK, code);
+            assert_eq!(5, lua_tointeger(L, -2));
+            assert_eq!(c"hi!", CStr::from_ptr(lua_tostring(L, -1)));
+            lua_close(L);
+        }
+    }
+
+    #[test]
+    fn dofile_missing_file_returns_errfile() {
+        unsafe {
+            let L = luaL_newstate();
+            let code = luaL_dofile(L, c"/nonexistent/script.lua".as_ptr());
+
+            assert_eq!(LUA_ERRFILE, code);
+            lua_close(L);
+        }
+    }
+}
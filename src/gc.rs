@@ -0,0 +1,67 @@
+use crate::lua_core::*;
+
+/// The modes the garbage collector can run in.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GcMode {
+    Incremental,
+    Generational,
+}
+impl GcMode {
+    /// Maps the mode returned by Lua when switching modes.
+    #[cfg(lua_version = "5.4")]
+    pub(crate) fn from_lua(mode: Int) -> Self {
+        match mode {
+            LUA_GCGEN => GcMode::Generational,
+            _ => GcMode::Incremental,
+        }
+    }
+}
+
+/// Parameters for the incremental garbage collector.
+/// Parameters that are `None` are left unchanged.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Incremental {
+    /// How long the collector waits before starting a new cycle, as a percentage of memory in use after the last one.
+    pub pause: Option<Int>,
+    /// How fast the collector runs relative to memory allocation, as a percentage.
+    pub step_multiplier: Option<Int>,
+    /// Size of each incremental step, as the base 2 logarithm of the size in bytes. Ignored before Lua 5.4.
+    pub step_size: Option<Int>,
+}
+
+/// Parameters for the generational garbage collector.
+/// Parameters that are `None` are left unchanged.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Generational {
+    /// How much memory may grow before a minor collection, as a percentage of memory in use after the last major one.
+    pub minor_multiplier: Option<Int>,
+    /// How much memory may grow before a major collection, as a percentage of memory in use after the last major one.
+    pub major_multiplier: Option<Int>,
+}
+
+/// Maps an optional parameter to Lua, where 0 leaves it unchanged.
+#[cfg(lua_version = "5.4")]
+pub(crate) fn gc_param(param: Option<Int>) -> Int {
+    param.unwrap_or(0)
+}
+
+#[cfg(all(test, lua_version = "5.4"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_lua_gen_returns_generational() {
+        assert_eq!(GcMode::Generational, GcMode::from_lua(LUA_GCGEN));
+    }
+
+    #[test]
+    fn from_lua_inc_returns_incremental() {
+        assert_eq!(GcMode::Incremental, GcMode::from_lua(LUA_GCINC));
+    }
+
+    #[test]
+    fn gc_param_none_returns_zero() {
+        assert_eq!(0, gc_param(None));
+        assert_eq!(150, gc_param(Some(150)));
+    }
+}
@@ -0,0 +1,189 @@
+use crate::{extra::Extra, lua_core::*};
+use alloc::string::ToString;
+#[cfg(feature = "tracing")]
+use core::ffi::c_void;
+use core::ffi::CStr;
+
+/// Records the error with its traceback, which is in the message when there is none.
+pub(crate) fn record_error(error: &str, traceback: Option<&str>) {
+    let traceback = traceback.unwrap_or(error);
+
+    #[cfg(feature = "log")]
+    log::error!(target: "llua", "{traceback}");
+    #[cfg(feature = "tracing")]
+    tracing::error!(traceback, "{error}");
+}
+
+/// Message handler keeping the traceback of the error to record it, leaving the message as it is.
+pub(crate) unsafe extern "C" fn keep_traceback(state: State) -> Int {
+    let message = lua_tostring(state, 1);
+    if !message.is_null() {
+        luaL_traceback(state, state, message, 1);
+        if let Some(extra) = Extra::from_state(state) {
+            let traceback = CStr::from_ptr(lua_tostring(state, -1)).to_string_lossy();
+            extra.set_traceback(traceback.to_string());
+        }
+        lua_pop(state, 1);
+    }
+
+    1
+}
+
+/// Pushes the Rust function wrapped so that each call opens a span.
+#[cfg(feature = "tracing")]
+pub(crate) unsafe fn push_traced(state: State, function: LuaFn) {
+    lua_pushlightuserdata(state, function as *mut c_void);
+    lua_pushcclosure(state, traced, 1);
+}
+
+/// Calls the Rust function in its upvalue inside a span.
+/// The span is kept with the state, so an error skipping this frame leaves it to be closed when the protected call returns.
+#[cfg(feature = "tracing")]
+unsafe extern "C" fn traced(state: State) -> Int {
+    let function: LuaFn = core::mem::transmute(lua_touserdata(state, lua_upvalueindex(1)));
+    let Some(extra) = Extra::from_state(state) else {
+        return function(state);
+    };
+
+    let span = callback_span(state);
+    let depth = extra.open_span(span);
+    let results = function(state);
+    extra.close_spans(depth);
+
+    results
+}
+
+/// Enters a span named after the running function, with the number of arguments it got.
+#[cfg(feature = "tracing")]
+unsafe fn callback_span(state: State) -> tracing::span::EnteredSpan {
+    let args = lua_gettop(state);
+    let mut ar = LuaDebug::new();
+    let mut name = "?".into();
+    if lua_getstack(state, 0, &mut ar) != 0 {
+        lua_getinfo(state, c"n".as_ptr(), &mut ar);
+        if !ar.name.is_null() {
+            name = CStr::from_ptr(ar.name).to_string_lossy();
+        }
+    }
+
+    tracing::info_span!("lua.callback", function = %name, args).entered()
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use super::*;
+    use crate::{Data, Error, Library, Lua};
+    use std::{
+        fmt::{Debug, Write},
+        sync::{Arc, Mutex},
+    };
+    use tracing::{
+        field::{Field, Visit},
+        span::{Attributes, Id, Record},
+        Event, Metadata, Subscriber,
+    };
+
+    /// Keeps the spans and events as text.
+    #[derive(Clone, Default)]
+    struct Recorder {
+        spans: Arc<Mutex<Vec<String>>>,
+        events: Arc<Mutex<Vec<String>>>,
+    }
+    struct Fields(String);
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+    impl Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes) -> Id {
+            let mut fields = Fields(span.metadata().name().into());
+            span.record(&mut fields);
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(fields.0);
+            Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _: &Id, _: &Record) {}
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, event: &Event) {
+            let mut fields = Fields(String::new());
+            event.record(&mut fields);
+            self.events.lock().unwrap().push(fields.0);
+        }
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    fn double(state: State) -> Int {
+        unsafe {
+            let n = luaL_checknumber(state, 1);
+            lua_pushnumber(state, n * 2.0);
+        }
+        1
+    }
+
+    #[test]
+    fn calls_open_spans() {
+        let recorder = Recorder::default();
+        tracing::subscriber::with_default(recorder.clone(), || {
+            let mut lua = Lua::new();
+            lua.set_global("double", Data::Function(double)).unwrap();
+            lua.interpret_named("traced.lua", "function f(a, b) return double(a) end")
+                .unwrap();
+            let result: [Data; 1] = lua.call("f", [Data::from(2), Data::from(3)]).unwrap();
+            assert_eq!([Data::from(4)], result);
+        });
+
+        let spans = recorder.spans.lock().unwrap();
+        assert_eq!(
+            vec![
+                "lua.interpret chunk=\"traced.lua\" args=0",
+                "lua.call function=f args=2",
+                "lua.callback function=double args=1",
+            ],
+            *spans
+        );
+    }
+
+    #[test]
+    fn errors_are_recorded_with_traceback() {
+        let recorder = Recorder::default();
+        let result = tracing::subscriber::with_default(recorder.clone(), || {
+            let mut lua = Lua::new();
+            lua.activate(&[Library::Basic]).unwrap();
+            lua.set_global("double", Data::Function(double)).unwrap();
+            lua.interpret("double('x')")
+        });
+
+        let Err(Error::Runtime(message)) = result else {
+            panic!("double('x') should fail");
+        };
+        assert!(message.contains("bad argument #1 to 'double'"), "{message}");
+        assert!(!message.contains("stack traceback"), "{message}");
+
+        let events = recorder.events.lock().unwrap();
+        assert_eq!(1, events.len(), "{events:?}");
+        assert!(events[0].contains("stack traceback"), "{events:?}");
+    }
+
+    #[test]
+    fn syntax_errors_from_check_syntax_are_not_recorded() {
+        let recorder = Recorder::default();
+        let result = tracing::subscriber::with_default(recorder.clone(), || {
+            let mut lua = Lua::new();
+            lua.check_syntax("stdin", "x =")
+        });
+
+        assert!(matches!(result, Err(Error::Runtime(_))));
+        assert!(recorder.events.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,48 @@
+extern crate alloc;
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Handle for stopping a running script from another thread.
+/// The script stops at the next hook check and the call fails with `Error::Interrupted`.
+#[derive(Clone, Debug, Default)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+impl InterruptHandle {
+    /// Interrupts the running call. Has no effect if nothing is running.
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Clears any pending interrupt.
+    pub(crate) fn clear(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+
+    /// Returns whether an interrupt was requested, clearing it.
+    pub(crate) fn take(&self) -> bool {
+        self.0.swap(false, Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_returns_interrupt_once() {
+        let handle = InterruptHandle::default();
+        handle.clone().interrupt();
+
+        assert!(handle.take());
+        assert!(!handle.take());
+    }
+
+    #[test]
+    fn clear_removes_interrupt() {
+        let handle = InterruptHandle::default();
+        handle.interrupt();
+        handle.clear();
+
+        assert!(!handle.take());
+    }
+}
@@ -1,27 +1,85 @@
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 
 extern crate alloc;
 
+mod allocator;
+#[cfg(lua_version = "5.4")]
+mod analysis;
+mod bytecode;
+#[cfg(feature = "std")]
+mod coverage;
+#[cfg(feature = "dap")]
+pub mod dap;
 mod data;
+mod debug;
+#[cfg(feature = "deterministic")]
+mod deterministic;
+mod extra;
+#[cfg(lua_version = "5.4")]
+pub mod ffi;
+mod gc;
+#[cfg(any(feature = "log", feature = "tracing"))]
+mod instrument;
+mod interrupt;
 mod library;
 mod lua;
 #[allow(improper_ctypes, dead_code)]
 mod lua_core;
 mod mtype;
+#[cfg(feature = "std")]
+mod output;
+#[cfg(any(feature = "std", feature = "deterministic"))]
+mod overrides;
+#[cfg(feature = "std")]
+mod profiler;
 mod stack;
+mod strict;
+#[cfg(feature = "std")]
+mod testing;
+#[cfg(feature = "std")]
+mod vfs;
 
 use alloc::string::String;
+pub use allocator::RustAllocator;
+#[cfg(lua_version = "5.4")]
+pub use analysis::GlobalUsage;
+pub use bytecode::disassemble;
+#[cfg(feature = "std")]
+pub use coverage::{Coverage, CoverageCollector, FileCoverage};
 pub use data::*;
+pub use debug::{DebugInfo, FunctionKind, HookContext, HookEvent, HookMask, Variable};
+#[cfg(feature = "deterministic")]
+pub use deterministic::VirtualClock;
+pub use gc::{GcMode, Generational, Incremental};
+pub use interrupt::InterruptHandle;
 pub use library::*;
 pub use lua::*;
-pub use lua_core::{Int, State};
+pub use lua_core::{CFunction, Int, State};
 pub use mtype::*;
+#[cfg(feature = "log")]
+pub use output::LogOutput;
+#[cfg(feature = "std")]
+pub use output::{BufferOutput, Output, PrefixedOutput};
+#[cfg(feature = "std")]
+pub use profiler::{FunctionKey, FunctionStats, Profile, Profiler};
 pub use stack::*;
+#[cfg(feature = "std")]
+pub use testing::{Outcome, TestReport, TestResult, TestRunner};
+#[cfg(feature = "std")]
+pub use vfs::{DirVfs, MemoryVfs, OverlayVfs, Vfs};
 
 /// Various errors that may be returned.
 #[derive(Debug, Clone, PartialEq)]
 pub enum Error {
     Runtime(String),
+    /// The call ran more instructions than allowed.
+    BudgetExceeded,
+    /// The call ran longer than allowed.
+    Timeout,
+    /// The call was stopped through an `InterruptHandle`.
+    Interrupted,
+    /// The state ran out of memory or went over its memory limit.
+    Memory,
     Data(DataErr),
     Library(LibraryErr),
 }
@@ -1,13 +1,31 @@
 use crate::lua_core::*;
+use alloc::{ffi::CString, string::String, vec::Vec};
+use core::ffi::{c_char, CStr};
 
 type ResultCode = Int;
 
+/// Registry field holding `package.loaded`.
+const LOADED: &CStr = c"_LOADED";
+
 /// Various errors that may occur during opening of a library.
-#[derive(Clone, PartialEq, Debug, Copy)]
-pub enum LibraryErr {}
+#[derive(Clone, PartialEq, Debug)]
+pub enum LibraryErr {
+    /// Opening the library did not return a single table.
+    InvalidOpenResult {
+        library: Library,
+        result: ResultCode,
+    },
+    /// The library has no function with the given name.
+    UnknownFunction { library: Library, function: String },
+    /// The library does not exist in the Lua version that was built.
+    Unavailable { library: Library },
+    /// The name of the custom library contains a nul byte.
+    InvalidName { library: Library },
+}
 
 /// Various libraries that may be enabled for Lua.
 #[derive(Clone, PartialEq, Debug, Copy)]
+#[allow(unpredictable_function_pointer_comparisons)]
 pub enum Library {
     Basic,
     Coroutine,
@@ -19,15 +37,22 @@ pub enum Library {
     Io,
     Os,
     Debug,
+    /// A library opened by a `luaopen_` function linked into the program, such as a C module.
+    /// It is installed as the global `name` and in `package.loaded`, so `require` finds it too.
+    Custom {
+        name: &'static str,
+        open: CFunction,
+    },
 }
 impl Library {
-    /// Returns all the libraries.
+    /// Returns all the libraries of the Lua version that was built.
     pub fn all() -> &'static [Self] {
         &[
             Library::Basic,
             Library::Coroutine,
             Library::Package,
             Library::String,
+            #[cfg(any(lua_version = "5.3", lua_version = "5.4"))]
             Library::Utf8,
             Library::Table,
             Library::Math,
@@ -37,32 +62,194 @@ impl Library {
         ]
     }
 
+    /// Returns the global name the library is installed under.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Library::Basic => "_G",
+            Library::Coroutine => "coroutine",
+            Library::Package => "package",
+            Library::String => "string",
+            Library::Utf8 => "utf8",
+            Library::Table => "table",
+            Library::Math => "math",
+            Library::Io => "io",
+            Library::Os => "os",
+            Library::Debug => "debug",
+            Library::Custom { name, .. } => name,
+        }
+    }
+
     /// Turns on the given library.
     pub(crate) fn enable(&self, state: State) -> Result<(), LibraryErr> {
+        let name = self.global_name()?;
+        self.open(state)?;
+
+        unsafe {
+            match self {
+                // The basic library installs itself into the global table.
+                Library::Basic => lua_pop(state, 1),
+                Library::Custom { .. } => {
+                    // The package library keeps this table if it is opened later
+                    if lua_getfield(state, LUA_REGISTRYINDEX, LOADED.as_ptr()) != LUA_TTABLE {
+                        lua_pop(state, 1);
+                        lua_newtable(state);
+                        lua_pushvalue(state, -1);
+                        lua_setfield(state, LUA_REGISTRYINDEX, LOADED.as_ptr());
+                    }
+                    lua_pushvalue(state, -2);
+                    lua_setfield(state, -2, map_cstr(&name));
+                    lua_pop(state, 1);
+                    lua_setglobal(state, map_cstr(&name));
+                }
+                _ => lua_setglobal(state, map_cstr(&name)),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Turns on only the given functions of the library.
+    /// The library is opened into a temporary table and only the allowed functions are copied into its global.
+    /// If any of the functions do not exist, nothing is installed.
+    pub(crate) fn enable_functions(
+        &self,
+        state: State,
+        functions: &[&str],
+    ) -> Result<(), LibraryErr> {
+        let global_name = self.global_name()?;
+        // A name with a nul byte can't be a field of the library
+        let names = functions
+            .iter()
+            .map(|function| {
+                CString::new(*function).map_err(|_| LibraryErr::UnknownFunction {
+                    library: *self,
+                    function: (*function).into(),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.open_temporary(state)?;
+
+        unsafe {
+            // Check everything exists before installing anything
+            for (function, name) in functions.iter().zip(names.iter()) {
+                let lua_type = lua_getfield(state, -1, map_cstr(name));
+                lua_pop(state, 1);
+
+                if lua_type == LUA_TNIL {
+                    lua_pop(state, 1);
+                    return Err(LibraryErr::UnknownFunction {
+                        library: *self,
+                        function: (*function).into(),
+                    });
+                }
+            }
+
+            self.push_installed_table(state, &global_name);
+            for name in names.iter() {
+                lua_getfield(state, -2, map_cstr(name));
+                lua_setfield(state, -2, map_cstr(name));
+            }
+
+            // Remove the installed table and the temporary one
+            lua_pop(state, 2);
+        }
+
+        Ok(())
+    }
+
+    /// Opens the library into a table that is not reachable from Lua, leaving it on the stack.
+    fn open_temporary(&self, state: State) -> Result<(), LibraryErr> {
+        if *self != Library::Basic {
+            return self.open(state);
+        }
+
+        unsafe {
+            // The basic library writes into the global table, so swap in a temporary one while opening it.
+            lua_pushglobaltable(state);
+            lua_newtable(state);
+            set_global_table(state);
+
+            let result = self.open(state);
+            let globals = if result.is_ok() { -2 } else { -1 };
+
+            // Restore the real global table
+            lua_pushvalue(state, globals);
+            set_global_table(state);
+
+            match result {
+                Ok(()) => lua_replace(state, -2),
+                Err(_) => lua_pop(state, 1),
+            }
+
+            result
+        }
+    }
+
+    /// Pushes the table the library's functions are installed into, creating it if it doesn't exist.
+    fn push_installed_table(&self, state: State, name: &CString) {
+        unsafe {
+            if *self == Library::Basic {
+                lua_pushglobaltable(state);
+                return;
+            }
+
+            if lua_getglobal(state, map_cstr(name)) != LUA_TTABLE {
+                lua_pop(state, 1);
+                lua_newtable(state);
+                lua_pushvalue(state, -1);
+                lua_setglobal(state, map_cstr(name));
+            }
+        }
+    }
+
+    /// Returns the global name as a C string, or an error if it contains a nul byte.
+    fn global_name(&self) -> Result<CString, LibraryErr> {
+        CString::new(self.name()).map_err(|_| LibraryErr::InvalidName { library: *self })
+    }
+
+    /// Opens the library, leaving its table on the stack.
+    fn open(&self, state: State) -> Result<(), LibraryErr> {
         let result = unsafe {
             match self {
                 Library::Basic => luaopen_base(state),
                 Library::Coroutine => luaopen_coroutine(state),
                 Library::Package => luaopen_package(state),
                 Library::String => luaopen_string(state),
+                #[cfg(any(lua_version = "5.3", lua_version = "5.4"))]
                 Library::Utf8 => luaopen_utf8(state),
+                #[cfg(any(lua_version = "5.1", lua_version = "5.2"))]
+                Library::Utf8 => return Err(LibraryErr::Unavailable { library: *self }),
                 Library::Table => luaopen_table(state),
                 Library::Math => luaopen_math(state),
                 Library::Io => luaopen_io(state),
                 Library::Os => luaopen_os(state),
                 Library::Debug => luaopen_debug(state),
+                Library::Custom { open, .. } => open(state),
             }
         };
 
-        open_result_to_result(result)
+        open_result_to_result(*self, result)
     }
 }
 
-fn open_result_to_result(i: ResultCode) -> Result<(), LibraryErr> {
-    match i {
+/// Pops the table on top of the stack and makes it the global table.
+unsafe fn set_global_table(state: State) {
+    #[cfg(lua_version = "5.1")]
+    lua_replace(state, LUA_GLOBALSINDEX);
+    #[cfg(not(lua_version = "5.1"))]
+    lua_rawseti(state, LUA_REGISTRYINDEX, LUA_RIDX_GLOBALS);
+}
+
+/// Maps a cstring to a pointer.
+fn map_cstr(c: &CString) -> *const c_char {
+    c.as_ptr()
+}
+
+fn open_result_to_result(library: Library, result: ResultCode) -> Result<(), LibraryErr> {
+    match result {
         1 => Ok(()),
-        // TODO: need to convert to an actual error
-        _ => todo!("openresult '{:?}'", i),
+        _ => Err(LibraryErr::InvalidOpenResult { library, result }),
     }
 }
 
@@ -70,6 +257,20 @@ fn open_result_to_result(i: ResultCode) -> Result<(), LibraryErr> {
 mod tests {
     use super::*;
     use crate::lua_core::luaL_newstate;
+    #[cfg(feature = "test-modules")]
+    use crate::{Data, Lua};
+
+    #[cfg(feature = "test-modules")]
+    extern "C" {
+        // The C module in tests/modules
+        fn luaopen_llua_greet(state: State) -> Int;
+    }
+
+    #[cfg(feature = "test-modules")]
+    const GREET: Library = Library::Custom {
+        name: "llua_greet",
+        open: luaopen_llua_greet,
+    };
 
     #[test]
     fn enable_all_libs_no_errors() {
@@ -82,6 +283,141 @@ mod tests {
         }
     }
 
+    #[test]
+    #[cfg(feature = "test-modules")]
+    fn enable_custom_installs_global_and_loaded() {
+        let mut m = Lua::new();
+        m.activate(&[GREET, Library::Basic, Library::Package])
+            .unwrap();
+        m.interpret("hello = llua_greet.greet('world') same = require('llua_greet') == llua_greet")
+            .unwrap();
+
+        assert_eq!(
+            Ok(Data::String("hello, world".into())),
+            m.get_global("hello")
+        );
+        assert_eq!(Ok(Data::Bool(true)), m.get_global("same"));
+    }
+
+    #[test]
+    #[cfg(feature = "test-modules")]
+    fn enable_functions_custom_unknown_function_returns_err() {
+        let mut m = Lua::new();
+        let result = m.activate_functions(GREET, &["missing"]).map(|_| ());
+
+        let expected = Err(LibraryErr::UnknownFunction {
+            library: GREET,
+            function: "missing".into(),
+        });
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    #[cfg(all(feature = "test-modules", feature = "dlopen", unix))]
+    fn require_loads_shared_module() {
+        let mut m = Lua::new();
+        m.activate(&[Library::Basic, Library::Package]).unwrap();
+        let cpath = concat!(env!("OUT_DIR"), "/modules/?.so");
+        m.set_global("cpath", Data::String(cpath.into())).unwrap();
+        m.interpret("package.cpath = cpath hello = require('llua_greet').greet('module')")
+            .unwrap();
+
+        assert_eq!(
+            Ok(Data::String("hello, module".into())),
+            m.get_global("hello")
+        );
+    }
+
+    #[test]
+    fn name_returns_global_name() {
+        assert_eq!("_G", Library::Basic.name());
+        assert_eq!("os", Library::Os.name());
+    }
+
+    #[test]
+    fn enable_functions_nul_in_name_returns_err() {
+        let state = unsafe { luaL_newstate() };
+        let result = Library::Math.enable_functions(state, &["floor", "fl\0oor"]);
+        unsafe { lua_close(state) };
+
+        let expected = Err(LibraryErr::UnknownFunction {
+            library: Library::Math,
+            function: "fl\0oor".into(),
+        });
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn enable_custom_nul_in_name_returns_err() {
+        unsafe extern "C" fn open_empty(state: State) -> Int {
+            lua_newtable(state);
+            1
+        }
+        let library = Library::Custom {
+            name: "bad\0name",
+            open: open_empty,
+        };
+        let state = unsafe { luaL_newstate() };
+        let result = library.enable(state);
+        unsafe { lua_close(state) };
+
+        assert_eq!(Err(LibraryErr::InvalidName { library }), result);
+    }
+
+    #[test]
+    fn open_result_to_result_invalid_returns_err() {
+        let result = open_result_to_result(Library::Math, 0);
+        let expected = Err(LibraryErr::InvalidOpenResult {
+            library: Library::Math,
+            result: 0,
+        });
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn enable_functions_leaves_stack_empty() {
+        let s = unsafe { luaL_newstate() };
+
+        Library::Os.enable_functions(s, &["time"]).unwrap();
+        let _ = Library::Os.enable_functions(s, &["missing"]);
+        Library::Basic.enable_functions(s, &["print"]).unwrap();
+
+        assert_eq!(0, unsafe { lua_gettop(s) });
+    }
+
+    #[test]
+    fn enable_installs_only_its_global() {
+        let s = unsafe { luaL_newstate() };
+
+        Library::Coroutine.enable(s).unwrap();
+        Library::Os.enable_functions(s, &["time"]).unwrap();
+
+        let print = CString::new("print").unwrap();
+        assert_eq!(LUA_TNIL, unsafe { lua_getglobal(s, map_cstr(&print)) });
+        let coroutine = CString::new("coroutine").unwrap();
+        assert_eq!(LUA_TTABLE, unsafe {
+            lua_getglobal(s, map_cstr(&coroutine))
+        });
+        let os = CString::new("os").unwrap();
+        unsafe { lua_getglobal(s, map_cstr(&os)) };
+        let execute = CString::new("execute").unwrap();
+        assert_eq!(LUA_TNIL, unsafe { lua_getfield(s, -1, map_cstr(&execute)) });
+    }
+
+    #[test]
+    #[cfg(any(lua_version = "5.1", lua_version = "5.2"))]
+    fn enable_utf8_before_5_3_returns_unavailable() {
+        let s = unsafe { luaL_newstate() };
+
+        assert_eq!(
+            Err(LibraryErr::Unavailable {
+                library: Library::Utf8
+            }),
+            Library::Utf8.enable(s)
+        );
+        assert!(!Library::all().contains(&Library::Utf8));
+    }
+
     #[test]
     fn luaopen_base_returns_ok() {
         let result = unsafe { luaopen_base(luaL_newstate()) };
@@ -146,6 +482,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(any(lua_version = "5.3", lua_version = "5.4"))]
     fn luaopen_utf8_returns_ok() {
         let result = unsafe { luaopen_utf8(luaL_newstate()) };
 
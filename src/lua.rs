@@ -1,19 +1,188 @@
 extern crate alloc;
 use crate::alloc::string::ToString;
-use crate::{lua_core::*, Data, Error, Library, LibraryErr, Stack};
-use alloc::ffi::CString;
-use core::ffi::CStr;
+#[cfg(any(feature = "log", feature = "tracing"))]
+use crate::instrument;
+use crate::{
+    allocator::{allocate, Allocator},
+    bytecode,
+    extra::Extra,
+    lua_core::*,
+    Data, Error, GcMode, Generational, HookContext, HookEvent, HookMask, Incremental,
+    InterruptHandle, Library, LibraryErr, RustAllocator, Stack, Variable,
+};
+#[cfg(feature = "deterministic")]
+use crate::{
+    deterministic::{self, Deterministic},
+    VirtualClock,
+};
+#[cfg(lua_version = "5.4")]
+use crate::{gc::gc_param, GlobalUsage};
+#[cfg(feature = "std")]
+use crate::{output, vfs, Output, Vfs};
+use alloc::{boxed::Box, ffi::CString, format, string::String, vec::Vec};
+#[cfg(feature = "std")]
+use core::time::Duration;
+use core::{
+    alloc::GlobalAlloc,
+    ffi::{c_char, c_void, CStr},
+};
 
 /// Abstraction for a Lua runtime.
 pub struct Lua {
-    lua: *const LuaState,
+    lua: State,
+    extra: Box<Extra>,
+    /// Must outlive the state, as it is used when closing it.
+    /// `None` for states from `from_raw`, which allocate with their own function.
+    allocator: Option<Box<Allocator>>,
+    /// Whether error messages from calls include a traceback.
+    traceback: bool,
 }
 impl Lua {
     /// Creates a new instance of Lua.
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
-        let lua = unsafe { luaL_newstate() };
+        Self::with_allocator(RustAllocator)
+    }
+
+    /// Creates a new instance of Lua that allocates all of its memory with the given allocator.
+    pub fn with_allocator<A: GlobalAlloc + 'static>(allocator: A) -> Self {
+        let mut allocator = Box::new(Allocator::new(allocator));
+        let ud = allocator.as_mut() as *mut Allocator as *mut c_void;
+        let lua = unsafe { lua_newstate(allocate, ud) };
+        if lua.is_null() {
+            panic!("cannot create a Lua state, as the allocator failed");
+        }
+        #[cfg(feature = "std")]
+        unsafe {
+            set_default_handlers(lua)
+        };
+
+        let mut extra = Box::<Extra>::default();
+        extra.attach(lua);
+
+        Self {
+            lua,
+            extra,
+            allocator: Some(allocator),
+            traceback: false,
+        }
+    }
+
+    /// Takes ownership of a state created with `luaL_newstate` or `lua_newstate`, closing it when dropped.
+    /// Its memory is not tracked, so `set_memory_limit` has no effect and `allocated` returns 0.
+    ///
+    /// # Safety
+    /// `state` must be a valid main state that nothing else closes, or uses while the `Lua` is in use.
+    /// Its extra space is taken over, and before Lua 5.3 the registry field `llua.extra`.
+    pub unsafe fn from_raw(state: State) -> Self {
+        let mut extra = Box::<Extra>::default();
+        extra.attach(state);
+
+        Self {
+            lua: state,
+            extra,
+            allocator: None,
+            traceback: false,
+        }
+    }
+
+    /// Returns the underlying state, for use with the C API. It stays owned by this `Lua`.
+    pub fn as_raw(&self) -> State {
+        self.lua
+    }
+
+    /// Returns the exact version of Lua that was built, such as `5.4.7`.
+    pub fn version() -> &'static str {
+        env!("LLUA_LUA_VERSION")
+    }
+
+    /// Sets whether error messages from calls include a traceback of the call stack.
+    pub fn set_traceback(&mut self, enabled: bool) {
+        self.traceback = enabled;
+    }
+
+    /// Returns a handle that can stop running calls from another thread.
+    pub fn interrupt_handle(&mut self) -> InterruptHandle {
+        self.extra.interrupt_handle(self.lua)
+    }
 
-        Self { lua }
+    /// Sets a hook that is called for the events in the mask, replacing any existing hook.
+    /// Returning an error from the hook aborts the running call with that error.
+    /// With the `std` feature, a panic in the hook aborts the call with a runtime error; without it, hooks must not panic.
+    pub fn set_hook<F>(&mut self, mask: HookMask, hook: F)
+    where
+        F: FnMut(&HookContext, HookEvent) -> Result<(), Error> + 'static,
+    {
+        self.extra.set_hook(self.lua, mask, Box::new(hook));
+    }
+
+    /// Removes the hook set with `set_hook`.
+    pub fn remove_hook(&mut self) {
+        self.extra.remove_hook(self.lua);
+    }
+
+    /// Sets whether reading an undeclared global, or assigning a new one outside a main chunk, raises an error.
+    /// Globals that exist or are assigned in a main chunk count as declared.
+    pub fn set_strict(&mut self, enabled: bool) {
+        self.extra.globals().set_strict(self.lua, enabled);
+    }
+
+    /// Declares globals that strict mode allows even while they are nil.
+    pub fn declare_globals(&mut self, names: &[&str]) {
+        for name in names {
+            self.extra.globals().declare(name);
+        }
+    }
+
+    /// Calls the watcher with the name and new value every time one of the given globals is written.
+    /// This replaces any existing watcher. Watched globals are stored outside the global table, so `rawget`, `pairs` and `next` don't see them until they are unwatched.
+    pub fn watch_globals<F>(&mut self, names: &[&str], watcher: F)
+    where
+        F: FnMut(&Variable) + 'static,
+    {
+        self.extra
+            .globals()
+            .watch(self.lua, names, Box::new(watcher));
+    }
+
+    /// Stops the watcher set with `watch_globals`.
+    pub fn unwatch_globals(&mut self) {
+        self.extra.globals().unwatch(self.lua);
+    }
+
+    /// Limits how many bytes the state may use. Going over the limit fails with `Error::Memory`.
+    /// Pass `None` to remove the limit.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        if let Some(allocator) = &mut self.allocator {
+            allocator.set_limit(limit);
+        }
+    }
+
+    /// Returns the number of bytes currently allocated by the state.
+    pub fn allocated(&self) -> usize {
+        self.allocator
+            .as_ref()
+            .map_or(0, |allocator| allocator.used())
+    }
+
+    /// Returns the largest number of bytes allocated by the state at once.
+    pub fn peak_allocated(&self) -> usize {
+        self.allocator
+            .as_ref()
+            .map_or(0, |allocator| allocator.peak())
+    }
+
+    /// Limits how many instructions a single call may run before failing with `Error::BudgetExceeded`.
+    /// Pass `None` to remove the limit.
+    pub fn set_instruction_limit(&mut self, limit: Option<u64>) {
+        self.extra.set_instruction_limit(self.lua, limit);
+    }
+
+    /// Limits how long a single call may run before failing with `Error::Timeout`.
+    /// Pass `None` to remove the limit.
+    #[cfg(feature = "std")]
+    pub fn set_time_limit(&mut self, limit: Option<Duration>) {
+        self.extra.set_time_limit(self.lua, limit);
     }
 
     /// Returns a handle to the stack.
@@ -22,18 +191,194 @@ impl Lua {
     }
 
     /// Activates the given libraries.
-    pub fn activate<'a>(&mut self, libaries: &'a [Library]) -> Result<&mut Self, LibraryErr> {
+    pub fn activate(&mut self, libaries: &[Library]) -> Result<&mut Self, LibraryErr> {
         for lib in libaries {
             lib.enable(self.lua)?;
         }
 
+        #[cfg(any(feature = "std", feature = "deterministic"))]
+        self.install_overrides(libaries);
+
         Ok(self)
     }
 
+    /// Activates only the given functions of a library, such as `["time", "clock"]` for `Library::Os`.
+    /// Returns an error without activating anything if a function does not exist.
+    pub fn activate_functions(
+        &mut self,
+        library: Library,
+        functions: &[&str],
+    ) -> Result<&mut Self, LibraryErr> {
+        library.enable_functions(self.lua, functions)?;
+
+        #[cfg(any(feature = "std", feature = "deterministic"))]
+        self.install_overrides(&[library]);
+
+        Ok(self)
+    }
+
+    /// Makes `require`, `loadfile`, `dofile` and the io library read files through the filesystem instead of the disk.
+    /// Files can't be written, and `io.popen`, `io.tmpfile`, `io.input`, `io.output` and `package.loadlib` are removed, along with the searchers for C libraries.
+    /// This applies to the libraries already activated and to those activated later.
+    #[cfg(feature = "std")]
+    pub fn set_vfs<V: Vfs + 'static>(&mut self, vfs: V) {
+        let installed = self.extra.vfs().is_some();
+        self.extra.set_vfs(Box::new(vfs));
+        if !installed {
+            vfs::install(self.lua, Library::all());
+        }
+    }
+
+    /// Sends the text of `print`, `io.write`, `io.stdout` and `io.stderr`, and from Lua 5.4 the messages of `warn`, to the output instead of the process's standard streams.
+    /// This applies to the libraries already activated and to those activated later.
+    /// `io.output()` returns the replacement of `io.stdout` until another file is set, and warnings are off until a script calls `warn('@on')`, as in Lua.
+    #[cfg(feature = "std")]
+    pub fn set_output<O: Output + 'static>(&mut self, output: O) {
+        let installed = self.extra.output().is_some();
+        self.extra.set_output(Box::new(output));
+        if !installed {
+            output::install(self.lua, Library::all());
+            #[cfg(lua_version = "5.4")]
+            output::set_warn(self.lua);
+        }
+    }
+
+    /// Makes scripts give the same results on every run and machine, for lockstep simulations and replays.
+    /// `math.random` draws from a generator seeded with `seed`, `os.time`, `os.clock` and `os.date` read the clock in UTC, and `pairs` visits keys in order.
+    /// `pairs` raises an error for tables with keys that are compared by address, such as tables and functions.
+    /// This applies to the libraries already activated and to those activated later.
+    #[cfg(feature = "deterministic")]
+    pub fn set_deterministic(&mut self, seed: u64, clock: VirtualClock) {
+        let installed = self.extra.deterministic().is_some();
+        self.extra
+            .set_deterministic(Deterministic::new(seed, clock));
+        if !installed {
+            deterministic::install(self.lua, Library::all());
+        }
+    }
+
+    /// Sets the clock that scripts see in deterministic mode, such as once per simulation step.
+    /// Does nothing when the mode is off.
+    #[cfg(feature = "deterministic")]
+    pub fn set_clock(&mut self, clock: VirtualClock) {
+        if let Some(deterministic) = self.extra.deterministic() {
+            deterministic.clock = clock;
+        }
+    }
+
+    /// Replaces functions of the libraries with the ones reading from the filesystem and writing to the output that are set, and those of the deterministic mode.
+    #[cfg(any(feature = "std", feature = "deterministic"))]
+    fn install_overrides(&mut self, libraries: &[Library]) {
+        #[cfg(feature = "std")]
+        if self.extra.vfs().is_some() {
+            vfs::install(self.lua, libraries);
+        }
+        #[cfg(feature = "std")]
+        if self.extra.output().is_some() {
+            output::install(self.lua, libraries);
+        }
+        #[cfg(feature = "deterministic")]
+        if self.extra.deterministic().is_some() {
+            deterministic::install(self.lua, libraries);
+        }
+    }
+
+    /// Performs a full garbage collection cycle.
+    pub fn gc_collect(&mut self) {
+        unsafe { lua_gc(self.lua, LUA_GCCOLLECT, 0) };
+    }
+
+    /// Performs a garbage collection step, as if `kb` kilobytes had been allocated.
+    /// Returns true if the step finished a cycle.
+    pub fn gc_step(&mut self, kb: Int) -> bool {
+        unsafe { lua_gc(self.lua, LUA_GCSTEP, kb) == 1 }
+    }
+
+    /// Stops the garbage collector until it is restarted.
+    pub fn gc_stop(&mut self) {
+        unsafe { lua_gc(self.lua, LUA_GCSTOP, 0) };
+    }
+
+    /// Restarts the garbage collector.
+    pub fn gc_restart(&mut self) {
+        unsafe { lua_gc(self.lua, LUA_GCRESTART, 0) };
+    }
+
+    /// Returns whether the garbage collector is running.
+    /// Lua 5.1 can't tell, so it always returns true there.
+    pub fn gc_is_running(&self) -> bool {
+        #[cfg(lua_version = "5.1")]
+        let running = true;
+        #[cfg(not(lua_version = "5.1"))]
+        let running = unsafe { lua_gc(self.lua, LUA_GCISRUNNING, 0) == 1 };
+
+        running
+    }
+
+    /// Switches the garbage collector to incremental mode with the given parameters.
+    /// Returns the previous mode.
+    /// Before Lua 5.4 the collector is always incremental and the step size can't be set.
+    pub fn gc_incremental(&mut self, params: Incremental) -> GcMode {
+        #[cfg(lua_version = "5.4")]
+        let mode = GcMode::from_lua(unsafe {
+            lua_gc(
+                self.lua,
+                LUA_GCINC,
+                gc_param(params.pause),
+                gc_param(params.step_multiplier),
+                gc_param(params.step_size),
+            )
+        });
+        #[cfg(not(lua_version = "5.4"))]
+        let mode = {
+            for (what, param) in [
+                (LUA_GCSETPAUSE, params.pause),
+                (LUA_GCSETSTEPMUL, params.step_multiplier),
+            ] {
+                if let Some(param) = param {
+                    unsafe { lua_gc(self.lua, what, param) };
+                }
+            }
+            GcMode::Incremental
+        };
+
+        mode
+    }
+
+    /// Switches the garbage collector to generational mode with the given parameters.
+    /// Returns the previous mode.
+    /// Before Lua 5.4 there is no generational mode, so this does nothing.
+    pub fn gc_generational(&mut self, params: Generational) -> GcMode {
+        #[cfg(lua_version = "5.4")]
+        let mode = GcMode::from_lua(unsafe {
+            lua_gc(
+                self.lua,
+                LUA_GCGEN,
+                gc_param(params.minor_multiplier),
+                gc_param(params.major_multiplier),
+            )
+        });
+        #[cfg(not(lua_version = "5.4"))]
+        let mode = {
+            let _ = params;
+            GcMode::Incremental
+        };
+
+        mode
+    }
+
+    /// Returns the number of bytes of memory in use, as counted by the garbage collector.
+    pub fn memory_used(&self) -> usize {
+        let kb = unsafe { lua_gc(self.lua, LUA_GCCOUNT, 0) } as usize;
+        let remainder = unsafe { lua_gc(self.lua, LUA_GCCOUNTB, 0) } as usize;
+
+        kb * 1024 + remainder
+    }
+
     /// Attempts to call the given Lua function.
-    pub fn call<'a, const RETURN_VALUES: usize, const ARGS: usize>(
+    pub fn call<const RETURN_VALUES: usize, const ARGS: usize>(
         &mut self,
-        function_name: &'a str,
+        function_name: &str,
         args: [Data; ARGS],
     ) -> Result<[Data; RETURN_VALUES], Error> {
         let function_name = CString::new(function_name).unwrap();
@@ -42,16 +387,24 @@ impl Lua {
 
     /// Attempts to call the given Lua function.
     /// May perform allocations for retrieving the data.
-    fn call_no_alloc<'a, const RETURN_VALUES: usize, const ARGS: usize>(
+    fn call_no_alloc<const RETURN_VALUES: usize, const ARGS: usize>(
         &mut self,
-        function_name: &'a CString,
+        function_name: &CString,
         args: [Data; ARGS],
     ) -> Result<[Data; RETURN_VALUES], Error> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!(
+            "lua.call",
+            function = %function_name.to_string_lossy(),
+            args = ARGS
+        )
+        .entered();
         const NIL: Data = Data::Nil;
         let mut data: [Data; RETURN_VALUES] = [NIL; RETURN_VALUES];
+        self.extra.begin_call(self.lua);
         unsafe {
             // Put function on stack
-            lua_getglobal(self.lua, map_cstr(&function_name));
+            lua_getglobal(self.lua, map_cstr(function_name));
 
             // Put args on stack
             for arg in args {
@@ -59,7 +412,8 @@ impl Lua {
             }
 
             // Call function
-            if lua_pcall(self.lua, ARGS as Int, RETURN_VALUES as Int, 0) == LUA_OK {
+            let result_code = self.pcall(ARGS as Int, RETURN_VALUES as Int);
+            if result_code == LUA_OK {
                 // Get data from stack
                 for return_value in data.iter_mut() {
                     *return_value = Data::pop(self.lua)?;
@@ -68,7 +422,7 @@ impl Lua {
                 // Remove function from stack
                 lua_pop(self.lua, lua_gettop(self.lua));
             } else {
-                return Err(self.get_error());
+                return Err(self.get_error(result_code));
             }
         }
 
@@ -78,25 +432,75 @@ impl Lua {
         Ok(data)
     }
 
-    /// Returns the error from Lua
-    fn get_error(&self) -> Error {
+    /// Calls the function below the arguments on top of the stack, adding the traceback handler if enabled or errors are recorded.
+    unsafe fn pcall(&mut self, nargs: Int, nresults: Int) -> ResultCode {
+        #[cfg(not(any(feature = "log", feature = "tracing")))]
+        let handler_fn = self.traceback.then_some(traceback as CFunction);
+        // Recorded errors always get a traceback, which stays out of the message unless enabled
+        #[cfg(any(feature = "log", feature = "tracing"))]
+        let handler_fn = Some(match self.traceback {
+            true => traceback as CFunction,
+            false => instrument::keep_traceback,
+        });
+
+        let mut handler = 0;
+        if let Some(handler_fn) = handler_fn {
+            handler = lua_gettop(self.lua) - nargs;
+            lua_pushcfunction(self.lua, handler_fn);
+            lua_insert(self.lua, handler);
+        }
+
+        self.extra.set_in_call(true);
+        let result_code = lua_pcall(self.lua, nargs, nresults, handler);
+        self.extra.set_in_call(false);
+        #[cfg(feature = "tracing")]
+        self.extra.close_spans(0);
+        #[cfg(any(feature = "log", feature = "tracing"))]
+        if result_code != LUA_OK {
+            instrument::record_error(
+                &self.error_message(),
+                self.extra.take_traceback().as_deref(),
+            );
+        }
+
+        result_code
+    }
+
+    /// Returns the error message on top of the stack.
+    fn error_message(&self) -> String {
         let chars = unsafe { lua_tostring(self.lua, lua_gettop(self.lua)) };
-        let cstr = unsafe { CStr::from_ptr(chars) };
-        let cstring = CString::from(cstr);
-        let error = cstring.to_str().unwrap().to_string();
+        match chars.is_null() {
+            true => "(error object is not a string)".to_string(),
+            false => {
+                let cstr = unsafe { CStr::from_ptr(chars) };
+                cstr.to_string_lossy().to_string()
+            }
+        }
+    }
 
-        Error::Runtime(error)
+    /// Returns the error from Lua, clearing the stack.
+    fn get_error(&mut self, result_code: ResultCode) -> Error {
+        let error = self.error_message();
+
+        // Clear the function and error so the state can be reused.
+        unsafe { lua_settop(self.lua, 0) };
+
+        match (self.extra.end_call(self.lua), result_code) {
+            (Some(aborted), _) => aborted,
+            (None, LUA_ERRMEM) => Error::Memory,
+            (None, _) => Error::Runtime(error),
+        }
     }
 
     /// Attempts to retrieve the given global.
-    pub fn get_global<'a>(&self, global_name: &'a str) -> Result<Data, Error> {
+    pub fn get_global(&self, global_name: &str) -> Result<Data, Error> {
         let global_name = CString::new(global_name).unwrap();
         self.get_global_no_alloc(&global_name)
     }
 
     /// Attempts to retrieve the given global.
     /// May perform an allocation for retrieving the data.
-    fn get_global_no_alloc<'a>(&self, global_name: &'a CString) -> Result<Data, Error> {
+    fn get_global_no_alloc(&self, global_name: &CString) -> Result<Data, Error> {
         unsafe {
             lua_getglobal(self.lua, map_cstr(global_name));
         }
@@ -106,17 +510,22 @@ impl Lua {
     }
 
     /// Interprets the given code.
-    pub fn interpret<'a>(&mut self, code: &'a str) -> Result<(), Error> {
+    pub fn interpret(&mut self, code: &str) -> Result<(), Error> {
         let code = CString::new(code).unwrap();
         self.interpret_noalloc(&code)
     }
 
     /// Interprets the given code.
     /// No allocations are performed.
-    fn interpret_noalloc<'a>(&mut self, code: &CString) -> Result<(), Error> {
+    fn interpret_noalloc(&mut self, code: &CString) -> Result<(), Error> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("lua.interpret", chunk = "(string)", args = 0).entered();
+
+        self.extra.begin_call(self.lua);
         unsafe {
             self.map_code(luaL_loadstring(self.lua, map_cstr(code)))?;
-            self.map_code(lua_pcall(self.lua, 0, 0, 0))?;
+            let result_code = self.pcall(0, 0);
+            self.map_code(result_code)?;
 
             // If executed successfully remove from the stack
             lua_pop(self.lua, lua_gettop(self.lua));
@@ -125,8 +534,140 @@ impl Lua {
         Ok(())
     }
 
+    /// Interprets the given code as a chunk with the given name, such as its file path.
+    /// The name is used in error messages and debug information.
+    pub fn interpret_named(&mut self, chunk_name: &str, code: &str) -> Result<(), Error> {
+        self.interpret_with_args(chunk_name, code, &[])
+    }
+
+    /// Interprets the given code as a named chunk, passing it the arguments as `...`.
+    pub fn interpret_with_args(
+        &mut self,
+        chunk_name: &str,
+        code: &str,
+        args: &[Data],
+    ) -> Result<(), Error> {
+        #[cfg(feature = "tracing")]
+        let _span =
+            tracing::info_span!("lua.interpret", chunk = chunk_name, args = args.len()).entered();
+
+        let chunk_name = CString::new(format!("@{chunk_name}")).unwrap();
+
+        self.extra.begin_call(self.lua);
+        unsafe {
+            self.map_code(luaL_loadbufferx(
+                self.lua,
+                code.as_ptr() as *const c_char,
+                code.len(),
+                map_cstr(&chunk_name),
+                core::ptr::null(),
+            ))?;
+            for arg in args {
+                arg.push(self.lua);
+            }
+            let result_code = self.pcall(args.len() as Int, 0);
+            self.map_code(result_code)?;
+
+            // If executed successfully remove from the stack
+            lua_pop(self.lua, lua_gettop(self.lua));
+        };
+
+        Ok(())
+    }
+
+    /// Compiles the given code as a chunk with the given name without running it.
+    pub fn check_syntax(&mut self, chunk_name: &str, code: &str) -> Result<(), Error> {
+        let chunk_name = CString::new(format!("@{chunk_name}")).unwrap();
+
+        unsafe {
+            self.map_code(luaL_loadbufferx(
+                self.lua,
+                code.as_ptr() as *const c_char,
+                code.len(),
+                map_cstr(&chunk_name),
+                core::ptr::null(),
+            ))?;
+            lua_pop(self.lua, 1);
+        }
+
+        Ok(())
+    }
+
+    /// Compiles the given code as a chunk with the given name without running it, returning its bytecode.
+    /// Stripping leaves out the debug information, such as line numbers and local names.
+    pub fn compile(&mut self, chunk_name: &str, code: &str, strip: bool) -> Result<Vec<u8>, Error> {
+        let chunk_name = CString::new(format!("@{chunk_name}")).unwrap();
+
+        unsafe {
+            self.map_code(luaL_loadbufferx(
+                self.lua,
+                code.as_ptr() as *const c_char,
+                code.len(),
+                map_cstr(&chunk_name),
+                core::ptr::null(),
+            ))?;
+            let chunk = bytecode::dump(self.lua, strip);
+            lua_pop(self.lua, 1);
+
+            // A loaded chunk is always a Lua function
+            Ok(chunk.unwrap_or_default())
+        }
+    }
+
+    /// Compiles the given code without running it and returns the globals it reads and writes.
+    /// Only available with Lua 5.4, whose bytecode it reads.
+    #[cfg(lua_version = "5.4")]
+    pub fn analyze_globals(&mut self, chunk_name: &str, code: &str) -> Result<GlobalUsage, Error> {
+        let chunk = self.compile(chunk_name, code, false)?;
+
+        // Chunks dumped by this state are always valid
+        Ok(GlobalUsage::from_bytecode(&chunk).unwrap_or_default())
+    }
+
+    /// Returns the string keys of the table found by following the given fields from the globals, sorted.
+    /// No metamethods are called. Returns `None` if there is no table at the path.
+    pub fn table_keys(&self, path: &[&str]) -> Option<Vec<String>> {
+        let top = unsafe { lua_gettop(self.lua) };
+        let keys = unsafe { self.table_keys_in(path) };
+        unsafe { lua_settop(self.lua, top) };
+
+        keys
+    }
+
+    /// Pushes the table at the path and returns its keys, leaving values on the stack for the caller to clean up.
+    unsafe fn table_keys_in(&self, path: &[&str]) -> Option<Vec<String>> {
+        lua_pushglobaltable(self.lua);
+        for field in path {
+            if lua_type(self.lua, -1) != LUA_TTABLE {
+                return None;
+            }
+
+            let field = CString::new(*field).ok()?;
+            lua_pushstring(self.lua, map_cstr(&field));
+            lua_rawget(self.lua, -2);
+        }
+
+        if lua_type(self.lua, -1) != LUA_TTABLE {
+            return None;
+        }
+
+        let mut keys = Vec::new();
+        lua_pushnil(self.lua);
+        while lua_next(self.lua, -2) != 0 {
+            // Only the value is popped, as converting other keys to strings would confuse `lua_next`
+            lua_pop(self.lua, 1);
+            if lua_type(self.lua, -1) == LUA_TSTRING {
+                let key = CStr::from_ptr(lua_tostring(self.lua, -1));
+                keys.push(key.to_string_lossy().into());
+            }
+        }
+        keys.sort();
+
+        Some(keys)
+    }
+
     /// Sets the given global variable.
-    pub fn set_global<'a>(&mut self, global_name: &'a str, data: Data) -> Result<(), Error> {
+    pub fn set_global(&mut self, global_name: &str, data: Data) -> Result<(), Error> {
         let global_name = CString::new(global_name).unwrap();
         self.set_global_noalloc(&global_name, data)
     }
@@ -141,10 +682,10 @@ impl Lua {
         Ok(())
     }
 
-    fn map_code(&self, result_code: ResultCode) -> Result<(), Error> {
+    fn map_code(&mut self, result_code: ResultCode) -> Result<(), Error> {
         match result_code {
             LUA_OK => Ok(()),
-            _ => Err(self.get_error()),
+            _ => Err(self.get_error(result_code)),
         }
     }
 }
@@ -154,16 +695,98 @@ impl Drop for Lua {
     }
 }
 
+/// Message handler that adds a traceback to error messages.
+unsafe extern "C" fn traceback(state: State) -> Int {
+    let message = lua_tostring(state, 1);
+    if !message.is_null() {
+        luaL_traceback(state, state, message, 1);
+    }
+
+    1
+}
+
+/// Sets the handlers `luaL_newstate` gives its states: one reporting errors raised outside of a protected call,
+/// and from Lua 5.4 one writing warnings to standard error once they are turned on with `@on`.
+/// Without std there is nowhere to report to, and Lua aborts on its own.
+#[cfg(feature = "std")]
+unsafe fn set_default_handlers(state: State) {
+    lua_atpanic(state, Some(unprotected_error));
+    #[cfg(lua_version = "5.4")]
+    lua_setwarnf(state, Some(warn_off), state as *mut c_void);
+}
+
+/// Called for an error raised outside of a protected call, writing it to standard error before aborting.
+/// A panic can't unwind out of this function, so the process is aborted directly.
+#[cfg(feature = "std")]
+unsafe extern "C" fn unprotected_error(state: State) -> Int {
+    let message = lua_tostring(state, -1);
+    let message = match message.is_null() {
+        true => "error object is not a string".into(),
+        false => CStr::from_ptr(message).to_string_lossy(),
+    };
+    std::eprintln!("PANIC: unprotected error in call to Lua API ({message})");
+    std::process::abort();
+}
+
+/// Handles the `@on` and `@off` control messages, returning whether the message was one.
+#[cfg(all(feature = "std", lua_version = "5.4"))]
+unsafe fn warn_control(ud: *mut c_void, message: *const core::ffi::c_char, tocont: Int) -> bool {
+    let message = CStr::from_ptr(message).to_bytes();
+    if tocont != 0 || !message.starts_with(b"@") {
+        return false;
+    }
+
+    match message {
+        b"@off" => lua_setwarnf(ud as State, Some(warn_off), ud),
+        b"@on" => lua_setwarnf(ud as State, Some(warn_on), ud),
+        _ => {}
+    }
+    true
+}
+
+#[cfg(all(feature = "std", lua_version = "5.4"))]
+unsafe extern "C" fn warn_off(ud: *mut c_void, message: *const core::ffi::c_char, tocont: Int) {
+    warn_control(ud, message, tocont);
+}
+
+#[cfg(all(feature = "std", lua_version = "5.4"))]
+unsafe extern "C" fn warn_on(ud: *mut c_void, message: *const core::ffi::c_char, tocont: Int) {
+    if !warn_control(ud, message, tocont) {
+        std::eprint!("Lua warning: ");
+        warn_continue(ud, message, tocont);
+    }
+}
+
+/// Writes a piece of a warning, waiting for the rest of the message when it continues.
+#[cfg(all(feature = "std", lua_version = "5.4"))]
+unsafe extern "C" fn warn_continue(
+    ud: *mut c_void,
+    message: *const core::ffi::c_char,
+    tocont: Int,
+) {
+    std::eprint!("{}", CStr::from_ptr(message).to_string_lossy());
+    if tocont != 0 {
+        lua_setwarnf(ud as State, Some(warn_continue), ud);
+    } else {
+        std::eprintln!();
+        lua_setwarnf(ud as State, Some(warn_on), ud);
+    }
+}
+
 /// Maps a cstring to a poitner
-fn map_cstr<'a>(c: &CString) -> *const u8 {
-    c.as_ptr() as *const u8
+fn map_cstr(c: &CString) -> *const c_char {
+    c.as_ptr()
 }
 
 #[cfg(test)]
+#[allow(
+    clippy::redundant_static_lifetimes,
+    clippy::let_and_return,
+    clippy::bool_assert_comparison
+)]
 mod tests {
-    use core::f64::INFINITY;
-
-    use crate::lua_core::{luaL_checknumber, Int, State};
+    use crate::lua_core::{luaL_checknumber, Int, LuaNum, State};
+    use crate::FunctionKind;
 
     use super::*;
 
@@ -215,7 +838,7 @@ end
 
         let result = m.call("div", [3.into(), 0.into()]).unwrap();
 
-        assert_eq!([Data::Number(INFINITY)], result);
+        assert_eq!([Data::Number(LuaNum::INFINITY)], result);
     }
 
     #[test]
@@ -312,6 +935,697 @@ end
         assert_eq!(true, result.is_ok());
     }
 
+    #[test]
+    fn activate_functions_installs_only_allowed() {
+        let mut m = Lua::new();
+        m.activate_functions(Library::Os, &["time", "clock"])
+            .unwrap();
+        m.interpret("has_time = os.time ~= nil; has_exit = os.exit ~= nil")
+            .unwrap();
+
+        assert_eq!(Ok(Data::Bool(true)), m.get_global("has_time"));
+        assert_eq!(Ok(Data::Bool(false)), m.get_global("has_exit"));
+    }
+
+    #[test]
+    fn activate_functions_basic_installs_only_allowed() {
+        let mut m = Lua::new();
+        m.activate_functions(Library::Basic, &["print", "type"])
+            .unwrap();
+        m.interpret("has_type = type ~= nil; has_load = load ~= nil")
+            .unwrap();
+
+        assert_eq!(Ok(Data::Bool(true)), m.get_global("has_type"));
+        assert_eq!(Ok(Data::Bool(false)), m.get_global("has_load"));
+    }
+
+    #[test]
+    fn activate_functions_unknown_function_returns_err() {
+        let mut m = Lua::new();
+        let result = m
+            .activate_functions(Library::Debug, &["traceback", "not_a_function"])
+            .map(|_| ());
+
+        let expected = Err(LibraryErr::UnknownFunction {
+            library: Library::Debug,
+            function: "not_a_function".into(),
+        });
+        assert_eq!(expected, result);
+
+        m.interpret("has_debug = debug ~= nil").unwrap();
+        assert_eq!(Ok(Data::Bool(false)), m.get_global("has_debug"));
+    }
+
+    #[test]
+    fn activate_makes_library_global() {
+        let mut m = Lua::new();
+        m.activate(&[Library::Math]).unwrap();
+        m.interpret("result = math.max(3, 7)").unwrap();
+
+        assert_eq!(Ok(Data::Number(7.0)), m.get_global("result"));
+    }
+
+    #[test]
+    fn call_infinite_loop_returns_budget_exceeded() {
+        let mut m = Lua::new();
+        m.activate(Library::all()).unwrap();
+        m.interpret("function spin() while true do end end")
+            .unwrap();
+        m.set_instruction_limit(Some(10_000));
+
+        let result: Result<[Data; 0], Error> = m.call("spin", []);
+        assert_eq!(Err(Error::BudgetExceeded), result);
+    }
+
+    #[test]
+    fn call_pcall_cannot_swallow_budget_exceeded() {
+        let mut m = Lua::new();
+        m.activate(Library::all()).unwrap();
+        m.interpret(
+            "function spin() while true do pcall(function() while true do end end) end end",
+        )
+        .unwrap();
+        m.set_instruction_limit(Some(10_000));
+
+        let result: Result<[Data; 0], Error> = m.call("spin", []);
+        assert_eq!(Err(Error::BudgetExceeded), result);
+    }
+
+    #[test]
+    fn call_after_budget_exceeded_still_works() {
+        let mut m = Lua::new();
+        m.activate(Library::all()).unwrap();
+        m.interpret("function spin() while true do end end function add(a, b) return a + b end")
+            .unwrap();
+        m.set_instruction_limit(Some(10_000));
+
+        let _: Result<[Data; 0], Error> = m.call("spin", []);
+        let result = m.call("add", [1.into(), 2.into()]);
+
+        assert_eq!(Ok([Data::Number(3.0)]), result);
+        assert_eq!(0, unsafe { lua_gettop(m.lua) });
+    }
+
+    #[test]
+    fn interpret_infinite_loop_returns_budget_exceeded() {
+        let mut m = Lua::new();
+        m.set_instruction_limit(Some(10_000));
+
+        let result = m.interpret("while true do end");
+        assert_eq!(Err(Error::BudgetExceeded), result);
+    }
+
+    #[test]
+    fn interpret_within_budget_returns_ok() {
+        let mut m = Lua::new();
+        m.set_instruction_limit(Some(10_000));
+
+        let result = m.interpret("x = 0 for i = 1, 10 do x = x + i end");
+        assert_eq!(Ok(()), result);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn interpret_infinite_loop_returns_timeout() {
+        let mut m = Lua::new();
+        m.set_time_limit(Some(Duration::from_millis(20)));
+
+        let result = m.interpret("while true do end");
+        assert_eq!(Err(Error::Timeout), result);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn get_global_after_deadline_runs_metamethod() {
+        let mut m = Lua::new();
+        m.activate(Library::all()).unwrap();
+        m.set_time_limit(Some(Duration::from_millis(1)));
+        m.interpret(
+            "setmetatable(_G, { __index = function() for i = 1, 10000 do end return 1 end })",
+        )
+        .unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+
+        // The deadline of the last call is over, but the metamethod runs outside of any call
+        assert_eq!(Ok(Data::Number(1.0)), m.get_global("missing"));
+    }
+
+    #[test]
+    fn interpret_syntax_error_clears_stack() {
+        let mut m = Lua::new();
+
+        let result = m.interpret("this is not lua");
+        assert!(matches!(result, Err(Error::Runtime(_))));
+        assert_eq!(0, unsafe { lua_gettop(m.lua) });
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn interrupt_handle_stops_running_call() {
+        let mut m = Lua::new();
+        m.activate(Library::all()).unwrap();
+        m.interpret("function spin() while true do end end function add(a, b) return a + b end")
+            .unwrap();
+
+        let handle = m.interrupt_handle();
+        let done = std::sync::Arc::new(core::sync::atomic::AtomicBool::new(false));
+        let thread = {
+            let done = done.clone();
+            std::thread::spawn(move || {
+                while !done.load(core::sync::atomic::Ordering::SeqCst) {
+                    handle.interrupt();
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            })
+        };
+
+        let result: Result<[Data; 0], Error> = m.call("spin", []);
+        done.store(true, core::sync::atomic::Ordering::SeqCst);
+        thread.join().unwrap();
+        assert_eq!(Err(Error::Interrupted), result);
+
+        let result = m.call("add", [1.into(), 2.into()]);
+        assert_eq!(Ok([Data::Number(3.0)]), result);
+    }
+
+    #[test]
+    fn interrupt_handle_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<InterruptHandle>();
+    }
+
+    #[test]
+    fn set_hook_line_reports_lines() {
+        use alloc::{rc::Rc, vec::Vec};
+        use core::cell::RefCell;
+
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let mut m = Lua::new();
+        {
+            let lines = lines.clone();
+            m.set_hook(
+                HookMask {
+                    line: true,
+                    ..Default::default()
+                },
+                move |ctx, event| {
+                    if let HookEvent::Line(line) = event {
+                        assert_eq!(Some(line), ctx.info().current_line);
+                        lines.borrow_mut().push(line);
+                    }
+                    Ok(())
+                },
+            );
+        }
+
+        m.interpret("local a = 1\nlocal b = 2\nlocal c = a + b")
+            .unwrap();
+
+        assert_eq!(vec![1, 2, 3], *lines.borrow());
+    }
+
+    #[test]
+    fn set_hook_call_reports_function_info() {
+        use alloc::{rc::Rc, vec::Vec};
+        use core::cell::RefCell;
+
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut m = Lua::new();
+        m.interpret("function greet()\n  return 1\nend").unwrap();
+        {
+            let calls = calls.clone();
+            m.set_hook(
+                HookMask {
+                    call: true,
+                    ..Default::default()
+                },
+                move |ctx, _| {
+                    let info = ctx.info();
+                    if info.what == FunctionKind::Lua {
+                        calls.borrow_mut().push(info);
+                    }
+                    Ok(())
+                },
+            );
+        }
+
+        // Names are only known when called from Lua
+        m.interpret("greet()").unwrap();
+
+        let calls = calls.borrow();
+        assert_eq!(1, calls.len());
+        assert_eq!(Some("greet".into()), calls[0].name);
+        assert_eq!("global", calls[0].name_what);
+        assert_eq!(FunctionKind::Lua, calls[0].what);
+        assert_eq!(Some(1), calls[0].line_defined);
+        assert_eq!(Some(3), calls[0].last_line_defined);
+    }
+
+    #[test]
+    fn set_hook_error_aborts_call() {
+        let mut m = Lua::new();
+        m.activate(Library::all()).unwrap();
+        m.interpret("function spin() while true do pcall(function() end) end end")
+            .unwrap();
+        m.set_hook(
+            HookMask {
+                count: 100,
+                ..Default::default()
+            },
+            |_, _| Err(Error::Runtime("stopped by hook".into())),
+        );
+
+        let result: Result<[Data; 0], Error> = m.call("spin", []);
+        assert_eq!(Err(Error::Runtime("stopped by hook".into())), result);
+
+        m.remove_hook();
+        m.interpret("x = 1").unwrap();
+    }
+
+    #[test]
+    fn set_hook_count_with_instruction_limit() {
+        use alloc::rc::Rc;
+        use core::cell::Cell;
+
+        let counts = Rc::new(Cell::new(0));
+        let mut m = Lua::new();
+        m.set_instruction_limit(Some(10_000));
+        {
+            let counts = counts.clone();
+            m.set_hook(
+                HookMask {
+                    count: 300,
+                    ..Default::default()
+                },
+                move |_, event| {
+                    assert_eq!(HookEvent::Count, event);
+                    counts.set(counts.get() + 1);
+                    Ok(())
+                },
+            );
+        }
+
+        let result = m.interpret("while true do end");
+
+        // The limits are checked every 1000 instructions, so the call stops after 11000
+        assert_eq!(Err(Error::BudgetExceeded), result);
+        assert_eq!(11_000 / 300, counts.get());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn set_hook_panic_returns_runtime_error() {
+        let mut m = Lua::new();
+        m.set_hook(
+            HookMask {
+                count: 1,
+                ..Default::default()
+            },
+            |_, _| panic!("stop"),
+        );
+
+        let result = m.interpret("while true do end");
+
+        assert_eq!(Err(Error::Runtime("hook panicked: stop".into())), result);
+    }
+
+    #[test]
+    fn hook_context_frame_returns_callers() {
+        use alloc::rc::Rc;
+        use core::cell::RefCell;
+
+        let frames = Rc::new(RefCell::new(None));
+        let mut m = Lua::new();
+        m.interpret("function inner() return 1 end\nfunction outer() return inner() + 1 end")
+            .unwrap();
+        {
+            let frames = frames.clone();
+            m.set_hook(
+                HookMask {
+                    call: true,
+                    ..Default::default()
+                },
+                move |ctx, _| {
+                    if ctx.info().name.as_deref() == Some("inner") {
+                        *frames.borrow_mut() = Some((ctx.frame(1), ctx.frame(50)));
+                    }
+                    Ok(())
+                },
+            );
+        }
+
+        m.interpret("outer()").unwrap();
+
+        let (caller, missing) = frames.borrow_mut().take().unwrap();
+        assert_eq!(Some("outer".into()), caller.unwrap().name);
+        assert_eq!(None, missing);
+    }
+
+    #[test]
+    fn interpret_named_uses_chunk_name() {
+        let mut m = Lua::new();
+        m.activate(Library::all()).unwrap();
+
+        let result = m.interpret_named("scripts/broken.lua", "\n\nerror('oops')");
+        let expected = Err(Error::Runtime("scripts/broken.lua:3: oops".into()));
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn interpret_with_args_passes_varargs() {
+        let mut m = Lua::new();
+
+        m.interpret_with_args("main.lua", "first, count = ..., select('#', ...)", &[])
+            .unwrap_err();
+        m.activate(&[Library::Basic]).unwrap();
+        m.interpret_with_args(
+            "main.lua",
+            "first, count = ..., select('#', ...)",
+            &["a".into(), 2.into()],
+        )
+        .unwrap();
+
+        assert_eq!(Ok(Data::String("a".into())), m.get_global("first"));
+        assert_eq!(Ok(Data::Number(2.0)), m.get_global("count"));
+    }
+
+    #[test]
+    fn set_traceback_adds_call_stack() {
+        let mut m = Lua::new();
+        m.activate(Library::all()).unwrap();
+        m.interpret_named("main.lua", "function fail()\n  error('oops')\nend")
+            .unwrap();
+
+        m.set_traceback(true);
+        let Err(Error::Runtime(message)) = m.call::<0, 0>("fail", []) else {
+            panic!("expected a runtime error");
+        };
+        assert!(message.starts_with("main.lua:2: oops\nstack traceback:"));
+        assert!(message.contains("main.lua:2: in function <main.lua:1>"));
+        assert_eq!(0, unsafe { lua_gettop(m.lua) });
+
+        m.set_traceback(false);
+        assert_eq!(
+            Err(Error::Runtime("main.lua:2: oops".into())),
+            m.call::<0, 0>("fail", [])
+        );
+    }
+
+    #[test]
+    fn version_matches_lua() {
+        let mut m = Lua::new();
+        m.activate(&[Library::Basic]).unwrap();
+        m.interpret("version = _VERSION").unwrap();
+
+        let Ok(Data::String(version)) = m.get_global("version") else {
+            panic!("expected _VERSION");
+        };
+        assert!(Lua::version().starts_with(&version["Lua ".len()..]));
+        assert_eq!(3, Lua::version().split('.').count());
+    }
+
+    #[test]
+    fn check_syntax_does_not_run() {
+        let mut m = Lua::new();
+
+        assert_eq!(Ok(()), m.check_syntax("stdin", "x = 1"));
+        assert_eq!(Ok(Data::Nil), m.get_global("x"));
+        assert!(matches!(
+            m.check_syntax("stdin", "x ="),
+            Err(Error::Runtime(message)) if message.starts_with("stdin:1: unexpected symbol near")
+        ));
+        assert_eq!(0, unsafe { lua_gettop(m.lua) });
+    }
+
+    #[test]
+    fn compile_returns_bytecode_without_running() {
+        let mut m = Lua::new();
+
+        let chunk = m.compile("main.lua", "x = 1\nlocal y = 2", false).unwrap();
+        let stripped = m.compile("main.lua", "x = 1\nlocal y = 2", true).unwrap();
+        assert!(chunk.starts_with(b"\x1bLua"));
+        #[cfg(any(lua_version = "5.3", lua_version = "5.4"))]
+        assert!(stripped.len() < chunk.len());
+        // Lua 5.1 and 5.2 always keep the debug information
+        #[cfg(any(lua_version = "5.1", lua_version = "5.2"))]
+        assert_eq!(stripped.len(), chunk.len());
+        assert_eq!(Ok(Data::Nil), m.get_global("x"));
+        assert!(m.compile("main.lua", "x =", false).is_err());
+        assert_eq!(0, unsafe { lua_gettop(m.lua) });
+    }
+
+    #[test]
+    fn table_keys_lists_string_keys() {
+        let mut m = Lua::new();
+        m.interpret("game = { player = { x = 1, y = 2, [1] = 3 } }")
+            .unwrap();
+
+        assert_eq!(Some(vec!["game".into()]), m.table_keys(&[]));
+        assert_eq!(
+            Some(vec!["x".into(), "y".into()]),
+            m.table_keys(&["game", "player"])
+        );
+        assert_eq!(None, m.table_keys(&["game", "player", "x"]));
+        assert_eq!(None, m.table_keys(&["missing", "field"]));
+        assert_eq!(0, unsafe { lua_gettop(m.lua) });
+    }
+
+    #[test]
+    fn hook_context_inspects_paused_frame() {
+        use crate::{Type, Variable};
+        use alloc::{rc::Rc, string::String, vec::Vec};
+        use core::cell::RefCell;
+
+        type Captured = (Vec<Variable>, Vec<Variable>, Result<String, Error>, Int);
+        let captured: Rc<RefCell<Option<Captured>>> = Rc::new(RefCell::new(None));
+        let mut m = Lua::new();
+        m.activate(Library::all()).unwrap();
+        {
+            let captured = captured.clone();
+            m.set_hook(
+                HookMask {
+                    line: true,
+                    ..Default::default()
+                },
+                move |ctx, event| {
+                    if event == HookEvent::Line(5) {
+                        *captured.borrow_mut() = Some((
+                            ctx.locals(0).unwrap(),
+                            ctx.upvalues(0).unwrap(),
+                            ctx.evaluate(0, "a * scale + #tostring(b)"),
+                            ctx.depth(),
+                        ));
+                    }
+                    Ok(())
+                },
+            );
+        }
+
+        let code = "local scale = 10
+function f(a)
+  local b = 'hi'
+  local c = { scale }
+  return a
+end
+f(4)";
+        m.interpret_named("inspect.lua", code).unwrap();
+
+        let (locals, upvalues, evaluated, depth) = captured.borrow_mut().take().unwrap();
+        let names: Vec<&str> = locals.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(vec!["a", "b", "c"], names);
+        assert_eq!("4", locals[0].value);
+        assert_eq!("\"hi\"", locals[1].value);
+        assert_eq!(Type::Table, locals[2].m_type);
+        assert_eq!("scale", upvalues[0].name);
+        assert_eq!(Ok("42".into()), evaluated);
+        assert_eq!(2, depth);
+    }
+
+    #[test]
+    fn hook_context_evaluate_error_returns_err() {
+        use alloc::{rc::Rc, string::String};
+        use core::cell::RefCell;
+
+        let captured: Rc<RefCell<Option<Result<String, Error>>>> = Rc::new(RefCell::new(None));
+        let mut m = Lua::new();
+        {
+            let captured = captured.clone();
+            m.set_hook(
+                HookMask {
+                    line: true,
+                    ..Default::default()
+                },
+                move |ctx, _| {
+                    *captured.borrow_mut() = Some(ctx.evaluate(0, "missing.field"));
+                    Ok(())
+                },
+            );
+        }
+
+        m.interpret("x = 1").unwrap();
+
+        let result = captured.borrow_mut().take().unwrap();
+        assert!(matches!(result, Err(Error::Runtime(_))));
+    }
+
+    #[test]
+    fn hook_context_evaluate_endless_loop_returns_err() {
+        use alloc::{rc::Rc, string::String};
+        use core::cell::RefCell;
+
+        let captured: Rc<RefCell<Option<Result<String, Error>>>> = Rc::new(RefCell::new(None));
+        let mut m = Lua::new();
+        m.activate(Library::all()).unwrap();
+        {
+            let captured = captured.clone();
+            m.set_hook(
+                HookMask {
+                    line: true,
+                    ..Default::default()
+                },
+                move |ctx, _| {
+                    let expression = "pcall(function() while true do end end)";
+                    *captured.borrow_mut() = Some(ctx.evaluate(0, expression));
+                    Ok(())
+                },
+            );
+        }
+
+        m.interpret("x = 1").unwrap();
+
+        let result = captured.borrow_mut().take().unwrap();
+        assert!(
+            matches!(result, Err(Error::Runtime(message)) if message.contains("evaluation budget exceeded"))
+        );
+    }
+
+    #[test]
+    fn gc_stop_and_restart() {
+        let mut m = Lua::new();
+        assert!(m.gc_is_running());
+
+        m.gc_stop();
+        #[cfg(not(lua_version = "5.1"))]
+        assert!(!m.gc_is_running());
+
+        m.gc_restart();
+        assert!(m.gc_is_running());
+    }
+
+    #[test]
+    fn gc_collect_frees_garbage() {
+        let mut m = Lua::new();
+        m.gc_stop();
+        m.interpret("t = {} for i = 1, 10000 do t[i] = {} end")
+            .unwrap();
+        let before = m.memory_used();
+
+        m.interpret("t = nil").unwrap();
+        m.gc_collect();
+
+        assert!(m.memory_used() < before);
+    }
+
+    #[test]
+    fn gc_step_finishes_cycle() {
+        let mut m = Lua::new();
+        m.interpret("t = {} for i = 1, 100 do t[i] = {} end")
+            .unwrap();
+
+        let finished = (0..10_000).any(|_| m.gc_step(0));
+        assert!(finished);
+    }
+
+    #[test]
+    #[cfg(lua_version = "5.4")]
+    fn gc_switching_modes_returns_previous_mode() {
+        let mut m = Lua::new();
+
+        let previous = m.gc_generational(Generational {
+            minor_multiplier: Some(20),
+            major_multiplier: None,
+        });
+        assert_eq!(GcMode::Incremental, previous);
+
+        let previous = m.gc_incremental(Incremental {
+            pause: Some(150),
+            ..Default::default()
+        });
+        assert_eq!(GcMode::Generational, previous);
+    }
+
+    #[test]
+    fn memory_used_matches_allocated() {
+        let m = Lua::new();
+
+        assert_eq!(m.allocated(), m.memory_used());
+    }
+
+    #[test]
+    fn interpret_over_memory_limit_returns_memory() {
+        let mut m = Lua::new();
+        m.activate(Library::all()).unwrap();
+        m.set_memory_limit(Some(m.allocated() + 1024 * 1024));
+
+        let result = m.interpret("t = {} for i = 1, 1024 * 1024 do t[i] = i end");
+        assert_eq!(Err(Error::Memory), result);
+
+        // The state is still usable afterwards
+        m.interpret("small = string.rep('x', 10)").unwrap();
+        assert_eq!(Ok(Data::String("xxxxxxxxxx".into())), m.get_global("small"));
+    }
+
+    #[test]
+    fn allocated_tracks_usage() {
+        let mut m = Lua::new();
+        let before = m.allocated();
+        assert!(before > 0);
+
+        m.interpret("t = {} for i = 1, 1000 do t[i] = {} end")
+            .unwrap();
+        assert!(m.allocated() > before);
+        assert!(m.peak_allocated() >= m.allocated());
+    }
+
+    #[test]
+    fn with_allocator_uses_given_allocator() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+        struct Counting;
+        unsafe impl GlobalAlloc for Counting {
+            unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+                ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+                RustAllocator.alloc(layout)
+            }
+
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+                RustAllocator.dealloc(ptr, layout)
+            }
+        }
+
+        let mut m = Lua::with_allocator(Counting);
+        m.interpret("x = 1").unwrap();
+
+        assert!(ALLOCATIONS.load(Ordering::SeqCst) > 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "the allocator failed")]
+    fn with_allocator_failing_allocator_panics() {
+        struct Failing;
+        unsafe impl GlobalAlloc for Failing {
+            unsafe fn alloc(&self, _: core::alloc::Layout) -> *mut u8 {
+                core::ptr::null_mut()
+            }
+
+            unsafe fn dealloc(&self, _: *mut u8, _: core::alloc::Layout) {}
+        }
+
+        Lua::with_allocator(Failing);
+    }
+
     #[test]
     fn interpret_noalloc_print() {
         let code = CString::new("print('Hello, World')").unwrap();
@@ -330,4 +1644,27 @@ end
 
         assert_eq!(Ok(()), result);
     }
+
+    #[test]
+    fn from_raw_adopts_state() {
+        let state = unsafe { luaL_newstate() };
+        let mut m = unsafe { Lua::from_raw(state) };
+        m.interpret("x = 6 * 7").unwrap();
+
+        assert_eq!(state, m.as_raw());
+        assert_eq!(Ok(Data::Number(42.0)), m.get_global("x"));
+        assert_eq!(0, m.allocated());
+    }
+
+    #[test]
+    fn as_raw_shares_globals() {
+        let mut m = Lua::new();
+        unsafe {
+            lua_pushnumber(m.as_raw(), 7.0);
+            lua_setglobal(m.as_raw(), c"seven".as_ptr());
+        }
+        m.interpret("doubled = seven * 2").unwrap();
+
+        assert_eq!(Ok(Data::Number(14.0)), m.get_global("doubled"));
+    }
 }
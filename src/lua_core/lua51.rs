@@ -0,0 +1,299 @@
+// https://www.lua.org/manual/5.1/manual.html
+// Lua 5.1 functions, and wrappers giving the functions it lacks the signatures of Lua 5.4.
+
+#![allow(non_snake_case)]
+
+extern crate alloc;
+
+use super::*;
+use alloc::{ffi::CString, format, string::String};
+use core::ffi::{c_void, CStr};
+
+/// Registry field the opened library is passed back through.
+const OPENED: &CStr = c"llua.opened";
+
+/// Stack levels shown before and after the ones a traceback leaves out.
+const TRACEBACK_LEVELS: (Int, Int) = (12, 10);
+
+extern "C" {
+    pub fn lua_call(state: State, nargs: Int, nresults: Int);
+    pub fn lua_pcall(state: State, nargs: Int, nresults: Int, msgh: Int) -> ResultCode;
+    pub fn lua_pushlstring(state: State, s: *const c_char, len: SizeT);
+    pub fn lua_pushstring(state: State, s: *const c_char);
+    pub fn lua_setfenv(state: State, index: Int) -> Int;
+    pub fn lua_tointeger(state: State, index: Int) -> LuaInt;
+    pub fn lua_tonumber(state: State, index: Int) -> LuaNum;
+    fn luaL_callmeta(state: State, obj: Int, e: *const c_char) -> Int;
+    fn lua_cpcall(state: State, function: CFunction, ud: *mut c_void) -> Int;
+    fn luaL_loadbuffer(
+        state: State,
+        buff: *const c_char,
+        size: usize,
+        name: *const c_char,
+    ) -> ResultCode;
+    #[link_name = "luaopen_base"]
+    fn open_base(state: State) -> Int;
+    #[link_name = "luaopen_debug"]
+    fn open_debug(state: State) -> Int;
+    #[link_name = "luaopen_io"]
+    fn open_io(state: State) -> Int;
+    #[link_name = "luaopen_math"]
+    fn open_math(state: State) -> Int;
+    #[link_name = "luaopen_os"]
+    fn open_os(state: State) -> Int;
+    #[link_name = "luaopen_package"]
+    fn open_package(state: State) -> Int;
+    #[link_name = "luaopen_string"]
+    fn open_string(state: State) -> Int;
+    #[link_name = "luaopen_table"]
+    fn open_table(state: State) -> Int;
+}
+
+pub unsafe fn lua_getglobal(state: State, name: *const c_char) -> Int {
+    lua_getfield(state, LUA_GLOBALSINDEX, name)
+}
+
+pub unsafe fn lua_setglobal(state: State, name: *const c_char) {
+    lua_setfield(state, LUA_GLOBALSINDEX, name)
+}
+
+pub unsafe fn lua_pushglobaltable(state: State) {
+    lua_pushvalue(state, LUA_GLOBALSINDEX)
+}
+
+/// Lua 5.1 has no loading modes, so binary chunks are refused here when the mode doesn't allow them.
+pub unsafe fn luaL_loadbufferx(
+    state: State,
+    buff: *const c_char,
+    size: usize,
+    name: *const c_char,
+    mode: *const c_char,
+) -> ResultCode {
+    const SIGNATURE: &[u8] = b"\x1bLua";
+    let binary =
+        size >= SIGNATURE.len() && core::slice::from_raw_parts(buff as *const u8, 4) == SIGNATURE;
+    if !mode.is_null() {
+        let mode = CStr::from_ptr(mode).to_bytes();
+        let allowed = if binary { b'b' } else { b't' };
+        if !mode.contains(&allowed) {
+            let message = format!(
+                "attempt to load a {} chunk (mode is '{}')\0",
+                if binary { "binary" } else { "text" },
+                String::from_utf8_lossy(mode)
+            );
+            lua_pushstring(state, message.as_ptr() as *const c_char);
+            return LUA_ERRSYNTAX;
+        }
+    }
+
+    luaL_loadbuffer(state, buff, size, name)
+}
+
+/// Pushes the value at the index converted to a string, the way `tostring` does, and returns it.
+pub unsafe fn luaL_tolstring(state: State, index: Int, len: *mut SizeT) -> *const c_char {
+    if luaL_callmeta(state, index, c"__tostring".as_ptr()) == 0 {
+        match lua_type(state, index) {
+            LUA_TNUMBER | LUA_TSTRING => lua_pushvalue(state, index),
+            LUA_TBOOLEAN if lua_toboolean(state, index) != 0 => {
+                lua_pushstring(state, c"true".as_ptr())
+            }
+            LUA_TBOOLEAN => lua_pushstring(state, c"false".as_ptr()),
+            LUA_TNIL => lua_pushstring(state, c"nil".as_ptr()),
+            tp => {
+                let description = format!(
+                    "{}: {:p}\0",
+                    CStr::from_ptr(lua_typename(state, tp)).to_string_lossy(),
+                    lua_topointer(state, index)
+                );
+                lua_pushstring(state, description.as_ptr() as *const c_char);
+            }
+        }
+    }
+
+    lua_tolstring(state, -1, len)
+}
+
+/// Pushes the message followed by the call stack of `other`, in the format of `debug.traceback`.
+pub unsafe fn luaL_traceback(state: State, other: State, message: *const c_char, level: Int) {
+    let text = |s: *const c_char| CStr::from_ptr(s).to_string_lossy();
+
+    let mut traceback = String::new();
+    if !message.is_null() {
+        traceback.push_str(&text(message));
+        traceback.push('\n');
+    }
+    traceback.push_str("stack traceback:");
+
+    let mut ar = LuaDebug::new();
+    let mut level = level;
+    let mut first_part = true;
+    while lua_getstack(other, level, &mut ar) != 0 {
+        level += 1;
+        if level > TRACEBACK_LEVELS.0 && first_part {
+            first_part = false;
+            if lua_getstack(other, level + TRACEBACK_LEVELS.1, &mut ar) == 0 {
+                // Few enough levels are left to show them all
+                level -= 1;
+            } else {
+                traceback.push_str("\n\t...");
+                while lua_getstack(other, level + TRACEBACK_LEVELS.1, &mut ar) != 0 {
+                    level += 1;
+                }
+            }
+            continue;
+        }
+
+        lua_getinfo(other, c"Snl".as_ptr(), &mut ar);
+        let source = text(ar.short_src.as_ptr());
+        traceback.push_str(&format!("\n\t{source}:"));
+        if ar.currentline > 0 {
+            traceback.push_str(&format!("{}:", ar.currentline));
+        }
+        if *ar.namewhat != 0 {
+            traceback.push_str(&format!(" in function '{}'", text(ar.name)));
+        } else {
+            match *ar.what as u8 {
+                b'm' => traceback.push_str(" in main chunk"),
+                b'C' | b't' => traceback.push_str(" ?"),
+                _ => traceback.push_str(&format!(" in function <{source}:{}>", ar.linedefined)),
+            }
+        }
+    }
+
+    let traceback = CString::new(traceback.replace('\0', "")).unwrap();
+    lua_pushstring(state, traceback.as_ptr());
+}
+
+/// A Lua 5.1 library opener and how to run it.
+struct Opener {
+    open: CFunction,
+    /// Position of the library's table among the values the opener returns, counting from 1.
+    result: Int,
+    /// Whether the opener runs with a temporary global table, so the globals it sets are discarded.
+    isolated: bool,
+    /// Names the opener registers in `package.loaded`.
+    loaded: &'static [&'static CStr],
+}
+
+/// Runs the opener as a C function, as some libraries replace the environment of the function calling them.
+/// Like the openers of later versions, it leaves only the library's table on the stack and returns 1.
+unsafe fn open_library(state: State, opener: Opener) -> Int {
+    lua_pushglobaltable(state);
+    let code = lua_cpcall(state, call_opener, &opener as *const Opener as *mut c_void);
+    if code != LUA_OK {
+        lua_pop(state, 1);
+    }
+    lua_replace(state, LUA_GLOBALSINDEX);
+
+    // Later versions leave registering the library to `require`
+    lua_getfield(state, LUA_REGISTRYINDEX, c"_LOADED".as_ptr());
+    if lua_type(state, -1) == LUA_TTABLE {
+        for name in opener.loaded {
+            lua_pushnil(state);
+            lua_setfield(state, -2, name.as_ptr());
+        }
+    }
+    lua_pop(state, 1);
+
+    if code != LUA_OK {
+        return 0;
+    }
+
+    lua_getfield(state, LUA_REGISTRYINDEX, OPENED.as_ptr());
+    lua_pushnil(state);
+    lua_setfield(state, LUA_REGISTRYINDEX, OPENED.as_ptr());
+
+    1
+}
+
+/// Calls the opener passed as light userdata, keeping its table in the registry.
+unsafe extern "C" fn call_opener(state: State) -> Int {
+    let opener = &*(lua_touserdata(state, 1) as *const Opener);
+    if opener.isolated {
+        lua_newtable(state);
+        lua_replace(state, LUA_GLOBALSINDEX);
+    }
+
+    // Openers may push other values before the ones they return
+    let returned = (opener.open)(state);
+    lua_pushvalue(state, lua_gettop(state) - returned + opener.result);
+    lua_setfield(state, LUA_REGISTRYINDEX, OPENED.as_ptr());
+
+    0
+}
+
+/// Opens a library other than the basic one, which registers itself as a global and in `package.loaded`.
+unsafe fn open_isolated(state: State, open: CFunction, name: &'static [&'static CStr]) -> Int {
+    open_library(
+        state,
+        Opener {
+            open,
+            result: 1,
+            isolated: true,
+            loaded: name,
+        },
+    )
+}
+
+/// Installs the basic library into the global table.
+pub unsafe fn luaopen_base(state: State) -> Int {
+    open_library(
+        state,
+        Opener {
+            open: open_base,
+            result: 1,
+            isolated: false,
+            loaded: &[c"_G"],
+        },
+    )
+}
+
+/// The coroutine library is part of the basic library in Lua 5.1, so it is taken from a temporary copy.
+pub unsafe fn luaopen_coroutine(state: State) -> Int {
+    open_library(
+        state,
+        Opener {
+            open: open_base,
+            result: 2,
+            isolated: true,
+            loaded: &[c"_G", c"coroutine"],
+        },
+    )
+}
+
+pub unsafe fn luaopen_debug(state: State) -> Int {
+    open_isolated(state, open_debug, &[c"debug"])
+}
+
+pub unsafe fn luaopen_io(state: State) -> Int {
+    open_isolated(state, open_io, &[c"io"])
+}
+
+pub unsafe fn luaopen_math(state: State) -> Int {
+    open_isolated(state, open_math, &[c"math"])
+}
+
+pub unsafe fn luaopen_os(state: State) -> Int {
+    open_isolated(state, open_os, &[c"os"])
+}
+
+/// Like later versions, the package library installs `require` into the global table.
+pub unsafe fn luaopen_package(state: State) -> Int {
+    open_library(
+        state,
+        Opener {
+            open: open_package,
+            result: 1,
+            isolated: false,
+            loaded: &[c"_G", c"package"],
+        },
+    )
+}
+
+pub unsafe fn luaopen_string(state: State) -> Int {
+    open_isolated(state, open_string, &[c"string"])
+}
+
+pub unsafe fn luaopen_table(state: State) -> Int {
+    open_isolated(state, open_table, &[c"table"])
+}
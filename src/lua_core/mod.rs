@@ -1,28 +1,147 @@
 // https://www.lua.org/manual/5.4/manual.html
+// Declarations that differ between versions follow the one selected by the `lua5x` features.
+// Where an older version lacks a function, or declares it differently, a wrapper gives it the Lua 5.4 signature.
 
 use core::ffi::c_char;
 
-/// The state of the Lua interpreter.
-#[allow(improper_ctypes)]
-pub struct LuaState;
+#[cfg(lua_version = "5.1")]
+mod lua51;
+#[cfg(lua_version = "5.1")]
+pub use lua51::*;
+
+/// The state of the Lua interpreter, only used behind pointers.
+#[repr(C)]
+pub struct LuaState {
+    _private: [u8; 0],
+}
 
 pub type Int = i32;
 pub type ResultCode = Int;
 
+/// Maximum size of the description of a function's source.
+pub const LUA_IDSIZE: usize = 60;
+
+/// Activation record describing a function, used by hooks and the debug interface.
+#[cfg(lua_version = "5.1")]
+#[repr(C)]
+pub struct LuaDebug {
+    pub event: Int,
+    pub name: *const c_char,
+    pub namewhat: *const c_char,
+    pub what: *const c_char,
+    pub source: *const c_char,
+    pub currentline: Int,
+    pub nups: Int,
+    pub linedefined: Int,
+    pub lastlinedefined: Int,
+    pub short_src: [c_char; LUA_IDSIZE],
+    i_ci: Int,
+}
+
+/// Activation record describing a function, used by hooks and the debug interface.
+#[cfg(any(lua_version = "5.2", lua_version = "5.3"))]
+#[repr(C)]
+pub struct LuaDebug {
+    pub event: Int,
+    pub name: *const c_char,
+    pub namewhat: *const c_char,
+    pub what: *const c_char,
+    pub source: *const c_char,
+    pub currentline: Int,
+    pub linedefined: Int,
+    pub lastlinedefined: Int,
+    pub nups: u8,
+    pub nparams: u8,
+    pub isvararg: c_char,
+    pub istailcall: c_char,
+    pub short_src: [c_char; LUA_IDSIZE],
+    i_ci: *const core::ffi::c_void,
+}
+
+/// Activation record describing a function, used by hooks and the debug interface.
+#[cfg(lua_version = "5.4")]
+#[repr(C)]
+pub struct LuaDebug {
+    pub event: Int,
+    pub name: *const c_char,
+    pub namewhat: *const c_char,
+    pub what: *const c_char,
+    pub source: *const c_char,
+    pub srclen: usize,
+    pub currentline: Int,
+    pub linedefined: Int,
+    pub lastlinedefined: Int,
+    pub nups: u8,
+    pub nparams: u8,
+    pub isvararg: c_char,
+    pub istailcall: c_char,
+    pub ftransfer: u16,
+    pub ntransfer: u16,
+    pub short_src: [c_char; LUA_IDSIZE],
+    i_ci: *const core::ffi::c_void,
+}
+impl LuaDebug {
+    /// Creates an empty activation record to be filled in by Lua.
+    pub fn new() -> Self {
+        unsafe { core::mem::zeroed() }
+    }
+}
+impl Default for LuaDebug {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Representation of a Lua memory allocation function.
+pub type Alloc = unsafe extern "C" fn(
+    ud: *mut core::ffi::c_void,
+    ptr: *mut core::ffi::c_void,
+    old_size: usize,
+    new_size: usize,
+) -> *mut core::ffi::c_void;
+/// Representation of a Lua hook.
+pub type Hook = unsafe extern "C" fn(State, *mut LuaDebug);
 /// Representation of a Lua function.
 pub type LuaFn = fn(State) -> Int;
+/// Representation of a function called from Lua through the C calling convention.
+pub type CFunction = unsafe extern "C" fn(State) -> Int;
 /// Representation of a Lua integer.
+#[cfg(lua_integer = "i32")]
+pub type LuaInt = i32;
+/// Representation of a Lua integer.
+#[cfg(lua_integer = "i64")]
 pub type LuaInt = i64;
 /// Representation of a Lua number.
+#[cfg(lua_number = "f32")]
+pub type LuaNum = f32;
+/// Representation of a Lua number.
+#[cfg(lua_number = "f64")]
 pub type LuaNum = f64;
 /// Handle to the state of the Lua interpreter.
-pub type State = *const LuaState;
+pub type State = *mut LuaState;
+/// Representation of a function receiving pieces of a dumped chunk.
+pub type Writer = unsafe extern "C" fn(
+    state: State,
+    p: *const core::ffi::c_void,
+    size: usize,
+    ud: *mut core::ffi::c_void,
+) -> Int;
+/// Representation of a warning function.
+#[cfg(lua_version = "5.4")]
+pub type WarnFunction =
+    unsafe extern "C" fn(ud: *mut core::ffi::c_void, message: *const c_char, tocont: Int);
+/// Representation of a continuation function.
+#[cfg(lua_version = "5.2")]
+pub type KFunction = unsafe extern "C" fn(State) -> Int;
+/// Representation of a continuation function.
+#[cfg(any(lua_version = "5.3", lua_version = "5.4"))]
+pub type KFunction = unsafe extern "C" fn(State, Int, isize) -> Int;
 
-#[cfg(windows)]
-pub type SizeT = i64;
+/// Representation of a C `size_t`.
+pub type SizeT = usize;
 
-#[cfg(not(windows))]
-pub type SizeT = Int;
+/// Number of results meaning all of them.
+pub const LUA_MULTRET: Int = -1;
 
 pub const LUA_TNONE: i32 = -1;
 pub const LUA_TNIL: i32 = 0;
@@ -35,42 +154,121 @@ pub const LUA_TFUNCTION: i32 = 6;
 pub const LUA_TUSERDATA: i32 = 7;
 pub const LUA_TTHREAD: i32 = 8;
 
+/// Pseudo-index of the registry table.
+#[cfg(lua_version = "5.1")]
+pub const LUA_REGISTRYINDEX: Int = -10_000;
+/// Pseudo-index of the registry table.
+#[cfg(not(lua_version = "5.1"))]
+pub const LUA_REGISTRYINDEX: Int = -1_000_000 - 1000;
+/// Pseudo-index of the global table.
+#[cfg(lua_version = "5.1")]
+pub const LUA_GLOBALSINDEX: Int = -10_002;
+/// Index of the global table within the registry.
+#[cfg(not(lua_version = "5.1"))]
+pub const LUA_RIDX_GLOBALS: LuaInt = 2;
+
+/// Size of the raw memory area associated with a Lua state.
+#[cfg(any(lua_version = "5.3", lua_version = "5.4"))]
+pub const LUA_EXTRASPACE: usize = core::mem::size_of::<*const ()>();
+
+pub const LUA_HOOKCALL: Int = 0;
+pub const LUA_HOOKRET: Int = 1;
+pub const LUA_HOOKLINE: Int = 2;
+pub const LUA_HOOKCOUNT: Int = 3;
+#[cfg(lua_version = "5.1")]
+pub const LUA_HOOKTAILRET: Int = 4;
+#[cfg(not(lua_version = "5.1"))]
+pub const LUA_HOOKTAILCALL: Int = 4;
+
+pub const LUA_MASKCALL: Int = 1 << 0;
+pub const LUA_MASKRET: Int = 1 << 1;
+pub const LUA_MASKLINE: Int = 1 << 2;
+pub const LUA_MASKCOUNT: Int = 1 << 3;
+
+pub const LUA_GCSTOP: Int = 0;
+pub const LUA_GCRESTART: Int = 1;
+pub const LUA_GCCOLLECT: Int = 2;
+pub const LUA_GCCOUNT: Int = 3;
+pub const LUA_GCCOUNTB: Int = 4;
+pub const LUA_GCSTEP: Int = 5;
+pub const LUA_GCSETPAUSE: Int = 6;
+pub const LUA_GCSETSTEPMUL: Int = 7;
+#[cfg(not(lua_version = "5.1"))]
+pub const LUA_GCISRUNNING: Int = 9;
+#[cfg(lua_version = "5.4")]
+pub const LUA_GCGEN: Int = 10;
+#[cfg(lua_version = "5.4")]
+pub const LUA_GCINC: Int = 11;
+
 pub const LUA_OK: Int = 0;
 pub const LUA_YIELD: Int = 1;
 pub const LUA_ERRRUN: Int = 2;
 pub const LUA_ERRSYNTAX: Int = 3;
 pub const LUA_ERRMEM: Int = 4;
+#[cfg(any(lua_version = "5.1", lua_version = "5.4"))]
 pub const LUA_ERRERR: Int = 5;
+#[cfg(any(lua_version = "5.2", lua_version = "5.3"))]
+pub const LUA_ERRERR: Int = 6;
 
 extern "C" {
+    pub fn lua_atpanic(state: State, panicf: Option<CFunction>) -> Option<CFunction>;
+    pub fn lua_checkstack(state: State, n: Int) -> Int;
     pub fn lua_close(state: State);
-    pub fn lua_getglobal(state: State, name: *const u8) -> Int;
+    pub fn lua_createtable(state: State, narr: Int, nrec: Int);
+    pub fn lua_error(state: State) -> Int;
+    pub fn lua_gethookcount(state: State) -> Int;
+    pub fn lua_getinfo(state: State, what: *const c_char, ar: *mut LuaDebug) -> Int;
+    pub fn lua_getmetatable(state: State, index: Int) -> Int;
+    pub fn lua_getlocal(state: State, ar: *const LuaDebug, n: Int) -> *const c_char;
+    pub fn lua_getstack(state: State, level: Int, ar: *mut LuaDebug) -> Int;
     pub fn lua_gettop(state: State) -> Int;
-    pub fn lua_isinteger(state: State, index: Int) -> Int;
+    pub fn lua_getupvalue(state: State, funcindex: Int, n: Int) -> *const c_char;
     pub fn lua_isstring(state: State, index: Int) -> Int;
-    pub fn lua_pcallk(
-        state: State,
-        nargs: Int,
-        nresults: Int,
-        msgh: Int,
-        ctx: Int,
-        function: Int,
-    ) -> ResultCode;
-    pub fn lua_pushboolean(state: State, boolean: LuaInt);
-    pub fn lua_pushcclosure(state: State, f: LuaFn, position: Int);
+    pub fn lua_newstate(f: Alloc, ud: *mut core::ffi::c_void) -> State;
+    pub fn lua_newthread(state: State) -> State;
+    pub fn lua_next(state: State, index: Int) -> Int;
+    pub fn lua_pushboolean(state: State, boolean: Int);
+    pub fn lua_pushcclosure(state: State, f: CFunction, position: Int);
+    pub fn lua_pushinteger(state: State, n: LuaInt);
+    pub fn lua_pushlightuserdata(state: State, p: *mut core::ffi::c_void);
     pub fn lua_pushnil(state: State);
     pub fn lua_pushnumber(state: State, n: LuaNum);
-    pub fn lua_pushstring(state: State, s: *const u8) -> *const u8;
-    pub fn lua_setglobal(state: State, name: *const u8);
+    pub fn lua_pushvalue(state: State, index: Int);
+    pub fn lua_rawequal(state: State, index1: Int, index2: Int) -> Int;
+    pub fn lua_rawset(state: State, index: Int);
+    pub fn lua_setfield(state: State, index: Int, k: *const c_char);
+    pub fn lua_setmetatable(state: State, index: Int) -> Int;
+    pub fn lua_setupvalue(state: State, funcindex: Int, n: Int) -> *const c_char;
+    pub fn lua_sethook(state: State, f: Option<Hook>, mask: Int, count: Int);
     pub fn lua_settop(state: State, index: Int);
     pub fn lua_toboolean(state: State, idx: Int) -> Int;
-    pub fn lua_tonumberx(state: State, index: Int, isnum: *const Int) -> LuaNum;
-    pub fn lua_tolstring(state: State, index: Int, len: *const SizeT) -> *const c_char;
+    pub fn lua_tolstring(state: State, index: Int, len: *mut SizeT) -> *const c_char;
+    pub fn lua_topointer(state: State, index: Int) -> *const core::ffi::c_void;
+    pub fn lua_touserdata(state: State, index: Int) -> *mut core::ffi::c_void;
     pub fn lua_type(state: State, index: Int) -> Int;
+    pub fn lua_typename(state: State, tp: Int) -> *const c_char;
+    pub fn lua_xmove(from: State, to: State, n: Int);
     pub fn luaL_checknumber(state: State, stack: Int) -> LuaNum;
-    pub fn luaL_dofile();
-    pub fn luaL_loadstring(state: State, string: *const u8) -> ResultCode;
+    pub fn luaL_loadstring(state: State, string: *const c_char) -> ResultCode;
     pub fn luaL_newstate() -> State;
+}
+
+#[cfg(not(lua_version = "5.1"))]
+extern "C" {
+    pub fn lua_copy(state: State, from_index: Int, to_index: Int);
+    pub fn lua_pushlstring(state: State, s: *const c_char, len: SizeT) -> *const c_char;
+    pub fn lua_pushstring(state: State, s: *const c_char) -> *const c_char;
+    pub fn lua_tointegerx(state: State, index: Int, isnum: *mut Int) -> LuaInt;
+    pub fn lua_tonumberx(state: State, index: Int, isnum: *mut Int) -> LuaNum;
+    pub fn luaL_loadbufferx(
+        state: State,
+        buff: *const c_char,
+        size: usize,
+        name: *const c_char,
+        mode: *const c_char,
+    ) -> ResultCode;
+    pub fn luaL_tolstring(state: State, index: Int, len: *mut SizeT) -> *const c_char;
+    pub fn luaL_traceback(state: State, other: State, message: *const c_char, level: Int);
     pub fn luaopen_base(state: State) -> ResultCode;
     pub fn luaopen_coroutine(state: State) -> ResultCode;
     pub fn luaopen_debug(state: State) -> ResultCode;
@@ -80,27 +278,191 @@ extern "C" {
     pub fn luaopen_package(state: State) -> ResultCode;
     pub fn luaopen_string(state: State) -> ResultCode;
     pub fn luaopen_table(state: State) -> ResultCode;
+    pub fn lua_setglobal(state: State, name: *const c_char);
+}
+
+#[cfg(lua_version = "5.2")]
+extern "C" {
+    pub fn lua_callk(state: State, nargs: Int, nresults: Int, ctx: Int, k: Option<KFunction>);
+    pub fn lua_pcallk(
+        state: State,
+        nargs: Int,
+        nresults: Int,
+        msgh: Int,
+        ctx: Int,
+        k: Option<KFunction>,
+    ) -> ResultCode;
+}
+
+#[cfg(any(lua_version = "5.1", lua_version = "5.2"))]
+extern "C" {
+    pub fn lua_gc(state: State, what: Int, data: Int) -> Int;
+    pub fn lua_insert(state: State, index: Int);
+    pub fn lua_replace(state: State, index: Int);
+    #[link_name = "lua_dump"]
+    fn lua_dump_unstripped(state: State, writer: Writer, data: *mut core::ffi::c_void) -> Int;
+    #[link_name = "lua_getfield"]
+    fn lua_getfield_untyped(state: State, index: Int, k: *const c_char);
+    #[link_name = "lua_rawget"]
+    fn lua_rawget_untyped(state: State, index: Int);
+    #[link_name = "lua_rawgeti"]
+    fn lua_rawgeti_untyped(state: State, index: Int, n: Int);
+    #[link_name = "lua_rawseti"]
+    fn lua_rawseti_int(state: State, index: Int, n: Int);
+}
+
+#[cfg(lua_version = "5.2")]
+extern "C" {
+    #[link_name = "lua_getglobal"]
+    fn lua_getglobal_untyped(state: State, name: *const c_char);
+}
+
+#[cfg(any(lua_version = "5.3", lua_version = "5.4"))]
+extern "C" {
+    pub fn lua_dump(state: State, writer: Writer, data: *mut core::ffi::c_void, strip: Int) -> Int;
+    pub fn lua_getfield(state: State, index: Int, k: *const c_char) -> Int;
+    pub fn lua_getglobal(state: State, name: *const c_char) -> Int;
+    pub fn lua_isinteger(state: State, index: Int) -> Int;
+    pub fn lua_callk(state: State, nargs: Int, nresults: Int, ctx: isize, k: Option<KFunction>);
+    pub fn lua_pcallk(
+        state: State,
+        nargs: Int,
+        nresults: Int,
+        msgh: Int,
+        ctx: isize,
+        k: Option<KFunction>,
+    ) -> ResultCode;
+    pub fn lua_rawget(state: State, index: Int) -> Int;
+    pub fn lua_rawgeti(state: State, index: Int, n: LuaInt) -> Int;
+    pub fn lua_rawseti(state: State, index: Int, n: LuaInt);
+    pub fn lua_rotate(state: State, index: Int, n: Int);
     pub fn luaopen_utf8(state: State) -> ResultCode;
 }
 
+#[cfg(lua_version = "5.3")]
+extern "C" {
+    pub fn lua_gc(state: State, what: Int, data: Int) -> Int;
+}
+
+#[cfg(lua_version = "5.4")]
+extern "C" {
+    pub fn lua_gc(state: State, what: Int, ...) -> Int;
+    pub fn lua_setwarnf(state: State, f: Option<WarnFunction>, ud: *mut core::ffi::c_void);
+}
+
+#[cfg(not(lua_version = "5.1"))]
+pub unsafe fn lua_call(state: State, nargs: Int, nresults: Int) {
+    lua_callk(state, nargs, nresults, 0, None)
+}
+
+#[cfg(not(lua_version = "5.1"))]
 pub unsafe fn lua_pcall(state: State, nargs: Int, nresults: Int, msgh: Int) -> ResultCode {
-    lua_pcallk(state, nargs, nresults, msgh, 0, 0)
+    lua_pcallk(state, nargs, nresults, msgh, 0, None)
+}
+
+#[cfg(any(lua_version = "5.3", lua_version = "5.4"))]
+pub unsafe fn lua_getextraspace(state: State) -> *mut *mut core::ffi::c_void {
+    (state as *mut u8).sub(LUA_EXTRASPACE) as *mut *mut core::ffi::c_void
+}
+
+/// Pseudo-index of the running C function's upvalue `i`.
+#[cfg(lua_version = "5.1")]
+pub fn lua_upvalueindex(i: Int) -> Int {
+    LUA_GLOBALSINDEX - i
+}
+
+/// Pseudo-index of the running C function's upvalue `i`.
+#[cfg(not(lua_version = "5.1"))]
+pub fn lua_upvalueindex(i: Int) -> Int {
+    LUA_REGISTRYINDEX - i
+}
+
+pub unsafe fn lua_newtable(state: State) {
+    lua_createtable(state, 0, 0)
+}
+
+#[cfg(not(lua_version = "5.1"))]
+pub unsafe fn lua_pushglobaltable(state: State) {
+    lua_rawgeti(state, LUA_REGISTRYINDEX, LUA_RIDX_GLOBALS);
 }
 
 pub unsafe fn lua_pop(state: State, n: Int) {
     lua_settop(state, -(n) - 1)
 }
 
-pub unsafe fn lua_pushcfunction(state: State, f: LuaFn) {
+#[cfg(any(lua_version = "5.3", lua_version = "5.4"))]
+pub unsafe fn lua_insert(state: State, index: Int) {
+    lua_rotate(state, index, 1)
+}
+
+#[cfg(any(lua_version = "5.3", lua_version = "5.4"))]
+pub unsafe fn lua_replace(state: State, index: Int) {
+    lua_copy(state, -1, index);
+    lua_pop(state, 1)
+}
+
+pub unsafe fn lua_pushcfunction(state: State, f: CFunction) {
     lua_pushcclosure(state, f, 0)
 }
 
+#[cfg(not(lua_version = "5.1"))]
 pub unsafe fn lua_tonumber(state: State, index: Int) -> LuaNum {
-    lua_tonumberx(state, index, core::ptr::null())
+    lua_tonumberx(state, index, core::ptr::null_mut())
+}
+
+#[cfg(not(lua_version = "5.1"))]
+pub unsafe fn lua_tointeger(state: State, index: Int) -> LuaInt {
+    lua_tointegerx(state, index, core::ptr::null_mut())
 }
 
 pub unsafe fn lua_tostring(state: State, idx: Int) -> *const c_char {
-    lua_tolstring(state, idx, core::ptr::null())
+    lua_tolstring(state, idx, core::ptr::null_mut())
+}
+
+/// Numbers are all floats before Lua 5.3.
+#[cfg(any(lua_version = "5.1", lua_version = "5.2"))]
+pub unsafe fn lua_isinteger(_state: State, _index: Int) -> Int {
+    0
+}
+
+/// Lua 5.1 and 5.2 always keep the debug information.
+#[cfg(any(lua_version = "5.1", lua_version = "5.2"))]
+pub unsafe fn lua_dump(
+    state: State,
+    writer: Writer,
+    data: *mut core::ffi::c_void,
+    _strip: Int,
+) -> Int {
+    lua_dump_unstripped(state, writer, data)
+}
+
+#[cfg(any(lua_version = "5.1", lua_version = "5.2"))]
+pub unsafe fn lua_getfield(state: State, index: Int, k: *const c_char) -> Int {
+    lua_getfield_untyped(state, index, k);
+    lua_type(state, -1)
+}
+
+#[cfg(lua_version = "5.2")]
+pub unsafe fn lua_getglobal(state: State, name: *const c_char) -> Int {
+    lua_getglobal_untyped(state, name);
+    lua_type(state, -1)
+}
+
+#[cfg(any(lua_version = "5.1", lua_version = "5.2"))]
+pub unsafe fn lua_rawget(state: State, index: Int) -> Int {
+    lua_rawget_untyped(state, index);
+    lua_type(state, -1)
+}
+
+#[cfg(any(lua_version = "5.1", lua_version = "5.2"))]
+pub unsafe fn lua_rawgeti(state: State, index: Int, n: LuaInt) -> Int {
+    lua_rawgeti_untyped(state, index, n as Int);
+    lua_type(state, -1)
+}
+
+#[cfg(any(lua_version = "5.1", lua_version = "5.2"))]
+pub unsafe fn lua_rawseti(state: State, index: Int, n: LuaInt) {
+    lua_rawseti_int(state, index, n as Int)
 }
 
 #[cfg(test)]
@@ -129,7 +491,7 @@ mod lua_tests {
         let string = CString::new("print('Hello')").unwrap();
         let state = unsafe { luaL_newstate() };
 
-        let s = string.as_ptr() as *const u8;
+        let s = string.as_ptr();
 
         let result = unsafe { luaL_loadstring(state, s) };
         let expected = LUA_OK;
@@ -32,7 +32,7 @@ fn cast_type(lua_type: Int) -> Option<Type> {
         LUA_TFUNCTION => Some(Type::Function),
         LUA_TUSERDATA => Some(Type::UserData),
         LUA_TTHREAD => Some(Type::Thread),
-        LUA_TNONE | _ => None,
+        _ => None,
     }
 }
 
@@ -0,0 +1,687 @@
+//! Destinations for what scripts print and warn.
+//!
+//! Once a `Lua` is given an `Output` with `Lua::set_output`, `print`, `io.write`, `io.stdout`, `io.stderr` and `warn` send their text to it instead of the process's standard streams.
+//! As in Lua, warnings are off until a script turns them on with `warn('@on')`.
+
+use crate::{extra::Extra, lua_core::*, strict::push_error, Library};
+use std::{cell::RefCell, ffi::CStr, io::Write, rc::Rc};
+
+/// Registry fields holding the replacements of `io.stdout` and `io.stderr`.
+const STREAMS: [&CStr; 2] = [c"llua.output.stdout", c"llua.output.stderr"];
+/// Registry field holding the file set with `io.output`, which is the replacement of `io.stdout` while unset.
+const DEFAULT_OUTPUT: &CStr = c"llua.output.default";
+
+/// Receives what scripts print and warn.
+pub trait Output {
+    /// Receives text from `print`, `io.write` and `io.stdout`.
+    fn stdout(&mut self, text: &str);
+
+    /// Receives text written to `io.stderr`.
+    fn stderr(&mut self, text: &str);
+
+    /// Receives a complete message from `warn`, which only exists from Lua 5.4.
+    fn warn(&mut self, message: &str);
+
+    /// Tells the output which chunk the text that follows comes from, such as `main.lua`.
+    /// Outputs that don't tell scripts apart can ignore it.
+    fn set_chunk(&mut self, _chunk: &str) {}
+}
+
+/// Keeps the output in memory. Clones share the same buffers, so one can be given to `Lua::set_output` and another read from.
+#[derive(Clone, Debug, Default)]
+pub struct BufferOutput {
+    captured: Rc<RefCell<Captured>>,
+}
+#[derive(Debug, Default)]
+struct Captured {
+    stdout: String,
+    stderr: String,
+    warnings: Vec<String>,
+}
+impl BufferOutput {
+    /// Creates empty buffers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns what was written to standard output.
+    pub fn stdout(&self) -> String {
+        self.captured.borrow().stdout.clone()
+    }
+
+    /// Returns what was written to standard error.
+    pub fn stderr(&self) -> String {
+        self.captured.borrow().stderr.clone()
+    }
+
+    /// Returns the warnings, in the order they were emitted.
+    pub fn warnings(&self) -> Vec<String> {
+        self.captured.borrow().warnings.clone()
+    }
+
+    /// Empties the buffers.
+    pub fn clear(&self) {
+        *self.captured.borrow_mut() = Captured::default();
+    }
+}
+impl Output for BufferOutput {
+    fn stdout(&mut self, text: &str) {
+        self.captured.borrow_mut().stdout.push_str(text);
+    }
+
+    fn stderr(&mut self, text: &str) {
+        self.captured.borrow_mut().stderr.push_str(text);
+    }
+
+    fn warn(&mut self, message: &str) {
+        self.captured.borrow_mut().warnings.push(message.into());
+    }
+}
+
+/// Writes each line of output to the writer after a prefix, such as the script's name, so the output of several scripts can be told apart.
+/// Lines from standard error are marked with `stderr: ` and warnings with `warning: `.
+/// Unfinished lines are written when the output is dropped.
+pub struct PrefixedOutput<W: Write> {
+    prefix: String,
+    writer: W,
+    stdout: String,
+    stderr: String,
+}
+impl<W: Write> PrefixedOutput<W> {
+    /// Writes lines to the writer, starting them with the prefix.
+    pub fn new(prefix: impl Into<String>, writer: W) -> Self {
+        Self {
+            prefix: prefix.into(),
+            writer,
+            stdout: String::new(),
+            stderr: String::new(),
+        }
+    }
+
+    fn write_line(&mut self, marker: &str, line: &str) {
+        // Output has nowhere to report errors, like the standard streams it replaces
+        let _ = writeln!(self.writer, "{}{marker}{line}", self.prefix);
+    }
+}
+impl<W: Write> Output for PrefixedOutput<W> {
+    fn stdout(&mut self, text: &str) {
+        self.stdout.push_str(text);
+        for line in take_lines(&mut self.stdout) {
+            self.write_line("", &line);
+        }
+    }
+
+    fn stderr(&mut self, text: &str) {
+        self.stderr.push_str(text);
+        for line in take_lines(&mut self.stderr) {
+            self.write_line("stderr: ", &line);
+        }
+    }
+
+    fn warn(&mut self, message: &str) {
+        self.write_line("warning: ", message);
+    }
+}
+impl<W: Write> Drop for PrefixedOutput<W> {
+    fn drop(&mut self) {
+        for (marker, pending) in [
+            ("", std::mem::take(&mut self.stdout)),
+            ("stderr: ", std::mem::take(&mut self.stderr)),
+        ] {
+            if !pending.is_empty() {
+                self.write_line(marker, &pending);
+            }
+        }
+        let _ = self.writer.flush();
+    }
+}
+
+/// Turns output into `log` records whose target is the chunk name of the script, one record per line.
+/// Standard output is logged at the info level, standard error at the error level and warnings at the warn level.
+#[cfg(feature = "log")]
+#[derive(Debug)]
+pub struct LogOutput {
+    chunk: String,
+    stdout: String,
+    stderr: String,
+}
+#[cfg(feature = "log")]
+impl LogOutput {
+    /// Creates an output logging under the target `llua` until a script's chunk name is known.
+    pub fn new() -> Self {
+        Self {
+            chunk: "llua".into(),
+            stdout: String::new(),
+            stderr: String::new(),
+        }
+    }
+
+    /// Logs the unfinished lines.
+    fn flush(&mut self) {
+        for (level, pending) in [
+            (log::Level::Info, std::mem::take(&mut self.stdout)),
+            (log::Level::Error, std::mem::take(&mut self.stderr)),
+        ] {
+            if !pending.is_empty() {
+                log::log!(target: &self.chunk, level, "{pending}");
+            }
+        }
+    }
+}
+#[cfg(feature = "log")]
+impl Default for LogOutput {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+#[cfg(feature = "log")]
+impl Output for LogOutput {
+    fn stdout(&mut self, text: &str) {
+        self.stdout.push_str(text);
+        for line in take_lines(&mut self.stdout) {
+            log::info!(target: &self.chunk, "{line}");
+        }
+    }
+
+    fn stderr(&mut self, text: &str) {
+        self.stderr.push_str(text);
+        for line in take_lines(&mut self.stderr) {
+            log::error!(target: &self.chunk, "{line}");
+        }
+    }
+
+    fn warn(&mut self, message: &str) {
+        log::warn!(target: &self.chunk, "{message}");
+    }
+
+    fn set_chunk(&mut self, chunk: &str) {
+        if chunk != self.chunk {
+            self.flush();
+            self.chunk = chunk.into();
+        }
+    }
+}
+#[cfg(feature = "log")]
+impl Drop for LogOutput {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Removes the complete lines from the pending text, returning them without their newlines.
+fn take_lines(pending: &mut String) -> Vec<String> {
+    let mut lines = Vec::new();
+    while let Some(end) = pending.find('\n') {
+        let mut line: String = pending.drain(..=end).collect();
+        line.pop();
+        lines.push(line);
+    }
+
+    lines
+}
+
+/// The output of a state, along with the warning being put together from pieces.
+pub(crate) struct Sink {
+    output: Box<dyn Output>,
+    #[cfg(lua_version = "5.4")]
+    warning: String,
+    /// Whether the last piece of warning said the message continues.
+    #[cfg(lua_version = "5.4")]
+    continued: bool,
+    #[cfg(lua_version = "5.4")]
+    warnings_off: bool,
+}
+impl Sink {
+    pub(crate) fn new(output: Box<dyn Output>) -> Self {
+        Self {
+            output,
+            #[cfg(lua_version = "5.4")]
+            warning: String::new(),
+            #[cfg(lua_version = "5.4")]
+            continued: false,
+            #[cfg(lua_version = "5.4")]
+            warnings_off: true,
+        }
+    }
+
+    /// Adds a piece of warning, sending the message once it is complete.
+    /// Single pieces starting with `@` are control messages, where `@off` and `@on` turn warnings off and on.
+    #[cfg(lua_version = "5.4")]
+    fn warn(&mut self, piece: &str, continues: bool, chunk: Option<&str>) {
+        if !self.continued && !continues && piece.starts_with('@') {
+            match piece {
+                "@off" => self.warnings_off = true,
+                "@on" => self.warnings_off = false,
+                _ => {}
+            }
+            return;
+        }
+
+        self.warning.push_str(piece);
+        self.continued = continues;
+        if !continues {
+            let warning = std::mem::take(&mut self.warning);
+            if !self.warnings_off {
+                if let Some(chunk) = chunk {
+                    self.output.set_chunk(chunk);
+                }
+                self.output.warn(&warning);
+            }
+        }
+    }
+}
+
+/// Replaces the functions of the given libraries that write to the standard streams with ones writing to the state's output.
+/// Only functions that are installed get replaced.
+pub(crate) fn install(state: State, libraries: &[Library]) {
+    unsafe {
+        for library in libraries {
+            match library {
+                Library::Basic => {
+                    lua_pushglobaltable(state);
+                    if lua_getfield(state, -1, c"print".as_ptr()) != LUA_TNIL {
+                        lua_pushcfunction(state, print);
+                        lua_setfield(state, -3, c"print".as_ptr());
+                    }
+                    lua_pop(state, 2);
+                }
+                Library::Io => {
+                    if lua_getglobal(state, c"io".as_ptr()) == LUA_TTABLE {
+                        if lua_getfield(state, -1, c"write".as_ptr()) != LUA_TNIL {
+                            lua_pushcfunction(state, io_write);
+                            lua_setfield(state, -3, c"write".as_ptr());
+                        }
+                        lua_pop(state, 1);
+
+                        if lua_getfield(state, -1, c"output".as_ptr()) != LUA_TNIL {
+                            lua_pushcclosure(state, io_output, 1);
+                            lua_setfield(state, -2, c"output".as_ptr());
+                        } else {
+                            lua_pop(state, 1);
+                        }
+
+                        for (stream, name) in [c"stdout", c"stderr"].into_iter().enumerate() {
+                            if lua_getfield(state, -1, name.as_ptr()) != LUA_TNIL {
+                                push_stream(state, stream);
+                                lua_setfield(state, -3, name.as_ptr());
+                            }
+                            lua_pop(state, 1);
+                        }
+                    }
+                    lua_pop(state, 1);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Sends warnings to the state's output.
+#[cfg(lua_version = "5.4")]
+pub(crate) fn set_warn(state: State) {
+    unsafe {
+        lua_setwarnf(state, Some(warn), state as *mut core::ffi::c_void);
+    }
+}
+
+#[cfg(lua_version = "5.4")]
+unsafe extern "C" fn warn(
+    ud: *mut core::ffi::c_void,
+    message: *const core::ffi::c_char,
+    tocont: Int,
+) {
+    let state = ud as State;
+    let chunk = caller_chunk(state);
+    let Some(sink) = Extra::from_state(state).and_then(|extra| extra.output()) else {
+        return;
+    };
+
+    sink.warn(
+        &CStr::from_ptr(message).to_string_lossy(),
+        tocont != 0,
+        chunk.as_deref(),
+    );
+}
+
+/// Returns the chunk name of the function calling the running one, without the `@` or `=` of file and special names.
+unsafe fn caller_chunk(state: State) -> Option<String> {
+    let mut ar = LuaDebug::new();
+    if lua_getstack(state, 1, &mut ar) == 0 {
+        return None;
+    }
+
+    lua_getinfo(state, c"S".as_ptr(), &mut ar);
+    let source = CStr::from_ptr(ar.source).to_string_lossy();
+    Some(match source.strip_prefix(['@', '=']) {
+        Some(name) => name.into(),
+        None => CStr::from_ptr(ar.short_src.as_ptr())
+            .to_string_lossy()
+            .into_owned(),
+    })
+}
+
+/// Sends the text to the state's standard output, or standard error for stream 1.
+unsafe fn send(state: State, stream: usize, text: &str) {
+    let chunk = caller_chunk(state);
+    let Some(sink) = Extra::from_state(state).and_then(|extra| extra.output()) else {
+        return;
+    };
+
+    if let Some(chunk) = &chunk {
+        sink.output.set_chunk(chunk);
+    }
+    match stream {
+        0 => sink.output.stdout(text),
+        _ => sink.output.stderr(text),
+    }
+}
+
+/// Returns the string at the index.
+unsafe fn string_at(state: State, index: Int) -> String {
+    let mut len = 0;
+    let string = lua_tolstring(state, index, &mut len) as *const u8;
+    if string.is_null() {
+        return String::new();
+    }
+
+    String::from_utf8_lossy(std::slice::from_raw_parts(string, len)).into_owned()
+}
+
+/// Pushes the object replacing the standard stream, creating it the first time.
+/// Its methods are closures over the stream's number.
+unsafe fn push_stream(state: State, stream: usize) {
+    let key = STREAMS[stream].as_ptr();
+    if lua_getfield(state, LUA_REGISTRYINDEX, key) == LUA_TTABLE {
+        return;
+    }
+    lua_pop(state, 1);
+
+    lua_newtable(state);
+    lua_newtable(state);
+    lua_newtable(state);
+    let methods: [(&CStr, CFunction); 4] = [
+        (c"close", stream_close),
+        (c"flush", stream_flush),
+        (c"setvbuf", stream_setvbuf),
+        (c"write", stream_write),
+    ];
+    for (name, method) in methods {
+        lua_pushinteger(state, stream as LuaInt);
+        lua_pushcclosure(state, method, 1);
+        lua_setfield(state, -2, name.as_ptr());
+    }
+    lua_setfield(state, -2, c"__index".as_ptr());
+    lua_pushstring(state, c"FILE*".as_ptr());
+    lua_setfield(state, -2, c"__name".as_ptr());
+    lua_setmetatable(state, -2);
+
+    lua_pushvalue(state, -1);
+    lua_setfield(state, LUA_REGISTRYINDEX, key);
+}
+
+/// Returns whether the value at the index is the replacement of `io.stdout` or `io.stderr`.
+unsafe fn is_stream(state: State, index: Int) -> bool {
+    STREAMS.iter().any(|key| {
+        lua_getfield(state, LUA_REGISTRYINDEX, key.as_ptr());
+        let found = lua_rawequal(state, index, -1) != 0;
+        lua_pop(state, 1);
+        found
+    })
+}
+
+/// Pushes the file set with `io.output`, or the replacement of `io.stdout` if none is.
+unsafe fn push_default_output(state: State) {
+    if lua_getfield(state, LUA_REGISTRYINDEX, DEFAULT_OUTPUT.as_ptr()) == LUA_TNIL {
+        lua_pop(state, 1);
+        push_stream(state, 0);
+    }
+}
+
+/// Writes the arguments from index `first` on to the stream, which must all be strings or numbers.
+/// On error, the message is pushed and `false` returned.
+unsafe fn write_args(state: State, first: Int, stream: usize) -> bool {
+    let last = lua_gettop(state);
+    for index in first..=last {
+        if lua_isstring(state, index) == 0 {
+            let got = CStr::from_ptr(lua_typename(state, lua_type(state, index))).to_string_lossy();
+            push_error(
+                state,
+                &format!(
+                    "bad argument #{} to 'write' (string expected, got {got})",
+                    index - first + 1
+                ),
+            );
+            return false;
+        }
+    }
+
+    let text: String = (first..=last)
+        .map(|index| string_at(state, index))
+        .collect();
+    send(state, stream, &text);
+    true
+}
+
+/// `print(...)`
+unsafe extern "C" fn print(state: State) -> Int {
+    let count = lua_gettop(state);
+    if lua_checkstack(state, count) == 0 {
+        push_error(state, "too many arguments to 'print'");
+        return lua_error(state);
+    }
+
+    // Convert everything first, as converting may raise errors
+    for index in 1..=count {
+        luaL_tolstring(state, index, std::ptr::null_mut());
+    }
+
+    let mut text = (count + 1..=2 * count)
+        .map(|index| string_at(state, index))
+        .collect::<Vec<_>>()
+        .join("\t");
+    text.push('\n');
+    send(state, 0, &text);
+    0
+}
+
+/// `io.write(...)`, writing to the file set with `io.output`.
+unsafe extern "C" fn io_write(state: State) -> Int {
+    push_default_output(state);
+    lua_getfield(state, -1, c"write".as_ptr());
+    lua_insert(state, 1);
+    lua_insert(state, 2);
+    lua_call(state, lua_gettop(state) - 1, LUA_MULTRET);
+    lua_gettop(state)
+}
+
+/// `io.output([file])`, with the replaced function as upvalue.
+/// The replacements of `io.stdout` and `io.stderr` are kept as they are, while file names and other files go to the replaced function.
+unsafe extern "C" fn io_output(state: State) -> Int {
+    if lua_type(state, 1) > LUA_TNIL {
+        if is_stream(state, 1) {
+            lua_pushvalue(state, 1);
+        } else {
+            lua_pushvalue(state, lua_upvalueindex(1));
+            lua_pushvalue(state, 1);
+            lua_call(state, 1, 1);
+        }
+        lua_setfield(state, LUA_REGISTRYINDEX, DEFAULT_OUTPUT.as_ptr());
+    }
+
+    push_default_output(state);
+    1
+}
+
+fn stream_number(state: State) -> usize {
+    unsafe { lua_tointeger(state, lua_upvalueindex(1)) as usize }
+}
+
+unsafe extern "C" fn stream_write(state: State) -> Int {
+    if !write_args(state, 2, stream_number(state)) {
+        return lua_error(state);
+    }
+
+    lua_pushvalue(state, 1);
+    1
+}
+
+unsafe extern "C" fn stream_flush(state: State) -> Int {
+    unsafe { lua_pushvalue(state, 1) };
+    1
+}
+
+unsafe extern "C" fn stream_setvbuf(state: State) -> Int {
+    unsafe { lua_pushboolean(state, 1) };
+    1
+}
+
+unsafe extern "C" fn stream_close(state: State) -> Int {
+    lua_pushnil(state);
+    lua_pushstring(state, c"cannot close standard file".as_ptr());
+    2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{overrides::lua_with, Lua};
+
+    #[test]
+    fn print_writes_to_output() {
+        let buffer = BufferOutput::new();
+        let mut lua = lua_with(|lua| lua.set_output(buffer.clone()));
+        lua.interpret(
+            "print('a', 1, nil, setmetatable({}, { __tostring = function() return 't' end }))",
+        )
+        .unwrap();
+
+        assert_eq!("a\t1\tnil\tt\n", buffer.stdout());
+    }
+
+    #[test]
+    fn io_write_and_stderr_write_to_output() {
+        let buffer = BufferOutput::new();
+        let mut lua = lua_with(|lua| lua.set_output(buffer.clone()));
+        lua.interpret("io.write('a', 1):write('b'); io.stdout:write('c'); io.stderr:write('d')")
+            .unwrap();
+
+        assert_eq!("a1bc", buffer.stdout());
+        assert_eq!("d", buffer.stderr());
+    }
+
+    #[test]
+    fn io_output_returns_replacement_stream() {
+        let buffer = BufferOutput::new();
+        let mut lua = lua_with(|lua| lua.set_output(buffer.clone()));
+        lua.interpret(
+            "assert(io.output() == io.stdout) io.output():write('a') \
+             io.output(io.stderr) io.write('b') assert(io.output() == io.stderr) \
+             io.output(io.stdout) io.write('c')",
+        )
+        .unwrap();
+
+        assert_eq!("ac", buffer.stdout());
+        assert_eq!("b", buffer.stderr());
+    }
+
+    #[test]
+    fn io_write_table_returns_err() {
+        let mut lua = lua_with(|lua| lua.set_output(BufferOutput::new()));
+        let Err(crate::Error::Runtime(message)) = lua.interpret("io.write({})") else {
+            panic!("io.write should fail");
+        };
+
+        assert!(message.contains("string expected, got table"), "{message}");
+    }
+
+    #[test]
+    fn activate_after_set_output_installs() {
+        let buffer = BufferOutput::new();
+        let mut lua = Lua::new();
+        lua.set_output(buffer.clone());
+        lua.activate(&[Library::Basic]).unwrap();
+        lua.interpret("print('hi')").unwrap();
+
+        assert_eq!("hi\n", buffer.stdout());
+    }
+
+    #[cfg(lua_version = "5.4")]
+    #[test]
+    fn warn_sends_complete_messages() {
+        let buffer = BufferOutput::new();
+        let mut lua = lua_with(|lua| lua.set_output(buffer.clone()));
+        lua.interpret(
+            "warn('off'); warn('@on'); warn('a', 'b'); warn('@off'); warn('hidden'); warn('@on'); warn('c')",
+        )
+        .unwrap();
+
+        assert_eq!(vec!["ab", "c"], buffer.warnings());
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn log_output_logs_with_chunk_target() {
+        use std::sync::Mutex;
+
+        static RECORDS: Mutex<Vec<(String, log::Level, String)>> = Mutex::new(Vec::new());
+        struct Logger;
+        impl log::Log for Logger {
+            fn enabled(&self, _: &log::Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, record: &log::Record) {
+                let entry = (
+                    record.target().to_string(),
+                    record.level(),
+                    record.args().to_string(),
+                );
+                RECORDS.lock().unwrap().push(entry);
+            }
+
+            fn flush(&self) {}
+        }
+        let _ = log::set_logger(&Logger);
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let mut lua = lua_with(|lua| lua.set_output(LogOutput::new()));
+        lua.interpret_named(
+            "logged.lua",
+            "print('hello', 1) io.stderr:write('bad\\n') if warn then warn('@on') warn('careful') end",
+        )
+        .unwrap();
+
+        let records: Vec<_> = RECORDS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(target, _, _)| target == "logged.lua")
+            .map(|(_, level, message)| (*level, message.clone()))
+            .collect();
+        let mut expected = vec![
+            (log::Level::Info, "hello\t1".to_string()),
+            (log::Level::Error, "bad".to_string()),
+        ];
+        if cfg!(lua_version = "5.4") {
+            expected.push((log::Level::Warn, "careful".to_string()));
+        }
+        assert_eq!(expected, records);
+    }
+
+    #[test]
+    fn prefixed_output_prefixes_lines() {
+        let mut written = Vec::new();
+        {
+            let mut output = PrefixedOutput::new("[main] ", &mut written);
+            output.stdout("one\ntw");
+            output.stdout("o\nthree");
+            output.stderr("oops\n");
+            output.warn("careful");
+        }
+
+        assert_eq!(
+            "[main] one\n[main] two\n[main] stderr: oops\n[main] warning: careful\n[main] three\n",
+            String::from_utf8(written).unwrap()
+        );
+    }
+}
@@ -0,0 +1,47 @@
+//! Helpers for the Rust functions that replace standard library functions, such as those of `Vfs` and deterministic mode.
+
+use crate::{lua_core::*, strict::push_error};
+use alloc::string::String;
+use core::ffi::CStr;
+
+/// Replaces the field of the table on top of the stack with the function, if it is set.
+/// The function gets the replaced value as its upvalue.
+pub(crate) unsafe fn replace(state: State, name: &CStr, function: CFunction) {
+    if lua_getfield(state, -1, name.as_ptr()) == LUA_TNIL {
+        lua_pop(state, 1);
+        return;
+    }
+
+    lua_pushcclosure(state, function, 1);
+    lua_setfield(state, -2, name.as_ptr());
+}
+
+/// Returns the number of results, or raises the error with the location of the calling code.
+/// The message is dropped before raising it, as the error never returns.
+pub(crate) unsafe fn check(state: State, result: Result<Int, String>) -> Int {
+    match result {
+        Ok(results) => results,
+        Err(message) => {
+            push_error(state, &message);
+            drop(message);
+            lua_error(state)
+        }
+    }
+}
+
+/// Calls the function that was replaced with the arguments, returning its results.
+pub(crate) unsafe extern "C" fn call_replaced(state: State) -> Int {
+    lua_pushvalue(state, lua_upvalueindex(1));
+    lua_insert(state, 1);
+    lua_call(state, lua_gettop(state) - 1, LUA_MULTRET);
+    lua_gettop(state)
+}
+
+/// Returns a state with every library, set up by the function, such as with `Lua::set_vfs`.
+#[cfg(test)]
+pub(crate) fn lua_with(setup: impl FnOnce(&mut crate::Lua)) -> crate::Lua {
+    let mut lua = crate::Lua::new();
+    lua.activate(crate::Library::all()).unwrap();
+    setup(&mut lua);
+    lua
+}
@@ -0,0 +1,396 @@
+//! An instrumenting profiler for scripts running in a `Lua` state.
+
+use crate::{lua_core::Int, FunctionKind, HookContext, HookEvent, HookMask, Lua};
+use core::{fmt, time::Duration};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io::{self, Write},
+    rc::Rc,
+    time::Instant,
+};
+
+/// How many instructions run between each count event.
+const INSTRUCTION_INTERVAL: Int = 1000;
+
+/// Identifies a profiled function.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct FunctionKey {
+    /// Printable source the function was defined in, or `[C]` for functions such as Rust callbacks.
+    pub source: String,
+    /// The line the function definition starts on.
+    pub line_defined: Option<Int>,
+    /// The name the function was called by.
+    pub name: String,
+}
+impl fmt::Display for FunctionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line_defined {
+            Some(line) => write!(f, "{}:{} {}", self.source, line, self.name),
+            None => write!(f, "{} {}", self.source, self.name),
+        }
+    }
+}
+
+/// Statistics gathered for a function.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct FunctionStats {
+    /// How many times the function was called.
+    pub calls: u64,
+    /// Time spent in the function, including functions it called.
+    pub inclusive: Duration,
+    /// Time spent in the function itself.
+    pub exclusive: Duration,
+    /// Approximate number of instructions run in the function itself.
+    pub instructions: u64,
+}
+
+/// A function on the profiled call stack.
+struct Frame {
+    key: FunctionKey,
+    start: Instant,
+    /// Time spent in functions called by this one.
+    children: Duration,
+}
+
+/// The results of profiling.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Profile {
+    functions: HashMap<FunctionKey, FunctionStats>,
+    /// Exclusive time spent in each call stack, keyed by its folded representation.
+    stacks: HashMap<String, Duration>,
+}
+impl Profile {
+    /// Returns the statistics for each function, sorted by exclusive time with the most expensive first.
+    pub fn functions(&self) -> Vec<(&FunctionKey, &FunctionStats)> {
+        let mut functions: Vec<_> = self.functions.iter().collect();
+        functions.sort_by(|(a_key, a), (b_key, b)| {
+            b.exclusive
+                .cmp(&a.exclusive)
+                .then_with(|| a_key.to_string().cmp(&b_key.to_string()))
+        });
+
+        functions
+    }
+
+    /// Writes the call stacks in the folded format used by flamegraph tools, weighted by microseconds.
+    pub fn write_folded<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut stacks: Vec<_> = self.stacks.iter().collect();
+        stacks.sort();
+
+        for (stack, time) in stacks {
+            writeln!(writer, "{} {}", stack, time.as_micros())?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes a text report of every function, with the most expensive first.
+    pub fn write_report<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(
+            writer,
+            "{:>10} {:>14} {:>14} {:>14}  function",
+            "calls", "inclusive ms", "exclusive ms", "instructions"
+        )?;
+
+        for (key, stats) in self.functions() {
+            writeln!(
+                writer,
+                "{:>10} {:>14.3} {:>14.3} {:>14}  {}",
+                stats.calls,
+                stats.inclusive.as_secs_f64() * 1000.0,
+                stats.exclusive.as_secs_f64() * 1000.0,
+                stats.instructions,
+                key
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Records what the profiled state is doing.
+#[derive(Default)]
+struct Recorder {
+    profile: Profile,
+    stack: Vec<Frame>,
+}
+impl Recorder {
+    fn on_event(&mut self, ctx: &HookContext, event: HookEvent) {
+        let now = Instant::now();
+        let depth = ctx.depth() as usize;
+
+        match event {
+            HookEvent::Call => {
+                // Errors unwind without return events, so drop any frames that are gone
+                self.unwind(depth.saturating_sub(1), now);
+                self.enter(ctx, now);
+            }
+            HookEvent::TailCall => {
+                // The called function replaces the running one
+                self.unwind(depth, now);
+                self.leave(now);
+                self.enter(ctx, now);
+            }
+            HookEvent::Return => {
+                self.unwind(depth, now);
+                self.leave(now);
+            }
+            HookEvent::Count => {
+                if let Some(frame) = self.stack.last() {
+                    let stats = self.profile.functions.entry(frame.key.clone()).or_default();
+                    stats.instructions += INSTRUCTION_INTERVAL as u64;
+                }
+            }
+            HookEvent::Line(_) => {}
+        }
+    }
+
+    /// Starts timing the function that was just called.
+    fn enter(&mut self, ctx: &HookContext, now: Instant) {
+        let info = ctx.info();
+        let name = match (info.name, info.what) {
+            (Some(name), _) => name,
+            (None, FunctionKind::Main) => "main chunk".into(),
+            (None, _) => "?".into(),
+        };
+        let key = FunctionKey {
+            source: info.short_source,
+            line_defined: info.line_defined,
+            name,
+        };
+
+        self.profile.functions.entry(key.clone()).or_default().calls += 1;
+        self.stack.push(Frame {
+            key,
+            start: now,
+            children: Duration::ZERO,
+        });
+    }
+
+    /// Stops timing the function on top of the stack.
+    fn leave(&mut self, now: Instant) {
+        let Some(frame) = self.stack.pop() else {
+            return;
+        };
+
+        let elapsed = now.duration_since(frame.start);
+        let exclusive = elapsed.saturating_sub(frame.children);
+
+        let mut folded: Vec<String> = self.stack.iter().map(|f| folded_name(&f.key)).collect();
+        folded.push(folded_name(&frame.key));
+        *self.profile.stacks.entry(folded.join(";")).or_default() += exclusive;
+
+        // Recursive calls are already counted by the outermost call
+        let recursive = self.stack.iter().any(|f| f.key == frame.key);
+        let stats = self.profile.functions.entry(frame.key).or_default();
+        stats.exclusive += exclusive;
+        if !recursive {
+            stats.inclusive += elapsed;
+        }
+
+        if let Some(parent) = self.stack.last_mut() {
+            parent.children += elapsed;
+        }
+    }
+
+    /// Leaves functions until the stack is no deeper than the given depth.
+    fn unwind(&mut self, depth: usize, now: Instant) {
+        while self.stack.len() > depth {
+            self.leave(now);
+        }
+    }
+}
+
+/// Returns the name of a function in a folded stack.
+fn folded_name(key: &FunctionKey) -> String {
+    let name = match key.line_defined {
+        Some(line) => format!("{}@{}:{}", key.name, key.source, line),
+        None => format!("{}@{}", key.name, key.source),
+    };
+
+    // Separators can't appear in frame names
+    name.replace([';', ' '], "_")
+}
+
+/// Profiles the calls made on a `Lua` state.
+pub struct Profiler {
+    recorder: Rc<RefCell<Recorder>>,
+}
+impl Profiler {
+    /// Starts profiling the given state. This replaces any hook set on it.
+    pub fn start(lua: &mut Lua) -> Self {
+        let recorder = Rc::new(RefCell::new(Recorder::default()));
+        let hook_recorder = recorder.clone();
+        lua.set_hook(
+            HookMask {
+                call: true,
+                ret: true,
+                line: false,
+                count: INSTRUCTION_INTERVAL,
+            },
+            move |ctx, event| {
+                hook_recorder.borrow_mut().on_event(ctx, event);
+                Ok(())
+            },
+        );
+
+        Self { recorder }
+    }
+
+    /// Stops profiling and returns the results.
+    pub fn stop(self, lua: &mut Lua) -> Profile {
+        lua.remove_hook();
+
+        let mut recorder = self.recorder.borrow_mut();
+        recorder.unwind(0, Instant::now());
+        core::mem::take(&mut recorder.profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lua_core::State, Data, Library, Stack};
+
+    fn rust_sleep(state: State) -> Int {
+        std::thread::sleep(Duration::from_millis(2));
+        Stack::new(state).push(Data::Nil);
+        1
+    }
+
+    const SCRIPT: &str = "function leaf(n)
+  local x = 0
+  for i = 1, n do x = x + i end
+  return x
+end
+function middle()
+  sleep()
+  return leaf(1000) + leaf(10)
+end
+function fact(n)
+  if n <= 1 then return 1 end
+  return n * fact(n - 1)
+end
+middle()
+middle()
+fact(5)";
+
+    fn profile() -> Profile {
+        let mut lua = Lua::new();
+        lua.activate(Library::all()).unwrap();
+        lua.set_global("sleep", Data::Function(rust_sleep)).unwrap();
+
+        let profiler = Profiler::start(&mut lua);
+        lua.interpret_named("game.lua", SCRIPT).unwrap();
+        profiler.stop(&mut lua)
+    }
+
+    fn stats<'a>(profile: &'a Profile, name: &str) -> (&'a FunctionKey, &'a FunctionStats) {
+        profile
+            .functions()
+            .into_iter()
+            .find(|(key, _)| key.name == name)
+            .unwrap()
+    }
+
+    #[test]
+    fn counts_calls_per_function() {
+        let profile = profile();
+
+        assert_eq!(2, stats(&profile, "middle").1.calls);
+        assert_eq!(4, stats(&profile, "leaf").1.calls);
+        assert_eq!(2, stats(&profile, "sleep").1.calls);
+        assert_eq!(5, stats(&profile, "fact").1.calls);
+    }
+
+    #[test]
+    fn keys_by_source_and_line() {
+        let profile = profile();
+
+        let (key, _) = stats(&profile, "middle");
+        assert_eq!("game.lua", key.source);
+        assert_eq!(Some(6), key.line_defined);
+        assert_eq!("game.lua:6 middle", key.to_string());
+
+        let (key, _) = stats(&profile, "sleep");
+        assert_eq!("[C] sleep", key.to_string());
+    }
+
+    #[test]
+    fn includes_time_in_rust_callbacks() {
+        let profile = profile();
+
+        let (_, sleep) = stats(&profile, "sleep");
+        let (_, middle) = stats(&profile, "middle");
+        assert!(sleep.exclusive >= Duration::from_millis(4));
+        assert!(middle.inclusive >= sleep.inclusive);
+        assert!(middle.exclusive < sleep.exclusive);
+    }
+
+    #[test]
+    fn recursion_counts_inclusive_once() {
+        let profile = profile();
+
+        let (_, fact) = stats(&profile, "fact");
+        let (_, main) = stats(&profile, "main chunk");
+        assert!(fact.inclusive <= main.inclusive);
+        assert!(fact.exclusive <= fact.inclusive);
+    }
+
+    #[test]
+    fn counts_instructions() {
+        let profile = profile();
+
+        let (_, leaf) = stats(&profile, "leaf");
+        assert!(leaf.instructions >= 2000);
+    }
+
+    #[test]
+    fn write_folded_outputs_stacks() {
+        let profile = profile();
+        let mut folded = Vec::new();
+        profile.write_folded(&mut folded).unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+
+        assert!(folded
+            .lines()
+            .any(|l| l.starts_with("main_chunk@game.lua:0;middle@game.lua:6;sleep@[C] ")));
+        assert!(folded
+            .lines()
+            .all(|l| l.rsplit_once(' ').unwrap().1.parse::<u128>().is_ok()));
+    }
+
+    #[test]
+    fn write_report_sorted_by_exclusive_time() {
+        let profile = profile();
+        let mut report = Vec::new();
+        profile.write_report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+
+        let lines: Vec<&str> = report.lines().collect();
+        assert!(lines[0].contains("exclusive ms"));
+        assert!(lines[1].ends_with("[C] sleep"));
+    }
+
+    #[test]
+    fn errors_unwind_stack() {
+        let mut lua = Lua::new();
+        lua.activate(Library::all()).unwrap();
+        lua.interpret_named(
+            "funcs.lua",
+            "function fail() error('x') end function after() end",
+        )
+        .unwrap();
+
+        let profiler = Profiler::start(&mut lua);
+        lua.interpret("pcall(fail) after()").unwrap();
+        let profile = profiler.stop(&mut lua);
+
+        let mut folded = Vec::new();
+        profile.write_folded(&mut folded).unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+        let after = folded.lines().find(|l| l.contains(";after@")).unwrap();
+        assert_eq!(1, after.matches(';').count());
+    }
+}
@@ -0,0 +1,369 @@
+extern crate alloc;
+
+use crate::{debug::describe, extra::Extra, lua_core::*, Type, Variable};
+use alloc::{boxed::Box, collections::BTreeSet, ffi::CString, format, string::String};
+use core::ffi::CStr;
+
+/// Function called with the new value when a watched global is written.
+pub(crate) type WatchFn = Box<dyn FnMut(&Variable)>;
+
+/// Registry field holding the values of watched globals.
+/// They are kept out of the global table so every write reaches `__newindex`.
+const WATCHED: &CStr = c"llua.watched";
+
+/// Rules applied to globals missing from the global table, through its metatable.
+#[derive(Default)]
+pub(crate) struct Globals {
+    /// Whether undeclared globals are rejected.
+    strict: bool,
+    /// Globals that may be used in strict mode even while they are nil.
+    declared: BTreeSet<String>,
+    watched: BTreeSet<String>,
+    watcher: Option<WatchFn>,
+}
+impl Globals {
+    pub(crate) fn set_strict(&mut self, state: State, enabled: bool) {
+        self.strict = enabled;
+        self.update_metatable(state);
+    }
+
+    pub(crate) fn declare(&mut self, name: &str) {
+        self.declared.insert(name.into());
+    }
+
+    /// Calls the watcher for every write to the given globals, replacing any existing watcher.
+    /// Their values are moved out of the global table, so `rawget`, `pairs` and `next` on it don't see them until they are unwatched.
+    pub(crate) fn watch(&mut self, state: State, names: &[&str], watcher: WatchFn) {
+        self.unwatch(state);
+        self.watched = names.iter().map(|name| String::from(*name)).collect();
+        self.watcher = Some(watcher);
+
+        unsafe {
+            // Move the current values out of the global table
+            lua_newtable(state);
+            lua_pushglobaltable(state);
+            for name in &self.watched {
+                let name = CString::new(name.as_str()).unwrap_or_default();
+                lua_pushstring(state, name.as_ptr());
+                lua_rawget(state, -2);
+                lua_setfield(state, -3, name.as_ptr());
+
+                lua_pushstring(state, name.as_ptr());
+                lua_pushnil(state);
+                lua_rawset(state, -3);
+            }
+            lua_pop(state, 1);
+            lua_setfield(state, LUA_REGISTRYINDEX, WATCHED.as_ptr());
+        }
+
+        self.update_metatable(state);
+    }
+
+    /// Stops watching globals, putting their values back into the global table.
+    pub(crate) fn unwatch(&mut self, state: State) {
+        unsafe {
+            lua_pushglobaltable(state);
+            if lua_getfield(state, LUA_REGISTRYINDEX, WATCHED.as_ptr()) == LUA_TTABLE {
+                for name in &self.watched {
+                    let name = CString::new(name.as_str()).unwrap_or_default();
+                    lua_pushstring(state, name.as_ptr());
+                    lua_getfield(state, -2, name.as_ptr());
+                    lua_rawset(state, -4);
+                }
+            }
+            lua_pop(state, 2);
+
+            lua_pushnil(state);
+            lua_setfield(state, LUA_REGISTRYINDEX, WATCHED.as_ptr());
+        }
+
+        self.watched.clear();
+        self.watcher = None;
+        self.update_metatable(state);
+    }
+
+    /// Installs the metatable on the global table while any rule is active, and removes it otherwise.
+    fn update_metatable(&self, state: State) {
+        unsafe {
+            lua_pushglobaltable(state);
+            if self.strict || !self.watched.is_empty() {
+                lua_createtable(state, 0, 3);
+                lua_pushcfunction(state, index);
+                lua_setfield(state, -2, c"__index".as_ptr());
+                lua_pushcfunction(state, new_index);
+                lua_setfield(state, -2, c"__newindex".as_ptr());
+                // Stop scripts from replacing or removing the metatable
+                lua_pushboolean(state, 0);
+                lua_setfield(state, -2, c"__metatable".as_ptr());
+            } else {
+                lua_pushnil(state);
+            }
+            lua_setmetatable(state, -2);
+            lua_pop(state, 1);
+        }
+    }
+}
+
+/// How an access to a global missing from the global table is handled.
+enum Access {
+    Allowed,
+    Watched,
+    /// The access is an error, whose message has been pushed.
+    Rejected,
+}
+
+/// Returns the name of the global being accessed, if the key is a string.
+unsafe fn key_name(state: State) -> Option<String> {
+    if lua_type(state, 2) != LUA_TSTRING {
+        return None;
+    }
+
+    Some(
+        CStr::from_ptr(lua_tostring(state, 2))
+            .to_string_lossy()
+            .into(),
+    )
+}
+
+/// Pushes an error message with the location of the code accessing the global.
+pub(crate) unsafe fn push_error(state: State, message: &str) {
+    let mut ar = LuaDebug::new();
+    let mut location = String::new();
+    if lua_getstack(state, 1, &mut ar) != 0 {
+        lua_getinfo(state, c"Sl".as_ptr(), &mut ar);
+        if ar.currentline > 0 {
+            let source = CStr::from_ptr(ar.short_src.as_ptr()).to_string_lossy();
+            location = format!("{source}:{}: ", ar.currentline);
+        }
+    }
+
+    let message = CString::new(format!("{location}{message}")).unwrap_or_default();
+    lua_pushstring(state, message.as_ptr());
+}
+
+/// Returns whether the code accessing the global is a main chunk or a C function.
+unsafe fn called_from_main(state: State) -> bool {
+    let mut ar = LuaDebug::new();
+    if lua_getstack(state, 1, &mut ar) == 0 {
+        return true;
+    }
+
+    lua_getinfo(state, c"S".as_ptr(), &mut ar);
+    matches!(CStr::from_ptr(ar.what).to_bytes(), b"main" | b"C")
+}
+
+/// Decides how to handle reading the global.
+unsafe fn check_read(state: State) -> Access {
+    let (Some(name), Some(extra)) = (key_name(state), Extra::from_state(state)) else {
+        return Access::Allowed;
+    };
+    let globals = extra.globals();
+
+    if globals.watched.contains(&name) {
+        Access::Watched
+    } else if globals.strict && !globals.declared.contains(&name) {
+        push_error(state, &format!("variable '{name}' is not declared"));
+        Access::Rejected
+    } else {
+        Access::Allowed
+    }
+}
+
+/// Decides how to handle writing the global. Assigning in a main chunk declares it.
+unsafe fn check_write(state: State) -> Access {
+    let (Some(name), Some(extra)) = (key_name(state), Extra::from_state(state)) else {
+        return Access::Allowed;
+    };
+    let globals = extra.globals();
+
+    if globals.watched.contains(&name) {
+        Access::Watched
+    } else if !globals.strict || globals.declared.contains(&name) {
+        Access::Allowed
+    } else if called_from_main(state) {
+        globals.declared.insert(name);
+        Access::Allowed
+    } else {
+        push_error(state, &format!("assign to undeclared variable '{name}'"));
+        Access::Rejected
+    }
+}
+
+/// Calls the watcher with the value being written.
+unsafe fn notify(state: State) {
+    let (Some(name), Some(extra)) = (key_name(state), Extra::from_state(state)) else {
+        return;
+    };
+
+    if let Some(watcher) = &mut extra.globals().watcher {
+        watcher(&Variable {
+            name,
+            value: describe(state, 3),
+            m_type: Type::get_type(state, 3).unwrap_or(Type::Nil),
+        });
+    }
+}
+
+/// `__index` of the global table.
+unsafe extern "C" fn index(state: State) -> Int {
+    match check_read(state) {
+        Access::Allowed => lua_pushnil(state),
+        Access::Watched => {
+            lua_getfield(state, LUA_REGISTRYINDEX, WATCHED.as_ptr());
+            lua_pushvalue(state, 2);
+            lua_rawget(state, -2);
+        }
+        // Nothing owned is alive here, as raising the error never returns
+        Access::Rejected => {
+            lua_error(state);
+        }
+    }
+
+    1
+}
+
+/// `__newindex` of the global table.
+unsafe extern "C" fn new_index(state: State) -> Int {
+    match check_write(state) {
+        Access::Allowed => {
+            lua_settop(state, 3);
+            lua_rawset(state, 1);
+        }
+        Access::Watched => {
+            lua_getfield(state, LUA_REGISTRYINDEX, WATCHED.as_ptr());
+            lua_pushvalue(state, 2);
+            lua_pushvalue(state, 3);
+            lua_rawset(state, -3);
+            lua_pop(state, 1);
+            notify(state);
+        }
+        Access::Rejected => {
+            lua_error(state);
+        }
+    }
+
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Data, Error, Library, Lua, Type, Variable};
+    use alloc::{rc::Rc, vec::Vec};
+    use core::cell::RefCell;
+
+    fn strict_lua() -> Lua {
+        let mut lua = Lua::new();
+        lua.activate(Library::all()).unwrap();
+        lua.set_strict(true);
+        lua
+    }
+
+    #[test]
+    fn strict_main_chunk_declares_globals() {
+        let mut lua = strict_lua();
+
+        lua.interpret("player = nil\nfunction f() player = 1 return player end")
+            .unwrap();
+        assert_eq!(Ok([Data::Number(1.0)]), lua.call("f", []));
+    }
+
+    #[test]
+    fn strict_undeclared_access_returns_error() {
+        let mut lua = strict_lua();
+        lua.interpret_named(
+            "game.lua",
+            "function read() return plyer end\nfunction write()\n  plyer = 0\nend",
+        )
+        .unwrap();
+
+        assert_eq!(
+            Err(Error::Runtime(
+                "game.lua:1: variable 'plyer' is not declared".into()
+            )),
+            lua.call::<1, 0>("read", [])
+        );
+        assert_eq!(
+            Err(Error::Runtime(
+                "game.lua:3: assign to undeclared variable 'plyer'".into()
+            )),
+            lua.call::<0, 0>("write", [])
+        );
+    }
+
+    #[test]
+    fn declare_globals_allows_access() {
+        let mut lua = strict_lua();
+        lua.declare_globals(&["config"]);
+        lua.interpret("function f() config = config or 2 return config end")
+            .unwrap();
+
+        assert_eq!(Ok([Data::Number(2.0)]), lua.call("f", []));
+    }
+
+    #[test]
+    fn set_strict_false_allows_undeclared() {
+        let mut lua = strict_lua();
+        lua.set_strict(false);
+        lua.interpret("function f() x = y end").unwrap();
+
+        assert_eq!(Ok([]), lua.call::<0, 0>("f", []));
+        assert_eq!(Ok(Data::Nil), lua.get_global("x"));
+    }
+
+    #[test]
+    fn watch_globals_sees_every_write() {
+        let writes: Rc<RefCell<Vec<Variable>>> = Rc::default();
+        let mut lua = Lua::new();
+        lua.activate(Library::all()).unwrap();
+        lua.interpret("hp = 10").unwrap();
+
+        let seen = writes.clone();
+        lua.watch_globals(&["hp"], move |write| seen.borrow_mut().push(write.clone()));
+        lua.interpret("hp = hp - 3\nhp = 'dead'\nother = 1")
+            .unwrap();
+
+        let writes_seen = writes.borrow();
+        let values: Vec<(&str, &str, Type)> = writes_seen
+            .iter()
+            .map(|w| (w.name.as_str(), w.value.as_str(), w.m_type))
+            .collect();
+        assert_eq!(
+            vec![("hp", "7", Type::Number), ("hp", "\"dead\"", Type::String)],
+            values
+        );
+        assert_eq!(Ok(Data::String("dead".into())), lua.get_global("hp"));
+        drop(writes_seen);
+
+        lua.unwatch_globals();
+        lua.interpret("hp = 1").unwrap();
+        assert_eq!(2, writes.borrow().len());
+        assert_eq!(Ok(Data::Number(1.0)), lua.get_global("hp"));
+    }
+
+    #[test]
+    fn watch_globals_hides_values_from_raw_access() {
+        let mut lua = Lua::new();
+        lua.activate(Library::all()).unwrap();
+        lua.interpret("hp = 10").unwrap();
+        lua.watch_globals(&["hp"], |_| {});
+
+        lua.interpret(
+            "function check()
+  local listed = false
+  for name in pairs(_G) do listed = listed or name == 'hp' end
+  return hp, rawget(_G, 'hp'), listed
+end",
+        )
+        .unwrap();
+        assert_eq!(
+            Ok([Data::Number(10.0), Data::Nil, Data::Bool(false)]),
+            lua.call::<3, 0>("check", [])
+        );
+
+        lua.unwatch_globals();
+        assert_eq!(
+            Ok([Data::Number(10.0), Data::Number(10.0), Data::Bool(true)]),
+            lua.call::<3, 0>("check", [])
+        );
+    }
+}
@@ -0,0 +1,608 @@
+//! Runs tests written in Lua, each in a fresh `Lua` state.
+
+use crate::{coverage::escape_xml, lua_core::LuaFn, BufferOutput, Data, Error, Library, Lua};
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// Lua code run in every test state before the test file.
+/// It provides the `assert` library, test discovery and recording mocks.
+const PRELUDE: &str = r#"
+local base_assert = assert
+local registered, prefix, list = {}, {}, {}
+
+-- Lua 5.1 has neither table.pack nor table.move, and unpack is a global there
+local unpack = table.unpack or unpack
+local function pack(...) return { n = select('#', ...), ... } end
+local function append(to, from)
+  for _, value in ipairs(from) do to[#to + 1] = value end
+  return to
+end
+
+local function show(value, seen)
+  if type(value) == "string" then
+    return string.format("%q", value)
+  elseif type(value) ~= "table" then
+    return tostring(value)
+  end
+
+  seen = seen or {}
+  if seen[value] then return "<cycle>" end
+  seen[value] = true
+
+  local keys = {}
+  for key in pairs(value) do keys[#keys + 1] = key end
+  table.sort(keys, function(a, b) return tostring(a) < tostring(b) end)
+
+  local parts = {}
+  for _, key in ipairs(keys) do
+    parts[#parts + 1] = "[" .. show(key, seen) .. "] = " .. show(value[key], seen)
+  end
+  return "{" .. table.concat(parts, ", ") .. "}"
+end
+
+local function deep_eq(a, b)
+  if a == b then return true end
+  if type(a) ~= "table" or type(b) ~= "table" then return false end
+  for key, value in pairs(a) do
+    if not deep_eq(value, b[key]) then return false end
+  end
+  for key in pairs(b) do
+    if a[key] == nil then return false end
+  end
+  return true
+end
+
+local function fail(message, reason)
+  if message then reason = message .. ": " .. reason end
+  error(reason, 3)
+end
+
+assert = setmetatable({
+  eq = function(actual, expected, message)
+    if not deep_eq(actual, expected) then
+      fail(message, "expected " .. show(expected) .. ", got " .. show(actual))
+    end
+  end,
+  near = function(actual, expected, tolerance, message)
+    tolerance = tolerance or 1e-9
+    if type(actual) ~= "number" or math.abs(actual - expected) > tolerance then
+      fail(message, "expected " .. show(expected) .. " +/- " .. tolerance .. ", got " .. show(actual))
+    end
+  end,
+  raises = function(f, pattern, message)
+    local ok, err = pcall(f)
+    if ok then
+      fail(message, "expected an error")
+    elseif pattern and not string.find(tostring(err), pattern) then
+      fail(message, "expected an error matching " .. show(pattern) .. ", got " .. show(err))
+    end
+    return err
+  end,
+}, { __call = function(_, ...) return base_assert(...) end })
+
+local mocks = {}
+mock = setmetatable({
+  calls = function(name) return base_assert(mocks[name], "not mocked: " .. name).calls end,
+  returns = function(name, ...) base_assert(mocks[name], "not mocked: " .. name).results = pack(...) end,
+  reset = function(name) base_assert(mocks[name], "not mocked: " .. name).calls = {} end,
+}, {
+  __call = function(_, name)
+    local record = { calls = {}, results = pack() }
+    mocks[name] = record
+    _G[name] = function(...)
+      record.calls[#record.calls + 1] = { ... }
+      return unpack(record.results, 1, record.results.n)
+    end
+  end,
+})
+
+function describe(name, f)
+  prefix[#prefix + 1] = name
+  f()
+  prefix[#prefix] = nil
+end
+
+function it(name, f)
+  local parts = append({}, prefix)
+  parts[#parts + 1] = name
+  registered[#registered + 1] = { name = table.concat(parts, " "), f = f }
+end
+
+function __llua_test_names()
+  local found = {}
+  for name, value in pairs(_G) do
+    if type(name) == "string" and name:sub(1, 5) == "test_" and type(value) == "function" then
+      found[#found + 1] = { name = name, f = value, line = debug.getinfo(value, "S").linedefined }
+    end
+  end
+  table.sort(found, function(a, b)
+    if a.line ~= b.line then return a.line < b.line end
+    return a.name < b.name
+  end)
+  append(found, registered)
+  list = found
+
+  local names = {}
+  for i, test in ipairs(list) do names[i] = test.name end
+  return table.concat(names, "\n")
+end
+
+function __llua_test_run(index)
+  local ok, err = xpcall(list[index].f, debug.traceback)
+  if not ok then error(err, 0) end
+end
+"#;
+
+/// Whether a test passed.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Outcome {
+    Passed,
+    /// The test failed with the given message and traceback.
+    Failed(String),
+}
+
+/// The result of running a single test.
+#[derive(Clone, PartialEq, Debug)]
+pub struct TestResult {
+    /// Name of the file the test is in.
+    pub file: String,
+    pub name: String,
+    pub outcome: Outcome,
+    pub duration: Duration,
+    /// What the test printed, followed by what it wrote to `io.stderr`.
+    pub output: String,
+}
+impl TestResult {
+    /// Returns whether the test passed.
+    pub fn passed(&self) -> bool {
+        self.outcome == Outcome::Passed
+    }
+}
+
+/// Runs the tests in Lua files.
+/// Tests are global functions named `test_*`, or registered with `describe` and `it`.
+pub struct TestRunner {
+    /// Host functions set as globals in every test state.
+    host_functions: Vec<(String, LuaFn)>,
+    /// Globals replaced by recording mocks in every test state.
+    mocks: Vec<String>,
+}
+impl TestRunner {
+    /// Creates a runner with no host functions.
+    pub fn new() -> Self {
+        Self {
+            host_functions: Vec::new(),
+            mocks: Vec::new(),
+        }
+    }
+
+    /// Sets a host function as a global in every test state.
+    pub fn host_function(&mut self, name: &str, function: LuaFn) -> &mut Self {
+        self.host_functions.push((name.into(), function));
+        self
+    }
+
+    /// Replaces a global with a recording mock in every test state.
+    /// Tests can read the calls made with `mock.calls(name)` and set its results with `mock.returns(name, ...)`.
+    pub fn mock(&mut self, name: &str) -> &mut Self {
+        self.mocks.push(name.into());
+        self
+    }
+
+    /// Runs every test in the given file.
+    pub fn run_file<P: AsRef<Path>>(&self, path: P) -> io::Result<Vec<TestResult>> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path)?;
+        Ok(self.run_source(&path.to_string_lossy(), &source))
+    }
+
+    /// Runs every test in the given source, using the name as its file name.
+    pub fn run_source(&self, name: &str, source: &str) -> Vec<TestResult> {
+        let start = Instant::now();
+        let output = BufferOutput::new();
+        let names = match self
+            .load(name, source, &output)
+            .and_then(|mut lua| discover(&mut lua))
+        {
+            Ok(names) => names,
+            Err(e) => {
+                return vec![TestResult {
+                    file: name.into(),
+                    name: "(load)".into(),
+                    outcome: Outcome::Failed(error_message(e)),
+                    duration: start.elapsed(),
+                    output: output.stdout() + &output.stderr(),
+                }]
+            }
+        };
+
+        names
+            .into_iter()
+            .enumerate()
+            .map(|(index, test)| {
+                let start = Instant::now();
+                let output = BufferOutput::new();
+                let result = self.load(name, source, &output).and_then(|mut lua| {
+                    // Discovery must run again to find the test in the new state
+                    discover(&mut lua)?;
+                    lua.call::<0, 1>("__llua_test_run", [Data::from(index as i64 + 1)])?;
+                    Ok(())
+                });
+
+                TestResult {
+                    file: name.into(),
+                    name: test,
+                    outcome: match result {
+                        Ok(()) => Outcome::Passed,
+                        Err(e) => Outcome::Failed(error_message(e)),
+                    },
+                    duration: start.elapsed(),
+                    output: output.stdout() + &output.stderr(),
+                }
+            })
+            .collect()
+    }
+
+    /// Creates a fresh state and runs the test file in it, capturing what it prints in the output.
+    fn load(&self, name: &str, source: &str, output: &BufferOutput) -> Result<Lua, Error> {
+        let mut lua = Lua::new();
+        lua.activate(Library::all())?;
+        lua.set_output(output.clone());
+        lua.interpret_named("(prelude)", PRELUDE)?;
+
+        for (host_name, function) in &self.host_functions {
+            lua.set_global(host_name, Data::Function(*function))?;
+        }
+        for mock in &self.mocks {
+            lua.call::<0, 1>("mock", [Data::String(mock.clone())])?;
+        }
+
+        lua.interpret_named(name, source)?;
+        Ok(lua)
+    }
+}
+impl Default for TestRunner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the names of the tests defined in the state.
+fn discover(lua: &mut Lua) -> Result<Vec<String>, Error> {
+    match lua.call::<1, 0>("__llua_test_names", [])? {
+        [Data::String(names)] if !names.is_empty() => Ok(names.lines().map(String::from).collect()),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// Returns the message to report for an error.
+fn error_message(error: Error) -> String {
+    match error {
+        Error::Runtime(message) => message,
+        error => format!("{:?}", error),
+    }
+}
+
+/// The results of running tests.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct TestReport {
+    results: Vec<TestResult>,
+}
+impl TestReport {
+    /// Creates an empty report.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the results of running tests.
+    pub fn add<I: IntoIterator<Item = TestResult>>(&mut self, results: I) {
+        self.results.extend(results);
+    }
+
+    /// Returns every result.
+    pub fn results(&self) -> &[TestResult] {
+        &self.results
+    }
+
+    /// Returns the number of tests that passed.
+    pub fn passed(&self) -> usize {
+        self.results.iter().filter(|r| r.passed()).count()
+    }
+
+    /// Returns the number of tests that failed.
+    pub fn failed(&self) -> usize {
+        self.results.len() - self.passed()
+    }
+
+    /// Writes a report for people, including the traceback of each failure.
+    pub fn write_text<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        for result in &self.results {
+            match &result.outcome {
+                Outcome::Passed => writeln!(writer, "PASS {}: {}", result.file, result.name)?,
+                Outcome::Failed(message) => {
+                    writeln!(writer, "FAIL {}: {}", result.file, result.name)?;
+                    for line in message.lines() {
+                        writeln!(writer, "    {}", line)?;
+                    }
+                }
+            }
+        }
+
+        writeln!(writer, "{} passed, {} failed", self.passed(), self.failed())
+    }
+
+    /// Writes the results in the Test Anything Protocol format.
+    pub fn write_tap<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(writer, "TAP version 13")?;
+        writeln!(writer, "1..{}", self.results.len())?;
+
+        for (number, result) in self.results.iter().enumerate() {
+            let number = number + 1;
+            let message = match &result.outcome {
+                Outcome::Passed => {
+                    writeln!(writer, "ok {} - {}: {}", number, result.file, result.name)?;
+                    None
+                }
+                Outcome::Failed(message) => {
+                    writeln!(
+                        writer,
+                        "not ok {} - {}: {}",
+                        number, result.file, result.name
+                    )?;
+                    Some(message)
+                }
+            };
+            if message.is_none() && result.output.is_empty() {
+                continue;
+            }
+
+            // Diagnostics go in a YAML block after the test
+            writeln!(writer, "  ---")?;
+            if let Some(message) = message {
+                writeln!(writer, "  message: |")?;
+                for line in message.lines() {
+                    writeln!(writer, "    {}", line)?;
+                }
+            }
+            if !result.output.is_empty() {
+                writeln!(writer, "  output: |")?;
+                for line in result.output.lines() {
+                    writeln!(writer, "    {}", line)?;
+                }
+            }
+            writeln!(writer, "  ...")?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the results in the JUnit XML format, with a test suite for each file.
+    pub fn write_junit<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut files: Vec<&str> = Vec::new();
+        for result in &self.results {
+            if !files.contains(&result.file.as_str()) {
+                files.push(&result.file);
+            }
+        }
+
+        writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(
+            writer,
+            r#"<testsuites tests="{}" failures="{}">"#,
+            self.results.len(),
+            self.failed()
+        )?;
+
+        for file in files {
+            let results: Vec<&TestResult> =
+                self.results.iter().filter(|r| r.file == file).collect();
+            let failures = results.iter().filter(|r| !r.passed()).count();
+            let time: Duration = results.iter().map(|r| r.duration).sum();
+            let file = escape_xml(file);
+
+            writeln!(
+                writer,
+                r#"  <testsuite name="{file}" tests="{}" failures="{failures}" time="{:.3}">"#,
+                results.len(),
+                time.as_secs_f64()
+            )?;
+            for result in results {
+                let opening = format!(
+                    r#"    <testcase name="{}" classname="{file}" time="{:.3}""#,
+                    escape_xml(&result.name),
+                    result.duration.as_secs_f64()
+                );
+                if result.passed() && result.output.is_empty() {
+                    writeln!(writer, "{opening}/>")?;
+                    continue;
+                }
+
+                writeln!(writer, "{opening}>")?;
+                if let Outcome::Failed(message) = &result.outcome {
+                    let summary = message.lines().next().unwrap_or_default();
+                    writeln!(
+                        writer,
+                        r#"      <failure message="{}">{}</failure>"#,
+                        escape_xml(summary),
+                        escape_xml(message)
+                    )?;
+                }
+                if !result.output.is_empty() {
+                    writeln!(
+                        writer,
+                        "      <system-out>{}</system-out>",
+                        escape_xml(&result.output)
+                    )?;
+                }
+                writeln!(writer, "    </testcase>")?;
+            }
+            writeln!(writer, "  </testsuite>")?;
+        }
+
+        writeln!(writer, "</testsuites>")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lua_core::State, Int, Stack};
+
+    fn spawn(state: State) -> Int {
+        Stack::new(state).push(Data::Bool(true));
+        1
+    }
+
+    fn run(source: &str) -> Vec<TestResult> {
+        TestRunner::new().run_source("spec.lua", source)
+    }
+
+    #[test]
+    fn run_source_discovers_test_functions() {
+        let results = run(
+            "function test_b() end\nfunction test_a() error('boom') end\nfunction helper() end",
+        );
+
+        assert_eq!(2, results.len());
+        assert_eq!("test_b", results[0].name);
+        assert!(results[0].passed());
+        assert_eq!("test_a", results[1].name);
+        assert!(!results[1].passed());
+    }
+
+    #[test]
+    fn run_source_discovers_describe_it() {
+        let results = run("describe('player', function()
+  it('moves', function() end)
+  describe('when dead', function()
+    it('stops', function() assert(false, 'still moving') end)
+  end)
+end)");
+
+        let names: Vec<&str> = results.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(vec!["player moves", "player when dead stops"], names);
+        assert!(results[0].passed());
+        assert!(!results[1].passed());
+    }
+
+    #[test]
+    fn run_source_uses_fresh_state_per_test() {
+        let results = run("count = 0
+function test_first() count = count + 1 assert.eq(count, 1) end
+function test_second() count = count + 1 assert.eq(count, 1) end");
+
+        assert!(results.iter().all(TestResult::passed));
+    }
+
+    #[test]
+    fn failures_include_traceback() {
+        let results = run("function test_eq()\n  assert.eq({1, 2}, {1, 3})\nend");
+
+        let Outcome::Failed(message) = &results[0].outcome else {
+            panic!("test passed");
+        };
+        assert!(
+            message.starts_with("spec.lua:2: expected {[1] = 1, [2] = 3}, got {[1] = 1, [2] = 2}")
+        );
+        assert!(message.contains("stack traceback:"));
+    }
+
+    #[test]
+    fn assert_library_checks_values() {
+        let results = run("function test_pass()
+  assert.eq({a = {1}}, {a = {1}})
+  assert.near(0.1 + 0.2, 0.3, 1e-9)
+  assert.raises(function() error('bad input') end, 'bad')
+  assert(true)
+end
+function test_near() assert.near(1.0, 1.1, 0.01) end
+function test_raises() assert.raises(function() end) end");
+
+        assert!(results[0].passed());
+        assert!(!results[1].passed());
+        assert!(!results[2].passed());
+    }
+
+    #[test]
+    fn load_error_is_reported() {
+        let results = run("function test_a(");
+
+        assert_eq!(1, results.len());
+        assert_eq!("(load)", results[0].name);
+        assert!(!results[0].passed());
+    }
+
+    #[test]
+    fn mocks_record_host_calls() {
+        let source = "function test_spawn()
+  mock.returns('spawn', 42)
+  assert.eq(spawn('orc', 3), 42)
+  assert.eq(mock.calls('spawn'), {{'orc', 3}})
+end
+function test_real() assert.eq(real('x'), true) end";
+
+        let mut runner = TestRunner::new();
+        runner.host_function("spawn", spawn).mock("spawn");
+        runner.host_function("real", spawn);
+        let results = runner.run_source("spec.lua", source);
+
+        assert!(results.iter().all(TestResult::passed), "{results:?}");
+    }
+
+    #[test]
+    fn write_tap_outputs_results() {
+        let mut report = TestReport::new();
+        report.add(run(
+            "function test_ok() end\nfunction test_bad() error('no', 0) end",
+        ));
+
+        let mut tap = Vec::new();
+        report.write_tap(&mut tap).unwrap();
+        let tap = String::from_utf8(tap).unwrap();
+
+        assert!(tap.starts_with("TAP version 13\n1..2\nok 1 - spec.lua: test_ok\nnot ok 2 - spec.lua: test_bad\n  ---\n  message: |\n    no\n"));
+        assert!(tap.ends_with("  ...\n"));
+    }
+
+    #[test]
+    fn write_junit_outputs_suites() {
+        let mut report = TestReport::new();
+        report.add(run(
+            "function test_ok() end\nfunction test_bad() error('a < b', 0) end",
+        ));
+
+        let mut xml = Vec::new();
+        report.write_junit(&mut xml).unwrap();
+        let xml = String::from_utf8(xml).unwrap();
+
+        assert!(xml.contains(r#"<testsuites tests="2" failures="1">"#));
+        assert!(xml.contains(r#"<testsuite name="spec.lua" tests="2" failures="1""#));
+        assert!(xml.contains(r#"<failure message="a &lt; b">"#));
+        assert_eq!(1, report.passed());
+        assert_eq!(1, report.failed());
+    }
+
+    #[test]
+    fn output_is_captured_per_test() {
+        let mut report = TestReport::new();
+        report.add(run("function test_a() print('from a') end
+function test_b() io.write('<b>') io.stderr:write('!') end"));
+
+        assert_eq!("from a\n", report.results()[0].output);
+        assert_eq!("<b>!", report.results()[1].output);
+
+        let mut tap = Vec::new();
+        report.write_tap(&mut tap).unwrap();
+        let tap = String::from_utf8(tap).unwrap();
+        assert!(tap.contains("ok 1 - spec.lua: test_a\n  ---\n  output: |\n    from a\n  ...\n"));
+
+        let mut xml = Vec::new();
+        report.write_junit(&mut xml).unwrap();
+        let xml = String::from_utf8(xml).unwrap();
+        assert!(xml.contains("<system-out>&lt;b&gt;!</system-out>"));
+    }
+}
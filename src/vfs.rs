@@ -0,0 +1,1014 @@
+//! Virtual filesystems that scripts and files are read through.
+//!
+//! Once a `Lua` is given a `Vfs` with `Lua::set_vfs`, `require`, `loadfile`, `dofile` and the io library read through it instead of the disk.
+//! Files opened through the io library are read-only.
+
+use crate::{
+    extra::Extra,
+    lua_core::*,
+    overrides::{call_replaced, check, replace},
+    Library,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::{c_char, CStr, CString},
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+/// Registry field holding the metatable of files opened through the filesystem.
+const FILE_METATABLE: &CStr = c"llua.vfs.file";
+
+/// Methods of files opened through the filesystem.
+const FILE_METHODS: &[(&CStr, CFunction)] = &[
+    (c"close", file_close),
+    (c"flush", file_flush),
+    (c"lines", file_lines),
+    (c"read", file_read),
+    (c"seek", file_seek),
+    (c"setvbuf", file_setvbuf),
+    (c"write", file_write),
+];
+
+/// A filesystem that files are read from.
+/// Paths are separated by `/` and relative to the root of the filesystem, which `..` can't leave.
+pub trait Vfs {
+    /// Opens the file for reading.
+    fn open(&self, path: &str) -> io::Result<Box<dyn Read + '_>>;
+
+    /// Reads the whole file.
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        self.open(path)?.read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    /// Returns the sorted names of the entries in the directory.
+    fn list(&self, path: &str) -> io::Result<Vec<String>>;
+
+    /// Returns whether the file exists.
+    fn exists(&self, path: &str) -> bool;
+}
+
+/// Files held in memory.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryVfs {
+    files: BTreeMap<String, Vec<u8>>,
+}
+impl MemoryVfs {
+    /// Creates an empty filesystem.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the file, replacing any existing one. Paths leaving the root are ignored.
+    pub fn insert(&mut self, path: &str, contents: impl Into<Vec<u8>>) -> &mut Self {
+        if let Ok(path) = normalize(path) {
+            self.files.insert(path, contents.into());
+        }
+
+        self
+    }
+}
+impl Vfs for MemoryVfs {
+    fn open(&self, path: &str) -> io::Result<Box<dyn Read + '_>> {
+        let contents = self.files.get(&normalize(path)?).ok_or_else(not_found)?;
+        Ok(Box::new(contents.as_slice()))
+    }
+
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.files
+            .get(&normalize(path)?)
+            .cloned()
+            .ok_or_else(not_found)
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        let directory = normalize(path)?;
+        let prefix = if directory.is_empty() {
+            directory
+        } else {
+            format!("{directory}/")
+        };
+
+        let entries: BTreeSet<&str> = self
+            .files
+            .keys()
+            .filter_map(|file| file.strip_prefix(&prefix))
+            .filter_map(|rest| rest.split('/').next())
+            .collect();
+        if entries.is_empty() && !prefix.is_empty() {
+            return Err(not_found());
+        }
+
+        Ok(entries.into_iter().map(String::from).collect())
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        normalize(path).is_ok_and(|path| self.files.contains_key(&path))
+    }
+}
+
+/// A directory on disk. Paths can't reach outside of it, not even through symbolic links.
+#[derive(Clone, Debug)]
+pub struct DirVfs {
+    root: PathBuf,
+}
+impl DirVfs {
+    /// Uses the directory as the root. Fails if it doesn't exist.
+    pub fn new(root: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self {
+            root: root.as_ref().canonicalize()?,
+        })
+    }
+
+    /// Returns where the path is on disk, checking it is inside the root.
+    fn resolve(&self, path: &str) -> io::Result<PathBuf> {
+        let resolved = self.root.join(normalize(path)?).canonicalize()?;
+        if !resolved.starts_with(&self.root) {
+            return Err(outside_root(path));
+        }
+
+        Ok(resolved)
+    }
+}
+impl Vfs for DirVfs {
+    fn open(&self, path: &str) -> io::Result<Box<dyn Read + '_>> {
+        Ok(Box::new(File::open(self.resolve(path)?)?))
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        let mut entries = fs::read_dir(self.resolve(path)?)?
+            .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().into_owned()))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort();
+
+        Ok(entries)
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.resolve(path).is_ok_and(|path| path.is_file())
+    }
+}
+
+/// Layers of filesystems, where files in earlier layers hide the ones with the same path in later layers.
+#[derive(Default)]
+pub struct OverlayVfs {
+    layers: Vec<Box<dyn Vfs>>,
+}
+impl OverlayVfs {
+    /// Stacks the layers, the first one on top.
+    pub fn new(layers: Vec<Box<dyn Vfs>>) -> Self {
+        Self { layers }
+    }
+
+    /// Adds a layer below the existing ones.
+    pub fn push<V: Vfs + 'static>(&mut self, layer: V) -> &mut Self {
+        self.layers.push(Box::new(layer));
+        self
+    }
+}
+impl Vfs for OverlayVfs {
+    fn open(&self, path: &str) -> io::Result<Box<dyn Read + '_>> {
+        for layer in &self.layers {
+            match layer.open(path) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                result => return result,
+            }
+        }
+
+        Err(not_found())
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        let mut entries = BTreeSet::new();
+        let mut found = false;
+        for layer in &self.layers {
+            match layer.list(path) {
+                Ok(listing) => {
+                    entries.extend(listing);
+                    found = true;
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        if !found {
+            return Err(not_found());
+        }
+
+        Ok(entries.into_iter().collect())
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.layers.iter().any(|layer| layer.exists(path))
+    }
+}
+
+/// Removes `.` and empty components from the path and resolves `..`, failing if it leaves the root.
+fn normalize(path: &str) -> io::Result<String> {
+    let mut components = Vec::new();
+    for component in path.split(['/', '\\']) {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop().ok_or_else(|| outside_root(path))?;
+            }
+            _ => components.push(component),
+        }
+    }
+
+    Ok(components.join("/"))
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "No such file or directory")
+}
+
+fn outside_root(path: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("{path} is outside the root"),
+    )
+}
+
+/// Replaces the functions of the given libraries that read files with ones reading through the state's filesystem.
+/// Only functions that are installed get replaced.
+pub(crate) fn install(state: State, libraries: &[Library]) {
+    unsafe {
+        for library in libraries {
+            match library {
+                Library::Basic => {
+                    lua_pushglobaltable(state);
+                    replace(state, c"dofile", do_file);
+                    replace(state, c"loadfile", load_file);
+                    lua_pop(state, 1);
+                }
+                Library::Package => {
+                    if lua_getglobal(state, c"package".as_ptr()) == LUA_TTABLE {
+                        install_searcher(state);
+                        replace(state, c"searchpath", search_path);
+                        // C libraries are loaded from the disk
+                        lua_pushnil(state);
+                        lua_setfield(state, -2, c"loadlib".as_ptr());
+                    }
+                    lua_pop(state, 1);
+                }
+                Library::Io => {
+                    if lua_getglobal(state, c"io".as_ptr()) == LUA_TTABLE {
+                        replace(state, c"close", io_close);
+                        replace(state, c"lines", io_lines);
+                        replace(state, c"open", io_open);
+                        replace(state, c"type", io_type);
+                        for name in [c"input", c"output", c"popen", c"tmpfile"] {
+                            lua_pushnil(state);
+                            lua_setfield(state, -2, name.as_ptr());
+                        }
+                    }
+                    lua_pop(state, 1);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Replaces the searcher for Lua files in the package table on top of the stack, and removes the ones for C libraries.
+unsafe fn install_searcher(state: State) {
+    #[cfg(lua_version = "5.1")]
+    let searchers = c"loaders";
+    #[cfg(not(lua_version = "5.1"))]
+    let searchers = c"searchers";
+
+    if lua_getfield(state, -1, searchers.as_ptr()) == LUA_TTABLE {
+        lua_pushvalue(state, -2);
+        lua_pushcclosure(state, search, 1);
+        lua_rawseti(state, -2, 2);
+        for n in [4, 3] {
+            lua_pushnil(state);
+            lua_rawseti(state, -2, n);
+        }
+    }
+    lua_pop(state, 1);
+}
+
+/// Returns the string or number at the index.
+unsafe fn string_arg(state: State, index: Int) -> Option<String> {
+    if lua_isstring(state, index) == 0 {
+        return None;
+    }
+
+    let mut len = 0;
+    let string = lua_tolstring(state, index, &mut len) as *const u8;
+    Some(String::from_utf8_lossy(std::slice::from_raw_parts(string, len)).into_owned())
+}
+
+unsafe fn push_bytes(state: State, bytes: &[u8]) {
+    lua_pushlstring(state, bytes.as_ptr() as *const c_char, bytes.len());
+}
+
+/// Pushes nil and the message, returning how many values were pushed.
+unsafe fn push_fail(state: State, message: &str) -> Int {
+    lua_pushnil(state);
+    push_bytes(state, message.as_bytes());
+    2
+}
+
+/// Reads the file through the state's filesystem.
+unsafe fn read(state: State, path: &str) -> io::Result<Vec<u8>> {
+    match Extra::from_state(state).and_then(|extra| extra.vfs()) {
+        Some(vfs) => vfs.read(path),
+        None => Err(not_found()),
+    }
+}
+
+/// Returns whether the file exists in the state's filesystem.
+unsafe fn exists(state: State, path: &str) -> bool {
+    Extra::from_state(state)
+        .and_then(|extra| extra.vfs())
+        .is_some_and(|vfs| vfs.exists(path))
+}
+
+/// Loads the file as a chunk and pushes it, setting the value at `env` as its environment.
+unsafe fn load(
+    state: State,
+    path: &str,
+    mode: Option<&str>,
+    env: Option<Int>,
+) -> Result<(), String> {
+    let contents = read(state, path).map_err(|e| format!("cannot open {path}: {e}"))?;
+    let name = CString::new(format!("@{path}")).unwrap_or_default();
+    let mode = mode.map(|mode| CString::new(mode).unwrap_or_default());
+
+    let result = luaL_loadbufferx(
+        state,
+        contents.as_ptr() as *const c_char,
+        contents.len(),
+        name.as_ptr(),
+        mode.as_ref().map_or(std::ptr::null(), |mode| mode.as_ptr()),
+    );
+    if result != LUA_OK {
+        let message = string_arg(state, -1).unwrap_or_default();
+        lua_pop(state, 1);
+        return Err(message);
+    }
+
+    if let Some(env) = env {
+        lua_pushvalue(state, env);
+        set_env(state);
+    }
+
+    Ok(())
+}
+
+/// Sets the value on top of the stack as the environment of the chunk below it, popping the value.
+#[cfg(lua_version = "5.1")]
+unsafe fn set_env(state: State) {
+    lua_setfenv(state, -2);
+}
+
+/// Sets the value on top of the stack as the environment of the chunk below it, popping the value.
+#[cfg(not(lua_version = "5.1"))]
+unsafe fn set_env(state: State) {
+    if lua_setupvalue(state, -2, 1).is_null() {
+        lua_pop(state, 1);
+    }
+}
+
+/// `loadfile([filename [, mode [, env]]])`, reading standard input through the replaced function.
+unsafe extern "C" fn load_file(state: State) -> Int {
+    let Some(path) = string_arg(state, 1) else {
+        return call_replaced(state);
+    };
+    let mode = string_arg(state, 2);
+    let env = (lua_gettop(state) >= 3).then_some(3);
+
+    match load(state, &path, mode.as_deref(), env) {
+        Ok(()) => 1,
+        Err(message) => push_fail(state, &message),
+    }
+}
+
+/// `dofile([filename])`, reading standard input through the replaced function.
+unsafe extern "C" fn do_file(state: State) -> Int {
+    let loaded = match string_arg(state, 1) {
+        Some(path) => load(state, &path, None, None),
+        None => return call_replaced(state),
+    };
+    if let Err(message) = loaded {
+        push_bytes(state, message.as_bytes());
+        drop(message);
+        return lua_error(state);
+    }
+
+    let base = lua_gettop(state) - 1;
+    lua_call(state, 0, LUA_MULTRET);
+    lua_gettop(state) - base
+}
+
+/// Looks for the name in the templates separated by `;`, replacing `?` with it.
+/// Returns the first file that exists, or the list of files tried.
+unsafe fn find(
+    state: State,
+    name: &str,
+    templates: &str,
+    separator: &str,
+    replacement: &str,
+) -> Result<String, String> {
+    let name = if separator.is_empty() {
+        name.into()
+    } else {
+        name.replace(separator, replacement)
+    };
+
+    let mut tried = String::new();
+    for template in templates.split(';').filter(|template| !template.is_empty()) {
+        let filename = template.replace('?', &name);
+        if exists(state, &filename) {
+            return Ok(filename);
+        }
+        tried.push_str(&format!("\n\tno file '{filename}'"));
+    }
+
+    Err(tried)
+}
+
+/// `package.searchpath(name, path [, sep [, rep]])`
+unsafe extern "C" fn search_path(state: State) -> Int {
+    let result = (|| -> Result<Int, &str> {
+        let name = string_arg(state, 1).ok_or("bad argument #1 to 'searchpath'")?;
+        let templates = string_arg(state, 2).ok_or("bad argument #2 to 'searchpath'")?;
+        let separator = string_arg(state, 3).unwrap_or_else(|| ".".into());
+        let replacement = string_arg(state, 4).unwrap_or_else(|| "/".into());
+
+        Ok(
+            match find(state, &name, &templates, &separator, &replacement) {
+                Ok(filename) => {
+                    push_bytes(state, filename.as_bytes());
+                    1
+                }
+                Err(tried) => push_fail(state, tried.trim_start_matches("\n\t")),
+            },
+        )
+    })();
+
+    check(state, result.map_err(String::from))
+}
+
+/// Searcher for Lua modules in the filesystem, with the package table as its upvalue.
+unsafe extern "C" fn search(state: State) -> Int {
+    let result = search_module(state);
+    check(state, result)
+}
+
+unsafe fn search_module(state: State) -> Result<Int, String> {
+    let name = string_arg(state, 1).unwrap_or_default();
+    lua_getfield(state, lua_upvalueindex(1), c"path".as_ptr());
+    let templates = string_arg(state, -1).ok_or("'package.path' must be a string")?;
+    lua_pop(state, 1);
+
+    match find(state, &name, &templates, ".", "/") {
+        Ok(filename) => {
+            load(state, &filename, None, None).map_err(|message| {
+                format!("error loading module '{name}' from file '{filename}':\n\t{message}")
+            })?;
+            push_bytes(state, filename.as_bytes());
+            Ok(2)
+        }
+        Err(tried) => {
+            push_bytes(state, tried.as_bytes());
+            Ok(1)
+        }
+    }
+}
+
+/// Pushes the metatable of files, creating it the first time.
+unsafe fn push_file_metatable(state: State) {
+    if lua_getfield(state, LUA_REGISTRYINDEX, FILE_METATABLE.as_ptr()) == LUA_TTABLE {
+        return;
+    }
+    lua_pop(state, 1);
+
+    lua_newtable(state);
+    lua_newtable(state);
+    for (name, method) in FILE_METHODS {
+        lua_pushcfunction(state, *method);
+        lua_setfield(state, -2, name.as_ptr());
+    }
+    lua_setfield(state, -2, c"__index".as_ptr());
+    lua_pushstring(state, c"FILE*".as_ptr());
+    lua_setfield(state, -2, c"__name".as_ptr());
+    lua_pushcfunction(state, file_tostring);
+    lua_setfield(state, -2, c"__tostring".as_ptr());
+
+    lua_pushvalue(state, -1);
+    lua_setfield(state, LUA_REGISTRYINDEX, FILE_METATABLE.as_ptr());
+}
+
+/// Pushes a file with the contents of the path.
+unsafe fn push_file(state: State, path: &str) -> io::Result<()> {
+    let contents = read(state, path)?;
+
+    lua_createtable(state, 0, 2);
+    push_bytes(state, &contents);
+    lua_setfield(state, -2, c"contents".as_ptr());
+    lua_pushinteger(state, 0);
+    lua_setfield(state, -2, c"position".as_ptr());
+    push_file_metatable(state);
+    lua_setmetatable(state, -2);
+
+    Ok(())
+}
+
+/// Returns whether the value at the index is a file opened through the filesystem.
+unsafe fn is_file(state: State, index: Int) -> bool {
+    if lua_type(state, index) != LUA_TTABLE || lua_getmetatable(state, index) == 0 {
+        return false;
+    }
+
+    lua_getfield(state, LUA_REGISTRYINDEX, FILE_METATABLE.as_ptr());
+    let is_file = lua_rawequal(state, -1, -2) != 0;
+    lua_pop(state, 2);
+
+    is_file
+}
+
+/// Returns the contents of the open file at the index.
+/// They stay valid while the file is on the stack and open.
+unsafe fn contents<'a>(state: State, index: Int) -> Result<&'a [u8], String> {
+    if !is_file(state, index) {
+        return Err(format!("bad argument #{index} (FILE* expected)"));
+    }
+
+    lua_getfield(state, index, c"contents".as_ptr());
+    let mut len = 0;
+    let contents = lua_tolstring(state, -1, &mut len) as *const u8;
+    lua_pop(state, 1);
+    if contents.is_null() {
+        return Err("attempt to use a closed file".into());
+    }
+
+    Ok(std::slice::from_raw_parts(contents, len))
+}
+
+unsafe fn position(state: State, index: Int) -> usize {
+    lua_getfield(state, index, c"position".as_ptr());
+    let position = lua_tointeger(state, -1);
+    lua_pop(state, 1);
+
+    position.max(0) as usize
+}
+
+unsafe fn set_position(state: State, index: Int, position: usize) {
+    lua_pushinteger(state, position as LuaInt);
+    lua_setfield(state, index, c"position".as_ptr());
+}
+
+/// What `read` reads.
+enum Format {
+    Bytes(usize),
+    Number,
+    Line { keep_newline: bool },
+    All,
+}
+
+unsafe fn format(state: State, index: Int) -> Option<Format> {
+    if lua_type(state, index) == LUA_TNUMBER {
+        return Some(Format::Bytes(lua_tointeger(state, index).max(0) as usize));
+    }
+
+    match string_arg(state, index)?
+        .trim_start_matches('*')
+        .chars()
+        .next()
+    {
+        Some('n') => Some(Format::Number),
+        Some('l') => Some(Format::Line {
+            keep_newline: false,
+        }),
+        Some('L') => Some(Format::Line { keep_newline: true }),
+        Some('a') => Some(Format::All),
+        _ => None,
+    }
+}
+
+/// Reads a number from the start of the bytes, returning whether it is one and how many bytes were used.
+unsafe fn push_number(state: State, bytes: &[u8]) -> (bool, usize) {
+    let start = bytes
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(bytes.len());
+    let len = bytes[start..]
+        .iter()
+        .take_while(|b| b.is_ascii_hexdigit() || b"+-.xXpP".contains(b))
+        .count();
+    let text = std::str::from_utf8(&bytes[start..start + len]).unwrap_or_default();
+
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let hex = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+        .and_then(|hex| LuaInt::from_str_radix(hex, 16).ok())
+        .map(|n| if negative { -n } else { n });
+
+    let read = start + len;
+    if let Some(n) = hex.or_else(|| text.parse::<LuaInt>().ok()) {
+        lua_pushinteger(state, n);
+    } else if let Ok(n) = text.parse::<LuaNum>() {
+        lua_pushnumber(state, n);
+    } else {
+        lua_pushnil(state);
+        return (false, read);
+    }
+
+    (true, read)
+}
+
+/// Reads from the file at index 1 with the formats from index `first` on, pushing a value for each.
+/// Reading stops at the first format that fails, which pushes nil.
+unsafe fn read_formats(state: State, first: Int) -> Result<Int, String> {
+    let contents = contents(state, 1)?;
+    let mut position = position(state, 1).min(contents.len());
+
+    let last = lua_gettop(state).max(first);
+    let mut results = 0;
+    for index in first..=last {
+        let format = if lua_type(state, index) == LUA_TNONE {
+            Format::Line {
+                keep_newline: false,
+            }
+        } else {
+            format(state, index)
+                .ok_or_else(|| format!("bad argument #{} to 'read' (invalid format)", index - 1))?
+        };
+
+        let rest = &contents[position..];
+        let (read, used) = match format {
+            Format::All => {
+                push_bytes(state, rest);
+                (true, rest.len())
+            }
+            Format::Number => push_number(state, rest),
+            _ if rest.is_empty() => {
+                lua_pushnil(state);
+                (false, 0)
+            }
+            Format::Bytes(count) => {
+                let count = count.min(rest.len());
+                push_bytes(state, &rest[..count]);
+                (true, count)
+            }
+            Format::Line { keep_newline } => {
+                let (line, used) = match rest.iter().position(|&b| b == b'\n') {
+                    Some(end) => (&rest[..end + keep_newline as usize], end + 1),
+                    None => (rest, rest.len()),
+                };
+                push_bytes(state, line);
+                (true, used)
+            }
+        };
+
+        position += used;
+        results += 1;
+        if !read {
+            break;
+        }
+    }
+
+    set_position(state, 1, position);
+    Ok(results)
+}
+
+/// Pushes an iterator reading the file at index 1 with the formats above the flag at index 2.
+unsafe fn push_lines(state: State) {
+    lua_pushcclosure(state, lines_iterator, lua_gettop(state));
+}
+
+/// Iterator over a file, with the file, whether to close it at the end, and the formats as upvalues.
+unsafe extern "C" fn lines_iterator(state: State) -> Int {
+    lua_settop(state, 0);
+    lua_pushvalue(state, lua_upvalueindex(1));
+    let mut upvalue = 3;
+    while lua_type(state, lua_upvalueindex(upvalue)) != LUA_TNONE {
+        lua_pushvalue(state, lua_upvalueindex(upvalue));
+        upvalue += 1;
+    }
+
+    let result = read_formats(state, 2);
+    let results = check(state, result);
+    if lua_type(state, -results) == LUA_TNIL && lua_toboolean(state, lua_upvalueindex(2)) != 0 {
+        close(state, 1);
+    }
+
+    results
+}
+
+unsafe fn close(state: State, index: Int) {
+    lua_pushnil(state);
+    lua_setfield(state, index, c"contents".as_ptr());
+}
+
+/// `io.open(filename [, mode])`, failing for modes other than reading.
+unsafe extern "C" fn io_open(state: State) -> Int {
+    let result = (|| -> Result<Int, &str> {
+        let path = string_arg(state, 1).ok_or("bad argument #1 to 'open' (string expected)")?;
+        let mode = string_arg(state, 2).unwrap_or_else(|| "r".into());
+        if !mode.starts_with('r') || mode.contains('+') {
+            return Ok(push_fail(state, &format!("{path}: read-only filesystem")));
+        }
+
+        Ok(match push_file(state, &path) {
+            Ok(()) => 1,
+            Err(e) => push_fail(state, &format!("{path}: {e}")),
+        })
+    })();
+
+    check(state, result.map_err(String::from))
+}
+
+/// `io.lines([filename, ...])`, reading standard input through the replaced function.
+unsafe extern "C" fn io_lines(state: State) -> Int {
+    let Some(path) = string_arg(state, 1) else {
+        return call_replaced(state);
+    };
+
+    let opened = push_file(state, &path).map_err(|e| format!("{path}: {e}"));
+    drop(path);
+    check(state, opened.map(|()| 0));
+
+    lua_replace(state, 1);
+    lua_pushboolean(state, 1);
+    lua_insert(state, 2);
+    push_lines(state);
+    1
+}
+
+/// `io.type(obj)`, for other values than files from the filesystem through the replaced function.
+unsafe extern "C" fn io_type(state: State) -> Int {
+    if !is_file(state, 1) {
+        return call_replaced(state);
+    }
+
+    let open = contents(state, 1).is_ok();
+    let name = if open { c"file" } else { c"closed file" };
+    lua_pushstring(state, name.as_ptr());
+    1
+}
+
+/// `io.close([file])`, for other values than files from the filesystem through the replaced function.
+unsafe extern "C" fn io_close(state: State) -> Int {
+    if !is_file(state, 1) {
+        return call_replaced(state);
+    }
+
+    file_close(state)
+}
+
+unsafe extern "C" fn file_close(state: State) -> Int {
+    let result = contents(state, 1).map(|_| 1);
+    let results = check(state, result);
+    close(state, 1);
+    lua_pushboolean(state, 1);
+    results
+}
+
+unsafe extern "C" fn file_flush(state: State) -> Int {
+    let result = contents(state, 1).map(|_| 1);
+    let results = check(state, result);
+    lua_pushvalue(state, 1);
+    results
+}
+
+unsafe extern "C" fn file_lines(state: State) -> Int {
+    let result = contents(state, 1).map(|_| 1);
+    let results = check(state, result);
+    lua_pushboolean(state, 0);
+    lua_insert(state, 2);
+    push_lines(state);
+    results
+}
+
+unsafe extern "C" fn file_read(state: State) -> Int {
+    let result = read_formats(state, 2);
+    check(state, result)
+}
+
+/// `file:seek([whence [, offset]])`
+unsafe extern "C" fn file_seek(state: State) -> Int {
+    let result = (|| -> Result<Int, String> {
+        let len = contents(state, 1)?.len() as LuaInt;
+        let base = match string_arg(state, 2).as_deref().unwrap_or("cur") {
+            "set" => 0,
+            "cur" => position(state, 1) as LuaInt,
+            "end" => len,
+            whence => {
+                return Err(format!(
+                    "bad argument #1 to 'seek' (invalid option '{whence}')"
+                ))
+            }
+        };
+
+        let position = base + lua_tointeger(state, 3);
+        if position < 0 {
+            return Ok(push_fail(state, "Invalid argument"));
+        }
+        set_position(state, 1, position as usize);
+        lua_pushinteger(state, position);
+        Ok(1)
+    })();
+
+    check(state, result)
+}
+
+unsafe extern "C" fn file_setvbuf(state: State) -> Int {
+    let result = contents(state, 1).map(|_| 1);
+    let results = check(state, result);
+    lua_pushboolean(state, 1);
+    results
+}
+
+unsafe extern "C" fn file_write(state: State) -> Int {
+    let result = contents(state, 1).map(|_| 0);
+    check(state, result);
+    push_fail(state, "read-only file")
+}
+
+unsafe extern "C" fn file_tostring(state: State) -> Int {
+    let description = match contents(state, 1) {
+        Ok(_) => format!("file ({:p})", lua_topointer(state, 1)),
+        Err(_) => "file (closed)".into(),
+    };
+    push_bytes(state, description.as_bytes());
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{overrides::lua_with, Data, Lua};
+
+    fn memory() -> MemoryVfs {
+        let mut vfs = MemoryVfs::new();
+        vfs.insert("greet.lua", "return { hello = function() return 'hi' end }")
+            .insert("lib/util.lua", "return 42")
+            .insert("data.txt", "first\nsecond\n3 4.5\nrest");
+        vfs
+    }
+
+    #[test]
+    fn normalize_resolves_dots() {
+        assert_eq!("a/c", normalize("./a/b/../c").unwrap());
+        assert_eq!("a", normalize("/a/").unwrap());
+    }
+
+    #[test]
+    fn normalize_leaving_root_returns_err() {
+        let error = normalize("a/../../b").unwrap_err();
+        assert_eq!(io::ErrorKind::PermissionDenied, error.kind());
+    }
+
+    #[test]
+    fn memory_list_returns_children() {
+        let vfs = memory();
+        assert_eq!(vec!["data.txt", "greet.lua", "lib"], vfs.list("").unwrap());
+        assert_eq!(vec!["util.lua"], vfs.list("lib").unwrap());
+        assert!(vfs.list("missing").is_err());
+    }
+
+    #[test]
+    fn dir_outside_root_returns_err() {
+        let root = std::env::temp_dir().join("llua_vfs_jail");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("inside.lua"), "return 1").unwrap();
+        let vfs = DirVfs::new(&root).unwrap();
+
+        assert!(vfs.exists("inside.lua"));
+        assert_eq!(b"return 1".to_vec(), vfs.read("/inside.lua").unwrap());
+        assert!(vfs.read("../llua_vfs_jail/inside.lua").is_err());
+        assert!(!vfs.exists("../../etc/passwd"));
+    }
+
+    #[test]
+    fn overlay_earlier_layer_wins() {
+        let mut top = MemoryVfs::new();
+        top.insert("a.lua", "top");
+        let mut bottom = MemoryVfs::new();
+        bottom.insert("a.lua", "bottom").insert("b.lua", "bottom");
+        let mut vfs = OverlayVfs::default();
+        vfs.push(top).push(bottom);
+
+        assert_eq!(b"top".to_vec(), vfs.read("a.lua").unwrap());
+        assert_eq!(b"bottom".to_vec(), vfs.read("b.lua").unwrap());
+        assert_eq!(vec!["a.lua", "b.lua"], vfs.list("").unwrap());
+        assert!(!vfs.exists("c.lua"));
+    }
+
+    #[test]
+    fn overlay_list_layer_error_returns_err() {
+        let mut vfs = OverlayVfs::default();
+        vfs.push(memory()).push(memory());
+
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            vfs.list("missing").unwrap_err().kind()
+        );
+        assert_eq!(
+            io::ErrorKind::PermissionDenied,
+            vfs.list("../outside").unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn require_searches_vfs() {
+        let mut lua = lua_with(|lua| lua.set_vfs(memory()));
+        lua.interpret("greeting = require('greet').hello(); answer = require('lib.util')")
+            .unwrap();
+
+        assert_eq!(
+            Data::String("hi".into()),
+            lua.get_global("greeting").unwrap()
+        );
+        assert_eq!(Data::from(42), lua.get_global("answer").unwrap());
+    }
+
+    #[test]
+    fn require_missing_module_lists_tried_files() {
+        let mut lua = lua_with(|lua| lua.set_vfs(memory()));
+        let Err(crate::Error::Runtime(message)) = lua.interpret("require('missing')") else {
+            panic!("require should fail");
+        };
+
+        assert!(message.contains("no file './missing.lua'"), "{message}");
+    }
+
+    #[test]
+    fn loadfile_and_dofile_read_vfs() {
+        let mut lua = lua_with(|lua| lua.set_vfs(memory()));
+        lua.interpret(
+            "answer = dofile('lib/util.lua') + loadfile('lib/util.lua')()
+             missing, message = loadfile('missing.lua')",
+        )
+        .unwrap();
+
+        assert_eq!(Data::from(84), lua.get_global("answer").unwrap());
+        assert_eq!(Data::Nil, lua.get_global("missing").unwrap());
+    }
+
+    #[test]
+    fn io_reads_vfs() {
+        let mut lua = lua_with(|lua| lua.set_vfs(memory()));
+        lua.interpret(
+            "local file = io.open('data.txt')
+             first, second = file:read('l', 'L')
+             a, b = file:read('n', 'n')
+             rest = file:read('a')
+             kind = io.type(file)
+             file:close()
+             closed = io.type(file)
+             count = 0
+             for line in io.lines('data.txt') do count = count + 1 end
+             writable, message = io.open('data.txt', 'w')
+             popen = io.popen
+             loadlib = package.loadlib",
+        )
+        .unwrap();
+
+        assert_eq!(
+            Data::String("first".into()),
+            lua.get_global("first").unwrap()
+        );
+        assert_eq!(
+            Data::String("second\n".into()),
+            lua.get_global("second").unwrap()
+        );
+        assert_eq!(Data::from(3), lua.get_global("a").unwrap());
+        assert_eq!(Data::Number(4.5), lua.get_global("b").unwrap());
+        assert_eq!(
+            Data::String("\nrest".into()),
+            lua.get_global("rest").unwrap()
+        );
+        assert_eq!(Data::String("file".into()), lua.get_global("kind").unwrap());
+        assert_eq!(
+            Data::String("closed file".into()),
+            lua.get_global("closed").unwrap()
+        );
+        assert_eq!(Data::from(4), lua.get_global("count").unwrap());
+        assert_eq!(Data::Nil, lua.get_global("writable").unwrap());
+        assert_eq!(Data::Nil, lua.get_global("popen").unwrap());
+        assert_eq!(Data::Nil, lua.get_global("loadlib").unwrap());
+    }
+
+    #[test]
+    fn activate_after_set_vfs_installs() {
+        let mut lua = Lua::new();
+        lua.set_vfs(memory());
+        lua.activate(&[Library::Basic]).unwrap();
+        lua.interpret("answer = dofile('lib/util.lua')").unwrap();
+
+        assert_eq!(Data::from(42), lua.get_global("answer").unwrap());
+    }
+}
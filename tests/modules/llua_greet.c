@@ -0,0 +1,17 @@
+/* A small C module for testing custom libraries and loading modules with `require`. */
+
+#include "lua.h"
+#include "lauxlib.h"
+
+static int greet(lua_State *L) {
+  const char *name = luaL_checkstring(L, 1);
+  lua_pushfstring(L, "hello, %s", name);
+  return 1;
+}
+
+int luaopen_llua_greet(lua_State *L) {
+  lua_newtable(L);
+  lua_pushcfunction(L, greet);
+  lua_setfield(L, -2, "greet");
+  return 1;
+}